@@ -38,6 +38,16 @@ return [
 			'url' => '/test/version',
 			'verb' => 'GET',
 		],
+		[
+			'name' => 'test#mapping',
+			'url' => '/test/mapping',
+			'verb' => 'GET',
+		],
+		[
+			'name' => 'room#access',
+			'url' => '/room',
+			'verb' => 'GET',
+		],
 		[
 			'name' => 'Auth#preAuth',
 			'url' => '/pre_auth',
@@ -8,4 +8,8 @@ fn main() {
     let appinfo = get_appinfo(&appinfo_path).expect("Failed to load appinfo");
     println!("cargo:rustc-env=NOTIFY_PUSH_VERSION={}", appinfo.version());
     println!("cargo:rustc-env=CARGO_PKG_VERSION={}", appinfo.version());
+
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/push.proto")
+        .expect("Failed to compile protobuf definitions");
 }
@@ -0,0 +1,58 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * @copyright Copyright (c) 2020 Robin Appelman <robin@icewind.nl>
+ *
+ * @license GNU AGPL version 3 or any later version
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+namespace OCA\NotifyPush;
+
+use OCA\NotifyPush\Queue\IQueue;
+use OCA\NotifyPush\Queue\RedisQueue;
+
+/**
+ * Whether the push server asked for fewer events because it can't keep up
+ */
+class BackPressure {
+	public const KEY = 'notify_push_backpressure';
+
+	/** Seconds a check is cached, so long running processes still notice when the state changes */
+	private const CACHE_TIME = 10;
+
+	private $queue;
+	/** @var bool */
+	private $active = false;
+	/** @var int */
+	private $checked = 0;
+
+	public function __construct(IQueue $queue) {
+		$this->queue = $queue;
+	}
+
+	public function isActive(): bool {
+		if (!$this->queue instanceof RedisQueue) {
+			return false;
+		}
+		if (time() - $this->checked >= self::CACHE_TIME) {
+			$this->active = (bool)$this->queue->getConnection()->exists(self::KEY);
+			$this->checked = time();
+		}
+		return $this->active;
+	}
+}
@@ -0,0 +1,55 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * @copyright Copyright (c) 2020 Robin Appelman <robin@icewind.nl>
+ *
+ * @license GNU AGPL version 3 or any later version
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+namespace OCA\NotifyPush\BackgroundJob;
+
+use OCA\NotifyPush\Queue\IQueue;
+use OCA\NotifyPush\Queue\RedisQueue;
+use OCP\AppFramework\Utility\ITimeFactory;
+use OCP\BackgroundJob\TimedJob;
+use OCP\IConfig;
+
+/**
+ * Publish a new test cookie, the push server compares the cookie it received with the one stored here
+ * to detect when messages from Nextcloud stop reaching it
+ */
+class Heartbeat extends TimedJob {
+	private $config;
+	private $queue;
+
+	public function __construct(ITimeFactory $time, IConfig $config, IQueue $queue) {
+		parent::__construct($time);
+		$this->config = $config;
+		$this->queue = $queue;
+		$this->setInterval(5 * 60);
+	}
+
+	protected function run($argument) {
+		if (!$this->queue instanceof RedisQueue) {
+			return;
+		}
+		$cookie = rand(1, pow(2, 30));
+		$this->queue->push('notify_test_cookie', $cookie);
+		$this->config->setAppValue('notify_push', 'cookie', (string)$cookie);
+	}
+}
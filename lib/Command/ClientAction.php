@@ -0,0 +1,71 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * @copyright Copyright (c) 2021 Robin Appelman <robin@icewind.nl>
+ *
+ * @license GNU AGPL version 3 or any later version
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+namespace OCA\NotifyPush\Command;
+
+use OCA\NotifyPush\Queue\IQueue;
+use Symfony\Component\Console\Command\Command;
+use Symfony\Component\Console\Input\InputArgument;
+use Symfony\Component\Console\Input\InputInterface;
+use Symfony\Component\Console\Input\InputOption;
+use Symfony\Component\Console\Output\OutputInterface;
+
+class ClientAction extends Command {
+	private const ACTIONS = ['reauth', 'reload_config'];
+
+	private $queue;
+
+	public function __construct(
+		IQueue $queue
+	) {
+		parent::__construct();
+		$this->queue = $queue;
+	}
+
+	/**
+	 * @return void
+	 */
+	protected function configure() {
+		$this
+			->setName('notify_push:client-action')
+			->setDescription('Ask connected clients to re-authenticate or reload their configuration')
+			->addArgument('action', InputArgument::REQUIRED, 'The action for the clients, "reauth" or "reload_config"')
+			->addOption('user', 'u', InputOption::VALUE_REQUIRED, 'Only send the action to the clients of this user');
+		parent::configure();
+	}
+
+	protected function execute(InputInterface $input, OutputInterface $output) {
+		$action = $input->getArgument('action');
+		if (!in_array($action, self::ACTIONS, true)) {
+			$output->writeln("<error>Unknown action $action, expected one of " . implode(', ', self::ACTIONS) . "</error>");
+			return 1;
+		}
+		$signal = ['action' => $action];
+		$user = $input->getOption('user');
+		if ($user) {
+			$signal['user'] = $user;
+		}
+		$this->queue->push("notify_signal", ['client_action' => $signal]);
+		return 0;
+	}
+}
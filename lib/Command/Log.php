@@ -24,6 +24,7 @@ declare(strict_types=1);
 namespace OCA\NotifyPush\Command;
 
 use OCA\NotifyPush\Queue\IQueue;
+use OCP\IConfig;
 use Symfony\Component\Console\Command\Command;
 use Symfony\Component\Console\Input\InputArgument;
 use Symfony\Component\Console\Input\InputInterface;
@@ -32,12 +33,15 @@ use Symfony\Component\Console\Output\OutputInterface;
 
 class Log extends Command {
 	private $queue;
+	private $config;
 
 	public function __construct(
-		IQueue $queue
+		IQueue $queue,
+		IConfig $config
 	) {
 		parent::__construct();
 		$this->queue = $queue;
+		$this->config = $config;
 	}
 
 	/**
@@ -48,22 +52,51 @@ class Log extends Command {
 			->setName('notify_push:log')
 			->setDescription('Temporarily set the log level of the push server')
 			->addOption("restore", "r", InputOption::VALUE_NONE, "restore the log level to the previous value")
+			->addOption("target", "t", InputOption::VALUE_REQUIRED, "only set the log level for a part of the push server: receive, send, auth, db or redis")
 			->addArgument("level", InputArgument::OPTIONAL, "the new log level to set");
 		parent::configure();
 	}
 
 	protected function execute(InputInterface $input, OutputInterface $output) {
 		$level = $input->getArgument("level");
-		if ($input->getOption("restore")) {
+		$target = $input->getOption("target");
+		if ($target) {
+			if ($input->getOption("restore")) {
+				$output->writeln("restoring log level for $target");
+				$this->queue->push("notify_config", ["log_target" => ["target" => $target]]);
+			} elseif ($level) {
+				$this->queue->push("notify_config", ["log_target" => ["target" => $target, "level" => $level]]);
+			}
+		} elseif ($input->getOption("restore")) {
 			$output->writeln("restoring log level");
-			$this->queue->push("notify_config", "log_restore");
+			$versions = $this->getVersions();
+			$version = array_pop($versions);
+			if ($version) {
+				$this->setVersions($versions);
+				$this->queue->push("notify_config", ["restore_log_spec" => $version]);
+			} else {
+				$this->queue->push("notify_config", "log_restore");
+			}
 		} elseif ($level) {
 			// by default dont touch the log level of the libraries
 			if (!strpos($level, "=") and $level !== "trace") {
 				$level = "notify_push=$level";
 			}
-			$this->queue->push("notify_config", ["log_spec" => $level]);
+			// the version lets the push server ignore the event if it's received twice
+			$version = (int)(microtime(true) * 1000);
+			$versions = $this->getVersions();
+			$versions[] = $version;
+			$this->setVersions($versions);
+			$this->queue->push("notify_config", ["log_spec" => ["spec" => $level, "version" => $version]]);
 		}
 		return 0;
 	}
+
+	private function getVersions(): array {
+		return json_decode($this->config->getAppValue('notify_push', 'log_spec_versions', '[]'), true) ?: [];
+	}
+
+	private function setVersions(array $versions): void {
+		$this->config->setAppValue('notify_push', 'log_spec_versions', json_encode($versions));
+	}
 }
@@ -66,6 +66,13 @@ class Metrics extends Command {
 					$output->writeln("<error>Invalid metrics received from push server</error>");
 					return 1;
 				}
+			} else {
+				$metrics = $this->getSnapshotMetrics($redis);
+				if ($metrics) {
+					$output->writeln("<comment>No response from push server, showing the last metrics snapshot</comment>");
+				}
+			}
+			if ($metrics) {
 				$output->writeln("Active connection count: " . $metrics['active_connection_count']);
 				$output->writeln("Total connection count: " . $metrics['total_connection_count']);
 				$output->writeln("Total database query count: " . $metrics['mapping_query_count']);
@@ -81,4 +88,34 @@ class Metrics extends Command {
 			return 1;
 		}
 	}
+
+	/**
+	 * Sum the metrics snapshots periodically written by all running push servers
+	 *
+	 * @param \Redis|\RedisCluster $redis
+	 * @return array|null
+	 */
+	private function getSnapshotMetrics($redis): ?array {
+		$keys = $redis->keys("notify_push_metrics_snapshot_*");
+		if (!$keys) {
+			return null;
+		}
+		$total = [
+			'active_connection_count' => 0,
+			'total_connection_count' => 0,
+			'mapping_query_count' => 0,
+			'events_received' => 0,
+			'messages_send' => 0,
+		];
+		foreach ($keys as $key) {
+			$snapshot = json_decode((string)$redis->get($key), true);
+			if (!is_array($snapshot)) {
+				continue;
+			}
+			foreach ($total as $name => $value) {
+				$total[$name] = $value + ($snapshot[$name] ?? 0);
+			}
+		}
+		return $total;
+	}
 }
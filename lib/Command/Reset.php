@@ -26,6 +26,7 @@ namespace OCA\NotifyPush\Command;
 use OCA\NotifyPush\Queue\IQueue;
 use Symfony\Component\Console\Command\Command;
 use Symfony\Component\Console\Input\InputInterface;
+use Symfony\Component\Console\Input\InputOption;
 use Symfony\Component\Console\Output\OutputInterface;
 
 class Reset extends Command {
@@ -44,12 +45,18 @@ class Reset extends Command {
 	protected function configure() {
 		$this
 			->setName('notify_push:reset')
-			->setDescription('Cancel all active connections to the push server');
+			->setDescription('Cancel all active connections to the push server')
+			->addOption('user', 'u', InputOption::VALUE_REQUIRED, 'Only cancel the connections of this user');
 		parent::configure();
 	}
 
 	protected function execute(InputInterface $input, OutputInterface $output) {
-		$this->queue->push("notify_signal", "reset");
+		$user = $input->getOption('user');
+		if ($user) {
+			$this->queue->push("notify_signal", ['reset_user' => $user]);
+		} else {
+			$this->queue->push("notify_signal", "reset");
+		}
 		return 0;
 	}
 }
@@ -0,0 +1,89 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * @copyright Copyright (c) 2021 Robin Appelman <robin@icewind.nl>
+ *
+ * @license GNU AGPL version 3 or any later version
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+namespace OCA\NotifyPush\Controller;
+
+use OCA\NotifyPush\Event\RoomAccessEvent;
+use OCA\NotifyPush\Queue\IQueue;
+use OCA\NotifyPush\Queue\RedisQueue;
+use OCP\AppFramework\Controller;
+use OCP\AppFramework\Http;
+use OCP\AppFramework\Http\DataResponse;
+use OCP\EventDispatcher\IEventDispatcher;
+use OCP\Files\IRootFolder;
+use OCP\IRequest;
+use OCP\IUserManager;
+
+class RoomController extends Controller {
+	private $queue;
+	private $eventDispatcher;
+	private $userManager;
+	private $rootFolder;
+
+	public function __construct(
+		IRequest $request,
+		IQueue $queue,
+		IEventDispatcher $eventDispatcher,
+		IUserManager $userManager,
+		IRootFolder $rootFolder
+	) {
+		parent::__construct('notify_push', $request);
+		$this->queue = $queue;
+		$this->eventDispatcher = $eventDispatcher;
+		$this->userManager = $userManager;
+		$this->rootFolder = $rootFolder;
+	}
+
+	/**
+	 * Check whether a user can join a room, used by the push server before relaying messages between clients
+	 *
+	 * Users can join `file:<file id>` rooms for files they have access to, other rooms need to be granted
+	 * by an app listening to the RoomAccessEvent.
+	 * The push server puts a short-lived token in redis that needs to be provided to access this information
+	 *
+	 * @NoAdminRequired
+	 * @PublicPage
+	 * @NoCSRFRequired
+	 */
+	public function access(string $user, string $room, string $token): DataResponse {
+		if (!$this->queue instanceof RedisQueue) {
+			return new DataResponse(false, Http::STATUS_NOT_FOUND);
+		}
+		if (!$this->queue->getConnection()->exists("notify_push_room_token_$token")) {
+			return new DataResponse(false, Http::STATUS_FORBIDDEN);
+		}
+		if (!$this->userManager->userExists($user)) {
+			return new DataResponse(false);
+		}
+
+		if (strpos($room, 'file:') === 0) {
+			$fileId = (int)substr($room, strlen('file:'));
+			$nodes = $this->rootFolder->getUserFolder($user)->getById($fileId);
+			return new DataResponse(count($nodes) > 0);
+		}
+
+		$event = new RoomAccessEvent($user, $room);
+		$this->eventDispatcher->dispatchTyped($event);
+		return new DataResponse($event->isAllowed());
+	}
+}
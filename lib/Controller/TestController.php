@@ -29,7 +29,9 @@ use OCA\NotifyPush\Queue\RedisQueue;
 use OCP\App\IAppManager;
 use OCP\AppFramework\Controller;
 use OCP\AppFramework\Http\DataDisplayResponse;
+use OCP\AppFramework\Http;
 use OCP\AppFramework\Http\DataResponse;
+use OCP\Files\Config\IUserMountCache;
 use OCP\IConfig;
 use OCP\IRequest;
 
@@ -37,17 +39,20 @@ class TestController extends Controller {
 	private $config;
 	private $queue;
 	private $appManager;
+	private $mountCache;
 
 	public function __construct(
 		IRequest $request,
 		IConfig $config,
 		IQueue $queue,
-		IAppManager $appManager
+		IAppManager $appManager,
+		IUserMountCache $mountCache
 	) {
 		parent::__construct('notify_push', $request);
 		$this->config = $config;
 		$this->queue = $queue;
 		$this->appManager = $appManager;
+		$this->mountCache = $mountCache;
 	}
 
 	/**
@@ -88,4 +93,34 @@ class TestController extends Controller {
 			$this->queue->getConnection()->set("notify_push_app_version", $this->appManager->getAppVersion('notify_push'));
 		}
 	}
+
+	/**
+	 * List the users that have access to a path in a storage, used by the push server to verify it's storage mapping
+	 *
+	 * The push server puts a short-lived token in redis that needs to be provided to access this information
+	 *
+	 * @NoAdminRequired
+	 * @PublicPage
+	 * @NoCSRFRequired
+	 */
+	public function mapping(int $storage, string $path, string $token): DataResponse {
+		if (!$this->queue instanceof RedisQueue) {
+			return new DataResponse([], Http::STATUS_NOT_FOUND);
+		}
+		$expected = $this->queue->getConnection()->get("notify_push_mapping_token");
+		if (!is_string($expected) || $expected === '' || !hash_equals($expected, $token)) {
+			return new DataResponse([], Http::STATUS_FORBIDDEN);
+		}
+
+		$users = [];
+		foreach ($this->mountCache->getMountsForStorageId($storage) as $mount) {
+			$root = $mount->getInternalPath();
+			// only the root itself and paths inside it, not siblings that start with the same name
+			if ($root === '' || $path === $root || strpos($path, rtrim($root, '/') . '/') === 0) {
+				$users[] = $mount->getUser()->getUID();
+			}
+		}
+
+		return new DataResponse(array_values(array_unique($users)));
+	}
 }
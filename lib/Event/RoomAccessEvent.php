@@ -0,0 +1,60 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * @copyright Copyright (c) 2021 Robin Appelman <robin@icewind.nl>
+ *
+ * @license GNU AGPL version 3 or any later version
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+namespace OCA\NotifyPush\Event;
+
+use OCP\EventDispatcher\Event;
+
+/**
+ * Dispatched when a client wants to join a room to relay ephemeral messages to other clients
+ *
+ * Rooms are opaque identifiers, apps that use rooms listen to this event and grant access for the rooms they own.
+ * Access is denied unless a listener grants it.
+ */
+class RoomAccessEvent extends Event {
+	private $user;
+	private $room;
+	private $allowed = false;
+
+	public function __construct(string $user, string $room) {
+		parent::__construct();
+		$this->user = $user;
+		$this->room = $room;
+	}
+
+	public function getUser(): string {
+		return $this->user;
+	}
+
+	public function getRoom(): string {
+		return $this->room;
+	}
+
+	public function allow(): void {
+		$this->allowed = true;
+	}
+
+	public function isAllowed(): bool {
+		return $this->allowed;
+	}
+}
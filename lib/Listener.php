@@ -39,16 +39,33 @@ use OCP\Share\IShare;
 
 class Listener implements IConsumer, IApp, INotifier, IDismissableNotifier {
 	private $queue;
+	private $backPressure;
+	/** @var array<string, bool> folders that already had a change send while under back-pressure */
+	private $sentFolders = [];
 
-	public function __construct(IQueue $queue) {
+	public function __construct(IQueue $queue, BackPressure $backPressure) {
 		$this->queue = $queue;
+		$this->backPressure = $backPressure;
 	}
 
 	public function cacheListener(Event $event): void {
 		if ($event instanceof ICacheEvent) {
+			$path = $event->getPath();
+			if ($this->backPressure->isActive()) {
+				// a single change for the parent folder is enough for clients to find the changed files
+				$path = dirname($path);
+				if ($path === '.') {
+					$path = '';
+				}
+				$key = $event->getStorageId() . '/' . $path;
+				if (isset($this->sentFolders[$key])) {
+					return;
+				}
+				$this->sentFolders[$key] = true;
+			}
 			$this->queue->push('notify_storage_update', [
 				'storage' => $event->getStorageId(),
-				'path' => $event->getPath(),
+				'path' => $path,
 			]);
 		}
 	}
@@ -77,12 +94,17 @@ class Listener implements IConsumer, IApp, INotifier, IDismissableNotifier {
 	public function receive(IEvent $event) {
 		$this->queue->push('notify_activity', [
 			'user' => $event->getAffectedUser(),
+			'activity_type' => $event->getType(),
+			'object_type' => $event->getObjectType(),
+			'object_id' => $event->getObjectId(),
 		]);
 	}
 
 	public function notify(INotification $notification): void {
 		$this->queue->push('notify_notification', [
 			'user' => $notification->getUser(),
+			'app' => $notification->getApp(),
+			'subject' => $notification->getSubject(),
 		]);
 	}
 
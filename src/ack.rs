@@ -0,0 +1,83 @@
+use crate::message::MessageType;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Maximum number of messages waiting for an acknowledgement per connection, the oldest is given up first
+pub const MAX_UNACKED: usize = 64;
+/// Number of times a message is send again before giving up on it
+pub const MAX_RETRANSMITS: u32 = 5;
+/// Time to wait for the first acknowledgement, doubled after every retransmit
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+struct Unacked {
+    message: MessageType,
+    retransmits: u32,
+    retry_at: Instant,
+}
+
+/// Custom messages send to a connection in acknowledgement mode that the client hasn't confirmed yet
+///
+/// Every message gets an id the client has to confirm with `ack <id>`, messages that aren't confirmed in time
+/// are send again with the same id, waiting twice as long after every attempt.
+#[derive(Default)]
+pub struct AckTracker {
+    next_id: u64,
+    unacked: BTreeMap<u64, Unacked>,
+}
+
+impl AckTracker {
+    /// Start waiting for the acknowledgement of a message, returning the id for the message
+    ///
+    /// Returns the number of messages that were given up to make room as well.
+    pub fn track(&mut self, message: MessageType) -> (u64, usize) {
+        let mut given_up = 0;
+        while self.unacked.len() >= MAX_UNACKED {
+            let oldest = *self.unacked.keys().next().unwrap();
+            self.unacked.remove(&oldest);
+            given_up += 1;
+        }
+        self.next_id += 1;
+        self.unacked.insert(
+            self.next_id,
+            Unacked {
+                message,
+                retransmits: 0,
+                retry_at: Instant::now() + INITIAL_BACKOFF,
+            },
+        );
+        (self.next_id, given_up)
+    }
+
+    /// Confirm that the client received a message, returns `false` for unknown ids
+    pub fn ack(&mut self, id: u64) -> bool {
+        self.unacked.remove(&id).is_some()
+    }
+
+    /// The next time a message has to be send again
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.unacked.values().map(|unacked| unacked.retry_at).min()
+    }
+
+    /// Take the messages that have to be send again, returning the number of messages that were given up as well
+    pub fn due(&mut self, now: Instant) -> (Vec<(u64, MessageType)>, usize) {
+        let mut retransmit = Vec::new();
+        let mut given_up = Vec::new();
+        for (id, unacked) in self.unacked.iter_mut() {
+            if unacked.retry_at > now {
+                continue;
+            }
+            if unacked.retransmits >= MAX_RETRANSMITS {
+                given_up.push(*id);
+                continue;
+            }
+            unacked.retransmits += 1;
+            unacked.retry_at = now + INITIAL_BACKOFF * 2u32.pow(unacked.retransmits);
+            retransmit.push((*id, unacked.message.clone()));
+        }
+        for id in &given_up {
+            self.unacked.remove(id);
+        }
+        (retransmit, given_up.len())
+    }
+}
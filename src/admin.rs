@@ -0,0 +1,249 @@
+use crate::metrics::LAGGED_MESSAGES;
+use crate::pre_auth::TokenHash;
+use crate::presence::UserPresence;
+use crate::redis::WriteCommand;
+use crate::{request_limit, App, UserId};
+use color_eyre::{eyre::WrapErr, Result};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// Only let requests through that provide the configured admin token as bearer token
+///
+/// Requests without a valid token are rejected as if the route doesn't exist
+pub fn admin_auth(app: Arc<App>) -> impl Filter<Extract = (Arc<App>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |auth: Option<String>| {
+        let app = app.clone();
+        async move {
+            let provided = auth
+                .as_deref()
+                .and_then(|auth| auth.strip_prefix("Bearer "));
+            match (app.admin_token.as_deref(), provided) {
+                (Some(expected), Some(provided))
+                    if expected.as_bytes().ct_eq(provided.as_bytes()).into() =>
+                {
+                    Ok(app)
+                }
+                _ => Err(warp::reject::not_found()),
+            }
+        }
+    })
+}
+
+/// All routes under `/admin`
+pub fn routes(app: Arc<App>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let auth = admin_auth(app.clone());
+
+    let mapping = warp::path!("mapping" / u32)
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<MappingQuery>())
+        .and_then(
+            |storage: u32, app: Arc<App>, query: MappingQuery| async move {
+                let reply = match mapping_report(&app, storage, query).await {
+                    Ok(report) => {
+                        warp::reply::with_status(warp::reply::json(&report), StatusCode::OK)
+                    }
+                    Err(e) => {
+                        log::error!("error while verifying mapping for {}: {:#}", storage, e);
+                        warp::reply::with_status(
+                            warp::reply::json(&format!("{:#}", e)),
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                        )
+                    }
+                };
+                Result::<_, Infallible>::Ok(reply)
+            },
+        );
+
+    // alternative to publishing a pre_auth event over redis for trusted services
+    let pre_auth = warp::path!("pre_auth")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json::<PreAuthRequest>())
+        .map(|app: Arc<App>, request: PreAuthRequest| {
+            let token = random_token();
+            log::debug!("Issued pre auth token for {} over http", request.user);
+            app.pre_auth.insert(TokenHash::new(&token), request.user);
+            token
+        });
+
+    let lagged = warp::path!("lagged")
+        .and(warp::get())
+        .and(auth.clone())
+        .map(|_app: Arc<App>| warp::reply::json(&lagged_report()));
+
+    let presence = warp::path!("presence")
+        .and(warp::get())
+        .and(auth.clone())
+        .and(warp::query::<PresenceQuery>())
+        .map(|app: Arc<App>, query: PresenceQuery| match query.user {
+            Some(user) => warp::reply::json(&user_presence(&app, user)),
+            None => warp::reply::json(&presence_report(&app)),
+        });
+
+    warp::path("admin")
+        .and(request_limit(app))
+        .and(mapping.or(pre_auth).or(lagged).or(presence))
+}
+
+pub(crate) fn random_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct PreAuthRequest {
+    user: UserId,
+}
+
+#[derive(Debug, Deserialize)]
+struct MappingQuery {
+    #[serde(default)]
+    path: String,
+    /// Compare the result with the users Nextcloud reports for the path
+    #[serde(default)]
+    compare: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct MappingReport {
+    storage: u32,
+    path: String,
+    users: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comparison: Option<MappingComparison>,
+}
+
+#[derive(Debug, Serialize)]
+struct MappingComparison {
+    nextcloud: Vec<String>,
+    /// Users that Nextcloud reports but the push server doesn't
+    missing: Vec<String>,
+    /// Users that the push server reports but Nextcloud doesn't
+    extra: Vec<String>,
+}
+
+async fn mapping_report(app: &App, storage: u32, query: MappingQuery) -> Result<MappingReport> {
+    let users = app
+        .storage_mapping
+        .get_user_names_for_storage_path(storage, &query.path)
+        .await?;
+
+    let comparison = if query.compare {
+        let nextcloud = nextcloud_storage_users(app, storage, &query.path).await?;
+        let ours: BTreeSet<&String> = users.iter().collect();
+        let theirs: BTreeSet<&String> = nextcloud.iter().collect();
+        Some(MappingComparison {
+            missing: theirs
+                .difference(&ours)
+                .map(|user| (*user).clone())
+                .collect(),
+            extra: ours
+                .difference(&theirs)
+                .map(|user| (*user).clone())
+                .collect(),
+            nextcloud,
+        })
+    } else {
+        None
+    };
+
+    Ok(MappingReport {
+        storage,
+        path: query.path,
+        users,
+        comparison,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct LaggedUser {
+    user: String,
+    dropped: u64,
+}
+
+/// Users that lost messages because a connection couldn't keep up, most affected first
+fn lagged_report() -> Vec<LaggedUser> {
+    let mut users: Vec<LaggedUser> = LAGGED_MESSAGES
+        .iter()
+        .map(|entry| LaggedUser {
+            user: entry.key().to_string(),
+            dropped: *entry.value(),
+        })
+        .collect();
+    users.sort_unstable_by(|a, b| b.dropped.cmp(&a.dropped));
+    users
+}
+
+#[derive(Debug, Deserialize)]
+struct PresenceQuery {
+    user: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OnlineUser {
+    user: String,
+    online: bool,
+    #[serde(flatten)]
+    presence: Option<UserPresence>,
+}
+
+fn user_presence(app: &App, user: String) -> OnlineUser {
+    let presence = app.connections.presence().get(&UserId::from(user.as_str()));
+    OnlineUser {
+        user,
+        online: presence.is_some(),
+        presence,
+    }
+}
+
+/// All users with an open connection, longest online first
+fn presence_report(app: &App) -> Vec<OnlineUser> {
+    let mut users: Vec<OnlineUser> = app
+        .connections
+        .presence()
+        .online()
+        .into_iter()
+        .map(|(user, presence)| OnlineUser {
+            user: user.to_string(),
+            online: true,
+            presence: Some(presence),
+        })
+        .collect();
+    users.sort_unstable_by_key(|user| user.presence.map(|presence| presence.online_since));
+    users
+}
+
+async fn nextcloud_storage_users(app: &App, storage: u32, path: &str) -> Result<Vec<String>> {
+    let token = random_token();
+    app.redis_writer
+        .write(vec![WriteCommand::Set {
+            key: "notify_push_mapping_token".into(),
+            value: token.clone(),
+            ttl: Some(60),
+        }])
+        .await
+        .wrap_err("Failed to set mapping token")?;
+    let result = app
+        .nc_client
+        .get_storage_users(storage, path, &token)
+        .await
+        .wrap_err("Failed to get storage mapping from Nextcloud");
+    app.redis_writer
+        .queue(vec![WriteCommand::Del {
+            key: "notify_push_mapping_token".into(),
+        }])
+        .await;
+    result
+}
@@ -0,0 +1,112 @@
+use crate::limits::LimitError;
+use crate::metrics::METRICS;
+use crate::protocol::ProtocolVersion;
+use serde_json::json;
+use std::time::Duration;
+use thiserror::Error;
+use warp::http::StatusCode;
+use warp::ws::Message;
+
+/// Why a client couldn't be authenticated or admitted
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("Invalid authentication message")]
+    InvalidMessage,
+    #[error("Client disconnected during authentication")]
+    Disconnected,
+    /// The Nextcloud server couldn't be reached to verify the credentials
+    #[error("Nextcloud server is unreachable")]
+    NextcloudUnreachable(String),
+    /// The Nextcloud server responded with an error while verifying the credentials
+    #[error("Nextcloud server error")]
+    NextcloudError(String),
+    /// Verifying credentials is paused because too many requests to Nextcloud failed
+    #[error("Nextcloud server is overloaded, retry after {}s", .0.as_secs())]
+    RateLimited(Duration),
+    #[error(transparent)]
+    LimitExceeded(#[from] LimitError),
+}
+
+impl AuthError {
+    /// Stable identifier for the failure, for clients and metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::InvalidMessage => "invalid_message",
+            AuthError::Disconnected => "disconnected",
+            AuthError::NextcloudUnreachable(_) => "nextcloud_unreachable",
+            AuthError::NextcloudError(_) => "nextcloud_error",
+            AuthError::RateLimited(_) => "rate_limited",
+            AuthError::LimitExceeded(_) => "limit_exceeded",
+        }
+    }
+
+    /// Details for the logs that aren't send to the client
+    fn details(&self) -> Option<&str> {
+        match self {
+            AuthError::NextcloudUnreachable(details) | AuthError::NextcloudError(details) => {
+                Some(details)
+            }
+            _ => None,
+        }
+    }
+
+    /// The error frame send to websocket clients
+    ///
+    /// Version 1 keeps the existing format, connections that negotiated version 2 during the handshake get a json object
+    /// with the reason so clients can show a matching error message.
+    pub fn to_message(&self, version: ProtocolVersion) -> Message {
+        match (version, self) {
+            (ProtocolVersion::V1, AuthError::LimitExceeded(e)) => Message::text(e.to_string()),
+            (ProtocolVersion::V1, _) => Message::text(format!("err: {}", self)),
+            (ProtocolVersion::V2, _) => Message::text(self.to_json().to_string()),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = json!({
+            "type": "error",
+            "reason": self.reason(),
+            "message": self.to_string(),
+        });
+        if let AuthError::RateLimited(retry_after) = self {
+            json["retry_after"] = json!(retry_after.as_secs());
+        }
+        json
+    }
+
+    /// Status code for http based connections
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            AuthError::InvalidMessage | AuthError::Disconnected => StatusCode::BAD_REQUEST,
+            AuthError::NextcloudUnreachable(_) | AuthError::NextcloudError(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            AuthError::RateLimited(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::LimitExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Log the failure and count it in the metrics
+    pub fn record(&self, client: &str) {
+        METRICS.add_auth_failure(self.reason());
+        match (self, self.details()) {
+            (AuthError::LimitExceeded(_), _) | (AuthError::Disconnected, _) => {
+                log::info!("rejecting {} ({}): {}", client, self.reason(), self)
+            }
+            (_, Some(details)) => {
+                log::warn!(
+                    "rejecting {} ({}): {}: {}",
+                    client,
+                    self.reason(),
+                    self,
+                    details
+                )
+            }
+            (_, None) => log::warn!("rejecting {} ({}): {}", client, self.reason(), self),
+        }
+    }
+}
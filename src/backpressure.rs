@@ -0,0 +1,114 @@
+use crate::redis::WriteCommand;
+use crate::App;
+use futures::future::select;
+use futures::pin_mut;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tokio::time::interval;
+
+/// Key that is set while the push server is overloaded, checked by the Nextcloud app before publishing file changes
+pub const BACKPRESSURE_KEY: &str = "notify_push_backpressure";
+
+/// Channel the changes in back-pressure are published to
+pub const BACKPRESSURE_CHANNEL: &str = "notify_push_backpressure";
+
+/// How often the number of waiting events is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the key is refreshed while the push server stays overloaded
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The key expires when the push server stops refreshing it, e.g. because it crashed while overloaded
+const KEY_TTL: usize = 30;
+
+/// Whether the push server is overloaded, based on the number of events waiting to be handled
+///
+/// Back-pressure is only lifted once the number of waiting events drops below half of the threshold,
+/// so a queue that hovers around the threshold doesn't toggle it on every check.
+pub struct BackPressure {
+    threshold: usize,
+    active: bool,
+}
+
+impl BackPressure {
+    pub fn new(threshold: usize) -> Self {
+        BackPressure {
+            threshold,
+            active: false,
+        }
+    }
+
+    /// Update the state with the current number of waiting events, returns true if the state changed
+    pub fn update(&mut self, pending: usize) -> bool {
+        let active = if self.active {
+            pending.saturating_mul(2) >= self.threshold
+        } else {
+            pending >= self.threshold
+        };
+        let changed = active != self.active;
+        self.active = active;
+        changed
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+}
+
+/// Ask Nextcloud to send fewer events while the push server can't keep up with them
+///
+/// While the number of events waiting to be handled is above the threshold, [`BACKPRESSURE_KEY`] is set in redis.
+/// The Nextcloud app checks the key and only sends a single change per folder instead of every changed file until it is removed.
+pub async fn backpressure_loop(app: Arc<App>, threshold: usize, cancel: oneshot::Receiver<()>) {
+    let loop_ = async move {
+        let mut state = BackPressure::new(threshold);
+        let mut last_refresh = Instant::now();
+        let mut interval = interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let pending = app.event_order.pending_count();
+            let changed = state.update(pending);
+            if changed {
+                if state.is_active() {
+                    log::warn!(
+                        "{} events are waiting to be handled, asking Nextcloud to send fewer events",
+                        pending
+                    );
+                } else {
+                    log::info!(
+                        "Event queue is back to {} events, lifting back-pressure",
+                        pending
+                    );
+                }
+            }
+            let refresh = state.is_active() && last_refresh.elapsed() >= REFRESH_INTERVAL;
+            if !changed && !refresh {
+                continue;
+            }
+            last_refresh = Instant::now();
+            let key = if state.is_active() {
+                WriteCommand::Set {
+                    key: BACKPRESSURE_KEY.into(),
+                    value: json!({"pending": pending, "threshold": threshold}).to_string(),
+                    ttl: Some(KEY_TTL),
+                }
+            } else {
+                WriteCommand::Del {
+                    key: BACKPRESSURE_KEY.into(),
+                }
+            };
+            let mut commands = vec![key];
+            if changed {
+                commands.push(WriteCommand::Publish {
+                    channel: BACKPRESSURE_CHANNEL.into(),
+                    message: json!({"active": state.is_active(), "pending": pending}).to_string(),
+                });
+            }
+            app.redis_writer.queue(commands).await;
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
@@ -0,0 +1,71 @@
+use crate::event::channels;
+use crate::protocol::{ProtocolVersion, MAX_FRAME_SIZE};
+use crate::App;
+use serde::Serialize;
+
+/// Redis key the capabilities of a push server are written to
+pub fn capabilities_key(id: &str) -> String {
+    format!("notify_push_capabilities_{}", id)
+}
+
+/// Features of the push server the Nextcloud app can use to decide which events to publish
+///
+/// Written to redis for every instance together with the instance metadata, push servers sharing a redis server
+/// can run different versions or configurations, so only features listed by all of them are safe to use.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    /// Redis channels the push server listens on
+    pub events: Vec<&'static str>,
+    pub protocol_versions: Vec<u8>,
+    /// Optional features, including those that depend on the configuration
+    pub features: Vec<&'static str>,
+    pub limits: CapabilityLimits,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapabilityLimits {
+    pub max_frame_size: usize,
+    pub max_connections_per_user: Option<usize>,
+    pub max_messages_per_second: Option<u32>,
+}
+
+impl Capabilities {
+    pub fn new(app: &App) -> Self {
+        let mut features = vec![
+            "custom_tags",
+            "custom_topics",
+            "scheduled_messages",
+            "multi_user_messages",
+            "acknowledgements",
+        ];
+        if app.file_change_hints {
+            features.push("file_change_hints");
+        }
+        if app.rooms_enabled {
+            features.push("rooms");
+        }
+        if app.user_patterns {
+            features.push("user_patterns");
+        }
+        if app.dedup.is_some() {
+            features.push("deduplication");
+        }
+        if app.connections.has_offline_queue() {
+            features.push("offline_queue");
+        }
+        let limits = app.limits.config();
+
+        Capabilities {
+            version: env!("NOTIFY_PUSH_VERSION"),
+            events: channels().to_vec(),
+            protocol_versions: vec![ProtocolVersion::V1 as u8, ProtocolVersion::V2 as u8],
+            features,
+            limits: CapabilityLimits {
+                max_frame_size: MAX_FRAME_SIZE,
+                max_connections_per_user: limits.max_connections_per_user,
+                max_messages_per_second: limits.max_messages_per_second,
+            },
+        }
+    }
+}
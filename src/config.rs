@@ -1,25 +1,42 @@
 mod nc;
 
 use crate::config::nc::parse_config_file;
+use crate::forwarded::ForwardedForTrust;
+use crate::limits::{LimitsConfig, UserPattern};
+use crate::logging::TargetLevel;
+use crate::memory::MemorySize;
+use crate::message::{DebounceBounds, DebounceWindows};
+use crate::nc::ClientTls;
+use crate::pre_auth::TokenRequirements;
+use crate::protocol::{PingConfig, DEFAULT_PING_INTERVAL};
+use crate::registry::{RegistryConfig, RegistryKind};
+use crate::statsd::MetricsPush;
+use crate::storage_mapping::{DatabaseErrorStrategy, PathMatch, PathMatchMode};
+use crate::webhook::WebhookConfig;
 use color_eyre::eyre::ContextCompat;
 use color_eyre::{eyre::WrapErr, Report, Result};
 use derivative::Derivative;
 use redis::ConnectionInfo;
+use reqwest::Url;
 use sqlx::any::AnyConnectOptions;
 use std::convert::{TryFrom, TryInto};
 use std::env::var;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use structopt::{clap::AppSettings, StructOpt};
+use thiserror::Error;
 
 #[derive(StructOpt, Debug)]
 #[structopt(global_setting = AppSettings::ColoredHelp)]
 #[structopt(name = "notify_push")]
 pub struct Opt {
     /// The database connect url
-    #[structopt(long)]
+    #[structopt(long, parse(try_from_str = parse_database_url))]
     pub database_url: Option<AnyConnectOptions>,
     /// The redis connect url
     #[structopt(long)]
@@ -30,15 +47,24 @@ pub struct Opt {
     /// The url the push server can access the nextcloud instance on
     #[structopt(long)]
     pub nextcloud_url: Option<String>,
+    /// Alternative url for the nextcloud instance, used when the nextcloud url is unreachable, can be passed multiple times
+    #[structopt(long)]
+    pub nextcloud_fallback_url: Vec<String>,
     /// The port to serve the push server on
     #[structopt(short, long)]
     pub port: Option<u16>,
     /// The port to serve metrics on
     #[structopt(short = "m", long)]
     pub metrics_port: Option<u16>,
-    /// The ip address to bind to
+    /// The port to serve the gRPC api on, requires the `grpc` feature
+    #[structopt(long)]
+    pub grpc_port: Option<u16>,
+    /// Publish all messages to an mqtt broker, as `mqtt://[user:password@]host[:port]`, requires the `mqtt` feature
     #[structopt(long)]
-    pub bind: Option<IpAddr>,
+    pub mqtt_url: Option<Url>,
+    /// The address to bind to, either an ip address or an ip address with port, can be passed multiple times
+    #[structopt(long)]
+    pub bind: Vec<BindAddress>,
     /// Listen to a unix socket instead of TCP
     #[structopt(long)]
     pub socket_path: Option<PathBuf>,
@@ -48,6 +74,9 @@ pub struct Opt {
     /// Listen to a unix socket instead of TCP for serving metrics
     #[structopt(long)]
     pub metrics_socket_path: Option<PathBuf>,
+    /// Push the metrics to a statsd or datadog agent, as `statsd://host:port` or `datadog://host:port`
+    #[structopt(long)]
+    pub metrics_push: Option<MetricsPush>,
     /// Disable validating of certificates when connecting to the nextcloud instance
     #[structopt(long)]
     pub allow_self_signed: bool,
@@ -60,9 +89,18 @@ pub struct Opt {
     /// The log level
     #[structopt(long)]
     pub log_level: Option<String>,
+    /// Log level for a part of the push server as `<target>=<level>`, targets are `receive`, `send`, `auth`, `db` and `redis`, can be passed multiple times
+    #[structopt(long)]
+    pub log_target: Vec<TargetLevel>,
     /// Print the parsed config and exit
     #[structopt(long)]
     pub dump_config: bool,
+    /// Print information about the environment the push server runs in as json and exit
+    #[structopt(long)]
+    pub probe: bool,
+    /// Collect diagnostic information for bug reports, print it as json and exit
+    #[structopt(long)]
+    pub diagnostics: bool,
     /// Disable ansi escape sequences in logging output
     #[structopt(long)]
     pub no_ansi: bool,
@@ -75,20 +113,194 @@ pub struct Opt {
     /// TLS key
     #[structopt(long)]
     pub tls_key: Option<PathBuf>,
+    /// How to match updated paths against mount points, `prefix` or `exact`
+    #[structopt(long)]
+    pub path_match: Option<PathMatchMode>,
+    /// Normalize unicode in paths before matching them against mount points
+    #[structopt(long)]
+    pub path_normalize_unicode: bool,
+    /// Match paths against mount points case-insensitively, for databases with a case-insensitive collation
+    #[structopt(long)]
+    pub path_case_insensitive: bool,
+    /// Token required to access the admin endpoints, the admin endpoints are disabled if not set
+    #[structopt(long)]
+    pub admin_token: Option<String>,
+    /// Url to POST messages to, can be passed multiple times
+    #[structopt(long)]
+    pub webhook_url: Vec<Url>,
+    /// Message type to send to the webhooks, e.g. `notify_file`, can be passed multiple times (default: all)
+    #[structopt(long)]
+    pub webhook_event: Vec<String>,
+    /// Secret to sign webhook requests with
+    #[structopt(long)]
+    pub webhook_secret: Option<String>,
+    /// Maximum number of storage update events processed at the same time
+    #[structopt(long)]
+    pub storage_update_concurrency: Option<usize>,
+    /// Maximum number of other events processed at the same time
+    #[structopt(long)]
+    pub event_concurrency: Option<usize>,
+    /// File to save resume tokens to on shutdown, allowing clients to resume their sessions after a restart
+    #[structopt(long)]
+    pub state_file: Option<PathBuf>,
+    /// Named pipe or file to read events from for debugging, `-` for stdin
+    #[structopt(long)]
+    pub event_input: Option<PathBuf>,
+    /// Additional path prefix to serve all endpoints under, can be specified multiple times (default: push)
+    #[structopt(long)]
+    pub path_prefix: Vec<String>,
+    /// Which end of the forwarded-for chain contains the address of the client: `first` (default) or `last`
+    #[structopt(long)]
+    pub forwarded_for_trust: Option<ForwardedForTrust>,
+    /// Maximum number of open connections, 0 for unlimited (default: unlimited)
+    #[structopt(long)]
+    pub max_connections: Option<usize>,
+    /// Maximum number of open connections from a single ip, 0 for unlimited (default: unlimited)
+    #[structopt(long)]
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum number of open connections for a single user, 0 for unlimited (default: 64)
+    #[structopt(long)]
+    pub max_connections_per_user: Option<usize>,
+    /// Maximum number of messages send over a single connection per second, 0 for unlimited (default: unlimited)
+    #[structopt(long)]
+    pub max_messages_per_second: Option<u32>,
+    /// Maximum resident memory, e.g. `512M` or `2G`, new connections are refused above it and optional buffers are dropped when getting close (default: unlimited)
+    #[structopt(long)]
+    pub max_memory: Option<MemorySize>,
+    /// Maximum number of requests per second from a single ip to the test and admin endpoints, 0 for unlimited (default: 10)
+    #[structopt(long)]
+    pub max_requests_per_second: Option<u32>,
+    /// User name that isn't limited by the per user connection limit and message rate, `*` matches any characters, can be passed multiple times
+    #[structopt(long)]
+    pub limit_exempt_user: Vec<UserPattern>,
+    /// What to do with storage updates when the database is unavailable: `drop` (default), `buffer` or `cached`
+    #[structopt(long)]
+    pub database_error_strategy: Option<DatabaseErrorStrategy>,
+    /// Include the id, mtime and etag of the changed file in file notifications for protocol version 2 clients
+    #[structopt(long)]
+    pub file_change_hints: bool,
+    /// Publish to redis when users come online or go offline
+    #[structopt(long)]
+    pub presence: bool,
+    /// Let clients relay ephemeral messages to other clients in the same room, access to rooms is checked with Nextcloud
+    #[structopt(long)]
+    pub rooms: bool,
+    /// Allow broadcasts to users matching a name pattern, like `company-a-*`, which requires keeping the names of connected users
+    #[structopt(long)]
+    pub user_patterns: bool,
+    /// Keep messages for users without an open connection in redis for this many seconds and send them when the user connects, 0 to disable (default: 0)
+    #[structopt(long)]
+    pub offline_queue_ttl: Option<u64>,
+    /// Drop custom messages identical to one send to the same user within this many seconds, 0 to disable (default: 0)
+    #[structopt(long)]
+    pub dedup_window: Option<u64>,
+    /// Seconds between keep-alives on event streams and held long-poll requests, 0 to disable (default: ping interval for event streams, none for long-polling)
+    #[structopt(long)]
+    pub fallback_keep_alive: Option<u64>,
+    /// Send a banner describing the server limits to clients after authenticating
+    #[structopt(long)]
+    pub handshake_banner: bool,
+    /// Seconds between checks that the test cookie published by Nextcloud is received, 0 to disable (default: 60)
+    #[structopt(long)]
+    pub heartbeat_interval: Option<u64>,
+    /// Minimum number of seconds between two file change messages for a connection (default: 60)
+    #[structopt(long)]
+    pub debounce_file: Option<u64>,
+    /// Minimum number of seconds between two activity messages for a connection (default: 120)
+    #[structopt(long)]
+    pub debounce_activity: Option<u64>,
+    /// Minimum number of seconds between two notification messages for a connection (default: 30)
+    #[structopt(long)]
+    pub debounce_notification: Option<u64>,
+    /// Seconds without messages after which a connection is pinged, lower it below the idle timeout of proxies (default: 30)
+    #[structopt(long)]
+    pub ping_interval: Option<u64>,
+    /// Seconds a client has to reply to a ping before it's disconnected (default: the ping interval)
+    #[structopt(long)]
+    pub pong_grace: Option<u64>,
+    /// Round trip time in milliseconds above which messages for a connection are batched (default: disabled)
+    #[structopt(long)]
+    pub high_latency_threshold: Option<u64>,
+    /// Shortest debounce window in seconds clients can pick with the `debounce` command (default: 5)
+    #[structopt(long)]
+    pub debounce_min: Option<u64>,
+    /// Longest debounce window in seconds clients can pick with the `debounce` command (default: 600)
+    #[structopt(long)]
+    pub debounce_max: Option<u64>,
+    /// Number of events waiting to be handled above which Nextcloud is asked to send fewer events, 0 to disable (default: 1000)
+    #[structopt(long)]
+    pub backpressure_threshold: Option<usize>,
+    /// Map implementation used to track connections: `dashmap` (default) or `sharded`
+    #[structopt(long)]
+    pub connection_registry: Option<RegistryKind>,
+    /// Number of shards for the `sharded` connection registry (default: 64)
+    #[structopt(long)]
+    pub registry_shards: Option<usize>,
+    /// Minimum length of pre-auth tokens, 0 to disable (default: 16)
+    #[structopt(long)]
+    pub pre_auth_min_length: Option<usize>,
+    /// Minimum estimated entropy of pre-auth tokens in bits, 0 to disable (default: 48)
+    #[structopt(long)]
+    pub pre_auth_min_entropy: Option<f64>,
+    /// Pem file with additional CA certificates to trust when connecting to the nextcloud instance, reloaded on SIGHUP or when changed
+    #[structopt(long)]
+    pub nextcloud_ca_bundle: Option<PathBuf>,
+    /// Pem file with a client certificate and key to use when connecting to the nextcloud instance, reloaded on SIGHUP or when changed
+    #[structopt(long)]
+    pub nextcloud_client_cert: Option<PathBuf>,
+    /// Listening sockets passed by systemd, taken from the environment before the runtime is started
+    #[structopt(skip)]
+    pub inherited_sockets: Vec<RawFd>,
 }
 
-#[derive(Debug)]
+#[derive(Derivative)]
+#[derivative(Debug)]
 pub struct Config {
     pub database: AnyConnectOptions,
     pub database_prefix: String,
     pub redis: Vec<ConnectionInfo>,
     pub nextcloud_url: String,
+    pub nextcloud_fallback_urls: Vec<String>,
     pub metrics_bind: Option<Bind>,
+    pub grpc_bind: Vec<SocketAddr>,
+    #[derivative(Debug = "ignore")]
+    pub mqtt_url: Option<Url>,
+    pub metrics_push: Option<MetricsPush>,
     pub log_level: String,
+    pub log_targets: Vec<TargetLevel>,
     pub bind: Bind,
     pub allow_self_signed: bool,
     pub no_ansi: bool,
     pub tls: Option<TlsConfig>,
+    pub path_match: PathMatch,
+    #[derivative(Debug = "ignore")]
+    pub admin_token: Option<String>,
+    pub webhook: WebhookConfig,
+    pub storage_update_concurrency: usize,
+    pub event_concurrency: usize,
+    pub state_file: Option<PathBuf>,
+    pub event_input: Option<PathBuf>,
+    pub path_prefixes: Vec<String>,
+    pub forwarded_for_trust: ForwardedForTrust,
+    pub limits: LimitsConfig,
+    pub database_error_strategy: DatabaseErrorStrategy,
+    pub file_change_hints: bool,
+    pub presence: bool,
+    pub rooms: bool,
+    pub user_patterns: bool,
+    pub offline_queue_ttl: Option<Duration>,
+    pub dedup_window: Option<Duration>,
+    pub fallback_keep_alive: Option<Duration>,
+    pub handshake_banner: bool,
+    pub heartbeat_interval: Option<Duration>,
+    pub debounce_windows: DebounceWindows,
+    pub debounce_bounds: DebounceBounds,
+    pub ping: PingConfig,
+    pub backpressure_threshold: Option<usize>,
+    pub registry: RegistryConfig,
+    pub pre_auth_requirements: TokenRequirements,
+    pub nextcloud_ca_bundle: Option<PathBuf>,
+    pub nextcloud_client_cert: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -97,14 +309,47 @@ pub struct TlsConfig {
     pub cert: PathBuf,
 }
 
+impl TlsConfig {
+    /// Combine the configured certificate and key, both or neither need to be set
+    ///
+    /// The files are checked here since warp panics when it can't read them while starting the server.
+    fn from_parts(cert: Option<PathBuf>, key: Option<PathBuf>) -> Result<Option<Self>> {
+        match (cert, key) {
+            (Some(cert), Some(key)) => {
+                for path in [&cert, &key].iter() {
+                    File::open(path)
+                        .wrap_err_with(|| format!("Failed to open {}", path.to_string_lossy()))?;
+                }
+                Ok(Some(TlsConfig { cert, key }))
+            }
+            (None, None) => Ok(None),
+            _ => Err(Report::msg(
+                "Both a TLS certificate and key need to be configured to enable TLS",
+            )),
+        }
+    }
+}
+
 #[derive(Clone, Derivative)]
 #[derivative(Debug)]
 pub enum Bind {
-    Tcp(SocketAddr),
+    Tcp(Vec<SocketAddr>),
     Unix(
         PathBuf,
         #[derivative(Debug(format_with = "format_permissions"))] u32,
     ),
+    /// Listening socket passed in by systemd socket activation
+    Inherited(RawFd),
+}
+
+impl Bind {
+    /// The file descriptor of a socket passed by systemd
+    pub fn inherited_fd(&self) -> Option<RawFd> {
+        match self {
+            Bind::Inherited(fd) => Some(*fd),
+            _ => None,
+        }
+    }
 }
 
 fn format_permissions(permissions: &u32, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -114,8 +359,53 @@ fn format_permissions(permissions: &u32, f: &mut Formatter<'_>) -> std::fmt::Res
 impl Display for Bind {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Bind::Tcp(addr) => addr.fmt(f),
+            Bind::Tcp(addrs) => {
+                let addrs: Vec<String> = addrs.iter().map(ToString::to_string).collect();
+                addrs.join(", ").fmt(f)
+            }
             Bind::Unix(path, _) => path.to_string_lossy().fmt(f),
+            Bind::Inherited(fd) => write!(f, "socket passed by systemd (fd {})", fd),
+        }
+    }
+}
+
+/// Address to listen on, the port from the configured port is used when only an ip address is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindAddress {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl BindAddress {
+    fn with_default_port(self, port: u16) -> SocketAddr {
+        match self {
+            BindAddress::Ip(ip) => (ip, port).into(),
+            BindAddress::Socket(addr) => addr,
+        }
+    }
+
+    fn ip(self) -> IpAddr {
+        match self {
+            BindAddress::Ip(ip) => ip,
+            BindAddress::Socket(addr) => addr.ip(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid bind address {0}, expected an ip address or an ip address with port")]
+pub struct InvalidBindAddress(String);
+
+impl FromStr for BindAddress {
+    type Err = InvalidBindAddress;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            Ok(BindAddress::Socket(addr))
+        } else if let Ok(ip) = s.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(BindAddress::Ip(ip))
+        } else {
+            Err(InvalidBindAddress(s.to_string()))
         }
     }
 }
@@ -136,28 +426,55 @@ impl TryFrom<PartialConfig> for Config {
             })
             .transpose()?
             .unwrap_or(0o666);
-        let bind = match config.socket {
-            Some(socket) => Bind::Unix(socket, socket_permissions),
-            None => {
-                let ip = config
-                    .bind
-                    .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+
+        let ping_interval = config
+            .ping_interval
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_PING_INTERVAL);
+        let bind_addresses = if config.bind.is_empty() {
+            vec![BindAddress::Ip(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))]
+        } else {
+            config.bind
+        };
+
+        let bind = match (config.inherited_sockets.get(0), config.socket) {
+            (Some(fd), _) => Bind::Inherited(*fd),
+            (None, Some(socket)) => Bind::Unix(socket, socket_permissions),
+            (None, None) => {
                 let port = config.port.unwrap_or(7867);
-                Bind::Tcp((ip, port).into())
+                Bind::Tcp(
+                    bind_addresses
+                        .iter()
+                        .map(|addr| addr.with_default_port(port))
+                        .collect(),
+                )
             }
         };
 
         let metrics_bind = match (config.metrics_socket, config.metrics_port) {
-            (Some(socket), _) => Some(Bind::Unix(socket, socket_permissions)),
-            (None, Some(port)) => {
-                let ip = config
-                    .bind
-                    .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
-                Some(Bind::Tcp((ip, port).into()))
+            // a second socket from systemd is used for the metrics
+            _ if config.inherited_sockets.len() > 1 => {
+                Some(Bind::Inherited(config.inherited_sockets[1]))
             }
+            (Some(socket), _) => Some(Bind::Unix(socket, socket_permissions)),
+            (None, Some(port)) => Some(Bind::Tcp(
+                bind_addresses
+                    .iter()
+                    .map(|addr| (addr.ip(), port).into())
+                    .collect(),
+            )),
             _ => None,
         };
 
+        let grpc_bind = match config.grpc_port {
+            Some(port) => bind_addresses
+                .iter()
+                .map(|addr| (addr.ip(), port).into())
+                .collect(),
+            None => Vec::new(),
+        };
+
         let mut nextcloud_url = config
             .nextcloud_url
             .ok_or_else(|| Report::msg("No nextcloud url configured"))?;
@@ -174,17 +491,153 @@ impl TryFrom<PartialConfig> for Config {
                 .unwrap_or_else(|| String::from("oc_")),
             redis: config.redis,
             nextcloud_url,
+            nextcloud_fallback_urls: config.nextcloud_fallback_urls,
             metrics_bind,
+            grpc_bind,
+            mqtt_url: config.mqtt_url,
+            metrics_push: config.metrics_push,
             log_level: config.log_level.unwrap_or_else(|| String::from("warn")),
+            log_targets: config.log_targets,
             bind,
             allow_self_signed: config.allow_self_signed.unwrap_or(false),
             no_ansi: config.no_ansi.unwrap_or(false),
-            tls: config.tls,
+            tls: TlsConfig::from_parts(config.tls_cert, config.tls_key)?,
+            path_match: PathMatch {
+                mode: config.path_match.unwrap_or_default(),
+                normalize_unicode: config.path_normalize_unicode.unwrap_or(false),
+                case_insensitive: config.path_case_insensitive.unwrap_or(false),
+            },
+            admin_token: config.admin_token.filter(|token| !token.is_empty()),
+            webhook: WebhookConfig {
+                urls: config.webhook_urls,
+                events: config.webhook_events,
+                secret: config.webhook_secret.filter(|secret| !secret.is_empty()),
+            },
+            storage_update_concurrency: config.storage_update_concurrency.unwrap_or(32).max(1),
+            event_concurrency: config.event_concurrency.unwrap_or(512).max(1),
+            state_file: config.state_file,
+            event_input: config.event_input,
+            path_prefixes: if config.path_prefixes.is_empty() {
+                vec!["push".into()]
+            } else {
+                config
+                    .path_prefixes
+                    .iter()
+                    .map(|prefix| prefix.trim_matches('/').to_string())
+                    .filter(|prefix| !prefix.is_empty())
+                    .collect()
+            },
+            forwarded_for_trust: config.forwarded_for_trust.unwrap_or_default(),
+            limits: LimitsConfig {
+                max_connections: config.max_connections.filter(|limit| *limit > 0),
+                max_connections_per_ip: config.max_connections_per_ip.filter(|limit| *limit > 0),
+                max_connections_per_user: config
+                    .max_connections_per_user
+                    .or(LimitsConfig::default().max_connections_per_user)
+                    .filter(|limit| *limit > 0),
+                max_messages_per_second: config.max_messages_per_second.filter(|limit| *limit > 0),
+                max_memory: config
+                    .max_memory
+                    .map(|size| size.0)
+                    .filter(|limit| *limit > 0),
+                max_requests_per_second: config
+                    .max_requests_per_second
+                    .or(LimitsConfig::default().max_requests_per_second)
+                    .filter(|limit| *limit > 0),
+                exempt_users: config.limit_exempt_users,
+            },
+            database_error_strategy: config.database_error_strategy.unwrap_or_default(),
+            file_change_hints: config.file_change_hints.unwrap_or(false),
+            presence: config.presence.unwrap_or(false),
+            rooms: config.rooms.unwrap_or(false),
+            user_patterns: config.user_patterns.unwrap_or(false),
+            offline_queue_ttl: config
+                .offline_queue_ttl
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            dedup_window: config
+                .dedup_window
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            fallback_keep_alive: config
+                .fallback_keep_alive
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            handshake_banner: config.handshake_banner.unwrap_or(false),
+            heartbeat_interval: Some(config.heartbeat_interval.unwrap_or(60))
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            debounce_windows: DebounceWindows {
+                file: config
+                    .debounce_file
+                    .unwrap_or(DebounceWindows::default().file),
+                activity: config
+                    .debounce_activity
+                    .unwrap_or(DebounceWindows::default().activity),
+                notification: config
+                    .debounce_notification
+                    .unwrap_or(DebounceWindows::default().notification),
+            },
+            debounce_bounds: DebounceBounds {
+                min: config
+                    .debounce_min
+                    .map(Duration::from_secs)
+                    .unwrap_or(DebounceBounds::default().min),
+                max: config
+                    .debounce_max
+                    .map(Duration::from_secs)
+                    .unwrap_or(DebounceBounds::default().max),
+            },
+            ping: PingConfig {
+                interval: ping_interval,
+                pong_grace: config
+                    .pong_grace
+                    .filter(|seconds| *seconds > 0)
+                    .map(Duration::from_secs)
+                    .unwrap_or(ping_interval),
+                high_latency: config
+                    .high_latency_threshold
+                    .filter(|millis| *millis > 0)
+                    .map(Duration::from_millis),
+            },
+            backpressure_threshold: Some(config.backpressure_threshold.unwrap_or(1000))
+                .filter(|threshold| *threshold > 0),
+            registry: RegistryConfig {
+                kind: config.connection_registry.unwrap_or_default(),
+                shards: config.registry_shards.filter(|shards| *shards > 0),
+            },
+            pre_auth_requirements: TokenRequirements {
+                min_length: config
+                    .pre_auth_min_length
+                    .unwrap_or(TokenRequirements::default().min_length),
+                min_entropy: config
+                    .pre_auth_min_entropy
+                    .unwrap_or(TokenRequirements::default().min_entropy),
+            },
+            nextcloud_ca_bundle: config.nextcloud_ca_bundle,
+            nextcloud_client_cert: config.nextcloud_client_cert,
         })
     }
 }
 
 impl Config {
+    /// The nextcloud url followed by the fallback urls
+    pub fn nextcloud_urls(&self) -> Vec<String> {
+        std::iter::once(&self.nextcloud_url)
+            .chain(self.nextcloud_fallback_urls.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Tls options for connecting to the nextcloud instance
+    pub fn nextcloud_tls(&self) -> ClientTls {
+        ClientTls {
+            allow_self_signed: self.allow_self_signed,
+            ca_bundle: self.nextcloud_ca_bundle.clone(),
+            client_cert: self.nextcloud_client_cert.clone(),
+        }
+    }
+
     pub fn from_opt(opt: Opt) -> Result<Self> {
         let from_config = opt
             .config_file
@@ -197,6 +650,21 @@ impl Config {
 
         from_opt.merge(from_env).merge(from_config).try_into()
     }
+
+    /// Config with only the required options set and the defaults for everything else
+    pub fn with_defaults(
+        database: AnyConnectOptions,
+        redis: Vec<ConnectionInfo>,
+        nextcloud_url: String,
+    ) -> Result<Self> {
+        PartialConfig {
+            database: Some(database),
+            redis,
+            nextcloud_url: Some(nextcloud_url),
+            ..PartialConfig::default()
+        }
+        .try_into()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -205,30 +673,114 @@ struct PartialConfig {
     pub database_prefix: Option<String>,
     pub redis: Vec<ConnectionInfo>,
     pub nextcloud_url: Option<String>,
+    pub nextcloud_fallback_urls: Vec<String>,
     pub port: Option<u16>,
     pub metrics_port: Option<u16>,
     pub metrics_socket: Option<PathBuf>,
+    pub grpc_port: Option<u16>,
+    pub mqtt_url: Option<Url>,
+    pub metrics_push: Option<MetricsPush>,
     pub log_level: Option<String>,
-    pub bind: Option<IpAddr>,
+    pub log_targets: Vec<TargetLevel>,
+    pub bind: Vec<BindAddress>,
     pub socket: Option<PathBuf>,
     pub socket_permissions: Option<String>,
+    pub inherited_sockets: Vec<RawFd>,
     pub allow_self_signed: Option<bool>,
     pub no_ansi: Option<bool>,
-    pub tls: Option<TlsConfig>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub path_match: Option<PathMatchMode>,
+    pub path_normalize_unicode: Option<bool>,
+    pub path_case_insensitive: Option<bool>,
+    pub admin_token: Option<String>,
+    pub webhook_urls: Vec<Url>,
+    pub webhook_events: Vec<String>,
+    pub webhook_secret: Option<String>,
+    pub storage_update_concurrency: Option<usize>,
+    pub event_concurrency: Option<usize>,
+    pub state_file: Option<PathBuf>,
+    pub event_input: Option<PathBuf>,
+    pub path_prefixes: Vec<String>,
+    pub forwarded_for_trust: Option<ForwardedForTrust>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_connections_per_user: Option<usize>,
+    pub max_messages_per_second: Option<u32>,
+    pub max_memory: Option<MemorySize>,
+    pub max_requests_per_second: Option<u32>,
+    pub limit_exempt_users: Vec<UserPattern>,
+    pub database_error_strategy: Option<DatabaseErrorStrategy>,
+    pub file_change_hints: Option<bool>,
+    pub presence: Option<bool>,
+    pub rooms: Option<bool>,
+    pub user_patterns: Option<bool>,
+    pub offline_queue_ttl: Option<u64>,
+    pub dedup_window: Option<u64>,
+    pub fallback_keep_alive: Option<u64>,
+    pub handshake_banner: Option<bool>,
+    pub heartbeat_interval: Option<u64>,
+    pub debounce_file: Option<u64>,
+    pub debounce_activity: Option<u64>,
+    pub debounce_notification: Option<u64>,
+    pub ping_interval: Option<u64>,
+    pub pong_grace: Option<u64>,
+    pub high_latency_threshold: Option<u64>,
+    pub debounce_min: Option<u64>,
+    pub debounce_max: Option<u64>,
+    pub backpressure_threshold: Option<usize>,
+    pub connection_registry: Option<RegistryKind>,
+    pub registry_shards: Option<usize>,
+    pub pre_auth_min_length: Option<usize>,
+    pub pre_auth_min_entropy: Option<f64>,
+    pub nextcloud_ca_bundle: Option<PathBuf>,
+    pub nextcloud_client_cert: Option<PathBuf>,
 }
 
 impl PartialConfig {
     fn from_env() -> Result<Self> {
-        let database = parse_var("DATABASE_URL").wrap_err("Failed to parse DATABASE_URL")?;
+        let database = var("DATABASE_URL")
+            .ok()
+            .map(|url| parse_database_url(&url))
+            .transpose()
+            .wrap_err("Failed to parse DATABASE_URL")?;
         let database_prefix = var("DATABASE_PREFIX").ok();
         let redis = parse_var("REDIS_URL").wrap_err("Failed to parse REDIS_URL")?;
         let nextcloud_url = var("NEXTCLOUD_URL").ok();
+        let nextcloud_fallback_urls = var("NEXTCLOUD_FALLBACK_URL")
+            .map(|urls| urls.split(',').map(String::from).collect())
+            .unwrap_or_default();
         let port = parse_var("PORT").ok().wrap_err("Invalid PORT")?;
         let metrics_port = parse_var("METRICS_PORT").wrap_err("Invalid METRICS_PORT")?;
         let metrics_socket =
             parse_var("METRICS_SOCKET_PATH").wrap_err("Invalid METRICS_SOCKET_PATH")?;
+        let grpc_port = parse_var("GRPC_PORT").wrap_err("Invalid GRPC_PORT")?;
+        let mqtt_url = parse_var("MQTT_URL").wrap_err("Invalid MQTT_URL")?;
+        let metrics_push = parse_var("METRICS_PUSH").wrap_err("Invalid METRICS_PUSH")?;
         let log_level = var("LOG").ok();
-        let bind = parse_var("BIND").wrap_err("Invalid BIND")?;
+        let log_targets = var("LOG_TARGETS")
+            .ok()
+            .map(|targets| {
+                targets
+                    .split(',')
+                    .filter(|target| !target.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<Result<Vec<TargetLevel>, _>>()
+            })
+            .transpose()
+            .wrap_err("Invalid LOG_TARGETS")?
+            .unwrap_or_default();
+        let bind = var("BIND")
+            .ok()
+            .map(|addrs| {
+                addrs
+                    .split(',')
+                    .map(|addr| addr.trim().parse())
+                    .collect::<Result<Vec<BindAddress>, _>>()
+            })
+            .transpose()
+            .wrap_err("Invalid BIND")?
+            .unwrap_or_default();
         let socket = var("SOCKET_PATH").map(PathBuf::from).ok();
         let socket_permissions = var("SOCKET_PERMISSIONS").ok();
         let allow_self_signed = var("ALLOW_SELF_SIGNED").map(|val| val == "true").ok();
@@ -237,27 +789,163 @@ impl PartialConfig {
         let tls_cert = parse_var("TLS_CERT").wrap_err("Invalid TLS_CERT")?;
         let tls_key = parse_var("TLS_KEY").wrap_err("Invalid TLS_KEY")?;
 
-        let tls = if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
-            Some(TlsConfig { cert, key })
-        } else {
-            None
-        };
+        let path_match = parse_var("PATH_MATCH").wrap_err("Invalid PATH_MATCH")?;
+        let path_normalize_unicode = var("PATH_NORMALIZE_UNICODE").map(|val| val == "true").ok();
+        let path_case_insensitive = var("PATH_CASE_INSENSITIVE").map(|val| val == "true").ok();
+        let admin_token = var("ADMIN_TOKEN").ok();
+        let webhook_urls = var("WEBHOOK_URL")
+            .ok()
+            .map(|urls| {
+                urls.split(',')
+                    .map(|url| Url::parse(url.trim()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()
+            .wrap_err("Invalid WEBHOOK_URL")?
+            .unwrap_or_default();
+        let webhook_events = var("WEBHOOK_EVENTS")
+            .map(|events| {
+                events
+                    .split(',')
+                    .map(|event| event.trim().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let webhook_secret = var("WEBHOOK_SECRET").ok();
+        let storage_update_concurrency = parse_var("STORAGE_UPDATE_CONCURRENCY")
+            .wrap_err("Invalid STORAGE_UPDATE_CONCURRENCY")?;
+        let event_concurrency =
+            parse_var("EVENT_CONCURRENCY").wrap_err("Invalid EVENT_CONCURRENCY")?;
+        let state_file = var("STATE_FILE").map(PathBuf::from).ok();
+        let event_input = var("EVENT_INPUT").map(PathBuf::from).ok();
+        let path_prefixes = var("PATH_PREFIX")
+            .map(|prefixes| prefixes.split(',').map(String::from).collect())
+            .unwrap_or_default();
+        let forwarded_for_trust =
+            parse_var("FORWARDED_FOR_TRUST").wrap_err("Invalid FORWARDED_FOR_TRUST")?;
+        let max_connections = parse_var("MAX_CONNECTIONS").wrap_err("Invalid MAX_CONNECTIONS")?;
+        let max_connections_per_ip =
+            parse_var("MAX_CONNECTIONS_PER_IP").wrap_err("Invalid MAX_CONNECTIONS_PER_IP")?;
+        let max_connections_per_user =
+            parse_var("MAX_CONNECTIONS_PER_USER").wrap_err("Invalid MAX_CONNECTIONS_PER_USER")?;
+        let max_messages_per_second =
+            parse_var("MAX_MESSAGES_PER_SECOND").wrap_err("Invalid MAX_MESSAGES_PER_SECOND")?;
+        let max_memory = parse_var("MAX_MEMORY").wrap_err("Invalid MAX_MEMORY")?;
+        let max_requests_per_second =
+            parse_var("MAX_REQUESTS_PER_SECOND").wrap_err("Invalid MAX_REQUESTS_PER_SECOND")?;
+        let limit_exempt_users = var("LIMIT_EXEMPT_USERS")
+            .map(|users| {
+                users
+                    .split(',')
+                    .filter(|user| !user.trim().is_empty())
+                    .map(|user| UserPattern::from(user.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let database_error_strategy =
+            parse_var("DATABASE_ERROR_STRATEGY").wrap_err("Invalid DATABASE_ERROR_STRATEGY")?;
+        let file_change_hints = var("FILE_CHANGE_HINTS").map(|val| val == "true").ok();
+        let presence = var("PRESENCE").map(|val| val == "true").ok();
+        let rooms = var("ROOMS").map(|val| val == "true").ok();
+        let user_patterns = var("USER_PATTERNS").map(|val| val == "true").ok();
+        let offline_queue_ttl =
+            parse_var("OFFLINE_QUEUE_TTL").wrap_err("Invalid OFFLINE_QUEUE_TTL")?;
+        let dedup_window = parse_var("DEDUP_WINDOW").wrap_err("Invalid DEDUP_WINDOW")?;
+        let fallback_keep_alive =
+            parse_var("FALLBACK_KEEP_ALIVE").wrap_err("Invalid FALLBACK_KEEP_ALIVE")?;
+        let handshake_banner = var("HANDSHAKE_BANNER").map(|val| val == "true").ok();
+        let heartbeat_interval =
+            parse_var("HEARTBEAT_INTERVAL").wrap_err("Invalid HEARTBEAT_INTERVAL")?;
+        let debounce_file = parse_var("DEBOUNCE_FILE").wrap_err("Invalid DEBOUNCE_FILE")?;
+        let debounce_activity =
+            parse_var("DEBOUNCE_ACTIVITY").wrap_err("Invalid DEBOUNCE_ACTIVITY")?;
+        let debounce_notification =
+            parse_var("DEBOUNCE_NOTIFICATION").wrap_err("Invalid DEBOUNCE_NOTIFICATION")?;
+        let ping_interval = parse_var("PING_INTERVAL").wrap_err("Invalid PING_INTERVAL")?;
+        let pong_grace = parse_var("PONG_GRACE").wrap_err("Invalid PONG_GRACE")?;
+        let high_latency_threshold =
+            parse_var("HIGH_LATENCY_THRESHOLD").wrap_err("Invalid HIGH_LATENCY_THRESHOLD")?;
+        let debounce_min = parse_var("DEBOUNCE_MIN").wrap_err("Invalid DEBOUNCE_MIN")?;
+        let debounce_max = parse_var("DEBOUNCE_MAX").wrap_err("Invalid DEBOUNCE_MAX")?;
+        let backpressure_threshold =
+            parse_var("BACKPRESSURE_THRESHOLD").wrap_err("Invalid BACKPRESSURE_THRESHOLD")?;
+        let connection_registry =
+            parse_var("CONNECTION_REGISTRY").wrap_err("Invalid CONNECTION_REGISTRY")?;
+        let registry_shards = parse_var("REGISTRY_SHARDS").wrap_err("Invalid REGISTRY_SHARDS")?;
+        let pre_auth_min_length =
+            parse_var("PRE_AUTH_MIN_LENGTH").wrap_err("Invalid PRE_AUTH_MIN_LENGTH")?;
+        let pre_auth_min_entropy =
+            parse_var("PRE_AUTH_MIN_ENTROPY").wrap_err("Invalid PRE_AUTH_MIN_ENTROPY")?;
+        let nextcloud_ca_bundle = var("NEXTCLOUD_CA_BUNDLE").map(PathBuf::from).ok();
+        let nextcloud_client_cert = var("NEXTCLOUD_CLIENT_CERT").map(PathBuf::from).ok();
 
         Ok(PartialConfig {
             database,
             database_prefix,
             redis: redis.into_iter().collect(),
             nextcloud_url,
+            nextcloud_fallback_urls,
             port,
             metrics_port,
             metrics_socket,
+            grpc_port,
+            mqtt_url,
+            metrics_push,
             log_level,
+            log_targets,
             bind,
             socket,
             socket_permissions,
+            inherited_sockets: Vec::new(),
             allow_self_signed,
             no_ansi,
-            tls,
+            tls_cert,
+            tls_key,
+            path_match,
+            path_normalize_unicode,
+            path_case_insensitive,
+            admin_token,
+            webhook_urls,
+            webhook_events,
+            webhook_secret,
+            storage_update_concurrency,
+            event_concurrency,
+            state_file,
+            event_input,
+            path_prefixes,
+            forwarded_for_trust,
+            max_connections,
+            max_connections_per_ip,
+            max_connections_per_user,
+            max_messages_per_second,
+            max_memory,
+            max_requests_per_second,
+            limit_exempt_users,
+            database_error_strategy,
+            file_change_hints,
+            presence,
+            rooms,
+            user_patterns,
+            offline_queue_ttl,
+            dedup_window,
+            fallback_keep_alive,
+            handshake_banner,
+            heartbeat_interval,
+            debounce_file,
+            debounce_activity,
+            debounce_notification,
+            ping_interval,
+            pong_grace,
+            high_latency_threshold,
+            debounce_min,
+            debounce_max,
+            backpressure_threshold,
+            connection_registry,
+            registry_shards,
+            pre_auth_min_length,
+            pre_auth_min_entropy,
+            nextcloud_ca_bundle,
+            nextcloud_client_cert,
         })
     }
 
@@ -266,31 +954,93 @@ impl PartialConfig {
     }
 
     fn from_opt(opt: Opt) -> Self {
-        let tls = if let (Some(cert), Some(key)) = (opt.tls_cert, opt.tls_key) {
-            Some(TlsConfig { cert, key })
-        } else {
-            None
-        };
-
         PartialConfig {
             database: opt.database_url,
             database_prefix: opt.database_prefix,
             redis: opt.redis_url,
             nextcloud_url: opt.nextcloud_url,
+            nextcloud_fallback_urls: opt.nextcloud_fallback_url,
             port: opt.port,
             metrics_port: opt.metrics_port,
             metrics_socket: opt.metrics_socket_path,
+            grpc_port: opt.grpc_port,
+            mqtt_url: opt.mqtt_url,
+            metrics_push: opt.metrics_push,
             log_level: opt.log_level,
+            log_targets: opt.log_target,
             bind: opt.bind,
             socket: opt.socket_path,
             socket_permissions: opt.socket_permissions,
+            inherited_sockets: opt.inherited_sockets,
             allow_self_signed: if opt.allow_self_signed {
                 Some(true)
             } else {
                 None
             },
             no_ansi: if opt.no_ansi { Some(true) } else { None },
-            tls,
+            tls_cert: opt.tls_cert,
+            tls_key: opt.tls_key,
+            path_match: opt.path_match,
+            path_normalize_unicode: if opt.path_normalize_unicode {
+                Some(true)
+            } else {
+                None
+            },
+            path_case_insensitive: if opt.path_case_insensitive {
+                Some(true)
+            } else {
+                None
+            },
+            admin_token: opt.admin_token,
+            webhook_urls: opt.webhook_url,
+            webhook_events: opt.webhook_event,
+            webhook_secret: opt.webhook_secret,
+            storage_update_concurrency: opt.storage_update_concurrency,
+            event_concurrency: opt.event_concurrency,
+            state_file: opt.state_file,
+            event_input: opt.event_input,
+            path_prefixes: opt.path_prefix,
+            forwarded_for_trust: opt.forwarded_for_trust,
+            max_connections: opt.max_connections,
+            max_connections_per_ip: opt.max_connections_per_ip,
+            max_connections_per_user: opt.max_connections_per_user,
+            max_messages_per_second: opt.max_messages_per_second,
+            max_memory: opt.max_memory,
+            max_requests_per_second: opt.max_requests_per_second,
+            limit_exempt_users: opt.limit_exempt_user,
+            database_error_strategy: opt.database_error_strategy,
+            file_change_hints: if opt.file_change_hints {
+                Some(true)
+            } else {
+                None
+            },
+            presence: if opt.presence { Some(true) } else { None },
+            rooms: if opt.rooms { Some(true) } else { None },
+            user_patterns: if opt.user_patterns { Some(true) } else { None },
+            offline_queue_ttl: opt.offline_queue_ttl,
+            dedup_window: opt.dedup_window,
+            fallback_keep_alive: opt.fallback_keep_alive,
+            handshake_banner: if opt.handshake_banner {
+                Some(true)
+            } else {
+                None
+            },
+            heartbeat_interval: opt.heartbeat_interval,
+            debounce_file: opt.debounce_file,
+            debounce_activity: opt.debounce_activity,
+            debounce_notification: opt.debounce_notification,
+            ping_interval: opt.ping_interval,
+            pong_grace: opt.pong_grace,
+            high_latency_threshold: opt.high_latency_threshold,
+            debounce_min: opt.debounce_min,
+            debounce_max: opt.debounce_max,
+            backpressure_threshold: opt.backpressure_threshold,
+            connection_registry: opt.connection_registry,
+            registry_shards: opt.registry_shards,
+            pre_auth_min_length: opt.pre_auth_min_length,
+            pre_auth_min_entropy: opt.pre_auth_min_entropy,
+            nextcloud_ca_bundle: opt.nextcloud_ca_bundle,
+            nextcloud_client_cert: opt.nextcloud_client_cert,
         }
     }
 
@@ -304,20 +1054,148 @@ impl PartialConfig {
                 self.redis
             },
             nextcloud_url: self.nextcloud_url.or(fallback.nextcloud_url),
+            nextcloud_fallback_urls: if self.nextcloud_fallback_urls.is_empty() {
+                fallback.nextcloud_fallback_urls
+            } else {
+                self.nextcloud_fallback_urls
+            },
             port: self.port.or(fallback.port),
             metrics_port: self.metrics_port.or(fallback.metrics_port),
             metrics_socket: self.metrics_socket.or(fallback.metrics_socket),
+            grpc_port: self.grpc_port.or(fallback.grpc_port),
+            mqtt_url: self.mqtt_url.or(fallback.mqtt_url),
+            metrics_push: self.metrics_push.or(fallback.metrics_push),
             log_level: self.log_level.or(fallback.log_level),
-            bind: self.bind.or(fallback.bind),
+            log_targets: if self.log_targets.is_empty() {
+                fallback.log_targets
+            } else {
+                self.log_targets
+            },
+            bind: if self.bind.is_empty() {
+                fallback.bind
+            } else {
+                self.bind
+            },
             socket: self.socket.or(fallback.socket),
             socket_permissions: self.socket_permissions.or(fallback.socket_permissions),
+            inherited_sockets: if self.inherited_sockets.is_empty() {
+                fallback.inherited_sockets
+            } else {
+                self.inherited_sockets
+            },
             allow_self_signed: self.allow_self_signed.or(fallback.allow_self_signed),
             no_ansi: self.no_ansi.or(fallback.no_ansi),
-            tls: self.tls.or(fallback.tls),
+            tls_cert: self.tls_cert.or(fallback.tls_cert),
+            tls_key: self.tls_key.or(fallback.tls_key),
+            path_match: self.path_match.or(fallback.path_match),
+            path_normalize_unicode: self
+                .path_normalize_unicode
+                .or(fallback.path_normalize_unicode),
+            path_case_insensitive: self
+                .path_case_insensitive
+                .or(fallback.path_case_insensitive),
+            admin_token: self.admin_token.or(fallback.admin_token),
+            webhook_urls: if self.webhook_urls.is_empty() {
+                fallback.webhook_urls
+            } else {
+                self.webhook_urls
+            },
+            webhook_events: if self.webhook_events.is_empty() {
+                fallback.webhook_events
+            } else {
+                self.webhook_events
+            },
+            webhook_secret: self.webhook_secret.or(fallback.webhook_secret),
+            storage_update_concurrency: self
+                .storage_update_concurrency
+                .or(fallback.storage_update_concurrency),
+            event_concurrency: self.event_concurrency.or(fallback.event_concurrency),
+            state_file: self.state_file.or(fallback.state_file),
+            event_input: self.event_input.or(fallback.event_input),
+            path_prefixes: if self.path_prefixes.is_empty() {
+                fallback.path_prefixes
+            } else {
+                self.path_prefixes
+            },
+            forwarded_for_trust: self.forwarded_for_trust.or(fallback.forwarded_for_trust),
+            max_connections: self.max_connections.or(fallback.max_connections),
+            max_connections_per_ip: self
+                .max_connections_per_ip
+                .or(fallback.max_connections_per_ip),
+            max_connections_per_user: self
+                .max_connections_per_user
+                .or(fallback.max_connections_per_user),
+            max_messages_per_second: self
+                .max_messages_per_second
+                .or(fallback.max_messages_per_second),
+            max_memory: self.max_memory.or(fallback.max_memory),
+            max_requests_per_second: self
+                .max_requests_per_second
+                .or(fallback.max_requests_per_second),
+            limit_exempt_users: if self.limit_exempt_users.is_empty() {
+                fallback.limit_exempt_users
+            } else {
+                self.limit_exempt_users
+            },
+            database_error_strategy: self
+                .database_error_strategy
+                .or(fallback.database_error_strategy),
+            file_change_hints: self.file_change_hints.or(fallback.file_change_hints),
+            presence: self.presence.or(fallback.presence),
+            rooms: self.rooms.or(fallback.rooms),
+            user_patterns: self.user_patterns.or(fallback.user_patterns),
+            offline_queue_ttl: self.offline_queue_ttl.or(fallback.offline_queue_ttl),
+            dedup_window: self.dedup_window.or(fallback.dedup_window),
+            fallback_keep_alive: self.fallback_keep_alive.or(fallback.fallback_keep_alive),
+            handshake_banner: self.handshake_banner.or(fallback.handshake_banner),
+            heartbeat_interval: self.heartbeat_interval.or(fallback.heartbeat_interval),
+            debounce_file: self.debounce_file.or(fallback.debounce_file),
+            debounce_activity: self.debounce_activity.or(fallback.debounce_activity),
+            debounce_notification: self
+                .debounce_notification
+                .or(fallback.debounce_notification),
+            ping_interval: self.ping_interval.or(fallback.ping_interval),
+            pong_grace: self.pong_grace.or(fallback.pong_grace),
+            high_latency_threshold: self
+                .high_latency_threshold
+                .or(fallback.high_latency_threshold),
+            debounce_min: self.debounce_min.or(fallback.debounce_min),
+            debounce_max: self.debounce_max.or(fallback.debounce_max),
+            backpressure_threshold: self
+                .backpressure_threshold
+                .or(fallback.backpressure_threshold),
+            connection_registry: self.connection_registry.or(fallback.connection_registry),
+            registry_shards: self.registry_shards.or(fallback.registry_shards),
+            pre_auth_min_length: self.pre_auth_min_length.or(fallback.pre_auth_min_length),
+            pre_auth_min_entropy: self.pre_auth_min_entropy.or(fallback.pre_auth_min_entropy),
+            nextcloud_ca_bundle: self.nextcloud_ca_bundle.or(fallback.nextcloud_ca_bundle),
+            nextcloud_client_cert: self
+                .nextcloud_client_cert
+                .or(fallback.nextcloud_client_cert),
         }
     }
 }
 
+/// Databases supported by this build, selected at compile time with the `mysql`, `postgres` and `sqlite` features
+pub const SUPPORTED_DATABASES: &[&str] = &[
+    #[cfg(feature = "mysql")]
+    "mysql",
+    #[cfg(feature = "postgres")]
+    "postgres",
+    #[cfg(feature = "sqlite")]
+    "sqlite",
+];
+
+/// Parse a database url, listing the supported databases if the url is for a database that wasn't compiled in
+fn parse_database_url(url: &str) -> Result<AnyConnectOptions> {
+    AnyConnectOptions::from_str(url).map_err(|e| {
+        Report::new(e).wrap_err(format!(
+            "Invalid or unsupported database url, this build supports: {}",
+            SUPPORTED_DATABASES.join(", ")
+        ))
+    })
+}
+
 fn parse_var<T>(name: &str) -> Result<Option<T>>
 where
     T: FromStr + 'static,
@@ -329,3 +1207,29 @@ where
         .transpose()
         .map_err(Report::from)
 }
+
+/// The first file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed in by systemd socket activation, see `sd_listen_fds(3)`
+///
+/// The sockets are only used when they are meant for this process, the variables are cleared afterwards
+/// so they aren't inherited by child processes. Since changing the environment isn't safe while other threads
+/// might read it, this has to be called before the runtime is started.
+pub fn take_systemd_sockets() -> Vec<RawFd> {
+    let pid = var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let count = var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect()
+}
@@ -1,58 +1,259 @@
-use crate::message::{DebounceMap, MessageType};
-use crate::metrics::METRICS;
+use crate::ack::AckTracker;
+use crate::auth::AuthError;
+use crate::control::ControlMessage;
+use crate::disconnect::DisconnectReason;
+use crate::idle::{Activity, IDLE_TIMEOUTS};
+use crate::latency::Rtt;
+use crate::message::{DebounceMap, HeldMessages, MessageType};
+use crate::metrics::{MetricsBatch, METRICS};
+use crate::observer::DaemonEvent;
+use crate::offline::OfflineQueue;
+use crate::pre_auth::TokenHash;
+use crate::presence::Presence;
+use crate::protocol::{
+    banner_message, ClientCommand, CommandParseError, ConnectionMode, ConnectionOptions,
+    ProtocolVersion,
+};
+use crate::registry::{Registry, RegistryConfig};
+use crate::room::{check_access, RoomError, RoomMembership};
 use crate::{App, UserId};
-use ahash::RandomState;
-use color_eyre::{Report, Result};
-use dashmap::DashMap;
+use color_eyre::Result;
 use futures::{future::select, pin_mut, SinkExt, StreamExt};
+use http_auth_basic::Credentials;
+use once_cell::sync::OnceCell;
+use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::num::NonZeroUsize;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tokio::sync::broadcast;
-use tokio::time::timeout;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::time::{sleep_until, timeout, Instant as TokioInstant};
 use warp::filters::ws::{Message, WebSocket};
 
-const USER_CONNECTION_LIMIT: usize = 64;
+/// Maximum number of messages kept for a user between two poll requests
+const PENDING_QUEUE_SIZE: usize = 32;
+
+/// Pending queues that haven't been polled for this long are removed
+const PENDING_EXPIRY: Duration = Duration::from_secs(120);
+
+/// Number of messages buffered for bridges
+const MIRROR_BUFFER: usize = 256;
 
 #[derive(Default)]
-pub struct ActiveConnections(DashMap<UserId, broadcast::Sender<MessageType>, RandomState>);
+pub struct ActiveConnections {
+    connections: Registry<UserId, broadcast::Sender<MessageType>>,
+    pending: Registry<UserId, Arc<PendingQueue>>,
+    /// Receives a copy of every message send to a user, connected or not
+    mirror: OnceCell<broadcast::Sender<(UserId, MessageType)>>,
+    presence: Presence,
+    offline: Option<OfflineQueue>,
+}
 
 impl ActiveConnections {
-    pub async fn add(&self, user: UserId) -> Result<broadcast::Receiver<MessageType>> {
-        if let Some(sender) = self.0.get(&user) {
-            // stop a single user from trying to eat all the resources
-            if sender.receiver_count() > USER_CONNECTION_LIMIT {
-                Err(Report::msg("connection limit exceeded"))
-            } else {
-                Ok(sender.subscribe())
-            }
-        } else {
-            let (tx, rx) = broadcast::channel(4);
-            self.0.insert(user, tx);
-            Ok(rx)
+    pub fn new(config: RegistryConfig, offline: Option<OfflineQueue>) -> Self {
+        ActiveConnections {
+            connections: Registry::new(config),
+            pending: Registry::new(config),
+            mirror: OnceCell::new(),
+            presence: Presence::default(),
+            offline,
         }
     }
 
+    /// The users that currently have a connection open
+    pub fn presence(&self) -> &Presence {
+        &self.presence
+    }
+
+    /// Receive a copy of every message send to a user, for bridges to other systems
+    ///
+    /// Nothing is copied until the first bridge subscribes, bridges that can't keep up miss messages.
+    pub fn mirror(&self) -> broadcast::Receiver<(UserId, MessageType)> {
+        self.mirror
+            .get_or_init(|| broadcast::channel(MIRROR_BUFFER).0)
+            .subscribe()
+    }
+
+    pub async fn add(&self, user: UserId) -> broadcast::Receiver<MessageType> {
+        // messages send once the connection is added are delivered to it directly, so they don't need to be queued
+        if let Some(offline) = &self.offline {
+            offline.connected(&user);
+        }
+        self.connections
+            .get_or_insert_with(user, || broadcast::channel(4).0)
+            .subscribe()
+    }
+
     pub async fn send_to_user(&self, user: &UserId, msg: MessageType) {
-        if let Some(tx) = self.0.get(user) {
+        if let Some(mirror) = self.mirror.get() {
+            mirror.send((user.clone(), msg.clone())).ok();
+        }
+        if let Some(queue) = self.pending.get(user) {
+            queue.push(msg.clone());
+        }
+        if let Some(offline) = &self.offline {
+            offline.push(user, &msg).await;
+        }
+        if let Some(tx) = self.connections.get(user) {
             tx.send(msg).ok();
         }
     }
+
+    pub fn has_offline_queue(&self) -> bool {
+        self.offline.is_some()
+    }
+
+    /// Remove a connection of the user, messages for the user are queued once its last connection is closed
+    pub fn disconnect(&self, user: &UserId) {
+        if self.presence.disconnect(user) == 0 {
+            if let Some(offline) = &self.offline {
+                offline.disconnected(user);
+            }
+        }
+    }
+
+    /// Take the messages queued while the user had no connection
+    pub async fn take_offline(&self, user: &UserId) -> Vec<MessageType> {
+        match &self.offline {
+            Some(offline) => offline.take(user).await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Send a message to every user that is connected or polling
+    ///
+    /// The map is locked one shard at a time while sending, so connecting users aren't blocked
+    /// for the whole broadcast.
+    pub fn send_to_all(&self, msg: MessageType) {
+        let mirror = self.mirror.get();
+        self.pending.for_each(|user, queue| {
+            if let Some(mirror) = mirror {
+                mirror.send((user.clone(), msg.clone())).ok();
+            }
+            queue.push(msg.clone());
+        });
+        self.connections.for_each(|user, tx| {
+            // polling users were mirrored above already
+            if let (Some(mirror), None) = (mirror, self.pending.get(user)) {
+                mirror.send((user.clone(), msg.clone())).ok();
+            }
+            tx.send(msg.clone()).ok();
+        });
+    }
+
+    /// Wait until messages are pending for the user or the wait time has passed
+    ///
+    /// Messages for the user are only queued once the user has polled, the queue is kept until the user
+    /// hasn't polled for two minutes.
+    pub async fn poll(&self, user: UserId, wait: Duration) -> Vec<MessageType> {
+        let cutoff = Instant::now() - PENDING_EXPIRY;
+        self.pending.retain(|_, queue| queue.last_poll() > cutoff);
+
+        let queue = self.pending.get_or_insert_with(user, Arc::default);
+        queue.touch();
+        let messages = queue.take();
+        if !messages.is_empty() {
+            return messages;
+        }
+        timeout(wait, queue.notify.notified()).await.ok();
+        queue.touch();
+        queue.take()
+    }
+
+    /// Drop the pending queues of all users, users that keep polling get a new queue with their next poll
+    pub fn clear_pending(&self) {
+        self.pending.retain(|_, _| false);
+    }
+
+    /// Remove the pending queue for a user, returning any messages that weren't polled yet
+    ///
+    /// Used when a user that was polling before opens a websocket connection
+    pub fn take_pending(&self, user: &UserId) -> Vec<MessageType> {
+        self.pending
+            .remove(user)
+            .map(|queue| queue.take())
+            .unwrap_or_default()
+    }
 }
 
-pub async fn handle_user_socket(mut ws: WebSocket, app: Arc<App>, forwarded_for: Vec<IpAddr>) {
-    let user_id = match timeout(
+/// Messages for a user waiting to be picked up by a poll request
+///
+/// Messages are debounced before they are queued, messages held back by the debounce are added
+/// to the queue when it's taken after the debounce window has passed.
+pub struct PendingQueue {
+    messages: Mutex<VecDeque<MessageType>>,
+    debounce: Mutex<DebounceMap>,
+    last_poll: Mutex<Instant>,
+    notify: Notify,
+}
+
+impl Default for PendingQueue {
+    fn default() -> Self {
+        PendingQueue {
+            messages: Mutex::default(),
+            debounce: Mutex::default(),
+            last_poll: Mutex::new(Instant::now()),
+            notify: Notify::new(),
+        }
+    }
+}
+
+impl PendingQueue {
+    fn push(&self, msg: MessageType) {
+        if !self.debounce.lock().unwrap().should_send(&msg) {
+            return;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= PENDING_QUEUE_SIZE {
+            messages.pop_front();
+        }
+        messages.push_back(msg);
+        self.notify.notify_one();
+    }
+
+    fn take(&self) -> Vec<MessageType> {
+        let mut messages: Vec<MessageType> = self.messages.lock().unwrap().drain(..).collect();
+        let mut debounce = self.debounce.lock().unwrap();
+        for msg in debounce.get_held_messages() {
+            if debounce.should_send(&msg) {
+                messages.push(msg);
+            }
+        }
+        messages
+    }
+
+    fn touch(&self) {
+        *self.last_poll.lock().unwrap() = Instant::now();
+    }
+
+    fn last_poll(&self) -> Instant {
+        *self.last_poll.lock().unwrap()
+    }
+}
+
+/// How long messages for connections in mobile mode are collected before sending them together
+const MOBILE_BATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Handle a websocket connection, `version` is the protocol version negotiated during the handshake
+pub async fn handle_user_socket(
+    mut ws: WebSocket,
+    app: Arc<App>,
+    forwarded_for: Vec<IpAddr>,
+    version: ProtocolVersion,
+) {
+    let client_ip = forwarded_for.first().copied();
+    let (user_id, held) = match timeout(
         Duration::from_secs(15),
         socket_auth(&mut ws, forwarded_for, &app),
     )
     .await
     {
-        Ok(Ok(user_id)) => user_id,
+        Ok(Ok(authenticated)) => authenticated,
         Ok(Err(e)) => {
-            log::warn!("{}", e);
-            ws.send(Message::text(format!("err: {}", e))).await.ok();
+            e.record("websocket");
+            ws.send(e.to_message(version)).await.ok();
             return;
         }
         Err(_) => {
@@ -63,20 +264,48 @@ pub async fn handle_user_socket(mut ws: WebSocket, app: Arc<App>, forwarded_for:
         }
     };
 
-    log::info!("new websocket authenticated as {}", user_id);
+    log::info!(target: "notify_push::auth", "new websocket authenticated as {}", user_id);
     ws.send(Message::text("authenticated")).await.ok();
 
-    let mut rx = match app.connections.add(user_id.clone()).await {
-        Ok(rx) => rx,
+    // stop a single user or client from trying to eat all the resources
+    let _permit = match app.limits.admit(&user_id, client_ip) {
+        Ok(permit) => permit,
         Err(e) => {
-            ws.send(Message::text(e.to_string())).await.ok();
+            let e = AuthError::from(e);
+            e.record(&format!("websocket for {}", user_id));
+            ws.send(e.to_message(version)).await.ok();
             return;
         }
     };
 
+    if app.handshake_banner {
+        // clients can only switch versions with a command after the banner, so it uses the negotiated version
+        ws.send(banner_message(version, app.ping.interval))
+            .await
+            .ok();
+    }
+
+    let mut rx = app.connections.add(user_id.clone()).await;
+
+    // messages queued while the client was using the long-polling fallback
+    for msg in app.connections.take_pending(&user_id) {
+        ws.send(msg.to_message(version)).await.ok();
+    }
+    // scheduled messages that were due while the user wasn't connected
+    for msg in app.schedule.take_missed(&user_id) {
+        ws.send(msg.to_message(version)).await.ok();
+    }
+    // messages received since the last connection of the user closed
+    for msg in app.connections.take_offline(&user_id).await {
+        ws.send(msg.to_message(version)).await.ok();
+    }
+
     let (mut user_ws_tx, mut user_ws_rx) = ws.split();
 
+    app.connections.presence().connect(&user_id);
     METRICS.add_connection();
+    app.observer
+        .emit(|| DaemonEvent::ConnectionOpened(user_id.clone()));
 
     // Every time we send a ping, we set this to a random non-zero value
     // when a pong is returned, we check it against the expected value and reset this to 0
@@ -85,132 +314,477 @@ pub async fn handle_user_socket(mut ws: WebSocket, app: Arc<App>, forwarded_for:
     let expect_pong = AtomicUsize::default();
     let expect_pong = &expect_pong;
 
+    // when the connection is dropped without being closed, the time it was idle hints at a proxy timeout
+    let activity = Activity::default();
+    let activity = &activity;
+
+    // measured from the pings, clients with a high latency get their messages batched
+    let rtt = Rtt::default();
+    let rtt = &rtt;
+
+    // options set by the client after authenticating
+    let options = Mutex::new(ConnectionOptions {
+        version,
+        debounce_bounds: app.debounce_bounds,
+        ping: app.ping,
+        ..ConnectionOptions::default()
+    });
+    let options = &options;
+
+    // replies to client commands are send by the transmit loop
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Message>(8);
+
+    // rooms joined by the client, messages relayed by other clients are send as replies
+    let membership = Mutex::new(RoomMembership::new(user_id.clone(), reply_tx.clone()));
+    let membership = &membership;
+
+    // custom messages the client didn't acknowledge yet, when it enabled acknowledgements
+    let acks = Mutex::new(AckTracker::default());
+    let acks = &acks;
+
+    let app = &app;
+    let resume_user = user_id.clone();
+    let closed_user = user_id.clone();
+
     let transmit = async move {
-        let mut debounce = DebounceMap::default();
+        // messages that were held back when a resumed session was lost are send with the next debounce check
+        let mut debounce = DebounceMap::with_held(held);
+        let mut rate = app.limits.message_rate(&user_id);
+
+        let mut control = app.control_rx();
 
-        let mut reset = app.reset_rx();
+        // messages for connections in mobile mode are collected and send together
+        let mut batch: Vec<MessageType> = Vec::new();
+        let mut batch_deadline = TokioInstant::now();
+
+        let mut metrics = MetricsBatch::default();
 
         'tx_loop: loop {
+            let (mobile, window) = {
+                let options = options.lock().unwrap();
+                (options.mode == ConnectionMode::Mobile, options.debounce)
+            };
+            debounce.set_mobile(mobile);
+            debounce.set_window(window);
+            let batched = mobile || rtt.exceeds(app.ping.high_latency);
+            let retry_at = acks.lock().unwrap().next_retry();
+            let rate_ready = rate.ready_at().map(TokioInstant::from_std);
+            // once a ping is send, the client only has the grace period to reply
+            let wait = if expect_pong.load(Ordering::SeqCst) > 0 {
+                app.ping.pong_grace
+            } else {
+                app.ping.interval
+            };
+
             tokio::select! {
-                msg = timeout(Duration::from_secs(30), rx.recv()) => {
+                msg = timeout(wait, rx.recv()) => {
+                    if msg.is_err() {
+                        metrics.flush();
+                    }
                     match msg {
+                        Ok(Ok(msg)) if !options.lock().unwrap().accepts(&msg) => {
+                            // tagged message for a tag this connection doesn't have, or a type the client didn't subscribe to
+                        }
                         Ok(Ok(msg)) => {
-                            if debounce.should_send(&msg) {
-                                log::debug!(target: "notify_push::send", "Sending {} to {}", msg, user_id);
-                                METRICS.add_message();
-                                user_ws_tx.send(msg.into()).await.ok();
-                            } else {
+                            if !debounce.should_send(&msg) {
                                 log::debug!(target: "notify_push::send", "Debouncing {} to {}", msg, user_id);
+                                app.observer.emit(|| DaemonEvent::MessageDebounced(user_id.clone(), msg));
+                            } else if let Some(msg) = rate.admit(msg) {
+                                if batched {
+                                    log::debug!(target: "notify_push::send", "Batching {} to {}", msg, user_id);
+                                    if batch.is_empty() {
+                                        batch_deadline = TokioInstant::now() + MOBILE_BATCH_INTERVAL;
+                                    }
+                                    batch.push(msg);
+                                } else {
+                                    log::debug!(target: "notify_push::send", "Sending {} to {}", msg, user_id);
+                                    metrics.add_message();
+                                    let message = encode_message(options, acks, &msg);
+                                    user_ws_tx.send(message).await.ok();
+                                    activity.touch();
+                                    app.observer.emit(|| DaemonEvent::MessageDelivered(user_id.clone(), msg));
+                                }
+                            } else {
+                                log::debug!(target: "notify_push::send", "Deferring message to {}, rate limit exceeded", user_id);
                             }
                         }
                         Err(_timout) if debounce.has_held_message() => {
                             // if any message got held back for debounce, we try sending them now
                             for msg in debounce.get_held_messages() {
-                                if debounce.should_send(&msg) {
+                                if !debounce.should_send(&msg) {
+                                    continue;
+                                }
+                                if batched {
+                                    if batch.is_empty() {
+                                        batch_deadline = TokioInstant::now() + MOBILE_BATCH_INTERVAL;
+                                    }
+                                    batch.push(msg);
+                                } else {
                                     log::debug!(target: "notify_push::send", "Sending debounced {} to {}", msg, user_id);
-                                    METRICS.add_message();
-                                    user_ws_tx.send(msg.into()).await.ok();
+                                    metrics.add_message();
+                                    let message = encode_message(options, acks, &msg);
+                                    user_ws_tx.send(message).await.ok();
+                                    activity.touch();
+                                    app.observer.emit(|| DaemonEvent::MessageDelivered(user_id.clone(), msg));
                                 }
                             }
                         }
+                        Err(_timout) if mobile => {
+                            // waking up the radio of a mobile client just for a ping is too expensive
+                            expect_pong.store(0, Ordering::SeqCst);
+                        }
+                        Err(_timout) if expect_pong.load(Ordering::SeqCst) == 0 && activity.idle() < app.ping.interval => {
+                            // the client replied during the grace period, the next ping is only due once it's idle for the full interval
+                        }
                         Err(_timout) => {
                             let data = rand::random::<NonZeroUsize>().into();
                             let last_ping = expect_pong.swap(data, Ordering::SeqCst);
                             if last_ping > 0 {
                                 log::info!("{} didn't reply to ping, closing", user_id);
-                                break;
+                                break DisconnectReason::Timeout;
                             }
                             log::debug!(target: "notify_push::send", "Sending ping to {}", user_id);
                             user_ws_tx
                                 .send(Message::ping(data.to_le_bytes()))
                                 .await
                                 .ok();
+                            activity.touch();
+                            rtt.ping_send();
+                        }
+                        Ok(Err(RecvError::Lagged(count))) => {
+                            log::debug!(target: "notify_push::send", "{} messages to {} dropped, recommending sync", count, user_id);
+                            METRICS.add_lagged_messages(&user_id, count);
+                            let message = options.lock().unwrap().sync_recommended_message(count);
+                            user_ws_tx.send(message).await.ok();
+                            activity.touch();
+                            app.observer.emit(|| DaemonEvent::MessagesDropped(user_id.clone(), count));
                         }
-                        Ok(Err(_)) => {
-                            // we dont care about dropped messages
+                        Ok(Err(RecvError::Closed)) => {}
+                    }
+                },
+                _ = sleep_until(batch_deadline), if !batch.is_empty() => {
+                    for msg in batch.drain(..) {
+                        log::debug!(target: "notify_push::send", "Sending batched {} to {}", msg, user_id);
+                        metrics.add_message();
+                        let message = encode_message(options, acks, &msg);
+                        user_ws_tx.send(message).await.ok();
+                        app.observer.emit(|| DaemonEvent::MessageDelivered(user_id.clone(), msg));
+                    }
+                    activity.touch();
+                },
+                _ = sleep_until(rate_ready.unwrap_or_else(TokioInstant::now)), if rate_ready.is_some() => {
+                    for msg in rate.take_ready() {
+                        if batched {
+                            if batch.is_empty() {
+                                batch_deadline = TokioInstant::now() + MOBILE_BATCH_INTERVAL;
+                            }
+                            batch.push(msg);
+                        } else {
+                            log::debug!(target: "notify_push::send", "Sending deferred {} to {}", msg, user_id);
+                            metrics.add_message();
+                            let message = encode_message(options, acks, &msg);
+                            user_ws_tx.send(message).await.ok();
+                            app.observer.emit(|| DaemonEvent::MessageDelivered(user_id.clone(), msg));
                         }
                     }
+                    let dropped = rate.take_dropped();
+                    if dropped > 0 {
+                        log::debug!(target: "notify_push::send", "{} deferred messages to {} dropped, recommending sync", dropped, user_id);
+                        let message = options.lock().unwrap().sync_recommended_message(dropped);
+                        user_ws_tx.send(message).await.ok();
+                        app.observer.emit(|| DaemonEvent::MessagesDropped(user_id.clone(), dropped));
+                    }
+                    activity.touch();
+                },
+                _ = sleep_until(retry_at.unwrap_or_else(TokioInstant::now)), if retry_at.is_some() => {
+                    let (due, given_up) = acks.lock().unwrap().due(TokioInstant::now());
+                    if given_up > 0 {
+                        log::debug!(target: "notify_push::send", "{} messages to {} were never acknowledged", given_up, user_id);
+                        METRICS.add_unacknowledged(given_up);
+                    }
+                    for (id, msg) in due {
+                        log::debug!(target: "notify_push::send", "Sending unacknowledged {} to {} again", msg, user_id);
+                        METRICS.add_retransmit();
+                        let message = options.lock().unwrap().encode_with_id(&msg, id);
+                        user_ws_tx.send(message).await.ok();
+                    }
+                    activity.touch();
                 },
-                _ = reset.recv() => {
-                    user_ws_tx.close().await.ok();
-                    log::debug!("Connection closed by reset request");
-                    break 'tx_loop;
+                Some(reply) = reply_rx.recv() => {
+                    user_ws_tx.send(reply).await.ok();
+                    activity.touch();
+                },
+                Ok(message) = control.recv() => {
+                    match message {
+                        ControlMessage::Action(action, _) if message.applies_to(&user_id) => {
+                            log::debug!(target: "notify_push::send", "Sending {} to {}", action, user_id);
+                            let version = options.lock().unwrap().version;
+                            user_ws_tx.send(action.to_message(version)).await.ok();
+                            activity.touch();
+                            if action.closes_connection() {
+                                user_ws_tx.close().await.ok();
+                                break 'tx_loop DisconnectReason::ServerReset;
+                            }
+                        }
+                        _ if message.applies_to(&user_id) => {
+                            user_ws_tx.close().await.ok();
+                            log::debug!("Connection closed by reset request");
+                            break 'tx_loop DisconnectReason::ServerReset;
+                        }
+                        _ => {}
+                    }
                 },
             };
+
+            if let Some(token) = &options.lock().unwrap().resume_token {
+                app.resume_tokens.set_held(token, debounce.held());
+            }
         }
     };
 
     let receive = async move {
         // handle messages until the client closes the connection
         while let Some(result) = user_ws_rx.next().await {
+            if result.is_ok() {
+                activity.touch();
+            }
             match result {
                 Ok(msg) if msg.is_pong() => {
                     let expected = expect_pong.swap(0, Ordering::SeqCst);
                     if msg.as_bytes() != expected.to_le_bytes() {
                         log::info!("received wrong pong, closing");
-                        break;
+                        return DisconnectReason::ProtocolError;
+                    }
+                    rtt.pong_received();
+                }
+                Ok(msg) if msg.is_text() => {
+                    let text = msg.to_str().unwrap_or_default();
+                    match text.parse::<ClientCommand>() {
+                        Ok(ClientCommand::ResumeToken) => {
+                            let token = app.resume_tokens.issue(resume_user.clone());
+                            let (reply, previous) = {
+                                let mut options = options.lock().unwrap();
+                                let reply = options.resume_token_message(&token);
+                                (reply, options.resume_token.replace(token))
+                            };
+                            if let Some(previous) = previous {
+                                app.resume_tokens.remove(&previous);
+                            }
+                            reply_tx.send(reply).await.ok();
+                        }
+                        Ok(ClientCommand::Join(room)) => {
+                            let reply = match join_room(app, membership, &resume_user, &room).await
+                            {
+                                Ok(()) => options.lock().unwrap().room_message("joined", &room),
+                                Err(e) => Message::text(format!("err: {}", e)),
+                            };
+                            reply_tx.send(reply).await.ok();
+                        }
+                        Ok(ClientCommand::Leave(room)) => {
+                            membership.lock().unwrap().leave(&app.rooms, &room);
+                            let reply = options.lock().unwrap().room_message("left", &room);
+                            reply_tx.send(reply).await.ok();
+                        }
+                        Ok(ClientCommand::Ack(id)) => {
+                            if !acks.lock().unwrap().ack(id) {
+                                log::debug!(target: "notify_push::receive", "Ignoring acknowledgement for unknown message {}", id);
+                            }
+                        }
+                        Ok(ClientCommand::Relay(room, payload)) => {
+                            let result = membership
+                                .lock()
+                                .unwrap()
+                                .relay(&app.rooms, &room, &payload);
+                            if let Err(e) = result {
+                                reply_tx
+                                    .send(Message::text(format!("err: {}", e)))
+                                    .await
+                                    .ok();
+                            }
+                        }
+                        Ok(command) => {
+                            log::debug!(target: "notify_push::receive", "Received command {:?}", command);
+                            let reply = options.lock().unwrap().apply(command);
+                            if let Some(reply) = reply {
+                                reply_tx.send(reply).await.ok();
+                            }
+                        }
+                        Err(CommandParseError::UnknownCommand(command)) => {
+                            log::debug!(target: "notify_push::receive", "Ignoring unknown command {}", command);
+                        }
+                        Err(e) => {
+                            reply_tx
+                                .send(Message::text(format!("err: {}", e)))
+                                .await
+                                .ok();
+                        }
                     }
                 }
                 Ok(_) => {}
                 Err(e) => {
-                    let formatted = e.to_string();
-                    // hack while warp only has opaque error types
-                    match formatted.as_str() {
-                        "WebSocket protocol error: Connection reset without closing handshake"
-                        | "IO error: Connection reset by peer (os error 104)" => {
-                            log::debug!("websocket error: {}", e)
-                        }
-                        _ => log::warn!("websocket error: {}", e),
-                    };
-                    break;
+                    let reason = DisconnectReason::from_error(&e);
+                    if reason.is_expected() {
+                        log::debug!("websocket error ({}): {}", reason, e);
+                    } else {
+                        log::warn!("websocket error ({}): {}", reason, e);
+                    }
+                    return reason;
                 }
             };
         }
+        DisconnectReason::Closed
     };
 
     pin_mut!(transmit);
     pin_mut!(receive);
 
-    select(transmit, receive).await;
+    let (reason, _) = select(transmit, receive).await.factor_first();
+    log::debug!("connection for {} closed: {}", closed_user, reason);
+    METRICS.add_disconnect(reason);
+    if reason == DisconnectReason::PeerReset {
+        IDLE_TIMEOUTS.record(activity.idle(), app.ping.interval);
+    }
 
+    if let Some(token) = options.lock().unwrap().resume_token.take() {
+        app.resume_tokens.release(&token);
+    }
+    membership.lock().unwrap().leave_all(&app.rooms);
+
+    app.connections.disconnect(&closed_user);
     METRICS.remove_connection();
+    app.observer
+        .emit(|| DaemonEvent::ConnectionClosed(closed_user, reason));
+}
+
+/// Encode a message for the connection, custom messages are tracked until the client acknowledges them if it enabled acknowledgements
+fn encode_message(
+    options: &Mutex<ConnectionOptions>,
+    acks: &Mutex<AckTracker>,
+    msg: &MessageType,
+) -> Message {
+    let options = options.lock().unwrap();
+    match msg {
+        MessageType::Custom(..) if options.ack_mode => {
+            let (id, given_up) = acks.lock().unwrap().track(msg.clone());
+            if given_up > 0 {
+                METRICS.add_unacknowledged(given_up);
+            }
+            options.encode_with_id(msg, id)
+        }
+        _ => options.encode(msg),
+    }
 }
 
-async fn read_socket_auth_message(rx: &mut WebSocket) -> Result<Message> {
+/// Join a room after Nextcloud confirmed that the user has access to it
+async fn join_room(
+    app: &App,
+    membership: &Mutex<RoomMembership>,
+    user: &UserId,
+    room: &str,
+) -> Result<(), RoomError> {
+    if !app.rooms_enabled {
+        return Err(RoomError::Disabled);
+    }
+    membership.lock().unwrap().can_join(room)?;
+    match check_access(app, user, room).await {
+        Ok(true) => membership
+            .lock()
+            .unwrap()
+            .join(&app.rooms, room.to_string()),
+        Ok(false) => Err(RoomError::AccessDenied),
+        Err(e) => {
+            log::warn!(
+                "Failed to check access to room {} for {}: {:#}",
+                room,
+                user,
+                e
+            );
+            Err(RoomError::AccessDenied)
+        }
+    }
+}
+
+async fn read_socket_auth_message(rx: &mut WebSocket) -> Result<Message, AuthError> {
     match rx.next().await {
         Some(Ok(msg)) => Ok(msg),
-        Some(Err(e)) => Err(Report::from(e).wrap_err("Socket error during authentication")),
-        None => Err(Report::msg("Client disconnected during authentication")),
+        Some(Err(e)) => {
+            log::debug!(target: "notify_push::auth", "Socket error during authentication: {}", e);
+            Err(AuthError::Disconnected)
+        }
+        None => Err(AuthError::Disconnected),
     }
 }
 
-async fn socket_auth(rx: &mut WebSocket, forwarded_for: Vec<IpAddr>, app: &App) -> Result<UserId> {
+async fn socket_auth(
+    rx: &mut WebSocket,
+    forwarded_for: Vec<IpAddr>,
+    app: &App,
+) -> Result<(UserId, HeldMessages), AuthError> {
     let username_msg = read_socket_auth_message(rx).await?;
     let username = username_msg
         .to_str()
-        .map_err(|_| Report::msg("Invalid authentication message"))?;
+        .map_err(|_| AuthError::InvalidMessage)?;
     let password_msg = read_socket_auth_message(rx).await?;
     let password = password_msg
         .to_str()
-        .map_err(|_| Report::msg("Invalid authentication message"))?;
+        .map_err(|_| AuthError::InvalidMessage)?;
 
-    // cleanup all pre_auth tokens older than 15s
-    let cutoff = Instant::now() - Duration::from_secs(15);
-    app.pre_auth.retain(|_, (time, _)| *time > cutoff);
+    authenticate(app, username, password, forwarded_for).await
+}
 
-    if let Some((_, (_, user))) = app.pre_auth.remove(password) {
+/// Authenticate a http request with either a basic auth header or a pre-auth token from the query
+pub(crate) async fn authenticate_request(
+    app: &App,
+    authorization: Option<String>,
+    token: Option<String>,
+    forwarded_for: Vec<IpAddr>,
+) -> Result<(UserId, HeldMessages), AuthError> {
+    match (authorization, token) {
+        (_, Some(token)) => authenticate(app, "", &token, forwarded_for).await,
+        (Some(authorization), None) => {
+            let credentials =
+                Credentials::from_header(authorization).map_err(|_| AuthError::InvalidMessage)?;
+            authenticate(
+                app,
+                &credentials.user_id,
+                &credentials.password,
+                forwarded_for,
+            )
+            .await
+        }
+        (None, None) => Err(AuthError::InvalidCredentials),
+    }
+}
+
+/// Authenticate a connection with either a pre-auth token, resume token or user credentials
+pub(crate) async fn authenticate(
+    app: &App,
+    username: &str,
+    password: &str,
+    forwarded_for: Vec<IpAddr>,
+) -> Result<(UserId, HeldMessages), AuthError> {
+    if let Some(user) = app.pre_auth.take(&TokenHash::new(password)) {
         log::debug!(
+            target: "notify_push::auth",
             "Authenticated socket for {} using pre authenticated token",
             user
         );
-        return Ok(user);
+        return Ok((user, HeldMessages::default()));
+    }
+
+    if username.is_empty() {
+        if let Some((user, held)) = app.resume_tokens.take(password) {
+            log::debug!(target: "notify_push::auth", "Resumed socket for {} using resume token", user);
+            return Ok((user, held));
+        }
     }
 
     if !username.is_empty() {
         app.nc_client
             .verify_credentials(username, password, forwarded_for)
             .await
+            .map(|user| (user, HeldMessages::default()))
     } else {
-        Err(Report::msg("Invalid credentials"))
+        Err(AuthError::InvalidCredentials)
     }
 }
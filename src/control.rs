@@ -0,0 +1,87 @@
+use crate::protocol::ProtocolVersion;
+use crate::UserId;
+use once_cell::sync::OnceCell;
+use parse_display::Display;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use warp::ws::Message;
+
+/// Actions the server can ask clients to take
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[display(style = "snake_case")]
+pub enum ClientAction {
+    /// Authenticate again, e.g. after the password of the user was reset or the app password revoked
+    ///
+    /// The connection is closed after the action is send.
+    Reauth,
+    /// Reload the configuration of the client from Nextcloud
+    ReloadConfig,
+}
+
+impl ClientAction {
+    /// Whether the connection is closed after sending the action
+    pub fn closes_connection(self) -> bool {
+        self == ClientAction::Reauth
+    }
+
+    pub fn to_message(self, version: ProtocolVersion) -> Message {
+        match version {
+            ProtocolVersion::V1 => Message::text(format!("action {}", self)),
+            ProtocolVersion::V2 => Message::text(self.to_json().to_string()),
+        }
+    }
+
+    /// The action in the format used by protocol version 2
+    pub fn to_json(self) -> serde_json::Value {
+        json!({"type": "action", "action": self.to_string()})
+    }
+}
+
+/// Control messages send to open connections
+#[derive(Debug, Clone)]
+pub enum ControlMessage {
+    /// Close all connections
+    Reset,
+    /// Close all connections of a single user
+    ResetUser(UserId),
+    /// Ask the connections of a single user, or all connections if no user is set, to take an action
+    Action(ClientAction, Option<UserId>),
+}
+
+impl ControlMessage {
+    /// Whether the message applies to a connection of the user
+    pub fn applies_to(&self, user: &UserId) -> bool {
+        match self {
+            ControlMessage::Reset => true,
+            ControlMessage::ResetUser(target) => target == user,
+            ControlMessage::Action(_, target) => {
+                target.as_ref().map_or(true, |target| target == user)
+            }
+        }
+    }
+}
+
+/// Broadcast of control messages to all open connections
+///
+/// The channel is only created once the first connection subscribes
+#[derive(Default)]
+pub struct ControlBus {
+    sender: OnceCell<broadcast::Sender<ControlMessage>>,
+}
+
+impl ControlBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<ControlMessage> {
+        self.sender().subscribe()
+    }
+
+    /// Send a message to all connections, returning the number of connections that received it
+    pub fn send(&self, message: ControlMessage) -> usize {
+        self.sender().send(message).unwrap_or(0)
+    }
+
+    fn sender(&self) -> &broadcast::Sender<ControlMessage> {
+        self.sender.get_or_init(|| broadcast::channel(4).0)
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::{Bind, TlsConfig};
+use crate::{listen_loop, serve, App};
+use color_eyre::Result;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::task::{spawn, JoinHandle};
+
+/// A spawned task that can be stopped independently of the other tasks
+pub struct TaskHandle {
+    name: &'static str,
+    cancel: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl TaskHandle {
+    /// Spawn a task that stops once the receiver it's given resolves
+    pub fn spawn<F, Fut>(name: &'static str, task: F) -> Self
+    where
+        F: FnOnce(oneshot::Receiver<()>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (cancel, cancel_rx) = oneshot::channel();
+        TaskHandle {
+            name,
+            cancel,
+            task: spawn(task(cancel_rx)),
+        }
+    }
+
+    /// Spawn a task that can fail to start, like a server that can't bind to it's address
+    pub fn try_spawn<F, Fut>(name: &'static str, task: F) -> Result<Self>
+    where
+        F: FnOnce(oneshot::Receiver<()>) -> Result<Fut>,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (cancel, cancel_rx) = oneshot::channel();
+        Ok(TaskHandle {
+            name,
+            cancel,
+            task: spawn(task(cancel_rx)?),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Stop the task and wait for it to finish
+    pub async fn stop(self) {
+        self.cancel.send(()).ok();
+        if let Err(e) = self.task.await {
+            log::error!("{} failed while stopping: {}", self.name, e);
+        }
+    }
+}
+
+/// The subsystems of a running push server
+///
+/// The http server, the redis listener and the background tasks can be stopped and started independently,
+/// dropping the daemon stops all of them without waiting for them to finish.
+pub struct Daemon {
+    app: Arc<App>,
+    server: Option<TaskHandle>,
+    listener: Option<TaskHandle>,
+    background: Vec<TaskHandle>,
+}
+
+impl Daemon {
+    pub fn new(app: Arc<App>) -> Self {
+        Daemon {
+            app,
+            server: None,
+            listener: None,
+            background: Vec::new(),
+        }
+    }
+
+    pub fn app(&self) -> &Arc<App> {
+        &self.app
+    }
+
+    /// Start serving the push endpoints, stopping the running server first
+    pub async fn start_server(&mut self, bind: Bind, tls: Option<&TlsConfig>) -> Result<()> {
+        self.stop_server().await;
+        log::trace!("Listening on {}", bind);
+        let app = self.app.clone();
+        self.server = Some(TaskHandle::try_spawn("server", |cancel| {
+            serve(app, bind, cancel, tls)
+        })?);
+        Ok(())
+    }
+
+    /// Stop serving the push endpoints, open connections are closed
+    pub async fn stop_server(&mut self) {
+        if let Some(server) = self.server.take() {
+            server.stop().await;
+        }
+    }
+
+    /// Start listening for events from redis, stopping the running listener first
+    pub async fn start_listener(&mut self) {
+        self.stop_listener().await;
+        let app = self.app.clone();
+        self.listener = Some(TaskHandle::spawn("redis listener", |cancel| {
+            listen_loop(app, cancel)
+        }));
+    }
+
+    /// Stop listening for events from redis, connected clients stay connected but won't receive messages
+    pub async fn stop_listener(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            listener.stop().await;
+        }
+    }
+
+    /// Spawn a background task that is stopped by [`stop_background`](Daemon::stop_background) or on shutdown
+    pub fn spawn_background<F, Fut>(&mut self, name: &'static str, task: F)
+    where
+        F: FnOnce(Arc<App>, oneshot::Receiver<()>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let app = self.app.clone();
+        self.background
+            .push(TaskHandle::spawn(name, |cancel| task(app, cancel)));
+    }
+
+    /// Spawn a background task that can fail to start
+    pub fn try_spawn_background<F, Fut>(&mut self, name: &'static str, task: F) -> Result<()>
+    where
+        F: FnOnce(Arc<App>, oneshot::Receiver<()>) -> Result<Fut>,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let app = self.app.clone();
+        self.background
+            .push(TaskHandle::try_spawn(name, |cancel| task(app, cancel))?);
+        Ok(())
+    }
+
+    /// Stop all background tasks and wait for them to finish
+    pub async fn stop_background(&mut self) {
+        for task in self.background.drain(..) {
+            log::trace!("Stopping {}", task.name());
+            task.stop().await;
+        }
+    }
+
+    /// Stop everything, the server first so no new clients connect while the rest is stopping
+    pub async fn shutdown(mut self) {
+        self.stop_server().await;
+        self.stop_listener().await;
+        self.stop_background().await;
+    }
+}
@@ -0,0 +1,57 @@
+use crate::UserId;
+use ahash::RandomState;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Suppresses identical custom messages send to the same user within a time window
+///
+/// Some apps publish the same event multiple times for a single change, unlike the debounce of the built-in
+/// message types only messages with exactly the same name, body, tag and topic are dropped.
+pub struct Deduplication {
+    window: Duration,
+    seen: DashMap<(UserId, u64), Instant, RandomState>,
+}
+
+impl Deduplication {
+    pub fn new(window: Duration) -> Self {
+        Deduplication {
+            window,
+            seen: DashMap::default(),
+        }
+    }
+
+    /// Hash of the content of a custom message
+    pub fn fingerprint(message: &str, body: &Value, tag: Option<&str>, topic: Option<&str>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        message.hash(&mut hasher);
+        body.to_string().hash(&mut hasher);
+        tag.hash(&mut hasher);
+        topic.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Forget messages send before the window, called once per event instead of once per user
+    pub fn expire(&self) {
+        let window = self.window;
+        self.seen.retain(|_, send| send.elapsed() < window);
+    }
+
+    /// Check if a message wasn't send to the user within the window and remember it if so
+    pub fn is_new(&self, user: &UserId, fingerprint: u64) -> bool {
+        match self.seen.entry((user.clone(), fingerprint)) {
+            Entry::Occupied(entry) if entry.get().elapsed() < self.window => false,
+            Entry::Occupied(mut entry) => {
+                entry.insert(Instant::now());
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Instant::now());
+                true
+            }
+        }
+    }
+}
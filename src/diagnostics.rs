@@ -0,0 +1,105 @@
+use crate::config::Config;
+use crate::probe::Probe;
+use crate::redis::WriteCommand;
+use crate::App;
+use color_eyre::{eyre::WrapErr, Result};
+use flexi_logger::LoggerHandle;
+use serde::Serialize;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Information to attach to bug reports, printed by `--diagnostics`
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    pub probe: Probe,
+    /// The parsed config with all passwords removed
+    pub config: String,
+    /// `None` if the self test passed
+    pub self_test_error: Option<String>,
+    /// Metrics of the push server currently running, if there is one
+    pub running_metrics: Option<Value>,
+}
+
+impl Diagnostics {
+    pub async fn collect(config: Config, log_handle: LoggerHandle) -> Result<Self> {
+        let probe = Probe::new(&config).await;
+        let redacted_config = redact(&format!("{:#?}", config));
+        let app = App::new(config, log_handle).await?;
+
+        let self_test_error = app.self_test().await.err().map(|e| format!("{:#}", e));
+        let running_metrics = match running_metrics(&app).await {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                log::warn!("{:#}", e);
+                None
+            }
+        };
+
+        Ok(Diagnostics {
+            probe,
+            config: redacted_config,
+            self_test_error,
+            running_metrics,
+        })
+    }
+}
+
+/// Ask a running push server to publish it's metrics
+async fn running_metrics(app: &App) -> Result<Option<Value>> {
+    let mut redis = app
+        .redis
+        .connect()
+        .await
+        .wrap_err("Failed to connect to redis")?;
+    redis
+        .write_batch(&[
+            WriteCommand::Del {
+                key: "notify_push_metrics".into(),
+            },
+            WriteCommand::Publish {
+                channel: "notify_query".into(),
+                message: r#""metrics""#.into(),
+            },
+        ])
+        .await
+        .wrap_err("Failed to query metrics")?;
+    sleep(Duration::from_secs(1)).await;
+    Ok(redis
+        .get("notify_push_metrics")
+        .await
+        .ok()
+        .and_then(|metrics| serde_json::from_str(&metrics).ok()))
+}
+
+/// Remove the values of all password fields from debug output
+fn redact(debug: &str) -> String {
+    const MARKERS: [&str; 2] = ["password: Some(\"", "passwd: Some(\""];
+
+    let mut result = String::with_capacity(debug.len());
+    let mut rest = debug;
+    while let Some((start, marker)) = MARKERS
+        .iter()
+        .filter_map(|marker| rest.find(marker).map(|start| (start, marker)))
+        .min()
+    {
+        let value_start = start + marker.len();
+        result.push_str(&rest[..value_start]);
+        result.push_str("***");
+
+        // skip to the closing quote, ignoring escaped quotes
+        let mut escaped = false;
+        let value_len = rest[value_start..]
+            .char_indices()
+            .find(|(_, c)| {
+                let end = *c == '"' && !escaped;
+                escaped = *c == '\\' && !escaped;
+                end
+            })
+            .map(|(index, _)| index)
+            .unwrap_or(rest.len() - value_start);
+        rest = &rest[value_start + value_len..];
+    }
+    result.push_str(rest);
+    result
+}
@@ -0,0 +1,60 @@
+use parse_display::Display;
+use std::error::Error;
+use std::io::{self, ErrorKind};
+
+/// Why a client connection was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display(style = "snake_case")]
+pub enum DisconnectReason {
+    /// The client closed the connection
+    Closed,
+    /// The connection was closed by a reset request
+    ServerReset,
+    /// The connection was reset by the client without closing it properly
+    PeerReset,
+    /// The client send invalid data
+    ProtocolError,
+    /// The client stopped responding
+    Timeout,
+    Other,
+}
+
+impl DisconnectReason {
+    /// Classify a websocket error
+    ///
+    /// Io errors are found by walking the error sources, tungstenite's own error types can't be downcast to
+    /// without depending on the exact version used by warp, so those fall back to the error message.
+    pub fn from_error(error: &(dyn Error + 'static)) -> Self {
+        let mut source = Some(error);
+        while let Some(error) = source {
+            if let Some(io_error) = error.downcast_ref::<io::Error>() {
+                return match io_error.kind() {
+                    ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof => DisconnectReason::PeerReset,
+                    ErrorKind::TimedOut => DisconnectReason::Timeout,
+                    _ => DisconnectReason::Other,
+                };
+            }
+            source = error.source();
+        }
+
+        let message = error.to_string();
+        if message.contains("without closing handshake") {
+            DisconnectReason::PeerReset
+        } else if message.contains("protocol error") {
+            DisconnectReason::ProtocolError
+        } else {
+            DisconnectReason::Other
+        }
+    }
+
+    /// Whether the disconnect is part of normal operation and not worth a warning
+    pub fn is_expected(&self) -> bool {
+        matches!(
+            self,
+            DisconnectReason::Closed | DisconnectReason::ServerReset | DisconnectReason::PeerReset
+        )
+    }
+}
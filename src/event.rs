@@ -1,11 +1,17 @@
+use crate::control::ClientAction;
+use crate::fair::{FairPermit, FairSemaphore};
+use crate::limits::UserPattern;
+use crate::logging::LogTarget;
 use crate::metrics::METRICS;
 use crate::{Redis, UserId};
 use color_eyre::{eyre::WrapErr, Result};
+use derivative::Derivative;
 use parse_display::Display;
 use redis::Msg;
 use serde::Deserialize;
 use serde_json::Value;
 use std::convert::TryFrom;
+use std::fmt;
 use thiserror::Error;
 use tokio_stream::{Stream, StreamExt};
 
@@ -24,29 +30,192 @@ pub struct GroupUpdate {
 #[derive(Debug, Deserialize)]
 pub struct ShareCreate {
     pub user: UserId,
+    /// The storage of the shared file, if known
+    #[serde(default)]
+    pub storage: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct Activity {
+pub struct ShareDelete {
     pub user: UserId,
+    /// The storage of the shared file, if known
+    #[serde(default)]
+    pub storage: Option<u32>,
+    /// The id of the shared file, if known
+    #[serde(default)]
+    pub file_id: Option<u64>,
 }
 
+/// The permissions of a user for a share changed
 #[derive(Debug, Deserialize)]
-pub struct Notification {
+pub struct SharePermissions {
     pub user: UserId,
+    /// The storage of the shared file, if known
+    #[serde(default)]
+    pub storage: Option<u32>,
+    /// The id of the shared file, if known
+    #[serde(default)]
+    pub file_id: Option<u64>,
+    /// The new permissions as Nextcloud permission bitmask, if known
+    #[serde(default)]
+    pub permissions: Option<u32>,
 }
 
+/// A mount that was added to or removed from a user
 #[derive(Debug, Deserialize)]
+pub struct MountUpdate {
+    pub user: UserId,
+    /// The storage the mount points to, if known
+    #[serde(default)]
+    pub storage: Option<u32>,
+}
+
+/// The users an event is for, published either as a single user id or as an array of user ids
+///
+/// Publishing one event for all recipients saves a redis message per user, e.g. for a share with many recipients.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "OneOrMany")]
+pub struct Users(Vec<UserId>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(UserId),
+    Many(Vec<UserId>),
+}
+
+impl From<OneOrMany> for Users {
+    fn from(users: OneOrMany) -> Self {
+        match users {
+            OneOrMany::One(user) => Users(vec![user]),
+            OneOrMany::Many(users) => Users(users),
+        }
+    }
+}
+
+impl Users {
+    pub fn as_slice(&self) -> &[UserId] {
+        &self.0
+    }
+}
+
+impl IntoIterator for Users {
+    type Item = UserId;
+    type IntoIter = std::vec::IntoIter<UserId>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl fmt::Display for Users {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_slice() {
+            [user] => user.fmt(f),
+            users => write!(f, "{} users", users.len()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Activity {
+    #[serde(alias = "users")]
+    pub user: Users,
+    /// The type of the activity, e.g. `file_created`
+    #[serde(default)]
+    pub activity_type: Option<String>,
+    #[serde(default)]
+    pub object_type: Option<String>,
+    #[serde(default)]
+    pub object_id: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Notification {
+    #[serde(alias = "users")]
+    pub user: Users,
+    /// The id of the notification, if known
+    #[serde(default)]
+    pub id: Option<u64>,
+    /// The app that created the notification
+    #[serde(default)]
+    pub app: Option<String>,
+    /// The subject of the notification as set by the app, before it's translated
+    #[serde(default)]
+    pub subject: Option<String>,
+}
+
+#[derive(Derivative, Deserialize)]
+#[derivative(Debug)]
 pub struct PreAuth {
     pub user: UserId,
-    pub token: String,
+    /// The token in plain text, accepted for compatibility with older versions of the app
+    #[serde(default)]
+    #[derivative(Debug = "ignore")]
+    pub token: Option<String>,
+    /// Hex encoded sha256 hash of the token
+    #[serde(default)]
+    pub token_hash: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Config {
-    LogSpec(String),
+    LogSpec(LogSpec),
+    /// Restore the log spec that was set last
     LogRestore,
+    /// Restore the log spec from before the spec with this version was set
+    RestoreLogSpec(u64),
+    LogTarget(LogTargetChange),
+    Tune(Tuning),
+}
+
+/// Change the log level of a single target, the level set for the target is removed if no level is given
+#[derive(Debug, Deserialize)]
+pub struct LogTargetChange {
+    pub target: LogTarget,
+    #[serde(default)]
+    pub level: Option<String>,
+}
+
+/// A temporary log spec, optionally with a version so receiving the event again doesn't set it twice
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum LogSpec {
+    Plain(String),
+    Versioned { spec: String, version: u64 },
+}
+
+impl LogSpec {
+    pub fn spec(&self) -> &str {
+        match self {
+            LogSpec::Plain(spec) | LogSpec::Versioned { spec, .. } => spec,
+        }
+    }
+
+    pub fn version(&self) -> Option<u64> {
+        match self {
+            LogSpec::Plain(_) => None,
+            LogSpec::Versioned { version, .. } => Some(*version),
+        }
+    }
+}
+
+/// Runtime-tunable options, options that aren't set are left unchanged
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tuning {
+    /// Debounce window for file notifications in seconds
+    pub debounce_file: Option<u64>,
+    /// Debounce window for activity notifications in seconds
+    pub debounce_activity: Option<u64>,
+    /// Debounce window for notification notifications in seconds
+    pub debounce_notification: Option<u64>,
+    /// Connection limits, `0` disables the limit
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_connections_per_user: Option<usize>,
+    pub max_messages_per_second: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Display)]
@@ -57,16 +226,61 @@ pub enum Query {
 
 #[derive(Debug, Deserialize)]
 pub struct Custom {
-    pub user: UserId,
+    #[serde(alias = "users")]
+    pub user: Users,
     pub message: String,
     #[serde(default)]
     pub body: Value,
+    /// Only send the message to connections of the user that have this tag
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Only send the message to connections of the user that subscribed to a matching topic, e.g. `deck/board/7`
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Unix timestamp to send the message at instead of sending it right away
+    #[serde(default)]
+    pub deliver_at: Option<u64>,
+    /// Seconds a scheduled message is kept after its delivery time for users that aren't connected at that time
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// A custom message for every member of a group
+#[derive(Debug, Deserialize)]
+pub struct GroupMessage {
+    pub group: String,
+    pub message: String,
+    #[serde(default)]
+    pub body: Value,
+}
+
+/// A custom message for every connected user, e.g. to announce maintenance
+#[derive(Debug, Deserialize)]
+pub struct Broadcast {
+    pub message: String,
+    #[serde(default)]
+    pub body: Value,
+    /// Only send the message to connected users with a matching name, e.g. `company-a-*`
+    #[serde(default)]
+    pub pattern: Option<UserPattern>,
 }
 
 #[derive(Debug, Deserialize, Display)]
 #[serde(rename_all = "snake_case")]
 pub enum Signal {
     Reset,
+    #[display("reset user {0}")]
+    ResetUser(UserId),
+    #[display("client action {0.action}")]
+    ClientAction(ClientActionSignal),
+}
+
+/// Ask clients to take an action, for all connections or only the connections of a single user
+#[derive(Debug, Deserialize)]
+pub struct ClientActionSignal {
+    pub action: ClientAction,
+    #[serde(default)]
+    pub user: Option<UserId>,
 }
 
 #[derive(Debug, Display)]
@@ -77,6 +291,14 @@ pub enum Event {
     GroupUpdate(GroupUpdate),
     #[display("share create notification for user {0.user}")]
     ShareCreate(ShareCreate),
+    #[display("share delete notification for user {0.user}")]
+    ShareDelete(ShareDelete),
+    #[display("share permissions notification for user {0.user}")]
+    SharePermissions(SharePermissions),
+    #[display("mount added notification for user {0.user}")]
+    MountAdded(MountUpdate),
+    #[display("mount removed notification for user {0.user}")]
+    MountRemoved(MountUpdate),
     #[display("test cookie {0}")]
     TestCookie(u32),
     #[display("activity notification for user {0.user}")]
@@ -87,6 +309,10 @@ pub enum Event {
     PreAuth(PreAuth),
     #[display("custom notification {0.message} for user {0.user}")]
     Custom(Custom),
+    #[display("custom notification {0.message} for group {0.group}")]
+    GroupMessage(GroupMessage),
+    #[display("broadcast {0.message}")]
+    Broadcast(Broadcast),
     #[display("config update")]
     Config(Config),
     #[display("{0} query")]
@@ -95,6 +321,120 @@ pub enum Event {
     Signal(Signal),
 }
 
+impl Event {
+    /// Whether handling the event requires a database lookup
+    pub fn is_expensive(&self) -> bool {
+        matches!(self, Event::StorageUpdate(_) | Event::GroupMessage(_))
+    }
+
+    /// What the event is grouped by when events have to wait for their turn
+    pub fn fairness_key(&self) -> FairnessKey {
+        match self {
+            Event::StorageUpdate(StorageUpdate { storage, .. }) => FairnessKey::Storage(*storage),
+            Event::GroupMessage(GroupMessage { group, .. }) => FairnessKey::Group(group.clone()),
+            _ => match self.users() {
+                Some(users) => FairnessKey::Users(users.to_vec()),
+                None => FairnessKey::Other,
+            },
+        }
+    }
+
+    /// The user the event is for, if the event targets a single user
+    pub fn user(&self) -> Option<&UserId> {
+        match self.users()? {
+            [user] => Some(user),
+            _ => None,
+        }
+    }
+
+    /// The users the event is for, if the event targets specific users
+    pub fn users(&self) -> Option<&[UserId]> {
+        match self {
+            Event::GroupUpdate(GroupUpdate { user, .. })
+            | Event::ShareCreate(ShareCreate { user, .. })
+            | Event::ShareDelete(ShareDelete { user, .. })
+            | Event::SharePermissions(SharePermissions { user, .. })
+            | Event::MountAdded(MountUpdate { user, .. })
+            | Event::MountRemoved(MountUpdate { user, .. })
+            | Event::PreAuth(PreAuth { user, .. }) => Some(std::slice::from_ref(user)),
+            Event::Activity(Activity { user, .. })
+            | Event::Notification(Notification { user, .. })
+            | Event::Custom(Custom { user, .. }) => Some(user.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// Decode an event from the redis channel it was published on and its payload
+    pub fn parse(channel: &str, payload: &[u8]) -> Result<Self, MessageDecodeError> {
+        match channel {
+            "notify_storage_update" => Ok(Event::StorageUpdate(serde_json::from_slice(payload)?)),
+            "notify_group_membership_update" => {
+                Ok(Event::GroupUpdate(serde_json::from_slice(payload)?))
+            }
+            "notify_user_share_created" => Ok(Event::ShareCreate(serde_json::from_slice(payload)?)),
+            "notify_user_share_deleted" => Ok(Event::ShareDelete(serde_json::from_slice(payload)?)),
+            "notify_user_share_permissions" => {
+                Ok(Event::SharePermissions(serde_json::from_slice(payload)?))
+            }
+            "notify_mount_added" => Ok(Event::MountAdded(serde_json::from_slice(payload)?)),
+            "notify_mount_removed" => Ok(Event::MountRemoved(serde_json::from_slice(payload)?)),
+            "notify_test_cookie" => Ok(Event::TestCookie(serde_json::from_slice(payload)?)),
+            "notify_activity" => Ok(Event::Activity(serde_json::from_slice(payload)?)),
+            "notify_notification" => Ok(Event::Notification(serde_json::from_slice(payload)?)),
+            "notify_pre_auth" => Ok(Event::PreAuth(serde_json::from_slice(payload)?)),
+            "notify_custom" => Ok(Event::Custom(serde_json::from_slice(payload)?)),
+            "notify_group_message" => Ok(Event::GroupMessage(serde_json::from_slice(payload)?)),
+            "notify_push_broadcast" => Ok(Event::Broadcast(serde_json::from_slice(payload)?)),
+            "notify_config" => Ok(Event::Config(serde_json::from_slice(payload)?)),
+            "notify_query" => Ok(Event::Query(serde_json::from_slice(payload)?)),
+            "notify_signal" => Ok(Event::Signal(serde_json::from_slice(payload)?)),
+            _ => Err(MessageDecodeError::UnsupportedEventType),
+        }
+    }
+}
+
+/// The storage, group or users an event is for
+///
+/// Storage updates and group messages are grouped by the storage or group instead of the users they reach,
+/// since those are only known after the expensive lookup.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FairnessKey {
+    Storage(u32),
+    Group(String),
+    Users(Vec<UserId>),
+    Other,
+}
+
+/// Limits the number of events that are handled at the same time
+///
+/// Expensive and cheap events have separate limits so a burst of expensive events can't delay the cheap ones
+pub struct EventLimits {
+    expensive: FairSemaphore<FairnessKey>,
+    cheap: FairSemaphore<FairnessKey>,
+}
+
+impl EventLimits {
+    pub fn new(expensive: usize, cheap: usize) -> Self {
+        EventLimits {
+            expensive: FairSemaphore::new(expensive),
+            cheap: FairSemaphore::new(cheap),
+        }
+    }
+
+    /// Wait until the event is allowed to be handled
+    ///
+    /// When the limit is reached, waiting events are let through round-robin across the storages, groups or users they target,
+    /// so a burst of events for one of them doesn't delay the events for everyone else.
+    pub async fn acquire(&self, event: &Event) -> FairPermit<'_, FairnessKey> {
+        let semaphore = if event.is_expensive() {
+            &self.expensive
+        } else {
+            &self.cheap
+        };
+        semaphore.acquire(event.fairness_key()).await
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum MessageDecodeError {
     #[error("unsupported event type")]
@@ -107,66 +447,41 @@ impl TryFrom<Msg> for Event {
     type Error = MessageDecodeError;
 
     fn try_from(msg: Msg) -> Result<Self, Self::Error> {
-        match msg.get_channel_name() {
-            "notify_storage_update" => Ok(Event::StorageUpdate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_group_membership_update" => Ok(Event::GroupUpdate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_user_share_created" => Ok(Event::ShareCreate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_test_cookie" => Ok(Event::TestCookie(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_activity" => Ok(Event::Activity(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_notification" => Ok(Event::Notification(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_pre_auth" => Ok(Event::PreAuth(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_custom" => Ok(Event::Custom(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_config" => Ok(Event::Config(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_query" => Ok(Event::Query(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_signal" => Ok(Event::Signal(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            _ => Err(MessageDecodeError::UnsupportedEventType),
-        }
+        Event::parse(msg.get_channel_name(), msg.get_payload_bytes())
     }
 }
 
-pub async fn subscribe(
-    client: &Redis,
-) -> Result<impl Stream<Item = Result<Event, MessageDecodeError>>> {
-    let mut pubsub = client
-        .pubsub()
-        .await
-        .wrap_err("Failed to connect to redis")?;
-    let channels = [
+/// Redis channels the events are published on
+pub fn channels() -> [&'static str; 17] {
+    [
         "notify_storage_update",
         "notify_group_membership_update",
         "notify_user_share_created",
+        "notify_user_share_deleted",
+        "notify_user_share_permissions",
+        "notify_mount_added",
+        "notify_mount_removed",
         "notify_test_cookie",
         "notify_activity",
         "notify_notification",
         "notify_pre_auth",
         "notify_custom",
+        "notify_group_message",
+        "notify_push_broadcast",
         "notify_config",
         "notify_query",
         "notify_signal",
-    ];
-    for channel in channels.iter() {
+    ]
+}
+
+pub async fn subscribe(
+    client: &Redis,
+) -> Result<impl Stream<Item = Result<Event, MessageDecodeError>>> {
+    let mut pubsub = client
+        .pubsub()
+        .await
+        .wrap_err("Failed to connect to redis")?;
+    for channel in channels().iter() {
         pubsub
             .subscribe(*channel)
             .await
@@ -0,0 +1,114 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+/// Semaphore that hands out permits round-robin across keys
+///
+/// Waiters with the same key are served in order, but once all permits are taken every key with waiters
+/// gets the next free permit in turn, so a burst for a single key can't delay the waiters for every other key.
+pub struct FairSemaphore<K> {
+    state: Mutex<FairState<K>>,
+}
+
+struct FairState<K> {
+    available: usize,
+    waiting: HashMap<K, VecDeque<oneshot::Sender<()>>>,
+    /// Keys with waiters, in the order they get the next permit
+    order: VecDeque<K>,
+}
+
+impl<K: Hash + Eq + Clone> FairSemaphore<K> {
+    pub fn new(permits: usize) -> Self {
+        FairSemaphore {
+            state: Mutex::new(FairState {
+                available: permits,
+                waiting: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self, key: K) -> FairPermit<'_, K> {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            if state.available > 0 && state.order.is_empty() {
+                state.available -= 1;
+                return FairPermit { semaphore: self };
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let queue = state
+                .waiting
+                .entry(key.clone())
+                .or_insert_with(VecDeque::new);
+            if queue.is_empty() {
+                state.order.push_back(key);
+            }
+            queue.push_back(tx);
+            rx
+        };
+
+        let mut waiter = Waiter {
+            semaphore: self,
+            rx,
+            received: false,
+        };
+        // waiters are only removed from the queue by sending them a permit
+        waiter.received = (&mut waiter.rx).await.is_ok();
+        FairPermit { semaphore: self }
+    }
+
+    /// Pass a released permit to the next key with waiters
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        while let Some(key) = state.order.pop_front() {
+            let (tx, empty) = match state.waiting.get_mut(&key) {
+                Some(queue) => (queue.pop_front(), queue.is_empty()),
+                None => continue,
+            };
+            if empty {
+                state.waiting.remove(&key);
+            } else {
+                state.order.push_back(key);
+            }
+            // waiters that stopped waiting are skipped
+            if let Some(tx) = tx {
+                if tx.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        state.available += 1;
+    }
+}
+
+pub struct FairPermit<'a, K: Hash + Eq + Clone> {
+    semaphore: &'a FairSemaphore<K>,
+}
+
+impl<K: Hash + Eq + Clone> Drop for FairPermit<'_, K> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+/// Makes sure a permit that was sent to a waiter that stopped waiting is released again
+struct Waiter<'a, K: Hash + Eq + Clone> {
+    semaphore: &'a FairSemaphore<K>,
+    rx: oneshot::Receiver<()>,
+    received: bool,
+}
+
+impl<K: Hash + Eq + Clone> Drop for Waiter<'_, K> {
+    fn drop(&mut self) {
+        if !self.received {
+            self.rx.close();
+            if self.rx.try_recv().is_ok() {
+                self.semaphore.release();
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Maximum number of addresses used from the forwarded-for headers of a request
+///
+/// Clients can put anything in the header, longer chains are cut off so they can't fill the logs
+/// or the brute force protection of Nextcloud with made up addresses.
+pub const MAX_FORWARDED_FOR: usize = 16;
+
+/// Which end of the forwarded-for chain contains the address of the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedForTrust {
+    /// The first address, as set by the first proxy the client connected to
+    First,
+    /// The last address, as set by the reverse proxy in front of the push server
+    ///
+    /// Addresses before it are ignored, since any client can add those to the header.
+    Last,
+}
+
+impl Default for ForwardedForTrust {
+    fn default() -> Self {
+        ForwardedForTrust::First
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid forwarded-for trust {0}, expected `first` or `last`")]
+pub struct InvalidForwardedForTrust(String);
+
+impl FromStr for ForwardedForTrust {
+    type Err = InvalidForwardedForTrust;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(ForwardedForTrust::First),
+            "last" => Ok(ForwardedForTrust::Last),
+            _ => Err(InvalidForwardedForTrust(s.to_string())),
+        }
+    }
+}
+
+/// Build the chain of addresses for a request, starting with the address of the client
+///
+/// Only the trusted part of the forwarded-for headers is kept, limited to [`MAX_FORWARDED_FOR`] addresses,
+/// followed by the address of the peer that connected to the push server.
+pub fn client_chain(
+    forwarded_for: Vec<IpAddr>,
+    remote: Option<SocketAddr>,
+    trust: ForwardedForTrust,
+) -> Vec<IpAddr> {
+    let mut chain: Vec<IpAddr> = forwarded_for
+        .into_iter()
+        .filter(|ip| !ip.is_unspecified())
+        .collect();
+    if chain.len() > MAX_FORWARDED_FOR {
+        log::debug!(
+            "Ignoring {} addresses from the forwarded-for headers",
+            chain.len() - MAX_FORWARDED_FOR
+        );
+    }
+    match trust {
+        ForwardedForTrust::First => chain.truncate(MAX_FORWARDED_FOR),
+        ForwardedForTrust::Last => {
+            let skip = chain.len().saturating_sub(1);
+            chain.drain(..skip);
+        }
+    }
+    if let Some(remote) = remote {
+        chain.push(remote.ip());
+    }
+    chain
+}
@@ -0,0 +1,108 @@
+use crate::auth::AuthError;
+use crate::connection::authenticate;
+use crate::sse::{stream_messages, StreamItem};
+use crate::App;
+use futures::future::{join_all, FutureExt};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::spawn;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("notify_push");
+}
+
+use proto::push_server::{Push, PushServer};
+pub use proto::{Credentials, PushMessage};
+
+/// Server-streaming alternative to the websocket connection for clients that already speak gRPC
+///
+/// Like event streams the messages are send in the format of protocol version 2 and go through the same debouncing
+/// and limits as websocket connections.
+pub struct PushService {
+    app: Arc<App>,
+}
+
+impl PushService {
+    pub fn new(app: Arc<App>) -> Self {
+        PushService { app }
+    }
+}
+
+#[tonic::async_trait]
+impl Push for PushService {
+    type SubscribeStream = ReceiverStream<Result<PushMessage, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<Credentials>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let client_ip = request.remote_addr().map(|addr| addr.ip());
+        let credentials = request.into_inner();
+        let (user_id, held) = authenticate(
+            &self.app,
+            &credentials.username,
+            &credentials.password,
+            client_ip.into_iter().collect(),
+        )
+        .await
+        .map_err(|e| {
+            e.record("grpc client");
+            to_status(&e)
+        })?;
+
+        let app = self.app.clone();
+        let (tx, rx) = mpsc::channel(8);
+        spawn(async move {
+            stream_messages(&app, user_id, held, client_ip, tx, |item| match item {
+                StreamItem::Message(json) => Ok(PushMessage {
+                    r#type: json["type"].as_str().unwrap_or_default().to_string(),
+                    json: json.to_string(),
+                }),
+                StreamItem::Error(e) => Err(to_status(&e)),
+            })
+            .await
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+fn to_status(e: &AuthError) -> Status {
+    let message = e.to_json().to_string();
+    match e {
+        AuthError::InvalidCredentials => Status::unauthenticated(message),
+        AuthError::InvalidMessage => Status::invalid_argument(message),
+        AuthError::Disconnected => Status::cancelled(message),
+        AuthError::NextcloudUnreachable(_)
+        | AuthError::NextcloudError(_)
+        | AuthError::RateLimited(_) => Status::unavailable(message),
+        AuthError::LimitExceeded(_) => Status::resource_exhausted(message),
+    }
+}
+
+/// Serve the gRPC api on each of the addresses until cancelled
+pub fn serve_grpc(
+    app: Arc<App>,
+    addresses: Vec<SocketAddr>,
+    cancel: oneshot::Receiver<()>,
+) -> impl Future<Output = ()> + Send {
+    let cancel = cancel.map(|_| ()).shared();
+    let servers = addresses.into_iter().map(move |addr| {
+        let service = PushServer::new(PushService::new(app.clone()));
+        let cancel = cancel.clone();
+        async move {
+            if let Err(e) = Server::builder()
+                .add_service(service)
+                .serve_with_shutdown(addr, cancel)
+                .await
+            {
+                log::error!("Failed to serve gRPC api on {}: {}", addr, e);
+            }
+        }
+    });
+    join_all(servers).map(|_| ())
+}
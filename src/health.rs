@@ -0,0 +1,66 @@
+use color_eyre::{Report, Result};
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Maximum time a single health check can take before it's considered failed
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of checking a single dependency
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Failed { error: String },
+}
+
+impl CheckStatus {
+    /// Run a check, failing it if it doesn't finish within [`HEALTH_CHECK_TIMEOUT`]
+    pub async fn run(check: impl Future<Output = Result<()>>) -> Self {
+        let result = match timeout(HEALTH_CHECK_TIMEOUT, check).await {
+            Ok(result) => result,
+            Err(_) => Err(Report::msg("Timeout while running check")),
+        };
+        match result {
+            Ok(()) => CheckStatus::Ok,
+            Err(e) => CheckStatus::Failed {
+                error: format!("{:#}", e),
+            },
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self, CheckStatus::Ok)
+    }
+}
+
+/// Number of items waiting to be handled
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueDepths {
+    /// Events received but not finished yet
+    pub pending_events: usize,
+    pub scheduled_messages: usize,
+    /// Storage updates kept while the database is unavailable
+    pub buffered_storage_updates: usize,
+}
+
+/// State of the push server and the services it depends on
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub redis: CheckStatus,
+    pub database: CheckStatus,
+    pub nextcloud: CheckStatus,
+    /// Whether messages published by Nextcloud are reaching the push server
+    pub receiving_events: bool,
+    pub queues: QueueDepths,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.redis.is_ok()
+            && self.database.is_ok()
+            && self.nextcloud.is_ok()
+            && self.receiving_events
+    }
+}
@@ -0,0 +1,108 @@
+use crate::App;
+use futures::future::select;
+use futures::pin_mut;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::interval;
+
+/// Whether messages published by Nextcloud are reaching the push server
+///
+/// Nextcloud stores the last test cookie it published, if the push server keeps seeing a different cookie than
+/// the one Nextcloud has, the messages from Nextcloud aren't arriving over redis.
+pub struct Heartbeat {
+    healthy: AtomicBool,
+    failure_count: AtomicUsize,
+    state: Mutex<HeartbeatState>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            healthy: AtomicBool::new(true),
+            failure_count: AtomicUsize::new(0),
+            state: Mutex::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct HeartbeatState {
+    /// Cookie published before the push server started, which the push server can't have received
+    startup_cookie: Option<u32>,
+    /// Cookie that didn't match during the previous check
+    mismatch: Option<u32>,
+}
+
+impl Heartbeat {
+    /// Compare the last received test cookie with the one Nextcloud published
+    ///
+    /// A cookie is only considered missing when it still doesn't match on the next check, to give the message
+    /// time to arrive if Nextcloud published it right before the check.
+    pub fn check(&self, received: u32, published: u32) {
+        let mut state = self.state.lock().unwrap();
+        if received == published {
+            state.mismatch = None;
+            if !self.healthy.swap(true, Ordering::Relaxed) {
+                log::info!("Receiving messages from Nextcloud again");
+            }
+            return;
+        }
+        if received == 0 && state.startup_cookie.is_none() && state.mismatch.is_none() {
+            state.startup_cookie = Some(published);
+        }
+        if state.startup_cookie == Some(published) {
+            return;
+        }
+        if state.mismatch == Some(published) {
+            self.failure_count.fetch_add(1, Ordering::Relaxed);
+            if self.healthy.swap(false, Ordering::Relaxed) {
+                log::error!(
+                    "Test cookie {} published by Nextcloud was not received (last received {}), \
+                    Nextcloud and the push server are probably not connected to the same redis server",
+                    published,
+                    received
+                );
+            }
+        }
+        state.mismatch = Some(published);
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Number of checks where the cookie published by Nextcloud wasn't received
+    pub fn failure_count(&self) -> usize {
+        self.failure_count.load(Ordering::Relaxed)
+    }
+}
+
+/// Periodically check that the test cookie published by Nextcloud is received
+pub async fn heartbeat_loop(
+    app: Arc<App>,
+    check_interval: Duration,
+    cancel: oneshot::Receiver<()>,
+) {
+    let loop_ = async move {
+        let mut interval = interval(check_interval);
+        loop {
+            interval.tick().await;
+            match app.nc_client.get_test_cookie().await {
+                Ok(published) => {
+                    let received = app.test_cookie.load(Ordering::SeqCst);
+                    log::debug!(
+                        "heartbeat: received test cookie {}, nextcloud published {}",
+                        received,
+                        published
+                    );
+                    app.heartbeat.check(received, published);
+                }
+                Err(e) => log::warn!("Failed to load test cookie from Nextcloud: {:#}", e),
+            }
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
@@ -0,0 +1,135 @@
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Idle times of connections that were dropped without being closed, to detect proxies with a short idle timeout
+pub static IDLE_TIMEOUTS: Lazy<IdleTimeouts> = Lazy::new(IdleTimeouts::default);
+
+/// Number of recent disconnects the timeout is estimated from
+const SAMPLE_COUNT: usize = 50;
+
+/// Minimum number of disconnects with a similar idle time before a timeout is assumed
+const MIN_SAMPLES: usize = 5;
+
+/// Disconnects within this range of each other are assumed to be caused by the same timeout
+const CLUSTER_WIDTH: Duration = Duration::from_secs(2);
+
+/// Time since the last frame was send or received over a connection
+pub struct Activity {
+    last: Mutex<Instant>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Activity {
+    pub fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.last.lock().unwrap().elapsed()
+    }
+}
+
+/// Estimate of the idle timeout of a proxy between the clients and the push server
+///
+/// A proxy that drops connections after a fixed idle time shows up as a cluster of disconnects with the same idle time,
+/// unrelated disconnects (clients losing network, etc) are spread out and ignored.
+#[derive(Default)]
+pub struct IdleTimeouts {
+    state: Mutex<IdleState>,
+}
+
+#[derive(Default)]
+struct IdleState {
+    samples: VecDeque<Duration>,
+    count: usize,
+    /// Last recommendation that was logged, to only log changes
+    recommended: Option<Duration>,
+}
+
+impl IdleTimeouts {
+    /// Record the idle time of a connection that was dropped without being closed
+    pub fn record(&self, idle: Duration, ping_interval: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if state.samples.len() == SAMPLE_COUNT {
+            state.samples.pop_front();
+        }
+        state.samples.push_back(idle);
+        state.count += 1;
+
+        let observed = estimate_timeout(&state.samples);
+        let recommended =
+            observed.and_then(|timeout| recommended_ping_interval(timeout, ping_interval));
+        if recommended != state.recommended {
+            if let (Some(observed), Some(recommended)) = (observed, recommended) {
+                log::warn!(
+                    "Connections are dropped after being idle for {}s, likely by a proxy timeout. \
+                    Increase the idle timeout of the proxy above {}s or lower the ping interval to {}s",
+                    observed.as_secs(),
+                    ping_interval.as_secs(),
+                    recommended.as_secs()
+                );
+            }
+            state.recommended = recommended;
+        }
+    }
+
+    /// Number of connections dropped without being closed
+    pub fn idle_disconnect_count(&self) -> usize {
+        self.state.lock().unwrap().count
+    }
+
+    /// Idle time after which most connections are dropped, if the recent disconnects point to a timeout
+    pub fn observed_timeout(&self) -> Option<Duration> {
+        estimate_timeout(&self.state.lock().unwrap().samples)
+    }
+
+    /// Ping interval that keeps connections alive with the observed timeout
+    pub fn recommended_ping_interval(&self, ping_interval: Duration) -> Option<Duration> {
+        self.observed_timeout()
+            .and_then(|timeout| recommended_ping_interval(timeout, ping_interval))
+    }
+}
+
+/// Find the largest cluster of idle times, if it contains at least half of the samples
+fn estimate_timeout(samples: &VecDeque<Duration>) -> Option<Duration> {
+    if samples.len() < MIN_SAMPLES {
+        return None;
+    }
+    let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+    sorted.sort_unstable();
+
+    let (start, size) = (0..sorted.len())
+        .map(|start| {
+            let size = sorted[start..]
+                .iter()
+                .take_while(|idle| **idle - sorted[start] <= CLUSTER_WIDTH)
+                .count();
+            (start, size)
+        })
+        .max_by_key(|(start, size)| (*size, std::cmp::Reverse(*start)))?;
+
+    if size >= MIN_SAMPLES && size * 2 >= sorted.len() {
+        Some(sorted[start])
+    } else {
+        None
+    }
+}
+
+/// Stay well below the observed timeout, pings are only send after the interval passed without other messages
+fn recommended_ping_interval(timeout: Duration, ping_interval: Duration) -> Option<Duration> {
+    let recommended = Duration::from_secs(timeout.as_secs() * 3 / 4);
+    if recommended < ping_interval && recommended >= Duration::from_secs(1) {
+        Some(recommended)
+    } else {
+        None
+    }
+}
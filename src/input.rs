@@ -0,0 +1,86 @@
+use crate::event::{Event, MessageDecodeError};
+use crate::App;
+use futures::future::select;
+use futures::pin_mut;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{stdin, AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::oneshot;
+
+/// Parse a line of the event input, the redis channel followed by the json payload
+///
+/// Empty lines and lines starting with `#` are skipped.
+pub fn parse_line(line: &str) -> Option<Result<Event, MessageDecodeError>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (channel, payload) = match line.find(char::is_whitespace) {
+        Some(split) => (&line[..split], line[split..].trim_start()),
+        None => (line, ""),
+    };
+    Some(Event::parse(channel, payload.as_bytes()))
+}
+
+/// Handle events written to stdin or a named pipe the same way as events received from redis
+///
+/// This is intended for reproducing how an event is handled without having to publish it to redis.
+/// Events are handled one at a time in the order they are written. A named pipe is opened again
+/// whenever the writer closes it, stdin or a regular file is only read once.
+pub async fn event_input_loop(app: Arc<App>, path: PathBuf, cancel: oneshot::Receiver<()>) {
+    let loop_ = async move {
+        if path == Path::new("-") {
+            log::info!("Reading events from stdin");
+            read_events(&app, stdin()).await;
+            return;
+        }
+
+        let is_fifo = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.file_type().is_fifo(),
+            Err(e) => {
+                log::error!("Can't read events from {}: {}", path.display(), e);
+                return;
+            }
+        };
+        log::info!("Reading events from {}", path.display());
+        loop {
+            // opening a named pipe waits until a writer opens it
+            match File::open(&path).await {
+                Ok(file) => read_events(&app, file).await,
+                Err(e) => {
+                    log::error!("Can't read events from {}: {}", path.display(), e);
+                    return;
+                }
+            }
+            if !is_fifo {
+                return;
+            }
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
+
+async fn read_events(app: &App, input: impl AsyncRead + Unpin) {
+    let mut lines = BufReader::new(input).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("Failed to read event input: {}", e);
+                return;
+            }
+        };
+        match parse_line(&line) {
+            Some(Ok(event)) => {
+                log::debug!(target: "notify_push::receive", "Received {} from event input", event);
+                app.dispatch(event).await;
+            }
+            Some(Err(e)) => log::warn!("Invalid event input {:?}: {}", line, e),
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use crate::capabilities::{capabilities_key, Capabilities};
+use crate::metrics::{Metrics, METRICS};
+use crate::redis::WriteCommand;
+use crate::App;
+use color_eyre::{eyre::WrapErr, Result};
+use futures::future::select;
+use futures::pin_mut;
+use parse_display::Display;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::time::interval;
+use warp::Reply;
+
+/// How often the version, instance, capabilities and metrics keys are refreshed
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Expiry of the version and instance keys, a daemon that stops refreshing them is considered gone after this
+const ANNOUNCE_TTL: usize = 90;
+
+/// Set containing the ids of all instances that wrote a metrics snapshot
+const INSTANCES_KEY: &str = "notify_push_instances";
+
+/// Conflicting instances found by the last check, for the metrics
+pub static INSTANCE_CONFLICTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Metadata published by a running daemon so Nextcloud can see which push servers are alive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceInfo {
+    pub id: String,
+    pub version: String,
+    pub started: u64,
+    pub connections: usize,
+    /// Not set by older versions
+    #[serde(default)]
+    pub nextcloud_url: Option<String>,
+}
+
+/// Snapshot of the metrics of a running daemon, written periodically so the metrics are available without querying the daemon
+#[derive(Serialize)]
+struct MetricsSnapshot<'a> {
+    instance: &'a str,
+    time: u64,
+    #[serde(flatten)]
+    metrics: &'a Metrics,
+}
+
+impl InstanceInfo {
+    pub fn new(app: &App) -> Self {
+        InstanceInfo {
+            id: app.instance_id.clone(),
+            version: env!("NOTIFY_PUSH_VERSION").to_string(),
+            started: app.start_time,
+            connections: METRICS.active_connection_count(),
+            nextcloud_url: Some(app.nc_client.primary_url().to_string()),
+        }
+    }
+
+    pub fn redis_key(&self) -> String {
+        instance_key(&self.id)
+    }
+}
+
+fn instance_key(id: &str) -> String {
+    format!("notify_push_instance_{}", id)
+}
+
+/// Another push server on the same redis server that causes duplicate or missing notifications
+#[derive(Debug, Clone, PartialEq, Display)]
+pub enum InstanceConflict {
+    /// Both instances handle the same events under one id, so they can't be told apart
+    #[display("another push server (started at {started}) uses the same instance id {id}")]
+    DuplicateId { id: String, started: u64 },
+    /// The other instance receives the events from a different Nextcloud instance on the same redis server
+    #[display("push server {id} is configured for a different Nextcloud instance at {url}")]
+    NextcloudUrl { id: String, url: String },
+}
+
+/// Find other instances on the same redis server that conflict with this one
+///
+/// Running multiple push servers for the same Nextcloud is fine, but two Nextcloud instances sharing a redis server
+/// without a separate key prefix receive each other's events, and an instance id in use twice mixes up the
+/// instance keys and metrics snapshots of both.
+pub async fn find_conflicts(app: &App) -> Result<Vec<InstanceConflict>> {
+    let own_url = app.nc_client.primary_url().to_string();
+    let mut connection = app.redis.connect().await?;
+    let mut conflicts = Vec::new();
+    for id in connection.smembers(INSTANCES_KEY).await? {
+        let info = match connection.get_optional(&instance_key(&id)).await? {
+            Some(info) => info,
+            None => continue,
+        };
+        let info: InstanceInfo = match serde_json::from_str(&info) {
+            Ok(info) => info,
+            Err(_) => continue,
+        };
+        if info.id == app.instance_id {
+            // our own key is only written by us, unless another instance uses the same id
+            if info.started != app.start_time {
+                conflicts.push(InstanceConflict::DuplicateId {
+                    id: info.id,
+                    started: info.started,
+                });
+            }
+        } else if let Some(url) = info.nextcloud_url.filter(|url| *url != own_url) {
+            conflicts.push(InstanceConflict::NextcloudUrl { id: info.id, url });
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Add the instance id and current load to a response
+///
+/// Load balancers can use these to route new connections to the least loaded instance
+pub fn with_affinity_headers(app: &App, reply: impl Reply) -> impl Reply {
+    let reply = warp::reply::with_header(reply, "x-notify-push-instance", app.instance_id.clone());
+    warp::reply::with_header(
+        reply,
+        "x-notify-push-load",
+        METRICS.active_connection_count().to_string(),
+    )
+}
+
+pub fn generate_instance_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Write the version, instance metadata, capabilities and metrics snapshot to redis
+pub async fn announce(app: &App) -> Result<()> {
+    let info = InstanceInfo::new(app);
+    let snapshot = MetricsSnapshot {
+        instance: &app.instance_id,
+        time: unix_timestamp(),
+        metrics: &METRICS,
+    };
+    app.redis_writer
+        .write(vec![
+            WriteCommand::Set {
+                key: "notify_push_version".into(),
+                value: env!("NOTIFY_PUSH_VERSION").into(),
+                ttl: Some(ANNOUNCE_TTL),
+            },
+            WriteCommand::Set {
+                key: info.redis_key(),
+                value: serde_json::to_string(&info)?,
+                ttl: Some(ANNOUNCE_TTL),
+            },
+            WriteCommand::Set {
+                key: capabilities_key(&info.id),
+                value: serde_json::to_string(&Capabilities::new(app))?,
+                ttl: Some(ANNOUNCE_TTL),
+            },
+            WriteCommand::Set {
+                key: snapshot_key(&info.id),
+                value: serde_json::to_string(&snapshot)?,
+                ttl: Some(ANNOUNCE_TTL),
+            },
+            WriteCommand::SAdd {
+                key: INSTANCES_KEY.into(),
+                member: info.id.clone(),
+            },
+        ])
+        .await
+        .wrap_err("Failed to write instance info")
+}
+
+fn snapshot_key(id: &str) -> String {
+    format!("notify_push_metrics_snapshot_{}", id)
+}
+
+/// Metrics summed over the snapshots of all running instances
+#[derive(Debug, Default)]
+pub struct ClusterMetrics {
+    pub instances: usize,
+    pub metrics: BTreeMap<String, u64>,
+}
+
+/// Aggregate the metrics snapshots of all running instances
+///
+/// Instances whose snapshot has expired are removed from the instance set.
+pub async fn cluster_metrics(app: &App) -> Result<ClusterMetrics> {
+    let mut connection = app.redis.connect().await?;
+    let mut cluster = ClusterMetrics::default();
+    for id in connection.smembers(INSTANCES_KEY).await? {
+        let snapshot = match connection.get_optional(&snapshot_key(&id)).await? {
+            Some(snapshot) => snapshot,
+            None => {
+                connection.srem(INSTANCES_KEY, &id).await?;
+                continue;
+            }
+        };
+        let snapshot: Map<String, Value> =
+            serde_json::from_str(&snapshot).wrap_err("Invalid metrics snapshot")?;
+        cluster.instances += 1;
+        for (name, value) in snapshot {
+            if name == "time" {
+                continue;
+            }
+            if let Some(value) = value.as_u64() {
+                *cluster.metrics.entry(name).or_default() += value;
+            }
+        }
+    }
+    Ok(cluster)
+}
+
+/// Periodically refresh the version and instance keys until cancelled
+///
+/// Before every refresh the other instances are checked for conflicts, new conflicts are logged as errors.
+pub async fn announce_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
+    let loop_ = async move {
+        let mut interval = interval(ANNOUNCE_INTERVAL);
+        let mut reported: Vec<InstanceConflict> = Vec::new();
+        loop {
+            interval.tick().await;
+            match find_conflicts(&app).await {
+                Ok(conflicts) => {
+                    INSTANCE_CONFLICTS.store(conflicts.len(), Ordering::Relaxed);
+                    for conflict in conflicts.iter().filter(|c| !reported.contains(c)) {
+                        log::error!(
+                            "Conflicting push server on the same redis server, \
+                            notifications will be duplicated or missing: {}",
+                            conflict
+                        );
+                    }
+                    reported = conflicts;
+                }
+                Err(e) => log::warn!("Failed to check for conflicting instances: {:#}", e),
+            }
+            if let Err(e) = announce(&app).await {
+                log::warn!("Failed to announce instance: {:#}", e);
+            }
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
@@ -0,0 +1,88 @@
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Round trip times measured from the pings send to websocket clients
+pub static LATENCIES: Lazy<Latencies> = Lazy::new(Latencies::default);
+
+/// Number of recent round trip times the percentiles are calculated from
+const SAMPLE_COUNT: usize = 1024;
+
+/// Percentiles of the round trip times that are exported in the metrics
+pub const RTT_QUANTILES: &[f64] = &[0.5, 0.9, 0.99];
+
+/// Round trip time of a single connection
+///
+/// The ping is timestamped when it's send, the time until the matching pong arrives is smoothed
+/// so a single slow pong doesn't flip the connection into high latency mode.
+#[derive(Default)]
+pub struct Rtt {
+    ping_send: Mutex<Option<Instant>>,
+    smoothed: Mutex<Option<Duration>>,
+}
+
+impl Rtt {
+    pub fn ping_send(&self) {
+        *self.ping_send.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Record the round trip of the last ping once its pong is received
+    pub fn pong_received(&self) {
+        let rtt = match self.ping_send.lock().unwrap().take() {
+            Some(send) => send.elapsed(),
+            None => return,
+        };
+        LATENCIES.record(rtt);
+        let mut smoothed = self.smoothed.lock().unwrap();
+        *smoothed = Some(match *smoothed {
+            // same weight as the smoothed rtt of tcp
+            Some(previous) => (previous * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    pub fn smoothed(&self) -> Option<Duration> {
+        *self.smoothed.lock().unwrap()
+    }
+
+    /// Whether the smoothed round trip time is above the threshold
+    pub fn exceeds(&self, threshold: Option<Duration>) -> bool {
+        match (threshold, self.smoothed()) {
+            (Some(threshold), Some(rtt)) => rtt > threshold,
+            _ => false,
+        }
+    }
+}
+
+/// Recent round trip times of all connections
+#[derive(Default)]
+pub struct Latencies {
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl Latencies {
+    pub fn record(&self, rtt: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == SAMPLE_COUNT {
+            samples.pop_front();
+        }
+        samples.push_back(rtt);
+    }
+
+    /// The round trip times at the requested quantiles, empty if no round trip was measured yet
+    pub fn percentiles(&self, quantiles: &[f64]) -> Vec<(f64, Duration)> {
+        let mut sorted: Vec<Duration> = self.samples.lock().unwrap().iter().copied().collect();
+        if sorted.is_empty() {
+            return Vec::new();
+        }
+        sorted.sort_unstable();
+        quantiles
+            .iter()
+            .map(|quantile| {
+                let index = ((sorted.len() - 1) as f64 * quantile).round() as usize;
+                (*quantile, sorted[index.min(sorted.len() - 1)])
+            })
+            .collect()
+    }
+}
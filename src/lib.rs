@@ -1,84 +1,189 @@
 use crate::config::{Bind, Config, TlsConfig};
 use crate::connection::{handle_user_socket, ActiveConnections};
+use crate::control::{ControlBus, ControlMessage};
+use crate::dedup::Deduplication;
 use crate::event::{
-    Activity, Custom, Event, GroupUpdate, Notification, PreAuth, ShareCreate, StorageUpdate,
+    Activity, Broadcast, Custom, Event, EventLimits, GroupMessage, GroupUpdate, LogTargetChange,
+    MountUpdate, Notification, PreAuth, ShareCreate, ShareDelete, SharePermissions, StorageUpdate,
+};
+use crate::forwarded::{client_chain, ForwardedForTrust};
+use crate::health::{CheckStatus, HealthReport, QueueDepths};
+use crate::heartbeat::Heartbeat;
+use crate::instance::{generate_instance_id, unix_timestamp, InstanceInfo};
+use crate::limits::{LimitError, Limits};
+use crate::logging::LogSpecs;
+use crate::message::{
+    ActivityPayload, DebounceBounds, FileChangeReason, FilePayload, MessageType,
+    NotificationPayload, DEBOUNCE_ACTIVITY, DEBOUNCE_FILE, DEBOUNCE_NOTIFICATION,
 };
-use crate::message::MessageType;
 use crate::metrics::METRICS;
-use crate::redis::Redis;
-use crate::storage_mapping::StorageMapping;
+use crate::nc::ClientTls;
+use crate::observer::{DaemonEvent, Observer};
+use crate::offline::OfflineQueue;
+use crate::ordering::{EventOrder, Outbox};
+use crate::poll::PollQuery;
+use crate::pre_auth::{PreAuthTokens, TokenHash, TokenRequirements};
+use crate::protocol::MAX_FRAME_SIZE;
+use crate::protocol::{negotiate_subprotocol, PingConfig};
+use crate::redis::{
+    is_failover_error, DemotedError, Redis, RedisWriter, WriteCommand, ROLE_CHECK_INTERVAL,
+};
+use crate::resume::ResumeTokens;
+use crate::room::Rooms;
+use crate::schedule::Schedule;
+use crate::sse::SseQuery;
+use crate::storage_mapping::{
+    DatabaseErrorStrategy, DatabaseUnavailable, StorageMapping, UpdateBuffer,
+};
+use crate::user::keep_user_names;
 pub use crate::user::UserId;
-use ahash::RandomState;
-use color_eyre::{eyre::WrapErr, Result};
-use dashmap::DashMap;
+use color_eyre::{eyre::WrapErr, Report, Result};
 use flexi_logger::LoggerHandle;
-use futures::future::{select, Either};
+use futures::future::{join_all, select, BoxFuture, Either};
 use futures::StreamExt;
 use futures::{pin_mut, FutureExt};
+use log::LevelFilter;
+use serde_json::Value;
 use smallvec::alloc::sync::Arc;
 use sqlx::AnyPool;
 use std::convert::Infallible;
 use std::fs;
 use std::future::Future;
-use std::net::{IpAddr, SocketAddr};
-use std::os::unix::fs::PermissionsExt;
+use std::net::{IpAddr, SocketAddr, TcpListener as StdTcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::time::{Duration, Instant};
-use tokio::net::UnixListener;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 use tokio::sync::{broadcast, oneshot};
-use tokio::time::sleep;
-use tokio_stream::wrappers::UnixListenerStream;
+use tokio::time::{interval, sleep};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
 use warp::filters::addr::remote;
-use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::{Filter, Rejection, Reply};
 use warp_real_ip::get_forwarded_for;
 
+#[cfg(not(any(feature = "mysql", feature = "postgres", feature = "sqlite")))]
+compile_error!("at least one of the `mysql`, `postgres` or `sqlite` features needs to be enabled");
+
+pub mod ack;
+pub mod admin;
+pub mod auth;
+pub mod backpressure;
+pub mod capabilities;
 pub mod config;
 pub mod connection;
+pub mod control;
+pub mod daemon;
+pub mod dedup;
+pub mod diagnostics;
+pub mod disconnect;
 pub mod event;
+pub mod fair;
+pub mod forwarded;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod heartbeat;
+pub mod idle;
+pub mod input;
+pub mod instance;
+pub mod latency;
+pub mod limits;
+pub mod logging;
+pub mod memory;
 pub mod message;
 pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
 pub mod nc;
+pub mod observer;
+pub mod offline;
+pub mod ordering;
+pub mod poll;
+pub mod pre_auth;
+pub mod presence;
+pub mod probe;
+pub mod protocol;
+pub mod reconnect;
 pub mod redis;
+pub mod registry;
+pub mod resume;
+pub mod room;
+pub mod schedule;
+pub mod schema;
+pub mod sse;
+pub mod statsd;
 pub mod storage_mapping;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod upgrade;
 pub mod user;
+pub mod webhook;
+
+/// How often the tls files used to connect to Nextcloud are checked for changes
+const TLS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time to wait before subscribing again after the redis server disconnected
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Time to wait before subscribing again after the first failover error, doubled for every failover error in a row
+const FAILOVER_DELAY: Duration = Duration::from_millis(100);
 
 pub struct App {
     connections: ActiveConnections,
     nc_client: nc::Client,
     storage_mapping: StorageMapping,
-    pre_auth: DashMap<String, (Instant, UserId), RandomState>,
+    pre_auth: PreAuthTokens,
     test_cookie: AtomicU32,
+    heartbeat: Heartbeat,
     redis: Redis,
-    log_handle: Mutex<LoggerHandle>,
-    reset_tx: broadcast::Sender<()>,
-    _reset_rx: broadcast::Receiver<()>,
+    redis_writer: RedisWriter,
+    log_specs: Mutex<LogSpecs>,
+    control: ControlBus,
+    instance_id: String,
+    start_time: u64,
+    admin_token: Option<String>,
+    event_limits: EventLimits,
+    event_order: EventOrder,
+    resume_tokens: ResumeTokens,
+    observer: Observer,
+    path_prefixes: Vec<String>,
+    forwarded_for_trust: ForwardedForTrust,
+    limits: Limits,
+    database_error_strategy: DatabaseErrorStrategy,
+    update_buffer: UpdateBuffer,
+    file_change_hints: bool,
+    handshake_banner: bool,
+    debounce_bounds: DebounceBounds,
+    ping: PingConfig,
+    pre_auth_requirements: TokenRequirements,
+    rooms: Rooms,
+    rooms_enabled: bool,
+    user_patterns: bool,
+    schedule: Schedule,
+    dedup: Option<Deduplication>,
+    fallback_keep_alive: Option<Duration>,
 }
 
 impl App {
     pub async fn new(config: Config, log_handle: LoggerHandle) -> Result<Self> {
-        let connections = ActiveConnections::default();
-        let nc_client = nc::Client::new(&config.nextcloud_url, config.allow_self_signed)?;
-        let test_cookie = AtomicU32::new(0);
-
-        let storage_mapping = StorageMapping::new(config.database, config.database_prefix).await?;
-        let pre_auth = DashMap::default();
-
-        let redis = Redis::new(config.redis)?;
-
-        let (reset_tx, reset_rx) = broadcast::channel(1);
+        let storage_mapping = StorageMapping::new(
+            config.database.clone(),
+            config.database_prefix.clone(),
+            config.path_match,
+        )
+        .await?;
+        let tls = config.nextcloud_tls();
 
-        Ok(App {
-            connections,
-            nc_client,
-            test_cookie,
-            pre_auth,
-            storage_mapping,
-            redis,
-            log_handle: Mutex::new(log_handle),
-            reset_tx,
-            _reset_rx: reset_rx,
-        })
+        Self::build(storage_mapping, config, log_handle, tls)
     }
 
     pub async fn with_connection(
@@ -87,31 +192,120 @@ impl App {
         log_handle: LoggerHandle,
         allow_self_signed: bool,
     ) -> Result<Self> {
-        let connections = ActiveConnections::default();
-        let nc_client = nc::Client::new(&config.nextcloud_url, allow_self_signed)?;
+        let storage_mapping = StorageMapping::from_connection(
+            connection,
+            config.database_prefix.clone(),
+            config.path_match,
+        )
+        .await?;
+        let tls = ClientTls {
+            allow_self_signed,
+            ..config.nextcloud_tls()
+        };
+
+        Self::build(storage_mapping, config, log_handle, tls)
+    }
+
+    /// Create the app with a custom storage mapping, for embedders that resolve storages to users themselves
+    pub async fn with_storage_mapping(
+        storage_mapping: StorageMapping,
+        config: Config,
+        log_handle: LoggerHandle,
+    ) -> Result<Self> {
+        let tls = config.nextcloud_tls();
+        Self::build(storage_mapping, config, log_handle, tls)
+    }
+
+    fn build(
+        storage_mapping: StorageMapping,
+        config: Config,
+        log_handle: LoggerHandle,
+        tls: ClientTls,
+    ) -> Result<Self> {
+        let redis_writer = RedisWriter::new(config.redis.clone())?;
+        let offline = match config.offline_queue_ttl {
+            Some(ttl) => Some(OfflineQueue::new(
+                Redis::new(config.redis.clone())?,
+                redis_writer.clone(),
+                ttl,
+            )),
+            None => None,
+        };
+        let connections = ActiveConnections::new(config.registry, offline);
+        let nc_client = nc::Client::with_urls(&config.nextcloud_urls(), tls)?;
         let test_cookie = AtomicU32::new(0);
 
-        let storage_mapping =
-            StorageMapping::from_connection(connection, config.database_prefix).await?;
-        let pre_auth = DashMap::default();
+        let pre_auth = PreAuthTokens::new(config.registry);
 
-        let redis = Redis::new(config.redis)?;
+        // the admin reports list users by name
+        if config.admin_token.is_some() {
+            keep_user_names();
+        }
+        // access to rooms is checked with Nextcloud by user name,
+        // and broadcasts for a user pattern are matched against the names of the connected users
+        if config.rooms || config.user_patterns {
+            keep_user_names();
+        }
+        // resume tokens are saved by user name so they can be restored by a different build
+        if config.state_file.is_some() {
+            keep_user_names();
+        }
 
-        let (reset_tx, reset_rx) = broadcast::channel(1);
+        let redis = Redis::new(config.redis)?;
 
         Ok(App {
             connections,
             nc_client,
             test_cookie,
+            heartbeat: Heartbeat::default(),
             pre_auth,
             storage_mapping,
             redis,
-            log_handle: Mutex::new(log_handle),
-            reset_tx,
-            _reset_rx: reset_rx,
+            redis_writer,
+            log_specs: Mutex::new(LogSpecs::new(
+                log_handle,
+                config.log_level.clone(),
+                &config.log_targets,
+            )),
+            control: ControlBus::default(),
+            instance_id: generate_instance_id(),
+            start_time: unix_timestamp(),
+            admin_token: config.admin_token,
+            event_limits: EventLimits::new(
+                config.storage_update_concurrency,
+                config.event_concurrency,
+            ),
+            event_order: EventOrder::default(),
+            resume_tokens: ResumeTokens::default(),
+            observer: Observer::default(),
+            path_prefixes: config.path_prefixes,
+            forwarded_for_trust: config.forwarded_for_trust,
+            limits: Limits::new(config.limits),
+            database_error_strategy: config.database_error_strategy,
+            update_buffer: UpdateBuffer::default(),
+            file_change_hints: config.file_change_hints,
+            handshake_banner: config.handshake_banner,
+            debounce_bounds: config.debounce_bounds,
+            ping: config.ping,
+            pre_auth_requirements: config.pre_auth_requirements,
+            rooms: Rooms::default(),
+            rooms_enabled: config.rooms,
+            user_patterns: config.user_patterns,
+            schedule: Schedule::default(),
+            dedup: config.dedup_window.map(Deduplication::new),
+            fallback_keep_alive: config.fallback_keep_alive,
         })
     }
 
+    /// Save the resume tokens of all connections so clients can resume their session after a restart
+    pub fn save_state(&self, path: &Path) -> Result<()> {
+        self.resume_tokens.save(path)
+    }
+
+    pub fn restore_state(&self, path: &Path) -> Result<()> {
+        self.resume_tokens.restore(path)
+    }
+
     pub async fn self_test(&self) -> Result<()> {
         let _ = self
             .storage_mapping
@@ -146,94 +340,491 @@ impl App {
         Ok(())
     }
 
-    async fn handle_event(&self, event: Event) {
+    /// Check the services the push server depends on
+    ///
+    /// The checks run concurrently and each fails after [`health::HEALTH_CHECK_TIMEOUT`].
+    pub async fn health(&self) -> HealthReport {
+        let redis = CheckStatus::run(async {
+            let mut redis = self.redis.connect().await?;
+            redis.get_optional("notify_push_app_version").await?;
+            Ok(())
+        });
+        let database = CheckStatus::run(self.storage_mapping.check_backend());
+        let nextcloud = CheckStatus::run(async {
+            self.nc_client.get_test_cookie().await?;
+            Ok(())
+        });
+        let (redis, database, nextcloud) = tokio::join!(redis, database, nextcloud);
+
+        HealthReport {
+            redis,
+            database,
+            nextcloud,
+            receiving_events: self.heartbeat.is_healthy(),
+            queues: QueueDepths {
+                pending_events: self.event_order.pending_count(),
+                scheduled_messages: self.schedule.scheduled_count(),
+                buffered_storage_updates: self.update_buffer.len(),
+            },
+        }
+    }
+
+    /// Handle an event as if it was received from redis
+    ///
+    /// This allows embedders and tests to feed events into the app without a redis server,
+    /// the messages for each user are still delivered in the order the events are dispatched in.
+    pub async fn dispatch(&self, event: Event) {
+        let ticket = self.event_order.ticket(&event);
+        let mut outbox = Outbox::default();
+        {
+            let _permit = self.event_limits.acquire(&event).await;
+            self.handle_event(event, &mut outbox).await;
+        }
+        ticket.deliver(outbox, &self.connections).await;
+    }
+
+    async fn handle_event(&self, event: Event, outbox: &mut Outbox) {
         match event {
             Event::StorageUpdate(StorageUpdate { storage, path }) => {
-                match self
-                    .storage_mapping
-                    .get_users_for_storage_path(storage, &path)
-                    .await
-                {
-                    Ok(users) => {
-                        for user in users {
-                            self.connections
-                                .send_to_user(&user, MessageType::File)
-                                .await;
-                        }
-                    }
-                    Err(e) => log::error!("{:#}", e),
+                if self.handle_storage_update(storage, path, outbox).await {
+                    self.replay_storage_updates(outbox).await;
                 }
             }
-            Event::GroupUpdate(GroupUpdate { user, .. }) => {
-                self.connections
-                    .send_to_user(&user, MessageType::File)
-                    .await;
+            Event::GroupUpdate(GroupUpdate { user, group }) => {
+                self.storage_mapping.invalidate_group(&group);
+                outbox.push(user, MessageType::File(None));
             }
-            Event::ShareCreate(ShareCreate { user }) => {
-                self.connections
-                    .send_to_user(&user, MessageType::File)
-                    .await;
+            Event::ShareCreate(ShareCreate { user, storage }) => {
+                // without a storage there is no way to know which cached mapping is missing the user
+                if let Some(storage) = storage {
+                    self.storage_mapping.invalidate_storage(storage);
+                }
+                outbox.push(user, MessageType::File(None));
+            }
+            Event::ShareDelete(ShareDelete {
+                user,
+                storage,
+                file_id,
+            }) => {
+                match storage {
+                    Some(storage) => self.storage_mapping.invalidate_storage(storage),
+                    None => self.storage_mapping.invalidate_user(&user),
+                }
+                let payload = FilePayload {
+                    file_id,
+                    reason: Some(FileChangeReason::ShareDeleted),
+                    ..FilePayload::default()
+                };
+                outbox.push(user, MessageType::File(Some(payload)));
+            }
+            Event::SharePermissions(SharePermissions {
+                user,
+                storage,
+                file_id,
+                permissions,
+            }) => {
+                if let Some(storage) = storage {
+                    self.storage_mapping.invalidate_storage(storage);
+                }
+                let payload = FilePayload {
+                    file_id,
+                    reason: Some(FileChangeReason::PermissionsChanged),
+                    permissions,
+                    ..FilePayload::default()
+                };
+                outbox.push(user, MessageType::File(Some(payload)));
+            }
+            Event::MountRemoved(MountUpdate { user, storage }) => {
+                match storage {
+                    Some(storage) => self.storage_mapping.invalidate_storage(storage),
+                    None => self.storage_mapping.invalidate_user(&user),
+                }
+                outbox.push(user, MessageType::File(None));
+            }
+            Event::MountAdded(MountUpdate { user, storage }) => {
+                match storage {
+                    Some(storage) => self.storage_mapping.invalidate_storage(storage),
+                    // the user isn't in the cached mapping of the new storage yet, so we can't find it
+                    None => self.storage_mapping.invalidate_all(),
+                }
+                outbox.push(user, MessageType::File(None));
             }
             Event::TestCookie(cookie) => {
                 self.test_cookie.store(cookie, Ordering::SeqCst);
             }
-            Event::Activity(Activity { user }) => {
-                self.connections
-                    .send_to_user(&user, MessageType::Activity)
-                    .await;
+            Event::Activity(Activity {
+                user: users,
+                activity_type,
+                object_type,
+                object_id,
+            }) => {
+                let has_payload =
+                    activity_type.is_some() || object_type.is_some() || object_id.is_some();
+                let payload = has_payload.then(|| ActivityPayload {
+                    activity_type,
+                    object_type,
+                    object_id,
+                });
+                for user in users {
+                    outbox.push(user, MessageType::Activity(payload.clone()));
+                }
             }
-            Event::Notification(Notification { user }) => {
-                self.connections
-                    .send_to_user(&user, MessageType::Notification)
-                    .await;
+            Event::Notification(Notification {
+                user: users,
+                id,
+                app,
+                subject,
+            }) => {
+                let has_payload = id.is_some() || app.is_some() || subject.is_some();
+                let payload = has_payload.then(|| NotificationPayload { id, app, subject });
+                for user in users {
+                    outbox.push(user, MessageType::Notification(payload.clone()));
+                }
             }
-            Event::PreAuth(PreAuth { user, token }) => {
-                self.pre_auth.insert(token, (Instant::now(), user));
+            Event::PreAuth(PreAuth {
+                user,
+                token,
+                token_hash,
+            }) => {
+                let hash = match (token, token_hash) {
+                    (Some(token), _) => match self.pre_auth_requirements.check(&token) {
+                        Ok(()) => TokenHash::new(&token),
+                        Err(e) => {
+                            log::error!(
+                                "Rejecting pre-auth token for {}: {}, check the random generator used by Nextcloud",
+                                user,
+                                e
+                            );
+                            return;
+                        }
+                    },
+                    // the requirements can only be checked by the app when only the hash is send
+                    (None, Some(hash)) => match TokenHash::from_hex(&hash) {
+                        Some(hash) => hash,
+                        None => {
+                            log::error!(
+                                "Rejecting pre-auth token for {}: invalid token hash",
+                                user
+                            );
+                            return;
+                        }
+                    },
+                    (None, None) => {
+                        log::error!("Rejecting pre-auth event for {} without token", user);
+                        return;
+                    }
+                };
+                if !self.pre_auth.insert(hash, user.clone()) {
+                    log::debug!("Ignoring duplicate pre-auth token for {}", user);
+                }
             }
             Event::Custom(Custom {
-                user,
+                user: users,
                 message,
                 body,
+                tag,
+                topic,
+                deliver_at,
+                ttl,
             }) => {
-                self.connections
-                    .send_to_user(&user, MessageType::Custom(message, body))
-                    .await;
+                let deliver_at = deliver_at.filter(|deliver_at| *deliver_at > unix_timestamp());
+                let users =
+                    self.deduplicate(users, &message, &body, tag.as_deref(), topic.as_deref());
+                for user in users {
+                    let msg = MessageType::Custom(
+                        message.clone(),
+                        body.clone(),
+                        tag.clone(),
+                        topic.clone(),
+                    );
+                    match deliver_at {
+                        Some(deliver_at) => {
+                            if let Err(e) =
+                                self.schedule.schedule(user.clone(), msg, deliver_at, ttl)
+                            {
+                                log::warn!("Failed to schedule {} for {}: {}", message, user, e);
+                            }
+                        }
+                        None => outbox.push(user, msg),
+                    }
+                }
+            }
+            Event::GroupMessage(GroupMessage {
+                group,
+                message,
+                body,
+            }) => match self.storage_mapping.get_group_members(&group).await {
+                Ok(members) => {
+                    for user in self.deduplicate(members, &message, &body, None, None) {
+                        outbox.push(
+                            user,
+                            MessageType::Custom(message.clone(), body.clone(), None, None),
+                        );
+                    }
+                }
+                Err(e) => log::warn!("Failed to load members of group {}: {:#}", group, e),
+            },
+            Event::Broadcast(Broadcast {
+                message,
+                body,
+                pattern: None,
+            }) => {
+                outbox.push_all(MessageType::Custom(message, body, None, None));
+            }
+            Event::Broadcast(Broadcast {
+                message,
+                pattern: Some(_),
+                ..
+            }) if !self.user_patterns => {
+                log::warn!(
+                    "Ignoring broadcast {} for a user pattern, user patterns are not enabled",
+                    message
+                );
+            }
+            Event::Broadcast(Broadcast {
+                message,
+                body,
+                pattern: Some(pattern),
+            }) => {
+                for (user, _) in self.connections.presence().online() {
+                    if user.name().map_or(false, |name| pattern.matches(&name)) {
+                        outbox.push(
+                            user,
+                            MessageType::Custom(message.clone(), body.clone(), None, None),
+                        );
+                    }
+                }
             }
             Event::Config(event::Config::LogSpec(spec)) => {
-                match self.log_handle.lock().await.parse_and_push_temp_spec(&spec) {
-                    Ok(()) => log::info!("Set log level to {}", spec),
+                match self
+                    .log_specs
+                    .lock()
+                    .await
+                    .push(spec.spec(), spec.version())
+                {
+                    Ok(true) => log::info!("Set log level to {}", spec.spec()),
+                    Ok(false) => {
+                        log::debug!("Ignoring log level {} that was already set", spec.spec())
+                    }
                     Err(e) => log::error!("Failed to set log level: {:?}", e),
                 }
             }
             Event::Config(event::Config::LogRestore) => {
-                self.log_handle.lock().await.pop_temp_spec();
-                log::info!("Restored log level");
-            }
-            Event::Query(event::Query::Metrics) => match self.redis.connect().await {
-                Ok(mut redis) => {
-                    if let Err(e) = redis
-                        .set(
-                            "notify_push_metrics",
-                            &serde_json::to_string(&METRICS).unwrap(),
-                        )
-                        .await
-                    {
-                        log::warn!("Failed to set metrics: {}", e);
+                if self.log_specs.lock().await.pop() {
+                    log::info!("Restored log level");
+                }
+            }
+            Event::Config(event::Config::RestoreLogSpec(version)) => {
+                if self.log_specs.lock().await.restore(version) {
+                    log::info!("Restored log level");
+                } else {
+                    log::debug!("Ignoring restore of log level {} that isn't set", version);
+                }
+            }
+            Event::Config(event::Config::LogTarget(LogTargetChange { target, level })) => {
+                let level = match level.as_deref().map(LevelFilter::from_str).transpose() {
+                    Ok(level) => level,
+                    Err(_) => {
+                        log::error!("Invalid log level {:?} for {}", level, target);
+                        return;
                     }
+                };
+                match self.log_specs.lock().await.set_target(target, level) {
+                    Ok(()) => match level {
+                        Some(level) => log::info!("Set log level for {} to {}", target, level),
+                        None => log::info!("Restored log level for {}", target),
+                    },
+                    Err(e) => log::error!("Failed to set log level: {:?}", e),
                 }
-                Err(e) => log::warn!("Failed to set metrics: {}", e),
-            },
+            }
+            Event::Config(event::Config::Tune(tuning)) => {
+                log::info!("Applying runtime tuning {:?}", tuning);
+                self.tune(tuning);
+            }
+            Event::Query(event::Query::Metrics) => {
+                self.redis_writer
+                    .queue(vec![WriteCommand::Set {
+                        key: "notify_push_metrics".into(),
+                        value: serde_json::to_string(&METRICS).unwrap(),
+                        ttl: None,
+                    }])
+                    .await;
+            }
             Event::Signal(event::Signal::Reset) => {
                 log::info!("Stopping all open connections");
-                if let Err(e) = self.reset_tx.send(()) {
-                    log::warn!("Failed to send reset command to all connections: {}", e);
+                self.control.send(ControlMessage::Reset);
+            }
+            Event::Signal(event::Signal::ResetUser(user)) => {
+                log::info!("Stopping all open connections for {}", user);
+                self.control.send(ControlMessage::ResetUser(user));
+            }
+            Event::Signal(event::Signal::ClientAction(event::ClientActionSignal {
+                action,
+                user,
+            })) => {
+                match &user {
+                    Some(user) => log::info!("Sending {} to all connections for {}", action, user),
+                    None => log::info!("Sending {} to all connections", action),
                 }
+                self.control.send(ControlMessage::Action(action, user));
             }
         }
     }
 
-    pub fn reset_rx(&self) -> broadcast::Receiver<()> {
-        self.reset_tx.subscribe()
+    /// Remove the users that got an identical custom message within the deduplication window
+    fn deduplicate(
+        &self,
+        users: impl IntoIterator<Item = UserId>,
+        message: &str,
+        body: &Value,
+        tag: Option<&str>,
+        topic: Option<&str>,
+    ) -> Vec<UserId> {
+        let dedup = match &self.dedup {
+            Some(dedup) => dedup,
+            None => return users.into_iter().collect(),
+        };
+        dedup.expire();
+        let fingerprint = Deduplication::fingerprint(message, body, tag, topic);
+        users
+            .into_iter()
+            .filter(|user| {
+                let is_new = dedup.is_new(user, fingerprint);
+                if !is_new {
+                    log::debug!("Dropping duplicate {} for {}", message, user);
+                }
+                is_new
+            })
+            .collect()
+    }
+
+    /// Apply runtime tuning received from redis
+    fn tune(&self, tuning: event::Tuning) {
+        if let Some(secs) = tuning.debounce_file {
+            DEBOUNCE_FILE.store(secs, Ordering::Relaxed);
+        }
+        if let Some(secs) = tuning.debounce_activity {
+            DEBOUNCE_ACTIVITY.store(secs, Ordering::Relaxed);
+        }
+        if let Some(secs) = tuning.debounce_notification {
+            DEBOUNCE_NOTIFICATION.store(secs, Ordering::Relaxed);
+        }
+        self.limits.update(|limits| {
+            if let Some(limit) = tuning.max_connections {
+                limits.max_connections = Some(limit).filter(|limit| *limit > 0);
+            }
+            if let Some(limit) = tuning.max_connections_per_ip {
+                limits.max_connections_per_ip = Some(limit).filter(|limit| *limit > 0);
+            }
+            if let Some(limit) = tuning.max_connections_per_user {
+                limits.max_connections_per_user = Some(limit).filter(|limit| *limit > 0);
+            }
+            if let Some(limit) = tuning.max_messages_per_second {
+                limits.max_messages_per_second = Some(limit).filter(|limit| *limit > 0);
+            }
+        });
+    }
+
+    /// Notify all users with access to the path, returns false if the users couldn't be loaded
+    async fn handle_storage_update(&self, storage: u32, path: String, outbox: &mut Outbox) -> bool {
+        match self
+            .storage_mapping
+            .get_user_paths_for_storage_path(storage, &path)
+            .await
+        {
+            Ok(users) => {
+                let hint = if self.file_change_hints {
+                    Some(self.file_change_hint(storage, &path).await)
+                } else {
+                    None
+                };
+                for (user, user_path) in users {
+                    // the path differs per user, depending on where the storage is mounted for them
+                    let payload = match (&hint, user_path) {
+                        (Some(hint), Some(user_path)) => Some(FilePayload {
+                            path: Some(user_path.clone()),
+                            filter_path: Some(user_path),
+                            ..hint.clone().unwrap_or_default()
+                        }),
+                        (Some(hint), None) => hint.clone(),
+                        (None, user_path) => user_path.map(|user_path| FilePayload {
+                            filter_path: Some(user_path),
+                            ..FilePayload::default()
+                        }),
+                    };
+                    outbox.push(user, MessageType::File(payload));
+                }
+                true
+            }
+            Err(e) => {
+                // the reconnect loop already logged the database being unavailable
+                if e.is::<DatabaseUnavailable>() {
+                    log::debug!("{:#}", e);
+                } else {
+                    log::error!("{:#}", e);
+                }
+                match self.database_error_strategy {
+                    DatabaseErrorStrategy::Drop => METRICS.add_dropped_storage_update(),
+                    DatabaseErrorStrategy::Buffer => {
+                        if !self.update_buffer.push(storage, path) {
+                            METRICS.add_dropped_storage_update();
+                        }
+                    }
+                    DatabaseErrorStrategy::Cached => {
+                        match self.storage_mapping.get_stale_users_for_storage(storage) {
+                            Some(users) => {
+                                for user in users {
+                                    outbox.push(user, MessageType::File(None));
+                                }
+                            }
+                            None => METRICS.add_dropped_storage_update(),
+                        }
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// Get the current state of the changed file, so clients can check what changed without scanning the entire folder
+    async fn file_change_hint(&self, storage: u32, path: &str) -> Option<FilePayload> {
+        match self.storage_mapping.get_file_change(storage, path).await {
+            Ok(change) => change.map(|change| FilePayload {
+                file_id: Some(change.fileid as u64),
+                mtime: Some(change.mtime as u64),
+                etag: Some(change.etag),
+                ..FilePayload::default()
+            }),
+            Err(e) => {
+                log::warn!("{:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Handle storage updates that were buffered while the database was unavailable
+    async fn replay_storage_updates(&self, outbox: &mut Outbox) {
+        let buffered = self.update_buffer.take();
+        if !buffered.is_empty() {
+            log::info!("Handling {} buffered storage updates", buffered.len());
+        }
+        for (storage, path) in buffered {
+            // failed updates are buffered again by `handle_storage_update`
+            self.handle_storage_update(storage, path, outbox).await;
+        }
+    }
+
+    pub fn control_rx(&self) -> broadcast::Receiver<ControlMessage> {
+        self.control.subscribe()
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// Subscribe to the events happening inside the daemon
+    pub fn events(&self) -> broadcast::Receiver<DaemonEvent> {
+        self.observer.subscribe()
     }
 }
 
@@ -243,6 +834,11 @@ pub fn serve(
     cancel: oneshot::Receiver<()>,
     tls: Option<&TlsConfig>,
 ) -> Result<impl Future<Output = ()> + Send> {
+    let admin = admin::routes(app.clone());
+    let request_limit = request_limit(app.clone());
+    let affinity_app = app.clone();
+    let prefix = path_prefix(&app.path_prefixes);
+
     let app = warp::any().map(move || app.clone());
 
     let cors = warp::cors().allow_any_origin();
@@ -254,18 +850,91 @@ pub fn serve(
         .and(app.clone())
         .and(remote())
         .and(get_forwarded_for())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
         .map(
-            |ws: warp::ws::Ws, app, remote: Option<SocketAddr>, mut forwarded_for: Vec<IpAddr>| {
-                if let Some(remote) = remote {
-                    forwarded_for.push(remote.ip());
-                }
+            |ws: warp::ws::Ws,
+             app: Arc<App>,
+             remote: Option<SocketAddr>,
+             forwarded_for: Vec<IpAddr>,
+             subprotocols: Option<String>| {
+                // clients can pick the protocol version during the handshake instead of with the `version` command
+                let version = match subprotocols
+                    .as_deref()
+                    .map(negotiate_subprotocol)
+                    .transpose()
+                {
+                    Ok(version) => version,
+                    Err(e) => {
+                        log::debug!("rejecting websocket connection: {}", e);
+                        return warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST)
+                            .into_response();
+                    }
+                };
+                // warp can't negotiate permessage-deflate, clients asking for it get uncompressed frames
+                let ws = ws
+                    .max_frame_size(MAX_FRAME_SIZE)
+                    .max_message_size(MAX_FRAME_SIZE);
+                let forwarded_for = client_chain(forwarded_for, remote, app.forwarded_for_trust);
                 log::debug!("new websocket connection from {:?}", forwarded_for.first());
-                ws.on_upgrade(move |socket| handle_user_socket(socket, app, forwarded_for))
+                let reply = ws.on_upgrade(move |socket| {
+                    handle_user_socket(socket, app, forwarded_for, version.unwrap_or_default())
+                });
+                match version {
+                    Some(version) => warp::reply::with_header(
+                        reply,
+                        "sec-websocket-protocol",
+                        version.subprotocol(),
+                    )
+                    .into_response(),
+                    None => reply.into_response(),
+                }
+            },
+        )
+        .with(cors.clone());
+
+    // GET /events -> server-sent events for clients that can't use websockets
+    let events = warp::path!("events")
+        .and(warp::get())
+        .and(app.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<SseQuery>())
+        .and(remote())
+        .and(get_forwarded_for())
+        .and_then(
+            |app: Arc<App>,
+             authorization,
+             query,
+             remote: Option<SocketAddr>,
+             forwarded_for: Vec<IpAddr>| {
+                let forwarded_for = client_chain(forwarded_for, remote, app.forwarded_for_trust);
+                log::debug!("new event stream from {:?}", forwarded_for.first());
+                sse::handle_sse(app, authorization, query, forwarded_for)
+            },
+        )
+        .with(cors.clone());
+
+    // GET /poll -> long-polling for clients that can't use websockets or server-sent events
+    let poll = warp::path!("poll")
+        .and(warp::get())
+        .and(app.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<PollQuery>())
+        .and(remote())
+        .and(get_forwarded_for())
+        .and_then(
+            |app: Arc<App>,
+             authorization,
+             query,
+             remote: Option<SocketAddr>,
+             forwarded_for: Vec<IpAddr>| {
+                let forwarded_for = client_chain(forwarded_for, remote, app.forwarded_for_trust);
+                poll::handle_poll(app, authorization, query, forwarded_for)
             },
         )
         .with(cors);
 
     let cookie_test = warp::path!("test" / "cookie")
+        .and(request_limit.clone())
         .and(app.clone())
         .map(|app: Arc<App>| {
             let cookie = app.test_cookie.load(Ordering::SeqCst);
@@ -274,6 +943,7 @@ pub fn serve(
         });
 
     let reverse_cookie_test = warp::path!("test" / "reverse_cookie")
+        .and(request_limit.clone())
         .and(app.clone())
         .and_then(|app: Arc<App>| async move {
             let response = match app.nc_client.get_test_cookie().await {
@@ -291,6 +961,7 @@ pub fn serve(
         });
 
     let mapping_test = warp::path!("test" / "mapping" / u32)
+        .and(request_limit.clone())
         .and(app.clone())
         .and_then(|storage_id: u32, app: Arc<App>| async move {
             let access = app
@@ -315,6 +986,7 @@ pub fn serve(
         });
 
     let remote_test = warp::path!("test" / "remote" / IpAddr)
+        .and(request_limit.clone())
         .and(app.clone())
         .and_then(|remote: IpAddr, app: Arc<App>| async move {
             let result = app
@@ -327,37 +999,177 @@ pub fn serve(
             Result::<_, Infallible>::Ok(result)
         });
 
+    let instance = warp::path!("instance")
+        .and(app.clone())
+        .map(|app: Arc<App>| warp::reply::json(&InstanceInfo::new(&app)));
+
+    // readiness check for load balancers and orchestration, fails when a dependency is down or messages from Nextcloud stop arriving
+    let ready = warp::path!("ready")
+        .and(app.clone())
+        .and_then(|app: Arc<App>| async move {
+            let report = app.health().await;
+            let status = if report.is_healthy() {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            Result::<_, Infallible>::Ok(warp::reply::with_status(
+                warp::reply::json(&report),
+                status,
+            ))
+        });
+
     let version = warp::path!("test" / "version")
+        .and(request_limit.clone())
         .and(warp::post())
         .and(app)
         .and_then(|app: Arc<App>| async move {
-            Result::<_, Infallible>::Ok(match app.redis.connect().await {
-                Ok(mut client) => {
-                    client
-                        .set("notify_push_version", env!("NOTIFY_PUSH_VERSION"))
-                        .await
-                        .ok();
-                    "set"
-                }
+            Result::<_, Infallible>::Ok(match instance::announce(&app).await {
+                Ok(()) => "set",
                 Err(e) => {
-                    log::warn!("Failed to get redis connection for version set: {:#}", e);
+                    log::warn!("Failed to set version: {:#}", e);
                     "error"
                 }
             })
         });
 
     let routes = socket
+        .or(events)
+        .or(poll)
         .or(cookie_test)
         .or(reverse_cookie_test)
         .or(mapping_test)
         .or(remote_test)
-        .or(version);
+        .or(version)
+        .or(instance)
+        .or(ready)
+        .or(admin);
 
-    let routes = routes.clone().or(warp::path!("push" / ..).and(routes));
+    let routes = routes
+        .clone()
+        .or(prefix.and(routes))
+        .recover(reject_rate_limited)
+        .map(move |reply| instance::with_affinity_headers(&affinity_app, reply))
+        .with(warp::log::custom(metrics::record_http_request));
 
     serve_at(routes, bind, cancel, tls)
 }
 
+#[derive(Debug)]
+struct RequestRateExceeded;
+
+impl Reject for RequestRateExceeded {}
+
+/// Limit the rate of requests from a single ip to endpoints that cause load on redis, the database or Nextcloud
+pub(crate) fn request_limit(app: Arc<App>) -> BoxedFilter<()> {
+    remote()
+        .and(get_forwarded_for())
+        .and_then(
+            move |remote: Option<SocketAddr>, forwarded_for: Vec<IpAddr>| {
+                let app = app.clone();
+                async move {
+                    let ip = match client_chain(forwarded_for, remote, app.forwarded_for_trust)
+                        .first()
+                        .copied()
+                    {
+                        Some(ip) => ip,
+                        None => return Ok(()),
+                    };
+                    app.limits.admit_request(ip).map_err(|e: LimitError| {
+                        log::info!("rejecting request from {}: {}", ip, e);
+                        warp::reject::custom(RequestRateExceeded)
+                    })
+                }
+            },
+        )
+        .untuple_one()
+        .boxed()
+}
+
+async fn reject_rate_limited(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<RequestRateExceeded>().is_some() {
+        Ok(warp::reply::with_status(
+            LimitError::RequestRate.to_string(),
+            StatusCode::TOO_MANY_REQUESTS,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+/// Match any of the path prefixes the routes are also served under
+///
+/// This allows the push server to work behind reverse proxy configurations that don't strip the path
+fn path_prefix(prefixes: &[String]) -> BoxedFilter<()> {
+    prefixes
+        .iter()
+        .map(|prefix| {
+            prefix
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .fold(warp::any().boxed(), |filter, segment| {
+                    filter.and(warp::path(segment.to_string())).boxed()
+                })
+        })
+        .reduce(|a, b| a.or(b).unify().untuple_one().boxed())
+        .unwrap_or_else(|| {
+            warp::any()
+                .and_then(|| async { Err::<(), _>(warp::reject::not_found()) })
+                .untuple_one()
+                .boxed()
+        })
+}
+
+/// Remove a socket left behind by a previous run
+///
+/// The path is left alone if another server is still listening on the socket or if it isn't a socket at all
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if StdUnixStream::connect(path).is_ok() {
+                return Err(Report::msg(format!(
+                    "Socket {} is already in use",
+                    path.to_string_lossy()
+                )));
+            }
+            fs::remove_file(path).wrap_err_with(|| {
+                format!("Failed to remove stale socket {}", path.to_string_lossy())
+            })
+        }
+        Ok(_) => Err(Report::msg(format!(
+            "Can't listen on {}, the path exists and is not a socket",
+            path.to_string_lossy()
+        ))),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Take over a listening socket passed by systemd, which can either be a TCP or unix socket
+///
+/// The server listens on a duplicate of the socket, the original file descriptor stays open when the server
+/// shuts down so it can be handed to a new process on upgrade.
+fn inherited_listener(fd: RawFd) -> Result<Either<TcpListener, UnixListener>> {
+    // Safety: systemd passes the socket to this process only and it's not used anywhere else
+    let unix = unsafe { StdUnixListener::from_raw_fd(fd) };
+    // getting the address of a socket that isn't a unix socket fails
+    if unix.local_addr().is_ok() {
+        let listener = unix.try_clone()?;
+        let _ = unix.into_raw_fd();
+        listener.set_nonblocking(true)?;
+        let listener = UnixListener::from_std(listener)
+            .wrap_err("Failed to use the unix socket passed by systemd")?;
+        Ok(Either::Right(listener))
+    } else {
+        let tcp = unsafe { StdTcpListener::from_raw_fd(unix.into_raw_fd()) };
+        let listener = tcp.try_clone()?;
+        let _ = tcp.into_raw_fd();
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)
+            .wrap_err("Failed to use the socket passed by systemd")?;
+        Ok(Either::Left(listener))
+    }
+}
+
 fn serve_at<F, C>(
     filter: F,
     bind: Bind,
@@ -370,25 +1182,36 @@ where
     F::Extract: Reply,
 {
     let cancel = cancel.map(|_| ());
-    let server = warp::serve(filter);
     match (bind, tls) {
-        (Bind::Tcp(addr), Some(tls)) => {
-            let (_, server) = server
-                .tls()
-                .cert_path(&tls.cert)
-                .key_path(&tls.key)
-                .bind_with_graceful_shutdown(addr, cancel);
-            Ok(Either::Left(Either::Left(server)))
-        }
-        (Bind::Tcp(addr), None) => {
-            let (_, server) = server.bind_with_graceful_shutdown(addr, cancel);
-            Ok(Either::Left(Either::Right(server)))
+        (Bind::Tcp(addrs), tls) => {
+            // every address gets its own server, all of them stop on the same cancel signal
+            let cancel = cancel.shared();
+            let servers: Vec<BoxFuture<'static, ()>> = addrs
+                .into_iter()
+                .map(|addr| {
+                    let server = warp::serve(filter.clone());
+                    match tls {
+                        Some(tls) => server
+                            .tls()
+                            .cert_path(&tls.cert)
+                            .key_path(&tls.key)
+                            .bind_with_graceful_shutdown(addr, cancel.clone())
+                            .1
+                            .boxed(),
+                        None => server
+                            .bind_with_graceful_shutdown(addr, cancel.clone())
+                            .1
+                            .boxed(),
+                    }
+                })
+                .collect();
+            Ok(Either::Left(join_all(servers).map(|_| ())))
         }
         (Bind::Unix(socket_path, permissions), tls) => {
             if tls.is_some() {
                 log::warn!("Serving with TLS over a unix socket is not supported");
             }
-            fs::remove_file(&socket_path).ok();
+            remove_stale_socket(&socket_path)?;
 
             let listener = UnixListener::bind(&socket_path).wrap_err_with(|| {
                 format!(
@@ -399,25 +1222,88 @@ where
             fs::set_permissions(&socket_path, PermissionsExt::from_mode(permissions))?;
 
             let stream = UnixListenerStream::new(listener);
-            Ok(Either::Right(
-                server
+            Ok(Either::Right(Either::Left(
+                warp::serve(filter)
                     .serve_incoming_with_graceful_shutdown(stream, cancel)
                     .map(move |_| {
                         fs::remove_file(&socket_path).ok();
                     }),
-            ))
+            )))
+        }
+        (Bind::Inherited(fd), tls) => {
+            if tls.is_some() {
+                log::warn!("Serving with TLS over a socket passed by systemd is not supported");
+            }
+            let server = warp::serve(filter);
+            let server: BoxFuture<'static, ()> = match inherited_listener(fd)? {
+                Either::Left(listener) => server
+                    .serve_incoming_with_graceful_shutdown(TcpListenerStream::new(listener), cancel)
+                    .boxed(),
+                Either::Right(listener) => server
+                    .serve_incoming_with_graceful_shutdown(
+                        UnixListenerStream::new(listener),
+                        cancel,
+                    )
+                    .boxed(),
+            };
+            Ok(Either::Right(Either::Right(server)))
         }
     }
 }
 
 pub async fn listen_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
     let loop_ = async move {
+        // keeps growing while only replicas can be reached
+        let mut failover_delay = FAILOVER_DELAY;
         loop {
-            if let Err(e) = listen(app.clone()).await {
-                eprintln!("Failed to setup redis subscription: {:#}", e);
+            match listen(app.clone()).await {
+                Err(e) if is_failover_error(&e) => {
+                    log::warn!(target: "notify_push::redis", "Redis failover detected ({:#}), resubscribing in {}ms", e, failover_delay.as_millis());
+                    sleep(failover_delay).await;
+                    failover_delay = (failover_delay * 2).min(RECONNECT_DELAY);
+                    continue;
+                }
+                Err(e) => {
+                    log::error!(target: "notify_push::redis", "Failed to setup redis subscription: {:#}", e)
+                }
+                Ok(()) => {}
+            }
+            failover_delay = FAILOVER_DELAY;
+            log::warn!(target: "notify_push::redis", "Redis server disconnected, reconnecting in 1s");
+            sleep(RECONNECT_DELAY).await;
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
+
+/// Reload the tls files used to connect to Nextcloud on SIGHUP or when they change
+pub async fn tls_reload_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
+    if !app.nc_client.tls().has_files() {
+        return;
+    }
+    let loop_ = async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                log::error!("Failed to listen for SIGHUP: {:#}", e);
+                return;
+            }
+        };
+        let mut check = interval(TLS_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {},
+                _ = check.tick() => {
+                    if !app.nc_client.tls_changed() {
+                        continue;
+                    }
+                },
+            }
+            match app.nc_client.reload_tls() {
+                Ok(()) => log::info!("Reloaded tls configuration for Nextcloud"),
+                Err(e) => log::error!("Failed to reload tls configuration for Nextcloud: {:#}", e),
             }
-            log::warn!("Redis server disconnected, reconnecting in 1s");
-            sleep(Duration::from_secs(1)).await;
         }
     };
     pin_mut!(loop_);
@@ -427,25 +1313,51 @@ pub async fn listen_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
 pub async fn listen(app: Arc<App>) -> Result<()> {
     let mut event_stream = event::subscribe(&app.redis).await?;
 
-    let handle = move |event: Event| {
-        // todo: any way to do this without cloning the arc every event (scoped?)
+    let handle = {
         let app = app.clone();
-        async move {
-            app.handle_event(event).await;
+        move |event: Event| {
+            // todo: any way to do this without cloning the arc every event (scoped?)
+            let app = app.clone();
+            // the ticket is taken before spawning, so it follows the order the events are received in
+            let ticket = app.event_order.ticket(&event);
+            async move {
+                let mut outbox = Outbox::default();
+                {
+                    let _permit = app.event_limits.acquire(&event).await;
+                    app.handle_event(event, &mut outbox).await;
+                }
+                // the permit is released first, so events waiting for their turn can't block the ones they wait for
+                ticket.deliver(outbox, &app.connections).await;
+            }
         }
     };
 
-    while let Some(event) = event_stream.next().await {
-        match event {
-            Ok(event) => {
-                log::debug!(
-                    target: "notify_push::receive",
-                    "Received {}",
-                    event
-                );
-                tokio::spawn(handle(event));
+    let mut role_check = interval(ROLE_CHECK_INTERVAL);
+    // the first tick completes immediately
+    role_check.tick().await;
+
+    loop {
+        tokio::select! {
+            event = event_stream.next() => match event {
+                Some(Ok(event)) => {
+                    log::debug!(
+                        target: "notify_push::receive",
+                        "Received {}",
+                        event
+                    );
+                    tokio::spawn(handle(event));
+                }
+                Some(Err(e)) => {
+                    log::warn!("{:#}", e);
+                    app.observer.emit(|| DaemonEvent::EventDropped(format!("{:#}", e)));
+                }
+                None => break,
+            },
+            _ = role_check.tick() => {
+                if app.redis.is_demoted().await {
+                    return Err(DemotedError.into());
+                }
             }
-            Err(e) => log::warn!("{:#}", e),
         }
     }
     Ok(())
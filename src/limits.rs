@@ -0,0 +1,422 @@
+use crate::memory::MemoryPressure;
+use crate::message::MessageType;
+use crate::metrics::METRICS;
+use crate::user::keep_user_names;
+use crate::UserId;
+use ahash::RandomState;
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Configured limits, a limit of `None` means unlimited
+///
+/// Connections are checked against the global limit first, then the limit for the client ip and then the limit for the user.
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    /// Maximum number of open connections
+    pub max_connections: Option<usize>,
+    /// Maximum number of open connections from a single ip
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum number of open connections for a single user
+    pub max_connections_per_user: Option<usize>,
+    /// Maximum number of messages send over a single connection per second, short bursts of up to twice the rate are allowed
+    pub max_messages_per_second: Option<u32>,
+    /// Resident memory in bytes after which new connections are refused, optional buffers are dropped when getting close
+    pub max_memory: Option<u64>,
+    /// Maximum number of requests to the test and admin endpoints from a single ip per second, short bursts of up to twice the rate are allowed
+    pub max_requests_per_second: Option<u32>,
+    /// Users that aren't limited by `max_connections_per_user` and `max_messages_per_second`, e.g. monitoring accounts
+    pub exempt_users: Vec<UserPattern>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_connections_per_user: Some(64),
+            max_messages_per_second: None,
+            max_memory: None,
+            max_requests_per_second: Some(10),
+            exempt_users: Vec::new(),
+        }
+    }
+}
+
+/// User name pattern where `*` matches any number of characters
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub struct UserPattern(String);
+
+impl From<String> for UserPattern {
+    fn from(pattern: String) -> Self {
+        UserPattern(pattern.trim().to_string())
+    }
+}
+
+impl FromStr for UserPattern {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(UserPattern(s.trim().to_string()))
+    }
+}
+
+impl UserPattern {
+    pub fn matches(&self, user: &str) -> bool {
+        let mut parts = self.0.split('*');
+        let first = parts.next().unwrap_or_default();
+        if !user.starts_with(first) {
+            return false;
+        }
+        let mut rest = &user[first.len()..];
+        let parts: Vec<&str> = parts.collect();
+        let last = match parts.last() {
+            Some(last) => *last,
+            // no wildcard, the pattern has to match the entire name
+            None => return rest.is_empty(),
+        };
+        for part in &parts[..parts.len() - 1] {
+            match rest.find(part) {
+                Some(pos) => rest = &rest[pos + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+}
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("global connection limit exceeded")]
+    Global,
+    #[error("connection limit for ip exceeded")]
+    Ip,
+    #[error("connection limit exceeded")]
+    User,
+    #[error("memory limit exceeded")]
+    Memory,
+    #[error("request rate limit exceeded")]
+    RequestRate,
+}
+
+/// Keeps track of open connections and enforces the configured limits
+#[derive(Default)]
+pub struct Limits {
+    config: RwLock<LimitsConfig>,
+    connections: AtomicUsize,
+    per_ip: DashMap<IpAddr, usize, RandomState>,
+    per_user: DashMap<UserId, usize, RandomState>,
+    memory_pressure: AtomicU8,
+    requests: DashMap<IpAddr, MessageRate, RandomState>,
+    /// Names of the exempt users that connected, so their connections stay visible in the metrics
+    exempt: DashMap<UserId, String, RandomState>,
+}
+
+impl Limits {
+    pub fn new(config: LimitsConfig) -> Self {
+        if !config.exempt_users.is_empty() {
+            // exemptions are matched against the user name
+            keep_user_names();
+        }
+        Limits {
+            config: RwLock::new(config),
+            ..Limits::default()
+        }
+    }
+
+    /// Change the configured limits, existing connections are not affected
+    pub fn update(&self, update: impl FnOnce(&mut LimitsConfig)) {
+        update(&mut self.config.write().unwrap());
+    }
+
+    /// Admit a new connection if none of the limits are exceeded
+    ///
+    /// The connection is counted against the limits until the returned permit is dropped
+    pub fn admit(&self, user: &UserId, ip: Option<IpAddr>) -> Result<ConnectionPermit, LimitError> {
+        if self.memory_pressure() == MemoryPressure::Critical {
+            return Err(LimitError::Memory);
+        }
+        let config = self.config.read().unwrap().clone();
+        let connections = self.connections.fetch_add(1, Ordering::SeqCst);
+        // the permit releases everything counted so far if one of the limits is exceeded
+        let mut permit = ConnectionPermit {
+            limits: self,
+            user: None,
+            ip: None,
+        };
+        if exceeds(connections, config.max_connections) {
+            return Err(LimitError::Global);
+        }
+
+        if let Some(ip) = ip {
+            let count = increment(&self.per_ip, ip);
+            permit.ip = Some(ip);
+            if exceeds(count, config.max_connections_per_ip) {
+                return Err(LimitError::Ip);
+            }
+        }
+
+        let count = increment(&self.per_user, user.clone());
+        permit.user = Some(user.clone());
+        if exceeds(count, config.max_connections_per_user) && !self.is_exempt(user) {
+            return Err(LimitError::User);
+        }
+
+        Ok(permit)
+    }
+
+    /// Whether the user matches one of the exempt user patterns
+    pub fn is_exempt(&self, user: &UserId) -> bool {
+        if self.exempt.contains_key(user) {
+            return true;
+        }
+        let name = match user.name() {
+            Some(name) => name,
+            None => return false,
+        };
+        let exempt = self
+            .config
+            .read()
+            .unwrap()
+            .exempt_users
+            .iter()
+            .any(|pattern| pattern.matches(&name));
+        if exempt {
+            self.exempt.insert(user.clone(), name);
+        }
+        exempt
+    }
+
+    /// Open connections of each exempt user that connected, by user name
+    pub fn exempt_connections(&self) -> Vec<(String, usize)> {
+        let mut connections: Vec<(String, usize)> = self
+            .exempt
+            .iter()
+            .map(|entry| {
+                let count = self
+                    .per_user
+                    .get(entry.key())
+                    .map(|count| *count)
+                    .unwrap_or(0);
+                (entry.value().clone(), count)
+            })
+            .collect();
+        connections.sort_unstable();
+        connections
+    }
+
+    pub fn config(&self) -> LimitsConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn max_memory(&self) -> Option<u64> {
+        self.config.read().unwrap().max_memory
+    }
+
+    pub fn memory_pressure(&self) -> MemoryPressure {
+        MemoryPressure::from_u8(self.memory_pressure.load(Ordering::Relaxed))
+    }
+
+    /// Set the current memory pressure, returning the previous one
+    pub fn set_memory_pressure(&self, pressure: MemoryPressure) -> MemoryPressure {
+        MemoryPressure::from_u8(self.memory_pressure.swap(pressure as u8, Ordering::Relaxed))
+    }
+
+    /// Take a token from the request rate limit of the ip
+    ///
+    /// Limiters that are full again are removed once more than `MAX_TRACKED_REQUEST_IPS` ips are tracked
+    pub fn admit_request(&self, ip: IpAddr) -> Result<(), LimitError> {
+        let rate = self.config.read().unwrap().max_requests_per_second;
+        if rate.is_none() {
+            return Ok(());
+        }
+        if self.requests.len() > MAX_TRACKED_REQUEST_IPS {
+            self.requests.retain(|_, limiter| !limiter.is_full());
+        }
+        let allowed = self
+            .requests
+            .entry(ip)
+            .or_insert_with(|| MessageRate::new(rate))
+            .try_send();
+        if allowed {
+            Ok(())
+        } else {
+            Err(LimitError::RequestRate)
+        }
+    }
+
+    /// Create the rate limiter for messages send over a single connection of the user
+    pub fn message_rate(&self, user: &UserId) -> RateLimitedMessages {
+        if self.is_exempt(user) {
+            return RateLimitedMessages::new(MessageRate::new(None));
+        }
+        RateLimitedMessages::new(MessageRate::new(
+            self.config.read().unwrap().max_messages_per_second,
+        ))
+    }
+}
+
+/// Number of ips with a request rate limiter after which idle limiters are cleaned up
+const MAX_TRACKED_REQUEST_IPS: usize = 1024;
+
+/// Whether `count` existing connections leave no room for another one
+fn exceeds(count: usize, limit: Option<usize>) -> bool {
+    limit.map(|limit| count >= limit).unwrap_or(false)
+}
+
+/// Increment the count for the key, returning the count from before the increment
+fn increment<K: Hash + Eq>(map: &DashMap<K, usize, RandomState>, key: K) -> usize {
+    let mut count = map.entry(key).or_insert(0);
+    *count += 1;
+    *count - 1
+}
+
+fn decrement<K: Hash + Eq>(map: &DashMap<K, usize, RandomState>, key: &K) {
+    if let Some(mut count) = map.get_mut(key) {
+        *count = count.saturating_sub(1);
+    }
+    map.remove_if(key, |_, count| *count == 0);
+}
+
+/// An admitted connection, releases the connection from all limits when dropped
+pub struct ConnectionPermit<'a> {
+    limits: &'a Limits,
+    user: Option<UserId>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionPermit<'_> {
+    fn drop(&mut self) {
+        self.limits.connections.fetch_sub(1, Ordering::SeqCst);
+        if let Some(ip) = &self.ip {
+            decrement(&self.limits.per_ip, ip);
+        }
+        if let Some(user) = &self.user {
+            decrement(&self.limits.per_user, user);
+        }
+    }
+}
+
+/// Token bucket limiting the rate of messages send over a connection or requests from a single ip
+pub struct MessageRate {
+    rate: Option<f64>,
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl MessageRate {
+    fn new(rate: Option<u32>) -> Self {
+        let rate = rate.map(f64::from);
+        MessageRate {
+            rate,
+            tokens: rate.map(|rate| rate * 2.0).unwrap_or_default(),
+            last_update: Instant::now(),
+        }
+    }
+
+    /// Whether enough time has passed since the last message to refill all tokens
+    fn is_full(&self) -> bool {
+        match self.rate {
+            Some(rate) => {
+                self.tokens + self.last_update.elapsed().as_secs_f64() * rate >= rate * 2.0
+            }
+            None => true,
+        }
+    }
+
+    /// Take a token for sending a message, returns false if the rate limit is exceeded
+    pub fn try_send(&mut self) -> bool {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return true,
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+        self.tokens = (self.tokens + elapsed * rate).min(rate * 2.0);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// When the next token is available
+    fn next_token_at(&self) -> Instant {
+        match self.rate {
+            Some(rate) if self.tokens < 1.0 => {
+                self.last_update + Duration::from_secs_f64((1.0 - self.tokens) / rate)
+            }
+            _ => self.last_update,
+        }
+    }
+}
+
+/// Maximum number of messages kept back for a connection over the message rate, the oldest ones are dropped after that
+const MAX_DEFERRED_MESSAGES: usize = 64;
+
+/// Messages send over a single connection, messages over the message rate are kept back until the rate allows them
+pub struct RateLimitedMessages {
+    rate: MessageRate,
+    deferred: VecDeque<MessageType>,
+    /// Deferred messages that were dropped since the last [`RateLimitedMessages::take_dropped`]
+    dropped: u64,
+}
+
+impl RateLimitedMessages {
+    fn new(rate: MessageRate) -> Self {
+        RateLimitedMessages {
+            rate,
+            deferred: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Returns the message if it can be send right away, otherwise it's kept back
+    pub fn admit(&mut self, msg: MessageType) -> Option<MessageType> {
+        // messages that are kept back go first, to keep the order
+        if self.deferred.is_empty() && self.rate.try_send() {
+            return Some(msg);
+        }
+        METRICS.add_rate_limited_message();
+        if self.deferred.len() >= MAX_DEFERRED_MESSAGES {
+            self.deferred.pop_front();
+            self.dropped += 1;
+        }
+        self.deferred.push_back(msg);
+        None
+    }
+
+    /// When the next message that was kept back can be send, if any
+    pub fn ready_at(&self) -> Option<Instant> {
+        if self.deferred.is_empty() {
+            None
+        } else {
+            Some(self.rate.next_token_at())
+        }
+    }
+
+    /// Take the messages that were kept back and can be send now
+    pub fn take_ready(&mut self) -> Vec<MessageType> {
+        let mut ready = Vec::new();
+        while !self.deferred.is_empty() && self.rate.try_send() {
+            ready.extend(self.deferred.pop_front());
+        }
+        ready
+    }
+
+    /// Number of messages that were dropped because too many messages were kept back, the client should sync if this is non-zero
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+}
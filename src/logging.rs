@@ -0,0 +1,227 @@
+use flexi_logger::{FlexiLoggerError, LogSpecification, LoggerHandle};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Parts of the push server whose log level can be set separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogTarget {
+    /// Events received from Nextcloud and commands received from clients
+    Receive,
+    /// Messages send to clients
+    Send,
+    /// Authentication of clients
+    Auth,
+    /// Database queries
+    Db,
+    /// Redis connections
+    Redis,
+}
+
+impl LogTarget {
+    /// The target used by the log statements
+    pub fn module(&self) -> &'static str {
+        match self {
+            LogTarget::Receive => "notify_push::receive",
+            LogTarget::Send => "notify_push::send",
+            LogTarget::Auth => "notify_push::auth",
+            LogTarget::Db => "notify_push::db",
+            LogTarget::Redis => "notify_push::redis",
+        }
+    }
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogTarget::Receive => "receive",
+            LogTarget::Send => "send",
+            LogTarget::Auth => "auth",
+            LogTarget::Db => "db",
+            LogTarget::Redis => "redis",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid log target {0}, expected `receive`, `send`, `auth`, `db` or `redis`")]
+pub struct InvalidLogTarget(String);
+
+impl FromStr for LogTarget {
+    type Err = InvalidLogTarget;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "receive" => Ok(LogTarget::Receive),
+            "send" => Ok(LogTarget::Send),
+            "auth" => Ok(LogTarget::Auth),
+            "db" => Ok(LogTarget::Db),
+            "redis" => Ok(LogTarget::Redis),
+            _ => Err(InvalidLogTarget(s.to_string())),
+        }
+    }
+}
+
+/// Log level for a single target, written as `auth=debug`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetLevel {
+    pub target: LogTarget,
+    pub level: LevelFilter,
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidTargetLevel {
+    #[error("invalid target log level {0}, expected `<target>=<level>`")]
+    Format(String),
+    #[error(transparent)]
+    Target(#[from] InvalidLogTarget),
+    #[error("invalid log level {0}")]
+    Level(String),
+}
+
+impl FromStr for TargetLevel {
+    type Err = InvalidTargetLevel;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find('=')
+            .ok_or_else(|| InvalidTargetLevel::Format(s.to_string()))?;
+        let target = s[..split].trim().parse()?;
+        let level = s[split + 1..].trim();
+        let level = level
+            .parse()
+            .map_err(|_| InvalidTargetLevel::Level(level.to_string()))?;
+        Ok(TargetLevel { target, level })
+    }
+}
+
+/// Add the levels of the log targets to a log spec
+pub fn log_spec_with_targets(spec: &str, targets: impl IntoIterator<Item = TargetLevel>) -> String {
+    let mut parts: Vec<String> = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(String::from)
+        .collect();
+    for TargetLevel { target, level } in targets {
+        parts.push(format!(
+            "{}={}",
+            target.module(),
+            level.to_string().to_lowercase()
+        ));
+    }
+    parts.join(",")
+}
+
+/// Log specs temporarily set with `notify_config` events
+///
+/// Specs can be pushed with a version, restoring a version only removes the spec with that version
+/// so receiving a push or restore event a second time doesn't change the log level again.
+/// The levels of the log targets are applied on top of whichever spec is active.
+pub struct LogSpecs {
+    handle: LoggerHandle,
+    /// The log spec from the config
+    base: String,
+    /// Pushed specs with their version, `None` for specs pushed without a version
+    pushed: Vec<(Option<u64>, String)>,
+    /// Highest version that was pushed so far
+    latest: u64,
+    targets: BTreeMap<LogTarget, LevelFilter>,
+}
+
+impl LogSpecs {
+    pub fn new(handle: LoggerHandle, base: String, targets: &[TargetLevel]) -> Self {
+        LogSpecs {
+            handle,
+            base,
+            pushed: Vec::new(),
+            latest: 0,
+            targets: targets
+                .iter()
+                .map(|target| (target.target, target.level))
+                .collect(),
+        }
+    }
+
+    /// Set a temporary log spec, returns `false` if the version was already pushed before
+    pub fn push(&mut self, spec: &str, version: Option<u64>) -> Result<bool, FlexiLoggerError> {
+        if let Some(version) = version {
+            if version <= self.latest {
+                return Ok(false);
+            }
+        }
+        // make sure the spec is valid before changing any state
+        LogSpecification::parse(spec)?;
+        if let Some(version) = version {
+            self.latest = version;
+        }
+        self.pushed.push((version, spec.to_string()));
+        self.apply()?;
+        Ok(true)
+    }
+
+    /// Remove the last pushed spec, returns `false` if no spec was set
+    pub fn pop(&mut self) -> bool {
+        if self.pushed.pop().is_none() {
+            return false;
+        }
+        if let Err(e) = self.apply() {
+            log::error!("Failed to restore log level: {:?}", e);
+        }
+        true
+    }
+
+    /// Restore the log spec from before the spec with the version was pushed
+    ///
+    /// Specs pushed after it are removed too, returns `false` if the version isn't set.
+    pub fn restore(&mut self, version: u64) -> bool {
+        let position = match self
+            .pushed
+            .iter()
+            .position(|(pushed, _)| *pushed == Some(version))
+        {
+            Some(position) => position,
+            None => return false,
+        };
+        self.pushed.truncate(position);
+        if let Err(e) = self.apply() {
+            log::error!("Failed to restore log level: {:?}", e);
+        }
+        true
+    }
+
+    /// Set the level of a log target, `None` removes the level set for the target
+    pub fn set_target(
+        &mut self,
+        target: LogTarget,
+        level: Option<LevelFilter>,
+    ) -> Result<(), FlexiLoggerError> {
+        match level {
+            Some(level) => self.targets.insert(target, level),
+            None => self.targets.remove(&target),
+        };
+        self.apply()
+    }
+
+    fn apply(&mut self) -> Result<(), FlexiLoggerError> {
+        let spec = match self.pushed.last() {
+            Some((_, spec)) => spec,
+            None => &self.base,
+        };
+        let spec = log_spec_with_targets(
+            spec,
+            self.targets.iter().map(|(target, level)| TargetLevel {
+                target: *target,
+                level: *level,
+            }),
+        );
+        self.handle.set_new_spec(LogSpecification::parse(&spec)?);
+        Ok(())
+    }
+}
@@ -1,28 +1,54 @@
 use color_eyre::{eyre::WrapErr, Result};
 use flexi_logger::{detailed_format, AdaptiveFormat, Logger};
-use notify_push::config::{Config, Opt};
+use notify_push::backpressure::backpressure_loop;
+use notify_push::config::{take_systemd_sockets, Config, Opt};
+use notify_push::daemon::Daemon;
+use notify_push::diagnostics::Diagnostics;
+use notify_push::heartbeat::heartbeat_loop;
+use notify_push::input::event_input_loop;
+use notify_push::instance::announce_loop;
+use notify_push::logging::log_spec_with_targets;
+use notify_push::memory::memory_loop;
 use notify_push::message::DEBOUNCE_ENABLE;
 use notify_push::metrics::serve_metrics;
-use notify_push::{listen_loop, serve, App};
+use notify_push::presence::presence_loop;
+use notify_push::probe::Probe;
+use notify_push::reconnect::database_reconnect_loop;
+use notify_push::schedule::schedule_loop;
+use notify_push::statsd::metrics_push_loop;
+use notify_push::upgrade::exec_upgrade;
+use notify_push::webhook::webhook_loop;
+use notify_push::{tls_reload_loop, App};
+use std::os::unix::io::RawFd;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use structopt::StructOpt;
+use tokio::runtime::Builder;
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::oneshot;
-use tokio::task::spawn;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    // the sockets are taken from the environment while there are no other threads yet
+    let inherited_sockets = take_systemd_sockets();
+    Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(inherited_sockets))
+}
+
+async fn run(inherited_sockets: Vec<RawFd>) -> Result<()> {
     color_eyre::install()?;
     let _ = dotenv::dotenv();
 
-    let opt: Opt = Opt::from_args();
+    let mut opt: Opt = Opt::from_args();
+    opt.inherited_sockets = inherited_sockets;
     if opt.version {
         println!("notify_push {}", env!("NOTIFY_PUSH_VERSION"));
         return Ok(());
     }
     let dump_config = opt.dump_config;
+    let probe = opt.probe;
+    let diagnostics = opt.diagnostics;
     let config = Config::from_opt(opt).wrap_err("Failed to parse config")?;
 
     if dump_config {
@@ -30,7 +56,16 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    let log_handle = Logger::try_with_str(&config.log_level)?.log_to_stdout();
+    if probe {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&Probe::new(&config).await)?
+        );
+        return Ok(());
+    }
+
+    let log_spec = log_spec_with_targets(&config.log_level, config.log_targets.iter().copied());
+    let log_handle = Logger::try_with_str(&log_spec)?.log_to_stdout();
     let log_handle = if config.no_ansi {
         log_handle.format_for_stdout(detailed_format)
     } else {
@@ -38,16 +73,23 @@ async fn main() -> Result<()> {
     }
     .start()?;
 
-    let (serve_cancel, serve_cancel_handle) = oneshot::channel();
-    let (metrics_cancel, metrics_cancel_handle) = oneshot::channel();
-    let (listen_cancel, listen_cancel_handle) = oneshot::channel();
+    if diagnostics {
+        let diagnostics = Diagnostics::collect(config, log_handle).await?;
+        println!("{}", serde_json::to_string_pretty(&diagnostics)?);
+        return Ok(());
+    }
 
     log::trace!("Running with config: {:?}", config);
+    log::info!(
+        "Starting notify_push: {}",
+        serde_json::to_string(&Probe::new(&config).await)?
+    );
 
     if config.allow_self_signed {
         log::info!("Running with certificate validation disabled");
     }
 
+    config.debounce_windows.apply();
     if dotenv::var("DEBOUNCE_DISABLE").is_ok() {
         DEBOUNCE_ENABLE.store(false, Ordering::Relaxed);
     }
@@ -55,43 +97,156 @@ async fn main() -> Result<()> {
     let bind = config.bind.clone();
     let tls = config.tls.clone();
     let metrics_bind = config.metrics_bind.clone();
+    let grpc_bind = config.grpc_bind.clone();
+    let metrics_push = config.metrics_push.clone();
+    let heartbeat_interval = config.heartbeat_interval;
+    let backpressure_threshold = config.backpressure_threshold;
+    let mqtt_url = config.mqtt_url.clone();
+    let webhook = config.webhook.clone();
+    let state_file = config.state_file.clone();
+    let event_input = config.event_input.clone();
+    let presence = config.presence;
     let app = Arc::new(App::new(config, log_handle).await?);
+    if let Some(state_file) = &state_file {
+        if let Err(e) = app.restore_state(state_file) {
+            log::error!("Failed to restore state: {:#}", e);
+        }
+    }
     if let Err(e) = app.self_test().await {
         log::error!("Self test failed: {:#}", e);
     }
 
-    log::trace!("Listening on {}", bind);
-    let server = spawn(serve(app.clone(), bind, serve_cancel_handle, tls.as_ref())?);
+    // sockets passed by systemd are handed to the new process when upgrading
+    let inherited_sockets: Vec<_> = std::iter::once(&bind)
+        .chain(metrics_bind.iter())
+        .filter_map(|bind| bind.inherited_fd())
+        .collect();
+
+    let mut daemon = Daemon::new(app.clone());
+    daemon.start_server(bind, tls.as_ref()).await?;
 
     if let Some(metrics_bind) = metrics_bind {
         log::trace!("Metrics listening {}", metrics_bind);
-        spawn(serve_metrics(
-            metrics_bind,
-            metrics_cancel_handle,
-            tls.as_ref(),
-        )?);
+        daemon.try_spawn_background("metrics server", |app, cancel| {
+            serve_metrics(app, metrics_bind, cancel, tls.as_ref())
+        })?;
+    }
+
+    if !grpc_bind.is_empty() {
+        #[cfg(feature = "grpc")]
+        {
+            log::trace!("gRPC listening on {:?}", grpc_bind);
+            daemon.spawn_background("grpc server", |app, cancel| {
+                notify_push::grpc::serve_grpc(app, grpc_bind, cancel)
+            });
+        }
+        #[cfg(not(feature = "grpc"))]
+        log::warn!("A gRPC port is configured but this build doesn't include the gRPC api");
+    }
+
+    if let Some(metrics_push) = metrics_push {
+        log::trace!("Pushing metrics to {}", metrics_push.address);
+        daemon.spawn_background("metrics push", |app, cancel| {
+            metrics_push_loop(app, metrics_push, cancel)
+        });
+    }
+
+    if let Some(mqtt_url) = mqtt_url {
+        #[cfg(feature = "mqtt")]
+        {
+            log::trace!(
+                "Publishing messages to mqtt broker {}",
+                mqtt_url.host_str().unwrap_or_default()
+            );
+            daemon.spawn_background("mqtt bridge", |app, cancel| {
+                notify_push::mqtt::mqtt_bridge(app, mqtt_url, cancel)
+            });
+        }
+        #[cfg(not(feature = "mqtt"))]
+        {
+            let _ = mqtt_url;
+            log::warn!(
+                "An mqtt broker is configured but this build doesn't include the mqtt bridge"
+            );
+        }
     }
 
-    spawn(listen_loop(app, listen_cancel_handle));
+    if webhook.is_enabled() {
+        log::trace!("Forwarding messages to {} webhooks", webhook.urls.len());
+        daemon.spawn_background("webhooks", |app, cancel| webhook_loop(app, webhook, cancel));
+    }
 
-    // wait for either a sigint or sigterm
+    if let Some(heartbeat_interval) = heartbeat_interval {
+        daemon.spawn_background("heartbeat", |app, cancel| {
+            heartbeat_loop(app, heartbeat_interval, cancel)
+        });
+    }
+
+    if let Some(threshold) = backpressure_threshold {
+        daemon.spawn_background("backpressure", move |app, cancel| {
+            backpressure_loop(app, threshold, cancel)
+        });
+    }
+
+    if let Some(event_input) = event_input {
+        log::warn!(
+            "Reading events from {}, this is intended for debugging only",
+            event_input.display()
+        );
+        daemon.spawn_background("event input", |app, cancel| {
+            event_input_loop(app, event_input, cancel)
+        });
+    }
+
+    if presence {
+        daemon.spawn_background("presence", presence_loop);
+    }
+
+    daemon.spawn_background("schedule", schedule_loop);
+    daemon.spawn_background("announce", announce_loop);
+    daemon.spawn_background("database reconnect", database_reconnect_loop);
+    daemon.start_listener().await;
+    daemon.spawn_background("tls reload", tls_reload_loop);
+    daemon.spawn_background("memory", memory_loop);
+
+    // wait for either a sigint or sigterm, or a sigusr2 to upgrade
     let mut term = signal(SignalKind::terminate())?;
     let mut int = signal(SignalKind::interrupt())?;
-
-    select! {
-        _ = term.recv() => (),
-        _ = int.recv() => (),
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+
+    let upgrade = loop {
+        select! {
+            _ = term.recv() => break false,
+            _ = int.recv() => break false,
+            _ = usr2.recv() => {
+                if inherited_sockets.is_empty() {
+                    log::error!("Upgrading without downtime requires the listening sockets to be passed by systemd");
+                    continue;
+                }
+                break true;
+            },
+        };
     };
 
-    // then send cancel events to all of our spawned tasks
+    // then stop all subsystems
 
-    log::info!("shutdown signal received, shutting down");
+    if upgrade {
+        log::info!("upgrade signal received, shutting down");
+    } else {
+        log::info!("shutdown signal received, shutting down");
+    }
 
-    serve_cancel.send(()).ok();
-    metrics_cancel.send(()).ok();
-    listen_cancel.send(()).ok();
+    daemon.shutdown().await;
 
-    server.await?;
+    if let Some(state_file) = &state_file {
+        if let Err(e) = app.save_state(state_file) {
+            log::error!("Failed to save state: {:#}", e);
+        }
+    }
+
+    if upgrade {
+        exec_upgrade(&inherited_sockets)?;
+    }
 
     Ok(())
 }
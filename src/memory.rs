@@ -0,0 +1,143 @@
+use crate::App;
+use futures::future::select;
+use futures::pin_mut;
+use parse_display::Display;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tokio::time::interval;
+
+/// How often the memory usage is checked against the configured limit
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Percentage of the memory limit after which optional buffers are dropped
+const HIGH_WATERMARK: u64 = 80;
+
+/// Memory usage compared to the configured limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[display(style = "snake_case")]
+pub enum MemoryPressure {
+    Normal,
+    /// Optional buffers and caches are dropped
+    High,
+    /// New connections are refused
+    Critical,
+}
+
+impl MemoryPressure {
+    pub fn from_usage(used: u64, limit: u64) -> Self {
+        if used >= limit {
+            MemoryPressure::Critical
+        } else if used.saturating_mul(100) >= limit.saturating_mul(HIGH_WATERMARK) {
+            MemoryPressure::High
+        } else {
+            MemoryPressure::Normal
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            0 => MemoryPressure::Normal,
+            1 => MemoryPressure::High,
+            _ => MemoryPressure::Critical,
+        }
+    }
+}
+
+/// Amount of memory in bytes, parsed from a number with an optional `K`, `M` or `G` suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySize(pub u64);
+
+#[derive(Debug, Error)]
+#[error("invalid memory size {0}, expected a number of bytes with an optional K, M or G suffix")]
+pub struct InvalidMemorySize(String);
+
+impl FromStr for MemorySize {
+    type Err = InvalidMemorySize;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 1 << 10),
+            Some('M') => (&s[..s.len() - 1], 1 << 20),
+            Some('G') => (&s[..s.len() - 1], 1 << 30),
+            _ => (s, 1),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(multiplier))
+            .map(MemorySize)
+            .ok_or_else(|| InvalidMemorySize(s.to_string()))
+    }
+}
+
+/// Resident memory of the push server in bytes
+///
+/// This uses `VmRSS` from `/proc/self/status` which is always reported in kB, unlike `/proc/self/statm`
+/// which counts pages and the page size differs between architectures (4K on x86, up to 64K on arm64 and ppc64).
+pub fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))?
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(|kb| kb * 1024)
+}
+
+/// Check the memory usage against the configured limit and shed load when it's getting close
+///
+/// Above the high watermark the storage mapping cache, pending poll queues and messages held for resumable
+/// sessions are dropped on every check, above the limit new connections are refused as well.
+pub async fn memory_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
+    let limit = match app.limits.max_memory() {
+        Some(limit) => limit,
+        None => return,
+    };
+    let loop_ = async move {
+        let mut interval = interval(MEMORY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let used = match resident_memory() {
+                Some(used) => used,
+                None => {
+                    log::warn!("Can't determine memory usage, memory limit is not enforced");
+                    return;
+                }
+            };
+            let pressure = MemoryPressure::from_usage(used, limit);
+            let previous = app.limits.set_memory_pressure(pressure);
+            if pressure != previous {
+                if pressure > previous {
+                    log::warn!(
+                        "Memory usage of {}MB is close to the limit of {}MB, pressure is now {}",
+                        used >> 20,
+                        limit >> 20,
+                        pressure
+                    );
+                } else {
+                    log::info!(
+                        "Memory usage dropped to {}MB, pressure is now {}",
+                        used >> 20,
+                        pressure
+                    );
+                }
+            }
+            if pressure >= MemoryPressure::High {
+                app.storage_mapping.invalidate_all();
+                app.connections.clear_pending();
+                app.resume_tokens.clear_held();
+            }
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
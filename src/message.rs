@@ -1,32 +1,161 @@
+use crate::protocol::ProtocolVersion;
 use parse_display::Display;
 use rand::{thread_rng, Rng};
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::fmt::Write;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Instant;
 use tokio::time::Duration;
 use warp::ws::Message;
 
-#[derive(Debug, Clone, Display)]
+#[derive(Debug, Clone, Display, Serialize, Deserialize)]
 pub enum MessageType {
     #[display("notify_file")]
-    File,
+    File(Option<FilePayload>),
     #[display("notify_activity")]
-    Activity,
+    Activity(Option<ActivityPayload>),
     #[display("notify_notification")]
-    Notification,
+    Notification(Option<NotificationPayload>),
+    /// Custom message with a body, an optional tag and an optional topic to limit the connections it's send to
     #[display("{0}")]
-    Custom(String, Value),
+    Custom(String, Value, Option<String>, Option<String>),
+}
+
+/// Details about a changed file, only send to clients using protocol version 2
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FilePayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<u64>,
+    /// Path of the file relative to the user's files, the same file has a different path for every user it's shared with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// Changes every time the file or any of its children changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// Set when the user lost or changed access to the file instead of the file itself changing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<FileChangeReason>,
+    /// The new permissions of the user for the file, for permission changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<u32>,
+    /// Ids of all files that changed while file messages were held back by debouncing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ids: Option<Vec<u64>>,
+    /// Number of changes in the folder at `path` summarized by this message, when replaying a backlog of file messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    /// Path of the changed file relative to the user's files, used to match the paths a connection subscribed to
+    ///
+    /// Unlike `path` this is also known when file change hints are disabled, it's never send to clients
+    #[serde(skip)]
+    pub filter_path: Option<String>,
+}
+
+impl FilePayload {
+    /// The ids of the files the message is about, `None` if it's unknown which files changed
+    fn changed_ids(&self) -> Option<Vec<u64>> {
+        match (&self.ids, self.file_id) {
+            (Some(ids), _) => Some(ids.clone()),
+            (None, Some(file_id)) => Some(vec![file_id]),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileChangeReason {
+    ShareDeleted,
+    PermissionsChanged,
+}
+
+/// Details about an activity, only send to clients using protocol version 2
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ActivityPayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activity_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object_id: Option<u64>,
+}
+
+/// Details about a notification, only send to clients using protocol version 2
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NotificationPayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+}
+
+impl MessageType {
+    /// Encode the message in the format for the protocol version of the connection
+    pub fn to_message(&self, version: ProtocolVersion) -> Message {
+        match version {
+            ProtocolVersion::V1 => self.clone().into(),
+            ProtocolVersion::V2 => Message::text(self.to_json().to_string()),
+        }
+    }
+
+    /// The message as a binary MessagePack frame, using the structure of protocol version 2
+    pub fn to_msgpack(&self) -> Message {
+        // serializing a json value can't fail
+        Message::binary(rmp_serde::to_vec_named(&self.to_json()).unwrap_or_default())
+    }
+
+    /// The message in the format used by protocol version 2
+    pub fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        let ty = match self {
+            MessageType::File(payload) => {
+                extend_object(&mut object, payload);
+                "file"
+            }
+            MessageType::Activity(payload) => {
+                extend_object(&mut object, payload);
+                "activity"
+            }
+            MessageType::Notification(payload) => {
+                extend_object(&mut object, payload);
+                "notification"
+            }
+            MessageType::Custom(message, body, _, topic) => {
+                object.insert("message".into(), Value::String(message.clone()));
+                if !body.is_null() {
+                    object.insert("body".into(), body.clone());
+                }
+                if let Some(topic) = topic {
+                    object.insert("topic".into(), Value::String(topic.clone()));
+                }
+                "custom"
+            }
+        };
+        object.insert("type".into(), Value::String(ty.into()));
+        Value::Object(object)
+    }
+}
+
+/// Add all fields of a payload to a json object
+fn extend_object<T: Serialize>(object: &mut Map<String, Value>, payload: &Option<T>) {
+    if let Some(Ok(Value::Object(fields))) = payload.as_ref().map(serde_json::to_value) {
+        object.extend(fields);
+    }
 }
 
 impl From<MessageType> for Message {
     fn from(msg: MessageType) -> Self {
         match msg {
-            MessageType::File => Message::text(String::from("notify_file")),
-            MessageType::Activity => Message::text(String::from("notify_activity")),
-            MessageType::Notification => Message::text(String::from("notify_notification")),
-            MessageType::Custom(ty, Value::Null) => Message::text(ty),
-            MessageType::Custom(ty, body) => Message::text({
+            MessageType::File(_) => Message::text(String::from("notify_file")),
+            MessageType::Activity(_) => Message::text(String::from("notify_activity")),
+            MessageType::Notification(_) => Message::text(String::from("notify_notification")),
+            MessageType::Custom(ty, Value::Null, ..) => Message::text(ty),
+            MessageType::Custom(ty, body, ..) => Message::text({
                 let mut str = ty;
                 write!(&mut str, " {}", body).ok();
                 str
@@ -35,44 +164,145 @@ impl From<MessageType> for Message {
     }
 }
 
+/// The message types that are currently held back by debouncing for a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct HeldMessages {
+    pub file: bool,
+    pub activity: bool,
+    pub notification: bool,
+}
+
 pub static DEBOUNCE_ENABLE: AtomicBool = AtomicBool::new(true);
 
+/// Debounce windows in seconds, can be changed at runtime
+pub static DEBOUNCE_FILE: AtomicU64 = AtomicU64::new(60);
+pub static DEBOUNCE_ACTIVITY: AtomicU64 = AtomicU64::new(120);
+pub static DEBOUNCE_NOTIFICATION: AtomicU64 = AtomicU64::new(30);
+
+/// Debounce windows in seconds the push server starts with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebounceWindows {
+    pub file: u64,
+    pub activity: u64,
+    pub notification: u64,
+}
+
+impl Default for DebounceWindows {
+    fn default() -> Self {
+        DebounceWindows {
+            file: 60,
+            activity: 120,
+            notification: 30,
+        }
+    }
+}
+
+impl DebounceWindows {
+    /// Use the windows for all connections, until they are changed at runtime
+    pub fn apply(&self) {
+        DEBOUNCE_FILE.store(self.file, Ordering::Relaxed);
+        DEBOUNCE_ACTIVITY.store(self.activity, Ordering::Relaxed);
+        DEBOUNCE_NOTIFICATION.store(self.notification, Ordering::Relaxed);
+    }
+}
+
+/// Debounce windows are multiplied by this for connections in mobile mode
+const MOBILE_DEBOUNCE_FACTOR: u32 = 5;
+
+/// Maximum number of file ids collected while file messages are held back, clients do a full sync above it
+const MAX_HELD_FILE_IDS: usize = 64;
+
+/// Range a client can pick its own debounce window from with the `debounce` command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebounceBounds {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Default for DebounceBounds {
+    fn default() -> Self {
+        DebounceBounds {
+            min: Duration::from_secs(5),
+            max: Duration::from_secs(600),
+        }
+    }
+}
+
+impl DebounceBounds {
+    pub fn clamp(&self, window: Duration) -> Duration {
+        window.max(self.min).min(self.max)
+    }
+}
+
 pub struct DebounceMap {
+    mobile: bool,
+    /// Debounce window picked by the client, used for all message types instead of the server defaults
+    window: Option<Duration>,
     file: Instant,
     activity: Instant,
     notification: Instant,
     file_held: bool,
     activity_held: bool,
     notification_held: bool,
+    /// Ids of the files changed by the held back file messages, `None` once a change to an unknown file is held
+    held_file_ids: Option<Vec<u64>>,
+}
+
+impl HeldMessages {
+    pub fn messages(&self) -> impl Iterator<Item = MessageType> {
+        let file_opt = self.file.then(|| MessageType::File(None));
+        let activity_opt = self.activity.then(|| MessageType::Activity(None));
+        let notification_opt = self.notification.then(|| MessageType::Notification(None));
+        file_opt
+            .into_iter()
+            .chain(activity_opt.into_iter())
+            .chain(notification_opt.into_iter())
+    }
 }
 
 impl Default for DebounceMap {
     fn default() -> Self {
         let past = Instant::now() - Duration::from_secs(600);
         DebounceMap {
+            mobile: false,
+            window: None,
             file: past,
             activity: past,
             notification: past,
             file_held: false,
             activity_held: false,
             notification_held: false,
+            held_file_ids: Some(Vec::new()),
         }
     }
 }
 
 impl DebounceMap {
+    pub fn with_held(held: HeldMessages) -> Self {
+        DebounceMap {
+            file_held: held.file,
+            activity_held: held.activity,
+            notification_held: held.notification,
+            // the files changed before the session was lost aren't known
+            held_file_ids: if held.file { None } else { Some(Vec::new()) },
+            ..DebounceMap::default()
+        }
+    }
+
     /// Check if the debounce time has passed and set the last send time if so
     pub fn should_send(&mut self, ty: &MessageType) -> bool {
         if DEBOUNCE_ENABLE.load(Ordering::Relaxed) {
             let last_send = self.get_last_send(ty);
-            if Instant::now().duration_since(last_send) > Self::debounce_time(ty) {
+            if Instant::now().duration_since(last_send) > self.debounce_time(ty) {
                 self.set_last_send(ty);
                 self.set_held(ty, false);
                 true
             } else if Instant::now().duration_since(last_send) > Duration::from_millis(100) {
                 self.set_held(ty, true);
+                self.hold_file_ids(ty);
                 false
             } else {
+                self.hold_file_ids(ty);
                 false
             }
         } else {
@@ -80,25 +310,64 @@ impl DebounceMap {
         }
     }
 
+    /// Use the longer debounce windows for mobile connections
+    pub fn set_mobile(&mut self, mobile: bool) {
+        self.mobile = mobile;
+    }
+
+    /// Use the debounce window picked by the client, or the server defaults if `None`
+    pub fn set_window(&mut self, window: Option<Duration>) {
+        self.window = window;
+    }
+
     pub fn has_held_message(&self) -> bool {
         self.file_held || self.activity_held || self.notification_held
     }
 
+    /// The held back messages, file messages list the ids of the changed files if they are known
     pub fn get_held_messages(&self) -> impl Iterator<Item = MessageType> {
-        let file_opt = self.file_held.then(|| MessageType::File);
-        let activity_opt = self.activity_held.then(|| MessageType::Activity);
-        let notification_opt = self.notification_held.then(|| MessageType::Notification);
-        file_opt
-            .into_iter()
-            .chain(activity_opt.into_iter())
-            .chain(notification_opt.into_iter())
+        let ids = self.held_file_ids.clone().filter(|ids| !ids.is_empty());
+        self.held().messages().map(move |msg| match (msg, &ids) {
+            (MessageType::File(None), Some(ids)) => MessageType::File(Some(FilePayload {
+                ids: Some(ids.clone()),
+                ..FilePayload::default()
+            })),
+            (msg, _) => msg,
+        })
+    }
+
+    /// Remember which files changed while file messages are held back
+    fn hold_file_ids(&mut self, ty: &MessageType) {
+        let changed = match ty {
+            MessageType::File(payload) => payload.as_ref().and_then(FilePayload::changed_ids),
+            _ => return,
+        };
+        self.held_file_ids = match (self.held_file_ids.take(), changed) {
+            (Some(mut held), Some(changed)) => {
+                for id in changed {
+                    if !held.contains(&id) {
+                        held.push(id);
+                    }
+                }
+                Some(held).filter(|held| held.len() <= MAX_HELD_FILE_IDS)
+            }
+            _ => None,
+        };
+    }
+
+    pub fn held(&self) -> HeldMessages {
+        HeldMessages {
+            file: self.file_held,
+            activity: self.activity_held,
+            notification: self.notification_held,
+        }
     }
 
     fn get_last_send(&self, ty: &MessageType) -> Instant {
         match ty {
-            MessageType::File => self.file,
-            MessageType::Activity => self.activity,
-            MessageType::Notification => self.notification,
+            MessageType::File(_) => self.file,
+            MessageType::Activity(_) => self.activity,
+            MessageType::Notification(_) => self.notification,
             MessageType::Custom(..) => Instant::now() - Duration::from_secs(600), // no debouncing for custom messages
         }
     }
@@ -108,28 +377,45 @@ impl DebounceMap {
         // this helps mitigate against load bursts from many clients receiving the same updates
         let spread = Duration::from_millis(thread_rng().gen_range(0..1000));
         match ty {
-            MessageType::File => self.file = Instant::now() - spread,
-            MessageType::Activity => self.activity = Instant::now() - spread,
-            MessageType::Notification => self.notification = Instant::now() - spread,
+            MessageType::File(_) => self.file = Instant::now() - spread,
+            MessageType::Activity(_) => self.activity = Instant::now() - spread,
+            MessageType::Notification(_) => self.notification = Instant::now() - spread,
             MessageType::Custom(..) => {} // no debouncing for custom messages
         }
     }
 
     fn set_held(&mut self, ty: &MessageType, held: bool) {
         match ty {
-            MessageType::File => self.file_held = held,
-            MessageType::Activity => self.activity_held = held,
-            MessageType::Notification => self.notification_held = held,
+            MessageType::File(_) => {
+                self.file_held = held;
+                if !held {
+                    self.held_file_ids = Some(Vec::new());
+                }
+            }
+            MessageType::Activity(_) => self.activity_held = held,
+            MessageType::Notification(_) => self.notification_held = held,
             MessageType::Custom(..) => {} // no debouncing for custom messages
         }
     }
 
-    fn debounce_time(ty: &MessageType) -> Duration {
-        match ty {
-            MessageType::File => Duration::from_secs(60),
-            MessageType::Activity => Duration::from_secs(120),
-            MessageType::Notification => Duration::from_secs(30),
-            MessageType::Custom(..) => Duration::from_millis(1), // no debouncing for custom messages
+    fn debounce_time(&self, ty: &MessageType) -> Duration {
+        if let (Some(window), false) = (self.window, matches!(ty, MessageType::Custom(..))) {
+            return window;
+        }
+        let time = match ty {
+            MessageType::File(_) => Duration::from_secs(DEBOUNCE_FILE.load(Ordering::Relaxed)),
+            MessageType::Activity(_) => {
+                Duration::from_secs(DEBOUNCE_ACTIVITY.load(Ordering::Relaxed))
+            }
+            MessageType::Notification(_) => {
+                Duration::from_secs(DEBOUNCE_NOTIFICATION.load(Ordering::Relaxed))
+            }
+            MessageType::Custom(..) => return Duration::from_millis(1), // no debouncing for custom messages
+        };
+        if self.mobile {
+            time * MOBILE_DEBOUNCE_FACTOR
+        } else {
+            time
         }
     }
 }
@@ -1,15 +1,295 @@
 use crate::config::{Bind, TlsConfig};
-use crate::serve_at;
+use crate::disconnect::DisconnectReason;
+use crate::idle::IDLE_TIMEOUTS;
+use crate::instance::{cluster_metrics, INSTANCE_CONFLICTS};
+use crate::latency::{LATENCIES, RTT_QUANTILES};
+use crate::memory::resident_memory;
+use crate::{serve_at, App, UserId};
+use ahash::RandomState;
 use color_eyre::Result;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
 use serde::{Serialize, Serializer};
+use std::convert::Infallible;
 use std::fmt::Write;
 use std::future::Future;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
+use warp::http::StatusCode;
+use warp::log::Info;
 use warp::Filter;
 
 pub static METRICS: Metrics = Metrics::new();
 
+pub static HTTP_METRICS: Lazy<HttpMetrics> = Lazy::new(HttpMetrics::default);
+
+/// Number of messages lost per user because a connection of the user couldn't keep up
+pub static LAGGED_MESSAGES: Lazy<DashMap<UserId, u64, RandomState>> = Lazy::new(DashMap::default);
+
+/// Number of clients that couldn't be authenticated or admitted, by reason
+pub static AUTH_FAILURES: Lazy<DashMap<&'static str, usize, RandomState>> =
+    Lazy::new(DashMap::default);
+
+/// Number of updates a [`MetricsBatch`] collects before adding them to [`METRICS`]
+const BATCH_SIZE: usize = 256;
+/// Maximum time updates are kept in a [`MetricsBatch`] before adding them to [`METRICS`]
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Routes http requests are grouped by, requests for unknown paths are counted as `other`
+const HTTP_ROUTES: &[&str] = &[
+    "ws", "events", "poll", "test", "admin", "instance", "ready", "metrics",
+];
+
+/// Request counts and latencies per route
+#[derive(Default)]
+pub struct HttpMetrics {
+    requests: DashMap<(&'static str, u16), usize, RandomState>,
+    durations: DashMap<&'static str, RouteDuration, RandomState>,
+}
+
+#[derive(Default)]
+struct RouteDuration {
+    count: usize,
+    total: Duration,
+}
+
+impl HttpMetrics {
+    pub fn record(&self, path: &str, status: StatusCode, duration: Duration) {
+        let route = route_name(path);
+        *self.requests.entry((route, status.as_u16())).or_insert(0) += 1;
+        let mut route_duration = self
+            .durations
+            .entry(route)
+            .or_insert_with(RouteDuration::default);
+        route_duration.count += 1;
+        route_duration.total += duration;
+    }
+
+    fn collect(&self, samples: &mut Vec<Sample>) {
+        let mut requests: Vec<_> = self
+            .requests
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        requests.sort_unstable();
+        for ((route, status), count) in requests {
+            samples.push(
+                Sample::new("http_request_count", count as f64)
+                    .label("route", route)
+                    .label("status", status),
+            );
+        }
+        let mut durations: Vec<_> = self
+            .durations
+            .iter()
+            .map(|entry| (*entry.key(), entry.count, entry.total))
+            .collect();
+        durations.sort_unstable_by_key(|(route, _, _)| *route);
+        for (route, count, total) in durations {
+            samples.push(
+                Sample::new("http_request_duration_seconds_sum", total.as_secs_f64())
+                    .label("route", route),
+            );
+            samples.push(
+                Sample::new("http_request_duration_seconds_count", count as f64)
+                    .label("route", route),
+            );
+        }
+    }
+}
+
+/// Find the route for a request path, skipping any path prefix
+fn route_name(path: &str) -> &'static str {
+    path.split('/')
+        .find_map(|segment| HTTP_ROUTES.iter().find(|route| **route == segment))
+        .copied()
+        .unwrap_or("other")
+}
+
+/// Record a request handled by warp, for use with `warp::log::custom`
+pub fn record_http_request(info: Info) {
+    HTTP_METRICS.record(info.path(), info.status(), info.elapsed());
+}
+
+/// A single value of a metric with its labels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+impl Sample {
+    pub fn new(name: &'static str, value: f64) -> Self {
+        Sample {
+            name,
+            labels: Vec::new(),
+            value,
+        }
+    }
+
+    pub fn label(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.labels.push((name, value.to_string()));
+        self
+    }
+}
+
+/// Text format the metrics are exported in
+pub trait MetricsFormat {
+    /// Append a single sample to the output
+    fn write(&self, sample: &Sample, out: &mut String);
+}
+
+/// The prometheus exposition format served at `/metrics`
+pub struct Prometheus;
+
+impl MetricsFormat for Prometheus {
+    fn write(&self, sample: &Sample, out: &mut String) {
+        out.push_str(sample.name);
+        if !sample.labels.is_empty() {
+            out.push('{');
+            for (i, (name, value)) in sample.labels.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "{}=\"{}\"", name, value);
+            }
+            out.push('}');
+        }
+        let _ = writeln!(out, " {}", sample.value);
+    }
+}
+
+/// Collect all metrics of this push server
+///
+/// The samples are exported by every configured backend, the prometheus endpoint and the statsd push loop.
+pub fn collect_metrics(app: &App) -> Vec<Sample> {
+    let mut samples = vec![
+        Sample::new(
+            "active_connection_count",
+            METRICS.active_connection_count() as f64,
+        ),
+        Sample::new(
+            "total_connection_count",
+            METRICS.total_connection_count() as f64,
+        ),
+        Sample::new(
+            "online_user_count",
+            app.connections.presence().online_count() as f64,
+        ),
+        Sample::new("room_count", app.rooms.room_count() as f64),
+        Sample::new(
+            "scheduled_message_count",
+            app.schedule.scheduled_count() as f64,
+        ),
+        Sample::new("mapping_query_count", METRICS.mapping_query_count() as f64),
+        Sample::new("event_count_total", METRICS.events_received() as f64),
+        Sample::new("message_count_total", METRICS.messages_send() as f64),
+        Sample::new(
+            "storage_update_dropped_count",
+            METRICS.storage_updates_dropped() as f64,
+        ),
+        Sample::new(
+            "registry_operation_count",
+            METRICS.registry_operations() as f64,
+        ),
+        Sample::new(
+            "registry_contention_count",
+            METRICS.registry_contention() as f64,
+        ),
+        Sample::new("message_lagged_count", METRICS.messages_lagged() as f64),
+        Sample::new(
+            "message_retransmit_count",
+            METRICS.messages_retransmitted() as f64,
+        ),
+        Sample::new(
+            "message_unacknowledged_count",
+            METRICS.messages_unacknowledged() as f64,
+        ),
+        Sample::new(
+            "database_reconnect_count",
+            METRICS.database_reconnects() as f64,
+        ),
+        Sample::new(
+            "active_event_stream_count",
+            METRICS.active_event_stream_count() as f64,
+        ),
+        Sample::new(
+            "total_event_stream_count",
+            METRICS.total_event_stream_count() as f64,
+        ),
+        Sample::new("poll_request_count", METRICS.poll_request_count() as f64),
+        Sample::new(
+            "message_rate_limited_count",
+            METRICS.messages_rate_limited() as f64,
+        ),
+    ];
+    let disconnects = [
+        ("peer_reset", METRICS.disconnect_peer_reset_count()),
+        ("protocol_error", METRICS.disconnect_protocol_error_count()),
+        ("timeout", METRICS.disconnect_timeout_count()),
+        ("other", METRICS.disconnect_other_error_count()),
+    ];
+    for (reason, count) in disconnects.iter() {
+        samples.push(Sample::new("disconnect_error_count", *count as f64).label("reason", reason));
+    }
+    for (user, count) in app.limits.exempt_connections() {
+        samples.push(Sample::new("exempt_connection_count", count as f64).label("user", user));
+    }
+    let mut auth_failures: Vec<_> = AUTH_FAILURES
+        .iter()
+        .map(|entry| (*entry.key(), *entry.value()))
+        .collect();
+    auth_failures.sort_unstable();
+    for (reason, count) in auth_failures {
+        samples.push(Sample::new("auth_failure_count", count as f64).label("reason", reason));
+    }
+    samples.push(Sample::new(
+        "idle_disconnect_count",
+        IDLE_TIMEOUTS.idle_disconnect_count() as f64,
+    ));
+    if let Some(timeout) = IDLE_TIMEOUTS.observed_timeout() {
+        samples.push(Sample::new(
+            "observed_idle_timeout_seconds",
+            timeout.as_secs() as f64,
+        ));
+    }
+    if let Some(interval) = IDLE_TIMEOUTS.recommended_ping_interval(app.ping.interval) {
+        samples.push(Sample::new(
+            "recommended_ping_interval_seconds",
+            interval.as_secs() as f64,
+        ));
+    }
+    for (quantile, rtt) in LATENCIES.percentiles(RTT_QUANTILES) {
+        samples.push(
+            Sample::new("connection_rtt_seconds", rtt.as_secs_f64()).label("quantile", quantile),
+        );
+    }
+    if let Some(memory) = resident_memory() {
+        samples.push(Sample::new("resident_memory_bytes", memory as f64));
+    }
+    samples.push(Sample::new(
+        "event_source_healthy",
+        app.heartbeat.is_healthy() as u8 as f64,
+    ));
+    samples.push(Sample::new(
+        "heartbeat_failure_count",
+        app.heartbeat.failure_count() as f64,
+    ));
+    samples.push(Sample::new(
+        "instance_conflict_count",
+        INSTANCE_CONFLICTS.load(Ordering::Relaxed) as f64,
+    ));
+    samples.push(Sample::new(
+        "memory_pressure",
+        app.limits.memory_pressure() as u8 as f64,
+    ));
+    HTTP_METRICS.collect(&mut samples);
+    samples
+}
+
 #[derive(Default)]
 pub struct Metrics {
     active_connection_count: AtomicUsize,
@@ -17,6 +297,21 @@ pub struct Metrics {
     mapping_query_count: AtomicUsize,
     events_received: AtomicUsize,
     messages_send: AtomicUsize,
+    disconnect_peer_reset_count: AtomicUsize,
+    disconnect_protocol_error_count: AtomicUsize,
+    disconnect_timeout_count: AtomicUsize,
+    disconnect_other_error_count: AtomicUsize,
+    storage_updates_dropped: AtomicUsize,
+    registry_operations: AtomicUsize,
+    registry_contention: AtomicUsize,
+    messages_lagged: AtomicUsize,
+    messages_retransmitted: AtomicUsize,
+    messages_unacknowledged: AtomicUsize,
+    database_reconnects: AtomicUsize,
+    active_event_stream_count: AtomicUsize,
+    total_event_stream_count: AtomicUsize,
+    poll_request_count: AtomicUsize,
+    messages_rate_limited: AtomicUsize,
 }
 
 #[derive(Serialize)]
@@ -26,6 +321,21 @@ struct SerializeMetrics {
     mapping_query_count: usize,
     events_received: usize,
     messages_send: usize,
+    disconnect_peer_reset_count: usize,
+    disconnect_protocol_error_count: usize,
+    disconnect_timeout_count: usize,
+    disconnect_other_error_count: usize,
+    storage_updates_dropped: usize,
+    registry_operations: usize,
+    registry_contention: usize,
+    messages_lagged: usize,
+    messages_retransmitted: usize,
+    messages_unacknowledged: usize,
+    database_reconnects: usize,
+    active_event_stream_count: usize,
+    total_event_stream_count: usize,
+    poll_request_count: usize,
+    messages_rate_limited: usize,
 }
 
 impl From<Metrics> for SerializeMetrics {
@@ -36,6 +346,21 @@ impl From<Metrics> for SerializeMetrics {
             mapping_query_count: metrics.mapping_query_count(),
             events_received: metrics.events_received(),
             messages_send: metrics.messages_send(),
+            disconnect_peer_reset_count: metrics.disconnect_peer_reset_count(),
+            disconnect_protocol_error_count: metrics.disconnect_protocol_error_count(),
+            disconnect_timeout_count: metrics.disconnect_timeout_count(),
+            disconnect_other_error_count: metrics.disconnect_other_error_count(),
+            storage_updates_dropped: metrics.storage_updates_dropped(),
+            registry_operations: metrics.registry_operations(),
+            registry_contention: metrics.registry_contention(),
+            messages_lagged: metrics.messages_lagged(),
+            messages_retransmitted: metrics.messages_retransmitted(),
+            messages_unacknowledged: metrics.messages_unacknowledged(),
+            database_reconnects: metrics.database_reconnects(),
+            active_event_stream_count: metrics.active_event_stream_count(),
+            total_event_stream_count: metrics.total_event_stream_count(),
+            poll_request_count: metrics.poll_request_count(),
+            messages_rate_limited: metrics.messages_rate_limited(),
         }
     }
 }
@@ -48,6 +373,21 @@ impl From<&Metrics> for SerializeMetrics {
             mapping_query_count: metrics.mapping_query_count(),
             events_received: metrics.events_received(),
             messages_send: metrics.messages_send(),
+            disconnect_peer_reset_count: metrics.disconnect_peer_reset_count(),
+            disconnect_protocol_error_count: metrics.disconnect_protocol_error_count(),
+            disconnect_timeout_count: metrics.disconnect_timeout_count(),
+            disconnect_other_error_count: metrics.disconnect_other_error_count(),
+            storage_updates_dropped: metrics.storage_updates_dropped(),
+            registry_operations: metrics.registry_operations(),
+            registry_contention: metrics.registry_contention(),
+            messages_lagged: metrics.messages_lagged(),
+            messages_retransmitted: metrics.messages_retransmitted(),
+            messages_unacknowledged: metrics.messages_unacknowledged(),
+            database_reconnects: metrics.database_reconnects(),
+            active_event_stream_count: metrics.active_event_stream_count(),
+            total_event_stream_count: metrics.total_event_stream_count(),
+            poll_request_count: metrics.poll_request_count(),
+            messages_rate_limited: metrics.messages_rate_limited(),
         }
     }
 }
@@ -69,6 +409,21 @@ impl Metrics {
             mapping_query_count: AtomicUsize::new(0),
             events_received: AtomicUsize::new(0),
             messages_send: AtomicUsize::new(0),
+            disconnect_peer_reset_count: AtomicUsize::new(0),
+            disconnect_protocol_error_count: AtomicUsize::new(0),
+            disconnect_timeout_count: AtomicUsize::new(0),
+            disconnect_other_error_count: AtomicUsize::new(0),
+            storage_updates_dropped: AtomicUsize::new(0),
+            registry_operations: AtomicUsize::new(0),
+            registry_contention: AtomicUsize::new(0),
+            messages_lagged: AtomicUsize::new(0),
+            messages_retransmitted: AtomicUsize::new(0),
+            messages_unacknowledged: AtomicUsize::new(0),
+            database_reconnects: AtomicUsize::new(0),
+            active_event_stream_count: AtomicUsize::new(0),
+            total_event_stream_count: AtomicUsize::new(0),
+            poll_request_count: AtomicUsize::new(0),
+            messages_rate_limited: AtomicUsize::new(0),
         }
     }
 
@@ -92,6 +447,66 @@ impl Metrics {
         self.messages_send.load(Ordering::Relaxed)
     }
 
+    pub fn disconnect_peer_reset_count(&self) -> usize {
+        self.disconnect_peer_reset_count.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnect_protocol_error_count(&self) -> usize {
+        self.disconnect_protocol_error_count.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnect_timeout_count(&self) -> usize {
+        self.disconnect_timeout_count.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnect_other_error_count(&self) -> usize {
+        self.disconnect_other_error_count.load(Ordering::Relaxed)
+    }
+
+    pub fn storage_updates_dropped(&self) -> usize {
+        self.storage_updates_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn registry_operations(&self) -> usize {
+        self.registry_operations.load(Ordering::Relaxed)
+    }
+
+    pub fn registry_contention(&self) -> usize {
+        self.registry_contention.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_lagged(&self) -> usize {
+        self.messages_lagged.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_retransmitted(&self) -> usize {
+        self.messages_retransmitted.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_unacknowledged(&self) -> usize {
+        self.messages_unacknowledged.load(Ordering::Relaxed)
+    }
+
+    pub fn database_reconnects(&self) -> usize {
+        self.database_reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn active_event_stream_count(&self) -> usize {
+        self.active_event_stream_count.load(Ordering::Relaxed)
+    }
+
+    pub fn total_event_stream_count(&self) -> usize {
+        self.total_event_stream_count.load(Ordering::Relaxed)
+    }
+
+    pub fn poll_request_count(&self) -> usize {
+        self.poll_request_count.load(Ordering::Relaxed)
+    }
+
+    pub fn messages_rate_limited(&self) -> usize {
+        self.messages_rate_limited.load(Ordering::Relaxed)
+    }
+
     pub fn add_connection(&self) {
         self.total_connection_count.fetch_add(1, Ordering::Relaxed);
         self.active_connection_count.fetch_add(1, Ordering::Relaxed);
@@ -112,42 +527,170 @@ impl Metrics {
     pub fn add_message(&self) {
         self.messages_send.fetch_add(1, Ordering::Relaxed);
     }
+
+    pub fn add_dropped_storage_update(&self) {
+        self.storage_updates_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_registry_operation(&self) {
+        self.registry_operations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a lock on the connection registry that had to wait for another lock to be released
+    pub fn add_registry_contention(&self) {
+        self.registry_contention.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count messages a connection missed because it couldn't keep up
+    pub fn add_lagged_messages(&self, user: &UserId, count: u64) {
+        self.messages_lagged
+            .fetch_add(count as usize, Ordering::Relaxed);
+        *LAGGED_MESSAGES.entry(user.clone()).or_insert(0) += count;
+    }
+
+    pub fn add_retransmit(&self) {
+        self.messages_retransmitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count messages that were never acknowledged by the client, after all retransmits
+    pub fn add_unacknowledged(&self, count: usize) {
+        self.messages_unacknowledged
+            .fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Count attempts to replace the database connections after the database became unavailable
+    pub fn add_database_reconnect(&self) {
+        self.database_reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a server-sent event stream, in addition to the connection it uses
+    pub fn add_event_stream(&self) {
+        self.total_event_stream_count
+            .fetch_add(1, Ordering::Relaxed);
+        self.active_event_stream_count
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_event_stream(&self) {
+        self.active_event_stream_count
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add_poll_request(&self) {
+        self.poll_request_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count messages that were kept back because the connection exceeded the message rate
+    pub fn add_rate_limited_message(&self) {
+        self.messages_rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_auth_failure(&self, reason: &'static str) {
+        *AUTH_FAILURES.entry(reason).or_insert(0) += 1;
+    }
+
+    /// Count connections that were closed because of an error
+    pub fn add_disconnect(&self, reason: DisconnectReason) {
+        let counter = match reason {
+            DisconnectReason::Closed | DisconnectReason::ServerReset => return,
+            DisconnectReason::PeerReset => &self.disconnect_peer_reset_count,
+            DisconnectReason::ProtocolError => &self.disconnect_protocol_error_count,
+            DisconnectReason::Timeout => &self.disconnect_timeout_count,
+            DisconnectReason::Other => &self.disconnect_other_error_count,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts of send messages, collected by a single task and added to [`METRICS`] in bulk
+///
+/// With many busy connections, updating the shared counters for every message makes all threads contend for the same
+/// cache lines. The batch is flushed once it collected enough updates, after [`BATCH_INTERVAL`] or when it's dropped,
+/// so the exported metrics can lag behind by up to a second for busy tasks.
+/// Tasks that can be idle for longer should call [`MetricsBatch::flush`] when they are woken up without any work.
+pub struct MetricsBatch {
+    messages: usize,
+    last_flush: Instant,
+}
+
+impl Default for MetricsBatch {
+    fn default() -> Self {
+        MetricsBatch {
+            messages: 0,
+            last_flush: Instant::now(),
+        }
+    }
+}
+
+impl MetricsBatch {
+    pub fn add_message(&mut self) {
+        self.messages += 1;
+        if self.messages >= BATCH_SIZE || self.last_flush.elapsed() >= BATCH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Add the collected updates to the shared counters
+    pub fn flush(&mut self) {
+        if self.messages > 0 {
+            METRICS
+                .messages_send
+                .fetch_add(self.messages, Ordering::Relaxed);
+        }
+        self.messages = 0;
+        self.last_flush = Instant::now();
+    }
+}
+
+impl Drop for MetricsBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
 }
 
 pub fn serve_metrics(
+    app: Arc<App>,
     bind: Bind,
     cancel: oneshot::Receiver<()>,
     tls: Option<&TlsConfig>,
 ) -> Result<impl Future<Output = ()> + Send> {
-    let metrics = warp::path!("metrics").map(|| {
+    let metrics_app = app.clone();
+    let metrics = warp::path!("metrics").map(move || {
         let mut response = String::with_capacity(128);
-        let _ = writeln!(
-            &mut response,
-            "active_connection_count {}",
-            METRICS.active_connection_count()
-        );
-        let _ = writeln!(
-            &mut response,
-            "total_connection_count {}",
-            METRICS.total_connection_count()
-        );
-        let _ = writeln!(
-            &mut response,
-            "mapping_query_count {}",
-            METRICS.mapping_query_count()
-        );
-        let _ = writeln!(
-            &mut response,
-            "event_count_total {}",
-            METRICS.events_received()
-        );
-        let _ = writeln!(
-            &mut response,
-            "message_count_total {}",
-            METRICS.messages_send()
-        );
+        for sample in collect_metrics(&metrics_app) {
+            Prometheus.write(&sample, &mut response);
+        }
         response
     });
 
-    serve_at(metrics, bind, cancel, tls)
+    // metrics of all instances connected to the same redis server
+    let cluster = warp::path!("metrics" / "cluster").and_then(move || {
+        let app = app.clone();
+        async move {
+            let reply = match cluster_metrics(&app).await {
+                Ok(cluster) => {
+                    let mut response = String::with_capacity(128);
+                    let _ = writeln!(&mut response, "instance_count {}", cluster.instances);
+                    for (name, value) in cluster.metrics.iter() {
+                        let _ = writeln!(&mut response, "{} {}", name, value);
+                    }
+                    warp::reply::with_status(response, StatusCode::OK)
+                }
+                Err(e) => {
+                    log::error!("Failed to load cluster metrics: {:#}", e);
+                    warp::reply::with_status(format!("{:#}", e), StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            };
+            Result::<_, Infallible>::Ok(reply)
+        }
+    });
+
+    serve_at(
+        metrics
+            .or(cluster)
+            .with(warp::log::custom(record_http_request)),
+        bind,
+        cancel,
+        tls,
+    )
 }
@@ -0,0 +1,93 @@
+use crate::message::MessageType;
+use crate::user::keep_user_names;
+use crate::App;
+use futures::future::select;
+use futures::pin_mut;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::Url;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+
+/// Number of messages queued in the mqtt client
+const MQTT_BUFFER: usize = 256;
+
+/// Time to wait before reconnecting after the connection to the broker failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Characters that can't be used in a topic level
+const TOPIC_ESCAPE: &AsciiSet = &CONTROLS.add(b'/').add(b'+').add(b'#').add(b'%');
+
+/// Topic a message for the user is published to, `notify_push/users/<uid>/<type>`
+pub fn topic(user: &str, msg: &MessageType) -> String {
+    let ty = match msg {
+        MessageType::File(_) => "file",
+        MessageType::Activity(_) => "activity",
+        MessageType::Notification(_) => "notification",
+        MessageType::Custom(..) => "custom",
+    };
+    format!(
+        "notify_push/users/{}/{}",
+        utf8_percent_encode(user, TOPIC_ESCAPE),
+        ty
+    )
+}
+
+fn options(url: &Url) -> MqttOptions {
+    let client_id = format!("notify_push_{}", std::process::id());
+    let port = url.port().unwrap_or(1883);
+    let mut options = MqttOptions::new(client_id, url.host_str().unwrap_or("localhost"), port);
+    if !url.username().is_empty() {
+        options.set_credentials(url.username(), url.password().unwrap_or_default());
+    }
+    options
+}
+
+/// Publish every message send to a user to the mqtt broker until cancelled
+///
+/// The messages are published as the json of protocol version 2, whether the user is connected or not.
+pub async fn mqtt_bridge(app: Arc<App>, url: Url, cancel: oneshot::Receiver<()>) {
+    keep_user_names();
+    let mut rx = app.connections.mirror();
+
+    let (client, mut event_loop) = AsyncClient::new(options(&url), MQTT_BUFFER);
+    let publish = async move {
+        loop {
+            let (user, msg) = match rx.recv().await {
+                Ok(message) => message,
+                Err(RecvError::Lagged(count)) => {
+                    log::warn!("Dropped {} messages for the mqtt broker", count);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            // users restored from the state file are only known by their hash
+            let name = match user.name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let payload = msg.to_json().to_string();
+            if let Err(e) = client
+                .publish(topic(&name, &msg), QoS::AtLeastOnce, false, payload)
+                .await
+            {
+                log::warn!("Failed to publish {} to the mqtt broker: {}", msg, e);
+            }
+        }
+    };
+    let host = url.host_str().unwrap_or_default().to_string();
+    let connection = async move {
+        loop {
+            if let Err(e) = event_loop.poll().await {
+                log::warn!("Connection to mqtt broker {} failed: {}", host, e);
+                sleep(RECONNECT_DELAY).await;
+            }
+        }
+    };
+    pin_mut!(publish);
+    pin_mut!(connection);
+    select(select(publish, connection), cancel).await;
+}
@@ -1,21 +1,233 @@
+use crate::auth::AuthError;
 use crate::UserId;
 use color_eyre::{eyre::WrapErr, Report, Result};
-use reqwest::{StatusCode, Url};
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
 use std::fmt::Write;
+use std::fs;
 use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Time to wait for a connection to the Nextcloud server
+///
+/// When the hostname resolves to both ipv4 and ipv6 addresses the connector races them (happy eyeballs),
+/// this bounds the time spend when none of the addresses are reachable.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Length of the window in which failed requests are counted
+const ERROR_BUDGET_WINDOW: Duration = Duration::from_secs(10);
+/// Minimum number of failed requests in a window before throttling
+const ERROR_BUDGET_MIN_FAILURES: usize = 10;
+/// Fraction of requests in a window that can fail before throttling
+const ERROR_BUDGET_MAX_FAILURE_RATE: f64 = 0.5;
+/// How long new authentication attempts are rejected once the error budget is exceeded
+const ERROR_BUDGET_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Tls options for connecting to the Nextcloud server
+#[derive(Debug, Clone, Default)]
+pub struct ClientTls {
+    pub allow_self_signed: bool,
+    /// Pem file with additional CA certificates to trust
+    pub ca_bundle: Option<PathBuf>,
+    /// Pem file with the client certificate and private key to authenticate with
+    pub client_cert: Option<PathBuf>,
+}
+
+impl ClientTls {
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.ca_bundle.iter().chain(self.client_cert.iter())
+    }
+
+    /// Whether there are any files that can be reloaded
+    pub fn has_files(&self) -> bool {
+        self.files().next().is_some()
+    }
+
+    /// The most recent modification time of the configured files
+    fn modified(&self) -> Option<SystemTime> {
+        self.files()
+            .filter_map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .max()
+    }
+
+    fn build_http(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .danger_accept_invalid_certs(self.allow_self_signed)
+            .connect_timeout(CONNECT_TIMEOUT);
+        if let Some(path) = &self.ca_bundle {
+            let bundle = fs::read_to_string(path)
+                .wrap_err_with(|| format!("Failed to read ca bundle {}", path.display()))?;
+            // reqwest only parses a single certificate at once
+            for cert in bundle
+                .split_inclusive("-----END CERTIFICATE-----")
+                .filter(|cert| cert.contains("-----BEGIN CERTIFICATE-----"))
+            {
+                let cert = reqwest::Certificate::from_pem(cert.as_bytes())
+                    .wrap_err_with(|| format!("Invalid certificate in {}", path.display()))?;
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let Some(path) = &self.client_cert {
+            let pem = fs::read(path).wrap_err_with(|| {
+                format!("Failed to read client certificate {}", path.display())
+            })?;
+            let identity = reqwest::Identity::from_pem(&pem)
+                .wrap_err_with(|| format!("Invalid client certificate {}", path.display()))?;
+            builder = builder.identity(identity);
+        }
+        Ok(builder.build()?)
+    }
+}
 
 pub struct Client {
-    http: reqwest::Client,
-    base_url: Url,
+    http: RwLock<reqwest::Client>,
+    /// The configured base url followed by the alternative urls for the same Nextcloud instance
+    base_urls: Vec<Url>,
+    /// Index of the base url that was last reachable
+    preferred: AtomicUsize,
+    error_budget: Mutex<ErrorBudget>,
+    tls: ClientTls,
+    tls_modified: Mutex<Option<SystemTime>>,
+}
+
+/// Tracks failed requests to Nextcloud
+///
+/// When too many requests fail, new socket authentications are rejected for a while instead of piling more load
+/// on a Nextcloud server that is already struggling.
+struct ErrorBudget {
+    window_start: Instant,
+    requests: usize,
+    failures: usize,
+    throttled_until: Option<Instant>,
+}
+
+impl Default for ErrorBudget {
+    fn default() -> Self {
+        ErrorBudget {
+            window_start: Instant::now(),
+            requests: 0,
+            failures: 0,
+            throttled_until: None,
+        }
+    }
+}
+
+impl ErrorBudget {
+    fn record(&mut self, success: bool) {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) > ERROR_BUDGET_WINDOW {
+            self.window_start = now;
+            self.requests = 0;
+            self.failures = 0;
+        }
+        self.requests += 1;
+        if !success {
+            self.failures += 1;
+        }
+        let failure_rate = self.failures as f64 / self.requests as f64;
+        if self.failures >= ERROR_BUDGET_MIN_FAILURES
+            && failure_rate > ERROR_BUDGET_MAX_FAILURE_RATE
+            && self.throttled_until.is_none()
+        {
+            log::warn!(
+                "{} of {} requests to Nextcloud failed, rejecting new connections for {}s",
+                self.failures,
+                self.requests,
+                ERROR_BUDGET_BACKOFF.as_secs()
+            );
+            self.throttled_until = Some(now + ERROR_BUDGET_BACKOFF);
+        }
+    }
+
+    /// The time until requests are allowed again, if the budget is exceeded
+    fn retry_after(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        match self.throttled_until {
+            Some(until) if until > now => Some(until - now),
+            Some(_) => {
+                // start with a clean slate after backing off
+                *self = ErrorBudget::default();
+                None
+            }
+            None => None,
+        }
+    }
 }
 
 impl Client {
     pub fn new(base_url: &str, allow_self_signed: bool) -> Result<Self> {
-        let base_url = Url::parse(base_url).wrap_err("Invalid base url")?;
-        let http = reqwest::Client::builder()
-            .danger_accept_invalid_certs(allow_self_signed)
-            .build()?;
-        Ok(Client { http, base_url })
+        Self::with_tls(
+            base_url,
+            ClientTls {
+                allow_self_signed,
+                ..ClientTls::default()
+            },
+        )
+    }
+
+    pub fn with_tls(base_url: &str, tls: ClientTls) -> Result<Self> {
+        Self::with_urls(&[base_url.to_string()], tls)
+    }
+
+    /// Create a client with alternative base urls, for example an internal and public url for the same instance
+    ///
+    /// Credentials are verified against the next url when a url is unreachable, other requests use
+    /// the url that was last reachable.
+    pub fn with_urls(base_urls: &[String], tls: ClientTls) -> Result<Self> {
+        let base_urls = base_urls
+            .iter()
+            .map(|url| parse_base_url(url))
+            .collect::<Result<Vec<_>>>()?;
+        if base_urls.is_empty() {
+            return Err(Report::msg("No base url provided"));
+        }
+        let http = tls.build_http()?;
+        Ok(Client {
+            http: RwLock::new(http),
+            base_urls,
+            preferred: AtomicUsize::new(0),
+            error_budget: Mutex::default(),
+            tls_modified: Mutex::new(tls.modified()),
+            tls,
+        })
+    }
+
+    pub fn tls(&self) -> &ClientTls {
+        &self.tls
+    }
+
+    /// Reload the CA bundle and client certificate
+    ///
+    /// Requests that are already running keep using the old configuration,
+    /// if the new files can't be loaded the old configuration stays in use.
+    pub fn reload_tls(&self) -> Result<()> {
+        let modified = self.tls.modified();
+        let http = self.tls.build_http()?;
+        *self.http.write().unwrap() = http;
+        *self.tls_modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Whether any of the tls files changed since they were last loaded
+    pub fn tls_changed(&self) -> bool {
+        self.tls.modified() != *self.tls_modified.lock().unwrap()
+    }
+
+    fn http(&self) -> reqwest::Client {
+        self.http.read().unwrap().clone()
+    }
+
+    /// The configured base url, without the fallback urls
+    pub fn primary_url(&self) -> &Url {
+        &self.base_urls[0]
+    }
+
+    /// The base url that was last reachable
+    pub fn base_url(&self) -> &Url {
+        &self.base_urls[self.preferred.load(Ordering::Relaxed)]
     }
 
     pub async fn verify_credentials(
@@ -23,58 +235,99 @@ impl Client {
         username: &str,
         password: &str,
         forwarded_for: Vec<IpAddr>,
-    ) -> Result<UserId> {
-        log::debug!("Verifying credentials for {}", username);
-        let response = self
-            .http
-            .get(self.base_url.join("index.php/apps/notify_push/uid")?)
-            .basic_auth(username, Some(password))
-            .header(
-                "x-forwarded-for",
-                forwarded_for.iter().fold(
-                    String::with_capacity(forwarded_for.len() * 16),
-                    |mut joined, ip| {
-                        if !joined.is_empty() {
-                            write!(&mut joined, ", ").ok();
-                        }
-                        write!(&mut joined, "{}", ip).ok();
-                        joined
-                    },
-                ),
-            )
-            .send()
-            .await
-            .wrap_err("Error while connecting to nextcloud server")?;
+    ) -> Result<UserId, AuthError> {
+        if let Some(retry_after) = self.error_budget.lock().unwrap().retry_after() {
+            return Err(AuthError::RateLimited(retry_after + Duration::from_secs(1)));
+        }
 
-        match response.status() {
-            StatusCode::OK => Ok(response.text().await?.into()),
-            StatusCode::UNAUTHORIZED => Err(Report::msg("Invalid credentials")),
-            status if status.is_server_error() => {
-                Err(Report::msg(format!("Server error: {}", status)))
+        log::debug!(target: "notify_push::auth", "Verifying credentials for {}", username);
+        let forwarded_for = forwarded_for.iter().fold(
+            String::with_capacity(forwarded_for.len() * 16),
+            |mut joined, ip| {
+                if !joined.is_empty() {
+                    write!(&mut joined, ", ").ok();
+                }
+                write!(&mut joined, "{}", ip).ok();
+                joined
+            },
+        );
+
+        // start with the url that was last reachable, falling back to the others in order
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let mut last_error = None;
+        let mut response = None;
+        for index in (0..self.base_urls.len()).map(|i| (preferred + i) % self.base_urls.len()) {
+            let base_url = &self.base_urls[index];
+            let request = self
+                .http()
+                .get(
+                    base_url
+                        .join("index.php/apps/notify_push/uid")
+                        .map_err(|e| AuthError::NextcloudError(e.to_string()))?,
+                )
+                .basic_auth(username, Some(password))
+                .header("x-forwarded-for", &forwarded_for);
+            match self.send(request).await {
+                Ok(result) => {
+                    if index != preferred {
+                        log::info!("Nextcloud is reachable at {}, switching to it", base_url);
+                        self.preferred.store(index, Ordering::Relaxed);
+                    }
+                    response = Some(result);
+                    break;
+                }
+                Err(e) => {
+                    if self.base_urls.len() > 1 {
+                        log::warn!("Nextcloud is unreachable at {}: {}", base_url, e);
+                    }
+                    last_error = Some(e);
+                }
             }
-            status if status.is_client_error() => {
-                Err(Report::msg(format!("Client error: {}", status)))
+        }
+        let response = match response {
+            Some(response) => response,
+            None => {
+                return Err(AuthError::NextcloudUnreachable(
+                    last_error.map(|e| e.to_string()).unwrap_or_default(),
+                ))
             }
-            status => Err(Report::msg(format!("Unexpected status code: {}", status))),
+        };
+
+        match response.status() {
+            StatusCode::OK => Ok(response
+                .text()
+                .await
+                .map_err(|e| AuthError::NextcloudUnreachable(e.to_string()))?
+                .into()),
+            StatusCode::UNAUTHORIZED => Err(AuthError::InvalidCredentials),
+            status if status.is_server_error() => Err(AuthError::NextcloudError(format!(
+                "Server error: {}",
+                status
+            ))),
+            status if status.is_client_error() => Err(AuthError::NextcloudError(format!(
+                "Client error: {}",
+                status
+            ))),
+            status => Err(AuthError::NextcloudError(format!(
+                "Unexpected status code: {}",
+                status
+            ))),
         }
     }
 
     pub async fn get_test_cookie(&self) -> Result<u32> {
-        let response = self
-            .http
-            .get(
-                self.base_url
-                    .join("index.php/apps/notify_push/test/cookie")?,
-            )
-            .send()
-            .await?;
+        let request = self.http().get(
+            self.base_url()
+                .join("index.php/apps/notify_push/test/cookie")?,
+        );
+        let response = self.send(request).await?;
         let status = response.status();
         let text = response.text().await?;
         if status.is_client_error() {
             if text.contains("admin-trusted-domains") {
                 Err(Report::msg(format!(
                     "{} is not configured as a trusted domain",
-                    self.base_url.host_str().unwrap_or_default()
+                    self.base_url().host_str().unwrap_or_default()
                 )))
             } else {
                 Err(Report::msg(status.to_string()))
@@ -88,9 +341,9 @@ impl Client {
 
     pub async fn test_set_remote(&self, addr: IpAddr) -> Result<IpAddr> {
         Ok(self
-            .http
+            .http()
             .get(
-                self.base_url
+                self.base_url()
                     .join("index.php/apps/notify_push/test/remote")?,
             )
             .header("x-forwarded-for", addr.to_string())
@@ -103,13 +356,117 @@ impl Client {
 
     /// Ask the app to put it's version number into redis under 'notify_push_app_version'
     pub async fn request_app_version(&self) -> Result<()> {
-        self.http
+        self.http()
             .get(
-                self.base_url
+                self.base_url()
                     .join("index.php/apps/notify_push/test/version")?,
             )
             .send()
             .await?;
         Ok(())
     }
+
+    /// Get the version of the Nextcloud server from `status.php`
+    pub async fn get_nextcloud_version(&self) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Status {
+            versionstring: String,
+        }
+
+        let status: Status = self
+            .http()
+            .get(self.base_url().join("status.php")?)
+            .send()
+            .await
+            .wrap_err("Error while connecting to nextcloud server")?
+            .json()
+            .await
+            .wrap_err("Invalid response from status.php")?;
+        Ok(status.versionstring)
+    }
+
+    /// Ask the app which users have access to a path in a storage
+    ///
+    /// The `token` has to be stored in redis under `notify_push_mapping_token` before making the request
+    pub async fn get_storage_users(
+        &self,
+        storage: u32,
+        path: &str,
+        token: &str,
+    ) -> Result<Vec<String>> {
+        let mut url = self
+            .base_url()
+            .join("index.php/apps/notify_push/test/mapping")?;
+        url.query_pairs_mut()
+            .append_pair("storage", &storage.to_string())
+            .append_pair("path", path)
+            .append_pair("token", token);
+        let response = self
+            .http()
+            .get(url)
+            .send()
+            .await
+            .wrap_err("Error while connecting to nextcloud server")?;
+        match response.status() {
+            StatusCode::OK => Ok(response.json().await?),
+            status => Err(Report::msg(format!("Unexpected status code: {}", status))),
+        }
+    }
+
+    /// Ask Nextcloud whether the user is allowed to join the room
+    pub async fn get_room_access(&self, user: &str, room: &str, token: &str) -> Result<bool> {
+        let mut url = self.base_url().join("index.php/apps/notify_push/room")?;
+        url.query_pairs_mut()
+            .append_pair("user", user)
+            .append_pair("room", room)
+            .append_pair("token", token);
+        let response = self
+            .http()
+            .get(url)
+            .send()
+            .await
+            .wrap_err("Error while connecting to nextcloud server")?;
+        match response.status() {
+            StatusCode::OK => Ok(response.json().await?),
+            status => Err(Report::msg(format!("Unexpected status code: {}", status))),
+        }
+    }
+
+    /// Send a request and record the outcome in the error budget
+    async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let result = send_with_retry(request).await;
+        let success = match &result {
+            Ok(response) => !response.status().is_server_error(),
+            Err(_) => false,
+        };
+        self.error_budget.lock().unwrap().record(success);
+        result
+    }
+}
+
+/// Parse a base url, keeping the path for instances installed in a subdirectory
+///
+/// Without a trailing slash, joining a path to `https://example.com/nextcloud` would replace `nextcloud`.
+fn parse_base_url(url: &str) -> Result<Url> {
+    let mut url = Url::parse(url).wrap_err_with(|| format!("Invalid base url {}", url))?;
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// Send a request, retrying once if no connection could be made
+///
+/// New connections resolve the hostname again, so a retry picks up dns changes
+/// and addresses that became reachable since the last attempt.
+async fn send_with_retry(request: RequestBuilder) -> reqwest::Result<Response> {
+    let retry = request.try_clone();
+    match (request.send().await, retry) {
+        (Err(e), Some(retry)) if e.is_connect() => {
+            log::debug!("Failed to connect to nextcloud server ({}), retrying", e);
+            retry.send().await
+        }
+        (result, _) => result,
+    }
 }
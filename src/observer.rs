@@ -0,0 +1,46 @@
+use crate::disconnect::DisconnectReason;
+use crate::message::MessageType;
+use crate::UserId;
+use once_cell::sync::OnceCell;
+use tokio::sync::broadcast;
+
+/// Number of events buffered for each subscriber, slow subscribers will miss events
+const OBSERVER_BUFFER: usize = 256;
+
+/// Things happening inside the daemon, for embedders and debugging tools
+#[derive(Debug, Clone)]
+pub enum DaemonEvent {
+    ConnectionOpened(UserId),
+    ConnectionClosed(UserId, DisconnectReason),
+    MessageDelivered(UserId, MessageType),
+    MessageDebounced(UserId, MessageType),
+    /// Messages for a connection were dropped because the connection couldn't keep up
+    MessagesDropped(UserId, u64),
+    /// An event received from redis couldn't be handled
+    EventDropped(String),
+}
+
+/// Broadcast of daemon events
+///
+/// Nothing is broadcast until the first subscriber is created, so there is no overhead when nobody is listening.
+#[derive(Default)]
+pub struct Observer {
+    sender: OnceCell<broadcast::Sender<DaemonEvent>>,
+}
+
+impl Observer {
+    pub fn subscribe(&self) -> broadcast::Receiver<DaemonEvent> {
+        self.sender
+            .get_or_init(|| broadcast::channel(OBSERVER_BUFFER).0)
+            .subscribe()
+    }
+
+    /// Broadcast an event, the event is only constructed if there are subscribers
+    pub fn emit(&self, event: impl FnOnce() -> DaemonEvent) {
+        if let Some(sender) = self.sender.get() {
+            if sender.receiver_count() > 0 {
+                sender.send(event()).ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::message::{FilePayload, MessageType};
+use crate::redis::{Redis, RedisConnection, RedisWriter, WriteCommand};
+use crate::user::keep_user_names;
+use crate::UserId;
+use ahash::RandomState;
+use color_eyre::Result;
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Maximum number of messages queued for a user, the oldest messages are dropped first
+pub const MAX_OFFLINE_MESSAGES: usize = 64;
+
+/// Backlogs with more file messages than this are replayed as one summary per folder
+const SUMMARY_THRESHOLD: usize = 8;
+
+/// Maximum number of idle connections kept to take queued messages
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// Users whose queue expired are forgotten at most this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages for users whose last connection closed, replayed when they reconnect
+///
+/// The messages are kept in a redis list per user, so they are replayed no matter which push server
+/// the user reconnects to. Only the server that had the last connection of a user queues messages for it,
+/// and only until the ttl passed since the connection closed.
+pub struct OfflineQueue {
+    ttl: Duration,
+    redis: Redis,
+    writer: RedisWriter,
+    /// Users without a connection
+    disconnected: DashMap<UserId, Disconnected, RandomState>,
+    last_prune: Mutex<Instant>,
+    /// Connections used to take the queued messages, so reconnecting users don't have to wait for each other
+    idle: Mutex<Vec<RedisConnection>>,
+}
+
+struct Disconnected {
+    /// When the last connection of the user closed
+    since: Instant,
+    /// Number of messages queued since then
+    queued: usize,
+    /// Position of the queued file message without details, any further ones are identical and don't need to be queued
+    plain_file: Option<usize>,
+}
+
+impl OfflineQueue {
+    pub fn new(redis: Redis, writer: RedisWriter, ttl: Duration) -> Self {
+        // the queues are keyed by user name, so every push server finds them
+        keep_user_names();
+        OfflineQueue {
+            ttl,
+            redis,
+            writer,
+            disconnected: DashMap::default(),
+            last_prune: Mutex::new(Instant::now()),
+            idle: Mutex::default(),
+        }
+    }
+
+    fn key(user: &UserId) -> Option<String> {
+        let name = user.name()?;
+        Some(format!(
+            "notify_push_offline_{:x}",
+            Sha256::digest(name.as_bytes())
+        ))
+    }
+
+    /// Start queueing messages for a user that closed its last connection
+    pub fn disconnected(&self, user: &UserId) {
+        self.disconnected.insert(
+            user.clone(),
+            Disconnected {
+                since: Instant::now(),
+                queued: 0,
+                plain_file: None,
+            },
+        );
+        self.prune();
+    }
+
+    /// Stop queueing messages for a user that connects, before the connection receives any messages
+    pub fn connected(&self, user: &UserId) {
+        self.disconnected.remove(user);
+    }
+
+    /// Forget users that disconnected longer than the ttl ago
+    fn prune(&self) {
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if last_prune.elapsed() < PRUNE_INTERVAL {
+            return;
+        }
+        *last_prune = Instant::now();
+        drop(last_prune);
+        let ttl = self.ttl;
+        self.disconnected
+            .retain(|_, disconnected| disconnected.since.elapsed() <= ttl);
+    }
+
+    /// Queue a message if the user disconnected recently
+    ///
+    /// Tagged messages and messages for a topic are dropped, new connections didn't subscribe to any yet.
+    /// A file message without details is only queued once, unless it was dropped from the queue since.
+    pub async fn push(&self, user: &UserId, msg: &MessageType) {
+        if let MessageType::Custom(_, _, Some(_), _) | MessageType::Custom(_, _, _, Some(_)) = msg {
+            return;
+        }
+        match self.disconnected.get_mut(user) {
+            Some(disconnected) if disconnected.since.elapsed() > self.ttl => {
+                drop(disconnected);
+                self.disconnected.remove(user);
+                return;
+            }
+            Some(mut disconnected) => {
+                let plain_file = matches!(msg, MessageType::File(None));
+                let still_queued = disconnected
+                    .plain_file
+                    .map_or(false, |at| disconnected.queued - at < MAX_OFFLINE_MESSAGES);
+                if plain_file && still_queued {
+                    return;
+                }
+                if plain_file {
+                    disconnected.plain_file = Some(disconnected.queued);
+                }
+                disconnected.queued += 1;
+            }
+            None => return,
+        }
+        let key = match Self::key(user) {
+            Some(key) => key,
+            None => return,
+        };
+        let value = match serde_json::to_string(msg) {
+            Ok(value) => value,
+            Err(e) => {
+                log::warn!("Failed to encode {} for the offline queue: {}", msg, e);
+                return;
+            }
+        };
+        self.writer
+            .queue(vec![WriteCommand::Append {
+                key,
+                value,
+                max_len: MAX_OFFLINE_MESSAGES,
+                ttl: self.ttl.as_secs() as usize,
+            }])
+            .await;
+    }
+
+    /// Take the messages queued for a user while it had no connection
+    pub async fn take(&self, user: &UserId) -> Vec<MessageType> {
+        let key = match Self::key(user) {
+            Some(key) => key,
+            None => return Vec::new(),
+        };
+        match self.take_queued(&key).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                log::warn!("Failed to load offline messages for {}: {:#}", user, e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn take_queued(&self, key: &str) -> Result<Vec<MessageType>> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => self.redis.connect().await?,
+        };
+        // connections that failed are dropped
+        let items = connection.take_list(key).await?;
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(connection);
+        }
+        drop(idle);
+
+        Ok(summarize(
+            items
+                .iter()
+                .filter_map(|item| serde_json::from_str(item).ok())
+                .collect(),
+        ))
+    }
+}
+
+/// Replace the file messages of a large backlog by one message per folder with the number of changes in it
+///
+/// The summaries take the place of the first file message, other messages keep their order.
+fn summarize(messages: Vec<MessageType>) -> Vec<MessageType> {
+    let file_count = messages
+        .iter()
+        .filter(|msg| matches!(msg, MessageType::File(_)))
+        .count();
+    if file_count <= SUMMARY_THRESHOLD {
+        return messages;
+    }
+
+    let mut folders: Vec<(Option<String>, usize)> = Vec::new();
+    let mut summary_at = None;
+    let mut summarized = Vec::with_capacity(messages.len() - file_count);
+    for msg in messages {
+        match msg {
+            MessageType::File(payload) => {
+                let folder = payload
+                    .and_then(|payload| payload.path)
+                    .map(|path| parent_folder(&path).to_string());
+                match folders.iter_mut().find(|(path, _)| *path == folder) {
+                    Some((_, count)) => *count += 1,
+                    None => folders.push((folder, 1)),
+                }
+                summary_at.get_or_insert(summarized.len());
+            }
+            msg => summarized.push(msg),
+        }
+    }
+
+    let summaries = folders.into_iter().map(|(path, count)| {
+        MessageType::File(Some(FilePayload {
+            path,
+            count: Some(count),
+            ..FilePayload::default()
+        }))
+    });
+    let at = summary_at.unwrap_or_default();
+    summarized.splice(at..at, summaries);
+    summarized
+}
+
+fn parent_folder(path: &str) -> &str {
+    match path.trim_end_matches('/').rfind('/') {
+        Some(0) | None => "/",
+        Some(index) => &path[..index],
+    }
+}
@@ -0,0 +1,156 @@
+use crate::connection::ActiveConnections;
+use crate::event::Event;
+use crate::message::MessageType;
+use crate::user::UserId;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Keeps the messages for each user in the order the events were received in
+///
+/// Events are handled concurrently, so without this a notification could overtake a file change that is still
+/// waiting for the database to find the users with access to the file. Messages produced by an event are collected
+/// in an [`Outbox`] and only send once every earlier event that could send messages to the same users is finished.
+pub struct EventOrder {
+    state: Arc<OrderState>,
+}
+
+struct OrderState {
+    next: AtomicU64,
+    /// Events that are received but not finished yet, by the order they were received in
+    pending: Mutex<BTreeMap<u64, Recipients>>,
+    finished: watch::Sender<u64>,
+    /// Kept so the sender doesn't fail while nobody is waiting, waiting tickets clone it
+    finished_rx: watch::Receiver<u64>,
+}
+
+impl Default for EventOrder {
+    fn default() -> Self {
+        let (finished, finished_rx) = watch::channel(0);
+        EventOrder {
+            state: Arc::new(OrderState {
+                next: AtomicU64::new(0),
+                pending: Mutex::default(),
+                finished,
+                finished_rx,
+            }),
+        }
+    }
+}
+
+/// The users an event can send messages to, known before the event is handled
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recipients {
+    Nobody,
+    User(UserId),
+    Users(Vec<UserId>),
+    /// Storage updates and group messages only know their users after the database lookup
+    Unknown,
+    Everyone,
+}
+
+impl Recipients {
+    pub fn of(event: &Event) -> Self {
+        match event {
+            Event::StorageUpdate(_) | Event::GroupMessage(_) => Recipients::Unknown,
+            Event::Broadcast(_) => Recipients::Everyone,
+            event => match event.users() {
+                Some([user]) => Recipients::User(user.clone()),
+                Some(users) => Recipients::Users(users.to_vec()),
+                None => Recipients::Nobody,
+            },
+        }
+    }
+}
+
+impl EventOrder {
+    /// Take a ticket for an event, this has to be called in the order the events are received in
+    pub fn ticket(&self, event: &Event) -> Ticket {
+        let id = self.state.next.fetch_add(1, Ordering::SeqCst);
+        self.state
+            .pending
+            .lock()
+            .unwrap()
+            .insert(id, Recipients::of(event));
+        Ticket {
+            id,
+            state: self.state.clone(),
+        }
+    }
+
+    /// Number of events that are received but not finished yet
+    pub fn pending_count(&self) -> usize {
+        self.state.pending.lock().unwrap().len()
+    }
+}
+
+/// The place of an event in the order, the event is finished when the ticket is dropped
+pub struct Ticket {
+    id: u64,
+    state: Arc<OrderState>,
+}
+
+impl Ticket {
+    /// Send the messages of the event once all earlier events for the same users are finished
+    pub async fn deliver(self, outbox: Outbox, connections: &ActiveConnections) {
+        if !outbox.is_empty() {
+            let mut finished = self.state.finished_rx.clone();
+            while self.is_blocked(&outbox) {
+                if finished.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+        for (user, msg) in outbox.messages {
+            connections.send_to_user(&user, msg).await;
+        }
+        for msg in outbox.broadcasts {
+            connections.send_to_all(msg);
+        }
+    }
+
+    fn is_blocked(&self, outbox: &Outbox) -> bool {
+        self.state.pending.lock().unwrap().range(..self.id).any(
+            |(_, recipients)| match recipients {
+                Recipients::Nobody => false,
+                Recipients::User(user) => outbox.has_user(user),
+                Recipients::Users(users) => users.iter().any(|user| outbox.has_user(user)),
+                Recipients::Unknown | Recipients::Everyone => true,
+            },
+        )
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.state.pending.lock().unwrap().remove(&self.id);
+        self.state.finished.send(self.id).ok();
+    }
+}
+
+/// Messages produced while handling a single event
+#[derive(Debug, Default)]
+pub struct Outbox {
+    messages: Vec<(UserId, MessageType)>,
+    broadcasts: Vec<MessageType>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, user: UserId, msg: MessageType) {
+        self.messages.push((user, msg));
+    }
+
+    /// Send a message to every connected user
+    pub fn push_all(&mut self, msg: MessageType) {
+        self.broadcasts.push(msg);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.broadcasts.is_empty()
+    }
+
+    fn has_user(&self, user: &UserId) -> bool {
+        !self.broadcasts.is_empty() || self.messages.iter().any(|(recipient, _)| recipient == user)
+    }
+}
@@ -0,0 +1,147 @@
+use crate::auth::AuthError;
+use crate::connection::authenticate_request;
+use crate::metrics::METRICS;
+use crate::observer::DaemonEvent;
+use crate::{App, UserId};
+use futures::pin_mut;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::spawn;
+use tokio::time::{interval_at, Instant};
+use warp::http::header::CONTENT_TYPE;
+use warp::http::StatusCode;
+use warp::hyper::body::{Body, Bytes};
+use warp::Reply;
+
+/// How long a poll request waits for messages if the client doesn't request a timeout
+pub const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum time a poll request is kept open, to stay below the timeouts of most proxies
+pub const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PollQuery {
+    #[serde(default)]
+    token: Option<String>,
+    /// Seconds to wait for a message
+    #[serde(default)]
+    timeout: Option<u64>,
+}
+
+/// Long-polling fallback for clients that can't use websockets or server-sent events
+///
+/// The request is held open until messages are pending for the user or the timeout passes,
+/// the response contains all pending messages in the format of protocol version 2 and is empty on timeout.
+///
+/// If a fallback keep-alive is configured, whitespace is send while the request is held so proxies don't close it.
+pub async fn handle_poll(
+    app: Arc<App>,
+    authorization: Option<String>,
+    query: PollQuery,
+    forwarded_for: Vec<IpAddr>,
+) -> Result<impl Reply, Infallible> {
+    let client_ip = forwarded_for.first().copied();
+    let (user_id, _) =
+        match authenticate_request(&app, authorization, query.token, forwarded_for).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => {
+                e.record("poll");
+                let status = e.status();
+                return Ok(warp::reply::with_status(format!("err: {}", e), status).into_response());
+            }
+        };
+
+    METRICS.add_poll_request();
+
+    let wait = query
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_POLL_TIMEOUT)
+        .min(MAX_POLL_TIMEOUT);
+
+    let keep_alive = match app.fallback_keep_alive {
+        Some(keep_alive) => keep_alive,
+        None => {
+            let _permit = match app.limits.admit(&user_id, client_ip) {
+                Ok(permit) => permit,
+                Err(e) => return Ok(reject(&user_id, e.into())),
+            };
+            let messages = poll_messages(&app, &user_id, wait).await;
+            return Ok(warp::reply::json(&messages).into_response());
+        }
+    };
+
+    // the permit borrows the app, so it's taken by the task that holds the request
+    let (admitted_tx, admitted) = oneshot::channel();
+    let (mut sender, body) = Body::channel();
+    let poll_user = user_id.clone();
+    spawn(async move {
+        let user_id = poll_user;
+        let _permit = match app.limits.admit(&user_id, client_ip) {
+            Ok(permit) => permit,
+            Err(e) => {
+                admitted_tx.send(Err(e)).ok();
+                return;
+            }
+        };
+        admitted_tx.send(Ok(())).ok();
+
+        let poll = poll_messages(&app, &user_id, wait);
+        pin_mut!(poll);
+        let mut keep_alive = interval_at(Instant::now() + keep_alive, keep_alive);
+        loop {
+            tokio::select! {
+                messages = &mut poll => {
+                    sender.send_data(Bytes::from(messages.to_string())).await.ok();
+                    break;
+                }
+                _ = keep_alive.tick() => {
+                    if sender.send_data(Bytes::from_static(b" ")).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    match admitted.await {
+        Ok(Ok(())) => {
+            let mut response = warp::reply::Response::new(body);
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+            Ok(response)
+        }
+        Ok(Err(e)) => Ok(reject(&user_id, e.into())),
+        Err(_) => Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+fn reject(user_id: &UserId, e: AuthError) -> warp::reply::Response {
+    e.record(&format!("poll for {}", user_id));
+    warp::reply::with_status(e.to_string(), e.status()).into_response()
+}
+
+/// Wait for pending messages of the user and encode them as poll response
+async fn poll_messages(app: &App, user_id: &UserId, wait: Duration) -> Value {
+    let messages = app.connections.poll(user_id.clone(), wait).await;
+
+    let messages: Vec<Value> = messages
+        .into_iter()
+        .map(|msg| {
+            log::debug!(target: "notify_push::send", "Sending {} to {} by polling", msg, user_id);
+            METRICS.add_message();
+            let json = msg.to_json();
+            app.observer
+                .emit(|| DaemonEvent::MessageDelivered(user_id.clone(), msg));
+            json
+        })
+        .collect();
+
+    json!({ "messages": messages })
+}
@@ -0,0 +1,169 @@
+use crate::registry::{Registry, RegistryConfig};
+use crate::UserId;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+/// Sha256 hash of a pre-auth token
+///
+/// Only the hashes of pre-auth tokens are kept in memory, so a memory dump can't be used to open connections,
+/// hashes are compared in constant time.
+#[derive(Clone, Copy, Eq)]
+pub struct TokenHash([u8; 32]);
+
+impl TokenHash {
+    pub fn new(token: &str) -> Self {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&Sha256::digest(token.as_bytes()));
+        TokenHash(hash)
+    }
+
+    /// Parse a hex encoded hash
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(TokenHash(hash))
+    }
+}
+
+impl PartialEq for TokenHash {
+    fn eq(&self, other: &Self) -> bool {
+        self.0[..].ct_eq(&other.0[..]).into()
+    }
+}
+
+impl Hash for TokenHash {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+/// How long a pre-auth token can be used after it's issued
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(15);
+
+#[derive(Clone)]
+struct PreAuthToken {
+    issued: Instant,
+    user: UserId,
+    used: Arc<AtomicBool>,
+}
+
+/// Pre-auth tokens issued by Nextcloud or the admin endpoint
+///
+/// Tokens are kept until they expire, even once they are used, so receiving the same pre-auth event again
+/// doesn't make a used token valid again or extend its lifetime.
+pub struct PreAuthTokens {
+    tokens: Registry<TokenHash, PreAuthToken>,
+}
+
+impl PreAuthTokens {
+    pub fn new(config: RegistryConfig) -> Self {
+        PreAuthTokens {
+            tokens: Registry::new(config),
+        }
+    }
+
+    /// Add a token for the user, returns `false` if the token was already issued
+    pub fn insert(&self, hash: TokenHash, user: UserId) -> bool {
+        self.expire();
+        let mut inserted = false;
+        self.tokens.get_or_insert_with(hash, || {
+            inserted = true;
+            PreAuthToken {
+                issued: Instant::now(),
+                user,
+                used: Arc::default(),
+            }
+        });
+        inserted
+    }
+
+    /// The user for a token that hasn't expired or been used yet
+    pub fn take(&self, hash: &TokenHash) -> Option<UserId> {
+        self.expire();
+        let token = self.tokens.get(hash)?;
+        if token.issued.elapsed() > TOKEN_LIFETIME || token.used.swap(true, Ordering::SeqCst) {
+            None
+        } else {
+            Some(token.user)
+        }
+    }
+
+    fn expire(&self) {
+        self.tokens
+            .retain(|_, token| token.issued.elapsed() <= TOKEN_LIFETIME);
+    }
+}
+
+/// Minimum requirements for pre-auth tokens received from Nextcloud
+///
+/// Pre-auth tokens authenticate a connection without any other credentials,
+/// so a misconfigured token generator would allow connections to be opened by guessing tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenRequirements {
+    /// Minimum number of characters
+    pub min_length: usize,
+    /// Minimum estimated entropy in bits
+    pub min_entropy: f64,
+}
+
+impl Default for TokenRequirements {
+    fn default() -> Self {
+        TokenRequirements {
+            min_length: 16,
+            min_entropy: 48.0,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WeakTokenError {
+    #[error("token is too short, {0} characters while at least {1} are required")]
+    TooShort(usize, usize),
+    #[error("token is too predictable, {0:.0} bits of entropy while at least {1:.0} are required")]
+    LowEntropy(f64, f64),
+}
+
+impl TokenRequirements {
+    pub fn check(&self, token: &str) -> Result<(), WeakTokenError> {
+        let length = token.chars().count();
+        if length < self.min_length {
+            return Err(WeakTokenError::TooShort(length, self.min_length));
+        }
+        let entropy = estimate_entropy(token);
+        if entropy < self.min_entropy {
+            return Err(WeakTokenError::LowEntropy(entropy, self.min_entropy));
+        }
+        Ok(())
+    }
+}
+
+/// Estimate the entropy of a token in bits from the distribution of its characters
+///
+/// This only catches tokens with little variation like `aaaaaaaa` or `12121212`,
+/// a token that passes can still be predictable.
+fn estimate_entropy(token: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in token.chars() {
+        *counts.entry(c).or_default() += 1;
+    }
+    let length = token.chars().count() as f64;
+    let per_char: f64 = counts
+        .values()
+        .map(|count| {
+            let p = *count as f64 / length;
+            -p * p.log2()
+        })
+        .sum();
+    per_char * length
+}
@@ -0,0 +1,119 @@
+use crate::instance::unix_timestamp;
+use crate::observer::DaemonEvent;
+use crate::redis::WriteCommand;
+use crate::user::keep_user_names;
+use crate::{App, UserId};
+use ahash::RandomState;
+use dashmap::DashMap;
+use futures::future::select;
+use futures::pin_mut;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+
+/// Channel presence changes are published to
+pub const PRESENCE_CHANNEL: &str = "notify_push_presence";
+
+/// Open connections of an online user
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UserPresence {
+    pub connections: usize,
+    /// Unix timestamp of when the user came online
+    pub online_since: u64,
+}
+
+/// Users with at least one open websocket or event stream connection
+#[derive(Default)]
+pub struct Presence {
+    users: DashMap<UserId, UserPresence, RandomState>,
+}
+
+impl Presence {
+    pub fn connect(&self, user: &UserId) -> UserPresence {
+        let mut presence = self
+            .users
+            .entry(user.clone())
+            .or_insert_with(|| UserPresence {
+                connections: 0,
+                online_since: unix_timestamp(),
+            });
+        presence.connections += 1;
+        *presence
+    }
+
+    /// Remove a connection of the user, returning the number of connections the user still has open
+    pub fn disconnect(&self, user: &UserId) -> usize {
+        if let Some(mut presence) = self.users.get_mut(user) {
+            presence.connections = presence.connections.saturating_sub(1);
+        }
+        // a connection opened in the meantime keeps the user online
+        self.users
+            .remove_if(user, |_, presence| presence.connections == 0);
+        self.get(user).map_or(0, |presence| presence.connections)
+    }
+
+    pub fn get(&self, user: &UserId) -> Option<UserPresence> {
+        self.users.get(user).map(|presence| *presence)
+    }
+
+    pub fn online(&self) -> Vec<(UserId, UserPresence)> {
+        self.users
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    pub fn online_count(&self) -> usize {
+        self.users.len()
+    }
+}
+
+/// Publish the presence of users to redis whenever they open or close a connection
+///
+/// Every change is published to [`PRESENCE_CHANNEL`] as `{"user":"...","online":true,"connections":2}`.
+pub async fn presence_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
+    keep_user_names();
+    let mut events = app.observer.subscribe();
+    let loop_ = async move {
+        loop {
+            let user = match events.recv().await {
+                Ok(DaemonEvent::ConnectionOpened(user))
+                | Ok(DaemonEvent::ConnectionClosed(user, _)) => user,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(count)) => {
+                    log::warn!(
+                        "Missed {} connection changes for the presence updates",
+                        count
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            // users restored from the state file are only known by their hash
+            let name = match user.name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let connections = app
+                .connections
+                .presence()
+                .get(&user)
+                .map_or(0, |presence| presence.connections);
+            let message = json!({
+                "user": name,
+                "online": connections > 0,
+                "connections": connections,
+            });
+            app.redis_writer
+                .queue(vec![WriteCommand::Publish {
+                    channel: PRESENCE_CHANNEL.into(),
+                    message: message.to_string(),
+                }])
+                .await;
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
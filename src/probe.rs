@@ -0,0 +1,80 @@
+use crate::config::Config;
+use crate::nc;
+use color_eyre::{Report, Result};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Summary of the environment the push server is running in, logged on startup and printed by `--probe`
+#[derive(Debug, Serialize)]
+pub struct Probe {
+    pub version: &'static str,
+    pub bind: String,
+    pub metrics_bind: Option<String>,
+    pub database: String,
+    pub database_prefix: String,
+    pub redis_mode: &'static str,
+    pub redis_servers: usize,
+    pub nextcloud_url: String,
+    pub nextcloud_version: Option<String>,
+    pub features: Vec<&'static str>,
+}
+
+impl Probe {
+    pub async fn new(config: &Config) -> Self {
+        let nextcloud_version = match nextcloud_version(config).await {
+            Ok(version) => Some(version),
+            Err(e) => {
+                log::warn!("Failed to get the Nextcloud version: {:#}", e);
+                None
+            }
+        };
+
+        let mut features = Vec::new();
+        if config.tls.is_some() {
+            features.push("tls");
+        }
+        if config.metrics_bind.is_some() {
+            features.push("metrics");
+        }
+        if config.admin_token.is_some() {
+            features.push("admin");
+        }
+        if config.state_file.is_some() {
+            features.push("state_file");
+        }
+        if config.allow_self_signed {
+            features.push("allow_self_signed");
+        }
+        if config.path_match.normalize_unicode {
+            features.push("path_normalize_unicode");
+        }
+        if config.path_match.case_insensitive {
+            features.push("path_case_insensitive");
+        }
+
+        Probe {
+            version: env!("NOTIFY_PUSH_VERSION"),
+            bind: config.bind.to_string(),
+            metrics_bind: config.metrics_bind.as_ref().map(ToString::to_string),
+            database: format!("{:?}", config.database.kind()).to_lowercase(),
+            database_prefix: config.database_prefix.clone(),
+            redis_mode: if config.redis.len() > 1 {
+                "cluster"
+            } else {
+                "single"
+            },
+            redis_servers: config.redis.len(),
+            nextcloud_url: config.nextcloud_url.clone(),
+            nextcloud_version,
+            features,
+        }
+    }
+}
+
+async fn nextcloud_version(config: &Config) -> Result<String> {
+    let client = nc::Client::with_urls(&config.nextcloud_urls(), config.nextcloud_tls())?;
+    timeout(Duration::from_secs(5), client.get_nextcloud_version())
+        .await
+        .map_err(|_| Report::msg("Timeout while connecting to nextcloud server"))?
+}
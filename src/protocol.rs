@@ -0,0 +1,624 @@
+use crate::message::{
+    DebounceBounds, FilePayload, MessageType, DEBOUNCE_ACTIVITY, DEBOUNCE_FILE,
+    DEBOUNCE_NOTIFICATION,
+};
+use crate::room::MAX_ROOM_LENGTH;
+use parse_display::Display;
+use serde_json::json;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use thiserror::Error;
+use warp::ws::Message;
+
+/// The message format used for a connection
+///
+/// Version 1 sends bare strings like `notify_file`, version 2 sends json objects like `{"type":"file"}`
+/// which can carry additional information about the change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+pub enum ProtocolVersion {
+    #[display("1")]
+    V1 = 1,
+    #[display("2")]
+    V2 = 2,
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::V1
+    }
+}
+
+impl ProtocolVersion {
+    /// Name of the websocket subprotocol clients can request to use the version from the start of the connection
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            ProtocolVersion::V1 => "nextcloud-push-v1",
+            ProtocolVersion::V2 => "nextcloud-push-v2",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("unsupported websocket subprotocol {0}")]
+pub struct UnsupportedSubprotocol(String);
+
+/// Pick the protocol version from the subprotocols requested in the `Sec-WebSocket-Protocol` header
+///
+/// The first supported protocol in the order of preference of the client is used.
+pub fn negotiate_subprotocol(header: &str) -> Result<ProtocolVersion, UnsupportedSubprotocol> {
+    let versions = [ProtocolVersion::V1, ProtocolVersion::V2];
+    header
+        .split(',')
+        .map(str::trim)
+        .find_map(|protocol| {
+            versions
+                .iter()
+                .copied()
+                .find(|version| version.subprotocol() == protocol)
+        })
+        .ok_or_else(|| UnsupportedSubprotocol(header.to_string()))
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = CommandParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(ProtocolVersion::V1),
+            "2" => Ok(ProtocolVersion::V2),
+            _ => Err(CommandParseError::InvalidArgument("version", s.to_string())),
+        }
+    }
+}
+
+/// Time without messages after which the server pings the client, unless configured otherwise
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often idle connections are pinged and how long the server waits for the reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingConfig {
+    /// Time without messages after which the server pings the client
+    pub interval: Duration,
+    /// Time the client has to reply to a ping before it's disconnected
+    pub pong_grace: Duration,
+    /// Round trip time above which messages for a connection are batched
+    pub high_latency: Option<Duration>,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        PingConfig {
+            interval: DEFAULT_PING_INTERVAL,
+            pong_grace: DEFAULT_PING_INTERVAL,
+            high_latency: None,
+        }
+    }
+}
+
+/// Maximum size of a single frame or message send by the client
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Commands supported by the server, advertised in the banner
+const COMMANDS: &[&str] = &[
+    "version",
+    "resume_token",
+    "tag",
+    "untag",
+    "mode",
+    "encoding",
+    "capabilities",
+    "listen",
+    "debounce",
+    "sub_path",
+    "unsub_path",
+    "subscribe",
+    "unsubscribe",
+    "ack_mode",
+    "ack",
+];
+
+/// Banner describing the server limits and features
+///
+/// Send after authenticating when the handshake banner is enabled, or when the client requests it with `capabilities`
+pub fn banner_message(version: ProtocolVersion, ping_interval: Duration) -> Message {
+    Message::text(
+        json!({
+            "type": "banner",
+            "version": version as u8,
+            "max_version": ProtocolVersion::V2 as u8,
+            "ping_interval": ping_interval.as_secs(),
+            "debounce": {
+                "file": DEBOUNCE_FILE.load(Ordering::Relaxed),
+                "activity": DEBOUNCE_ACTIVITY.load(Ordering::Relaxed),
+                "notification": DEBOUNCE_NOTIFICATION.load(Ordering::Relaxed),
+            },
+            "max_frame_size": MAX_FRAME_SIZE,
+            "encodings": ["text", "msgpack"],
+            "events": ["file", "activity", "notification", "custom"],
+            "commands": COMMANDS,
+        })
+        .to_string(),
+    )
+}
+
+/// How a connection wants to receive messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display(style = "snake_case")]
+pub enum ConnectionMode {
+    Default,
+    /// For mobile clients in the background, where waking up the radio is expensive
+    ///
+    /// Messages are debounced longer and send in batches, and the server doesn't send pings
+    Mobile,
+}
+
+impl Default for ConnectionMode {
+    fn default() -> Self {
+        ConnectionMode::Default
+    }
+}
+
+impl FromStr for ConnectionMode {
+    type Err = CommandParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(ConnectionMode::Default),
+            "mobile" => Ok(ConnectionMode::Mobile),
+            _ => Err(CommandParseError::InvalidArgument("mode", s.to_string())),
+        }
+    }
+}
+
+/// How messages are encoded for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display(style = "snake_case")]
+pub enum Encoding {
+    /// Text frames in the format of the protocol version
+    Text,
+    /// Binary MessagePack frames with the same structure as the json objects of protocol version 2
+    #[display("msgpack")]
+    MessagePack,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Text
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = CommandParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Encoding::Text),
+            "msgpack" => Ok(Encoding::MessagePack),
+            _ => Err(CommandParseError::InvalidArgument(
+                "encoding",
+                s.to_string(),
+            )),
+        }
+    }
+}
+
+/// Maximum number of tags a single connection can have
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LENGTH: usize = 128;
+/// Maximum number of paths a single connection can subscribe to
+const MAX_SUB_PATHS: usize = 32;
+const MAX_SUB_PATH_LENGTH: usize = 1024;
+/// Maximum number of topic patterns a single connection can subscribe to
+const MAX_TOPICS: usize = 32;
+const MAX_TOPIC_LENGTH: usize = 256;
+
+/// Commands a client can send after authenticating
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientCommand {
+    /// Switch the connection to a different protocol version
+    Version(ProtocolVersion),
+    /// Request a token that can be used to resume the session after the connection is lost
+    ResumeToken,
+    /// Receive custom messages send to the tag
+    Tag(String),
+    /// Stop receiving custom messages send to the tag
+    Untag(String),
+    /// Switch the connection to a different delivery mode
+    Mode(ConnectionMode),
+    /// Switch the encoding of messages send over the connection
+    Encoding(Encoding),
+    /// Request the banner describing the limits and features of the server
+    Capabilities,
+    /// Only receive messages of the listed types, or all messages if `None`
+    Listen(Option<Vec<String>>),
+    /// Use a debounce window in seconds for all message types, or the server defaults if `None`
+    Debounce(Option<u64>),
+    /// Join a room to receive the messages other clients relay to it
+    Join(String),
+    /// Leave a room
+    Leave(String),
+    /// Relay a payload to the other connections in a room
+    Relay(String, String),
+    /// Only receive file messages for changes inside the directory, relative to the user's files
+    SubPath(String),
+    /// Stop receiving file messages for the directory
+    UnsubPath(String),
+    /// Receive custom messages send to topics matching the pattern
+    Subscribe(String),
+    /// Stop receiving custom messages for the topic pattern
+    Unsubscribe(String),
+    /// Enable or disable acknowledgements for custom messages, only supported with protocol version 2
+    AckMode(bool),
+    /// Confirm that the custom message with the id was received
+    Ack(u64),
+}
+
+#[derive(Debug, Error)]
+pub enum CommandParseError {
+    #[error("unknown command {0}")]
+    UnknownCommand(String),
+    #[error("invalid argument for {0}: {1}")]
+    InvalidArgument(&'static str, String),
+}
+
+impl FromStr for ClientCommand {
+    type Err = CommandParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, ' ');
+        let command = parts.next().unwrap_or_default();
+        let argument = parts.next().unwrap_or_default().trim();
+        match command {
+            "version" => Ok(ClientCommand::Version(argument.parse()?)),
+            "resume_token" => Ok(ClientCommand::ResumeToken),
+            "tag" | "untag" if argument.is_empty() || argument.len() > MAX_TAG_LENGTH => Err(
+                CommandParseError::InvalidArgument("tag", argument.to_string()),
+            ),
+            "tag" => Ok(ClientCommand::Tag(argument.to_string())),
+            "join" | "leave" | "relay" if argument.is_empty() => Err(
+                CommandParseError::InvalidArgument("room", argument.to_string()),
+            ),
+            "join" | "leave" if argument.len() > MAX_ROOM_LENGTH => Err(
+                CommandParseError::InvalidArgument("room", argument.to_string()),
+            ),
+            "join" => Ok(ClientCommand::Join(argument.to_string())),
+            "leave" => Ok(ClientCommand::Leave(argument.to_string())),
+            "relay" => parse_relay(argument),
+            "untag" => Ok(ClientCommand::Untag(argument.to_string())),
+            "sub_path" | "unsub_path"
+                if argument.is_empty() || argument.len() > MAX_SUB_PATH_LENGTH =>
+            {
+                Err(CommandParseError::InvalidArgument(
+                    "path",
+                    argument.to_string(),
+                ))
+            }
+            "subscribe" | "unsubscribe"
+                if argument.is_empty() || argument.len() > MAX_TOPIC_LENGTH =>
+            {
+                Err(CommandParseError::InvalidArgument(
+                    "topic",
+                    argument.to_string(),
+                ))
+            }
+            "subscribe" => Ok(ClientCommand::Subscribe(argument.to_string())),
+            "unsubscribe" => Ok(ClientCommand::Unsubscribe(argument.to_string())),
+            "sub_path" => Ok(ClientCommand::SubPath(normalize_sub_path(argument))),
+            "unsub_path" => Ok(ClientCommand::UnsubPath(normalize_sub_path(argument))),
+            "mode" => Ok(ClientCommand::Mode(argument.parse()?)),
+            "encoding" => Ok(ClientCommand::Encoding(argument.parse()?)),
+            "capabilities" => Ok(ClientCommand::Capabilities),
+            "listen" => parse_listen(argument).map(ClientCommand::Listen),
+            "ack_mode" => match argument {
+                "on" => Ok(ClientCommand::AckMode(true)),
+                "off" => Ok(ClientCommand::AckMode(false)),
+                _ => Err(CommandParseError::InvalidArgument(
+                    "ack_mode",
+                    argument.to_string(),
+                )),
+            },
+            "ack" => argument
+                .parse()
+                .map(ClientCommand::Ack)
+                .map_err(|_| CommandParseError::InvalidArgument("ack", argument.to_string())),
+            "debounce" if argument == "default" => Ok(ClientCommand::Debounce(None)),
+            "debounce" => argument
+                .parse()
+                .map(|seconds| ClientCommand::Debounce(Some(seconds)))
+                .map_err(|_| CommandParseError::InvalidArgument("debounce", argument.to_string())),
+            _ => Err(CommandParseError::UnknownCommand(command.to_string())),
+        }
+    }
+}
+
+/// Parse the room and payload for `relay`, separated by a space
+fn parse_relay(argument: &str) -> Result<ClientCommand, CommandParseError> {
+    let (room, payload) = match argument.find(char::is_whitespace) {
+        Some(split) => (&argument[..split], argument[split..].trim_start()),
+        None => (argument, ""),
+    };
+    if room.len() > MAX_ROOM_LENGTH {
+        return Err(CommandParseError::InvalidArgument("room", room.to_string()));
+    }
+    Ok(ClientCommand::Relay(room.to_string(), payload.to_string()))
+}
+
+/// Subscribed paths are stored with a leading slash and without trailing slash, `/` becomes an empty string
+fn normalize_sub_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+/// Whether the path is the subscribed directory or inside of it
+fn in_sub_path(sub_path: &str, path: &str) -> bool {
+    match path.strip_prefix(sub_path) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Whether a topic matches a subscribed pattern
+///
+/// Topics are split into levels by `/`, `*` matches a single level and `**` as the last level matches all levels below,
+/// so `deck/*` matches `deck/board` but not `deck/board/7`, which is matched by `deck/**`.
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for level in pattern.split('/') {
+        if level == "**" {
+            return topic.next().is_some();
+        }
+        match topic.next() {
+            Some(topic_level) if level == "*" || level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+/// Parse the message types for `listen`, separated by spaces or commas, an empty list or `*` listens to all messages
+fn parse_listen(argument: &str) -> Result<Option<Vec<String>>, CommandParseError> {
+    if argument.is_empty() || argument == "*" {
+        return Ok(None);
+    }
+    let mut types: Vec<String> = argument
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|ty| !ty.is_empty())
+        .map(String::from)
+        .collect();
+    types.sort_unstable();
+    types.dedup();
+    if types.len() > MAX_TAGS || types.iter().any(|ty| ty.len() > MAX_TAG_LENGTH) {
+        return Err(CommandParseError::InvalidArgument(
+            "listen",
+            argument.to_string(),
+        ));
+    }
+    Ok(Some(types))
+}
+
+/// Options negotiated by the client for a single connection
+#[derive(Debug, Default)]
+pub struct ConnectionOptions {
+    pub version: ProtocolVersion,
+    /// The last resume token issued for the connection
+    pub resume_token: Option<String>,
+    pub tags: HashSet<String>,
+    /// Topic patterns the client subscribed to, custom messages with a topic are only send if one of them matches
+    pub topics: HashSet<String>,
+    pub mode: ConnectionMode,
+    pub encoding: Encoding,
+    /// Message types the client subscribed to with `listen`, all messages are send if not set
+    pub listen: Option<HashSet<String>>,
+    /// Directories the client subscribed to with `sub_path`, file messages for all paths are send if empty
+    pub sub_paths: HashSet<String>,
+    /// Debounce window picked by the client, already limited to the configured bounds
+    pub debounce: Option<Duration>,
+    /// Custom messages are send with an id the client has to acknowledge
+    pub ack_mode: bool,
+    pub debounce_bounds: DebounceBounds,
+    pub ping: PingConfig,
+}
+
+impl ConnectionOptions {
+    /// Apply a command send by the client, returning the reply for the client if there is any
+    pub fn apply(&mut self, command: ClientCommand) -> Option<Message> {
+        match command {
+            ClientCommand::Version(version) => {
+                self.version = version;
+                // messages can only carry an id in the json format
+                if version == ProtocolVersion::V1 {
+                    self.ack_mode = false;
+                }
+                match version {
+                    ProtocolVersion::V1 => Some(Message::text("version 1")),
+                    ProtocolVersion::V2 => Some(Message::text(
+                        json!({"type": "version", "version": 2}).to_string(),
+                    )),
+                }
+            }
+            // issuing tokens, rooms and acknowledgements need the app or connection state, so these are handled by the connection itself
+            ClientCommand::ResumeToken
+            | ClientCommand::Join(_)
+            | ClientCommand::Leave(_)
+            | ClientCommand::Relay(..)
+            | ClientCommand::Ack(_) => None,
+            ClientCommand::AckMode(true) if self.version == ProtocolVersion::V1 => Some(
+                Message::text("err: acknowledgements require protocol version 2"),
+            ),
+            ClientCommand::AckMode(enabled) => {
+                self.ack_mode = enabled;
+                match self.version {
+                    ProtocolVersion::V1 => Some(Message::text("ack_mode off")),
+                    ProtocolVersion::V2 => Some(Message::text(
+                        json!({"type": "ack_mode", "enabled": enabled}).to_string(),
+                    )),
+                }
+            }
+            ClientCommand::Tag(_) if self.tags.len() >= MAX_TAGS => {
+                Some(Message::text("err: too many tags"))
+            }
+            ClientCommand::Tag(tag) => {
+                self.tags.insert(tag);
+                None
+            }
+            ClientCommand::Untag(tag) => {
+                self.tags.remove(&tag);
+                None
+            }
+            ClientCommand::Subscribe(_) if self.topics.len() >= MAX_TOPICS => {
+                Some(Message::text("err: too many topics"))
+            }
+            ClientCommand::Subscribe(pattern) => {
+                self.topics.insert(pattern);
+                None
+            }
+            ClientCommand::Unsubscribe(pattern) => {
+                self.topics.remove(&pattern);
+                None
+            }
+            ClientCommand::SubPath(_) if self.sub_paths.len() >= MAX_SUB_PATHS => {
+                Some(Message::text("err: too many paths"))
+            }
+            ClientCommand::SubPath(path) => {
+                self.sub_paths.insert(path);
+                None
+            }
+            ClientCommand::UnsubPath(path) => {
+                self.sub_paths.remove(&path);
+                None
+            }
+            ClientCommand::Mode(mode) => {
+                self.mode = mode;
+                match self.version {
+                    ProtocolVersion::V1 => Some(Message::text(format!("mode {}", mode))),
+                    ProtocolVersion::V2 => Some(Message::text(
+                        json!({"type": "mode", "mode": mode.to_string()}).to_string(),
+                    )),
+                }
+            }
+            // the confirmation is still send as text, so clients know from which message on to expect binary frames
+            ClientCommand::Encoding(encoding) => {
+                self.encoding = encoding;
+                match self.version {
+                    ProtocolVersion::V1 => Some(Message::text(format!("encoding {}", encoding))),
+                    ProtocolVersion::V2 => Some(Message::text(
+                        json!({"type": "encoding", "encoding": encoding.to_string()}).to_string(),
+                    )),
+                }
+            }
+            ClientCommand::Capabilities => Some(banner_message(self.version, self.ping.interval)),
+            ClientCommand::Debounce(seconds) => {
+                self.debounce =
+                    seconds.map(|seconds| self.debounce_bounds.clamp(Duration::from_secs(seconds)));
+                let seconds = self.debounce.map(|window| window.as_secs());
+                match (self.version, seconds) {
+                    (ProtocolVersion::V1, Some(seconds)) => {
+                        Some(Message::text(format!("debounce {}", seconds)))
+                    }
+                    (ProtocolVersion::V1, None) => Some(Message::text("debounce default")),
+                    (ProtocolVersion::V2, _) => Some(Message::text(
+                        json!({"type": "debounce", "debounce": seconds}).to_string(),
+                    )),
+                }
+            }
+            ClientCommand::Listen(types) => {
+                let listed = types.clone().unwrap_or_else(|| vec!["*".to_string()]);
+                self.listen = types.map(|types| types.into_iter().collect());
+                match self.version {
+                    ProtocolVersion::V1 => {
+                        Some(Message::text(format!("listen {}", listed.join(","))))
+                    }
+                    ProtocolVersion::V2 => Some(Message::text(
+                        json!({"type": "listen", "types": listed}).to_string(),
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Encode a message in the format and encoding used by the connection
+    pub fn encode(&self, message: &MessageType) -> Message {
+        match self.encoding {
+            Encoding::Text => message.to_message(self.version),
+            Encoding::MessagePack => message.to_msgpack(),
+        }
+    }
+
+    /// Encode a custom message together with the id the client has to acknowledge it with
+    pub fn encode_with_id(&self, message: &MessageType, id: u64) -> Message {
+        let mut json = message.to_json();
+        json["id"] = id.into();
+        match self.encoding {
+            Encoding::Text => Message::text(json.to_string()),
+            Encoding::MessagePack => {
+                Message::binary(rmp_serde::to_vec_named(&json).unwrap_or_default())
+            }
+        }
+    }
+
+    /// Whether a message should be send over this connection
+    ///
+    /// Tagged custom messages are only send to connections that have the tag and connections that used `listen`
+    /// only get the message types they subscribed to.
+    /// Custom messages with a topic are only send to connections subscribed to a matching topic pattern.
+    /// Connections that subscribed to paths only get file messages for changes inside those paths,
+    /// file messages where the changed path isn't known are always send.
+    pub fn accepts(&self, message: &MessageType) -> bool {
+        if let Some(listen) = &self.listen {
+            if !listen.contains(&message.to_string()) {
+                return false;
+            }
+        }
+        match message {
+            MessageType::Custom(_, _, tag, topic) => {
+                tag.as_ref().map_or(true, |tag| self.tags.contains(tag))
+                    && topic.as_ref().map_or(true, |topic| {
+                        self.topics
+                            .iter()
+                            .any(|pattern| topic_matches(pattern, topic))
+                    })
+            }
+            MessageType::File(Some(FilePayload {
+                filter_path: Some(path),
+                ..
+            })) if !self.sub_paths.is_empty() => self
+                .sub_paths
+                .iter()
+                .any(|sub_path| in_sub_path(sub_path, path)),
+            _ => true,
+        }
+    }
+
+    /// Confirm that the connection joined or left a room
+    pub fn room_message(&self, action: &str, room: &str) -> Message {
+        match self.version {
+            ProtocolVersion::V1 => Message::text(format!("{} {}", action, room)),
+            ProtocolVersion::V2 => Message::text(json!({"type": action, "room": room}).to_string()),
+        }
+    }
+
+    pub fn resume_token_message(&self, token: &str) -> Message {
+        match self.version {
+            ProtocolVersion::V1 => Message::text(format!("resume_token {}", token)),
+            ProtocolVersion::V2 => {
+                Message::text(json!({"type": "resume_token", "token": token}).to_string())
+            }
+        }
+    }
+
+    /// Tell the client that messages were lost and it should do a full sync
+    pub fn sync_recommended_message(&self, dropped: u64) -> Message {
+        match self.version {
+            ProtocolVersion::V1 => Message::text("sync_recommended"),
+            ProtocolVersion::V2 => {
+                Message::text(json!({"type": "sync_recommended", "dropped": dropped}).to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::metrics::METRICS;
+use crate::storage_mapping::StorageMapping;
+use crate::App;
+use futures::future::select;
+use futures::pin_mut;
+use std::cmp::min;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{interval, sleep};
+
+/// How often the database is checked when no queries are failing
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time to wait before the first reconnect attempt, doubled after every attempt until the database passes a check again
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Re-create the database connections when the database can't be reached anymore, e.g. after it restarted
+///
+/// The database is checked whenever a query fails and periodically otherwise. While reconnecting, queries fail
+/// immediately instead of waiting for a connection and the push server isn't ready.
+pub async fn database_reconnect_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
+    let loop_ = async move {
+        let mapping = &app.storage_mapping;
+        let mut interval = interval(CHECK_INTERVAL);
+        // the backoff is kept when queries keep failing right after reconnecting,
+        // so a backend that can't be fixed by reconnecting isn't reconnected in a tight loop
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = mapping.failed() => {},
+            }
+            match mapping.check_backend().await {
+                Ok(()) => backoff = INITIAL_BACKOFF,
+                Err(e) => {
+                    log::error!("Database can't be reached, reconnecting: {:#}", e);
+                    backoff = reconnect(mapping, backoff).await;
+                }
+            }
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
+
+/// Reconnect until the database can be reached again, returns the backoff for the next reconnect
+async fn reconnect(mapping: &StorageMapping, mut backoff: Duration) -> Duration {
+    mapping.set_available(false);
+    loop {
+        sleep(backoff).await;
+        METRICS.add_database_reconnect();
+        let result = mapping.reconnect().await;
+        backoff = min(backoff * 2, MAX_BACKOFF);
+        match result {
+            Ok(()) => {
+                log::info!("Reconnected to the database");
+                mapping.set_available(true);
+                return backoff;
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to reconnect to the database, retrying in {}s: {:#}",
+                    backoff.as_secs(),
+                    e
+                );
+            }
+        }
+    }
+}
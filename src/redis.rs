@@ -1,11 +1,45 @@
 use color_eyre::{Report, Result};
 use redis::aio::{Connection, PubSub};
 use redis::cluster::{ClusterClient, ClusterConnection};
-use redis::{AsyncCommands, Client, Commands, ConnectionInfo};
-use tokio::task::block_in_place;
+use redis::{AsyncCommands, Client, Cmd, Commands, ConnectionInfo, RedisError, RedisResult, Value};
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+use tokio::task::{block_in_place, spawn};
+
+/// How often to check if the server we're subscribed to is still the primary
+pub const ROLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Maximum number of queued write requests before writers have to wait
+const WRITE_QUEUE_SIZE: usize = 1024;
+/// Maximum number of write requests that are combined into a single pipeline
+const WRITE_BATCH_SIZE: usize = 64;
+
+/// Error codes returned by redis when the server we're talking to is no longer the primary
+const FAILOVER_ERROR_CODES: &[&str] = &["MOVED", "ASK", "READONLY", "MASTERDOWN", "CLUSTERDOWN"];
+
+#[derive(Debug, Error)]
+#[error("redis server is no longer the primary")]
+pub struct DemotedError;
+
+/// Check if an error indicates that the redis server has been demoted or the cluster has been resharded
+pub fn is_failover_error(error: &Report) -> bool {
+    error.chain().any(|err| {
+        err.is::<DemotedError>()
+            || err
+                .downcast_ref::<RedisError>()
+                .and_then(RedisError::code)
+                .map(|code| FAILOVER_ERROR_CODES.contains(&code))
+                .unwrap_or(false)
+    })
+}
 
 pub struct Redis {
     config: Vec<ConnectionInfo>,
+    subscribed: Mutex<Option<ConnectionInfo>>,
+    /// Connection to the subscribed server for the role check, reopened on error or when subscribing to another server
+    role_connection: AsyncMutex<Option<Connection>>,
 }
 
 impl Redis {
@@ -13,15 +47,68 @@ impl Redis {
         if config.is_empty() {
             return Err(Report::msg("No redis server configured"));
         }
-        Ok(Redis { config })
+        Ok(Redis {
+            config,
+            subscribed: Mutex::default(),
+            role_connection: AsyncMutex::default(),
+        })
     }
 
     /// Get an async pubsub connection
     pub async fn pubsub(&self) -> Result<PubSub> {
         // since pubsub performs a multicast for all nodes in a cluster,
         // listening to a single server in the cluster is sufficient for cluster setups
-        let client = Client::open(self.config.first().unwrap().clone())?;
-        Ok(client.get_async_connection().await?.into_pubsub())
+        let info = self.primary().await.clone();
+        let client = Client::open(info.clone())?;
+        let pubsub = client.get_async_connection().await?.into_pubsub();
+        *self.subscribed.lock().unwrap() = Some(info);
+        *self.role_connection.lock().await = None;
+        Ok(pubsub)
+    }
+
+    /// Find the first configured server that reports itself as primary,
+    /// falling back to the first configured server if none do or the role can't be determined
+    async fn primary(&self) -> &ConnectionInfo {
+        if self.config.len() > 1 {
+            for info in &self.config {
+                if let Ok(true) = is_primary(info).await {
+                    return info;
+                }
+            }
+        }
+        self.config.first().unwrap()
+    }
+
+    /// Check if the server we're subscribed to has been demoted to a replica
+    ///
+    /// Servers that don't support the `ROLE` command are never considered demoted
+    pub async fn is_demoted(&self) -> bool {
+        let subscribed = self.subscribed.lock().unwrap().clone();
+        let info = match subscribed {
+            Some(info) => info,
+            None => return false,
+        };
+        let mut cached = self.role_connection.lock().await;
+        if cached.is_none() {
+            match Client::open(info) {
+                Ok(client) => *cached = client.get_async_connection().await.ok(),
+                Err(_) => return false,
+            }
+        }
+        let result = match cached.as_mut() {
+            Some(connection) => query_role(connection).await,
+            None => return false,
+        };
+        match result {
+            Ok(primary) => !primary,
+            Err(e) => {
+                // an unsupported `ROLE` command doesn't break the connection
+                if e.is_io_error() || e.is_connection_dropped() {
+                    *cached = None;
+                }
+                false
+            }
+        }
     }
 
     pub async fn connect(&self) -> Result<RedisConnection> {
@@ -65,6 +152,37 @@ impl RedisConnection {
         })
     }
 
+    /// Get a key that might not exist
+    pub async fn get_optional(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(match self {
+            RedisConnection::Async(client) => client.get::<_, Option<String>>(key).await?,
+            RedisConnection::Cluster(client) => {
+                block_in_place(|| client.get::<_, Option<String>>(key))?
+            }
+        })
+    }
+
+    pub async fn smembers(&mut self, key: &str) -> Result<Vec<String>> {
+        Ok(match self {
+            RedisConnection::Async(client) => client.smembers::<_, Vec<String>>(key).await?,
+            RedisConnection::Cluster(client) => {
+                block_in_place(|| client.smembers::<_, Vec<String>>(key))?
+            }
+        })
+    }
+
+    pub async fn srem(&mut self, key: &str, member: &str) -> Result<()> {
+        match self {
+            RedisConnection::Async(client) => {
+                client.srem::<_, _, ()>(key, member).await?;
+            }
+            RedisConnection::Cluster(client) => {
+                block_in_place(|| client.srem::<_, _, ()>(key, member))?;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn set(&mut self, key: &str, value: &str) -> Result<()> {
         match self {
             RedisConnection::Async(client) => {
@@ -76,4 +194,230 @@ impl RedisConnection {
         }
         Ok(())
     }
+
+    /// Get all items of a list and remove the list
+    pub async fn take_list(&mut self, key: &str) -> Result<Vec<String>> {
+        Ok(match self {
+            RedisConnection::Async(client) => {
+                let (items,): (Vec<String>,) = redis::pipe()
+                    .atomic()
+                    .lrange(key, 0, -1)
+                    .del(key)
+                    .ignore()
+                    .query_async(client)
+                    .await?;
+                items
+            }
+            RedisConnection::Cluster(client) => block_in_place(|| {
+                let items = client.lrange::<_, Vec<String>>(key, 0, -1)?;
+                client.del::<_, ()>(key)?;
+                Ok::<_, RedisError>(items)
+            })?,
+        })
+    }
+
+    /// Send a batch of write commands, pipelined when possible
+    pub async fn write_batch(&mut self, commands: &[WriteCommand]) -> Result<()> {
+        match self {
+            RedisConnection::Async(client) => {
+                let mut pipe = redis::pipe();
+                for cmd in commands.iter().flat_map(WriteCommand::to_cmds) {
+                    pipe.add_command(cmd).ignore();
+                }
+                pipe.query_async::<_, ()>(client).await?;
+            }
+            RedisConnection::Cluster(client) => {
+                // a pipeline can't span multiple nodes, so send the commands one by one instead
+                block_in_place(|| {
+                    commands
+                        .iter()
+                        .flat_map(WriteCommand::to_cmds)
+                        .try_for_each(|cmd| cmd.query::<()>(&mut *client))
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn is_primary(info: &ConnectionInfo) -> Result<bool> {
+    let mut connection = Client::open(info.clone())?.get_async_connection().await?;
+    Ok(query_role(&mut connection).await?)
+}
+
+async fn query_role(connection: &mut Connection) -> RedisResult<bool> {
+    let role: Vec<Value> = redis::cmd("ROLE").query_async(connection).await?;
+    Ok(matches!(role.first(), Some(Value::Data(name)) if name == b"master"))
+}
+
+/// A write to redis made by the push server itself
+#[derive(Debug, Clone)]
+pub enum WriteCommand {
+    Set {
+        key: String,
+        value: String,
+        ttl: Option<usize>,
+    },
+    Del {
+        key: String,
+    },
+    /// Add a member to a set
+    SAdd {
+        key: String,
+        member: String,
+    },
+    /// Append an item to a list, keeping at most `max_len` items and expiring the list after `ttl` seconds
+    Append {
+        key: String,
+        value: String,
+        max_len: usize,
+        ttl: usize,
+    },
+    Publish {
+        channel: String,
+        message: String,
+    },
+}
+
+impl WriteCommand {
+    fn to_cmds(&self) -> Vec<Cmd> {
+        match self {
+            WriteCommand::Set { key, value, ttl } => {
+                let mut cmd = redis::cmd("SET");
+                cmd.arg(key).arg(value);
+                if let Some(ttl) = ttl {
+                    cmd.arg("EX").arg(*ttl);
+                }
+                vec![cmd]
+            }
+            WriteCommand::Del { key } => {
+                let mut cmd = redis::cmd("DEL");
+                cmd.arg(key);
+                vec![cmd]
+            }
+            WriteCommand::SAdd { key, member } => {
+                let mut cmd = redis::cmd("SADD");
+                cmd.arg(key).arg(member);
+                vec![cmd]
+            }
+            WriteCommand::Append {
+                key,
+                value,
+                max_len,
+                ttl,
+            } => {
+                let mut push = redis::cmd("RPUSH");
+                push.arg(key).arg(value);
+                let mut trim = redis::cmd("LTRIM");
+                trim.arg(key).arg(-(*max_len as isize)).arg(-1);
+                let mut expire = redis::cmd("EXPIRE");
+                expire.arg(key).arg(*ttl);
+                vec![push, trim, expire]
+            }
+            WriteCommand::Publish { channel, message } => {
+                let mut cmd = redis::cmd("PUBLISH");
+                cmd.arg(channel).arg(message);
+                vec![cmd]
+            }
+        }
+    }
+}
+
+struct WriteRequest {
+    commands: Vec<WriteCommand>,
+    done: Option<oneshot::Sender<bool>>,
+}
+
+/// Handle to the background task that batches all writes to redis into pipelines over a single connection
+#[derive(Clone)]
+pub struct RedisWriter {
+    tx: mpsc::Sender<WriteRequest>,
+}
+
+impl RedisWriter {
+    /// Spawn the writer task, the task stops once all handles are dropped
+    pub fn new(config: Vec<ConnectionInfo>) -> Result<Self> {
+        let redis = Redis::new(config)?;
+        let (tx, rx) = mpsc::channel(WRITE_QUEUE_SIZE);
+        spawn(write_loop(redis, rx));
+        Ok(RedisWriter { tx })
+    }
+
+    /// Queue commands to be written without waiting for them to be written
+    ///
+    /// This only waits if the write queue is full
+    pub async fn queue(&self, commands: Vec<WriteCommand>) {
+        let request = WriteRequest {
+            commands,
+            done: None,
+        };
+        if self.tx.send(request).await.is_err() {
+            log::warn!("Redis writer stopped, dropping write");
+        }
+    }
+
+    /// Write commands and wait until they're written
+    pub async fn write(&self, commands: Vec<WriteCommand>) -> Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        let request = WriteRequest {
+            commands,
+            done: Some(done_tx),
+        };
+        self.tx
+            .send(request)
+            .await
+            .map_err(|_| Report::msg("Redis writer stopped"))?;
+        match done_rx.await {
+            Ok(true) => Ok(()),
+            _ => Err(Report::msg("Failed to write to redis")),
+        }
+    }
+}
+
+async fn write_loop(redis: Redis, mut rx: mpsc::Receiver<WriteRequest>) {
+    let mut connection: Option<RedisConnection> = None;
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < WRITE_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
+        }
+
+        let commands: Vec<WriteCommand> = batch
+            .iter()
+            .flat_map(|request| request.commands.iter().cloned())
+            .collect();
+
+        if connection.is_none() {
+            match redis.connect().await {
+                Ok(new_connection) => connection = Some(new_connection),
+                Err(e) => log::warn!("Failed to connect to redis for writing: {:#}", e),
+            }
+        }
+
+        let success = match connection.as_mut() {
+            Some(client) => match client.write_batch(&commands).await {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!(
+                        "Failed to write {} commands to redis: {:#}",
+                        commands.len(),
+                        e
+                    );
+                    // reconnect for the next batch
+                    connection = None;
+                    false
+                }
+            },
+            None => false,
+        };
+
+        for request in batch {
+            if let Some(done) = request.done {
+                done.send(success).ok();
+            }
+        }
+    }
 }
@@ -0,0 +1,210 @@
+use crate::metrics::METRICS;
+use ahash::RandomState;
+use dashmap::DashMap;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::str::FromStr;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use thiserror::Error;
+
+/// Default number of shards for the sharded registry
+pub const DEFAULT_REGISTRY_SHARDS: usize = 64;
+
+/// Map implementation used for the maps that are accessed for every connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryKind {
+    DashMap,
+    /// Fixed number of `RwLock<HashMap>` shards, allows tuning the shard count and measuring lock contention
+    Sharded,
+}
+
+impl Default for RegistryKind {
+    fn default() -> Self {
+        RegistryKind::DashMap
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid registry {0}, expected `dashmap` or `sharded`")]
+pub struct InvalidRegistryKind(String);
+
+impl FromStr for RegistryKind {
+    type Err = InvalidRegistryKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dashmap" => Ok(RegistryKind::DashMap),
+            "sharded" => Ok(RegistryKind::Sharded),
+            _ => Err(InvalidRegistryKind(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegistryConfig {
+    pub kind: RegistryKind,
+    /// Number of shards for the sharded registry
+    pub shards: Option<usize>,
+}
+
+/// Concurrent map with a runtime selectable implementation
+///
+/// All operations are counted in the `registry_operation_count` metric,
+/// the sharded implementation also counts lock acquisitions that had to wait in `registry_contention_count`.
+pub enum Registry<K, V> {
+    DashMap(DashMap<K, V, RandomState>),
+    Sharded(ShardedMap<K, V>),
+}
+
+impl<K: Hash + Eq, V> Default for Registry<K, V> {
+    fn default() -> Self {
+        Registry::DashMap(DashMap::default())
+    }
+}
+
+impl<K: Hash + Eq, V> Registry<K, V> {
+    pub fn new(config: RegistryConfig) -> Self {
+        match config.kind {
+            RegistryKind::DashMap => Registry::DashMap(DashMap::default()),
+            RegistryKind::Sharded => Registry::Sharded(ShardedMap::new(
+                config.shards.unwrap_or(DEFAULT_REGISTRY_SHARDS),
+            )),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        METRICS.add_registry_operation();
+        match self {
+            Registry::DashMap(map) => map.get(key).map(|value| value.value().clone()),
+            Registry::Sharded(map) => map.read(key).get(key).cloned(),
+        }
+    }
+
+    /// Get the value for the key, inserting it first if the key doesn't exist yet
+    pub fn get_or_insert_with(&self, key: K, value: impl FnOnce() -> V) -> V
+    where
+        V: Clone,
+    {
+        METRICS.add_registry_operation();
+        match self {
+            Registry::DashMap(map) => map.entry(key).or_insert_with(value).value().clone(),
+            Registry::Sharded(map) => {
+                if let Some(existing) = map.read(&key).get(&key) {
+                    return existing.clone();
+                }
+                map.write(&key).entry(key).or_insert_with(value).clone()
+            }
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        METRICS.add_registry_operation();
+        match self {
+            Registry::DashMap(map) => {
+                map.insert(key, value);
+            }
+            Registry::Sharded(map) => {
+                map.write(&key).insert(key, value);
+            }
+        }
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        METRICS.add_registry_operation();
+        match self {
+            Registry::DashMap(map) => map.remove(key).map(|(_, value)| value),
+            Registry::Sharded(map) => map.write(key).remove(key),
+        }
+    }
+
+    /// Call the function for every entry, only a part of the map is locked at a time
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        METRICS.add_registry_operation();
+        match self {
+            Registry::DashMap(map) => {
+                for entry in map.iter() {
+                    f(entry.key(), entry.value());
+                }
+            }
+            Registry::Sharded(map) => {
+                for shard in map.shards.iter() {
+                    for (key, value) in lock_read(shard).iter() {
+                        f(key, value);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        METRICS.add_registry_operation();
+        match self {
+            Registry::DashMap(map) => map.retain(f),
+            Registry::Sharded(map) => {
+                for shard in map.shards.iter() {
+                    lock_write(shard).retain(|key, value| f(key, value));
+                }
+            }
+        }
+    }
+}
+
+type Shard<K, V> = RwLock<HashMap<K, V, RandomState>>;
+
+pub struct ShardedMap<K, V> {
+    hasher: RandomState,
+    shards: Vec<Shard<K, V>>,
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    fn new(shards: usize) -> Self {
+        ShardedMap {
+            hasher: RandomState::default(),
+            shards: (0..shards.max(1)).map(|_| RwLock::default()).collect(),
+        }
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &Shard<K, V> {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    fn read<Q: Hash + ?Sized>(&self, key: &Q) -> RwLockReadGuard<HashMap<K, V, RandomState>> {
+        lock_read(self.shard(key))
+    }
+
+    fn write<Q: Hash + ?Sized>(&self, key: &Q) -> RwLockWriteGuard<HashMap<K, V, RandomState>> {
+        lock_write(self.shard(key))
+    }
+}
+
+fn lock_read<K, V>(shard: &Shard<K, V>) -> RwLockReadGuard<HashMap<K, V, RandomState>> {
+    match shard.try_read() {
+        Ok(guard) => guard,
+        Err(_) => {
+            METRICS.add_registry_contention();
+            shard.read().unwrap()
+        }
+    }
+}
+
+fn lock_write<K, V>(shard: &Shard<K, V>) -> RwLockWriteGuard<HashMap<K, V, RandomState>> {
+    match shard.try_write() {
+        Ok(guard) => guard,
+        Err(_) => {
+            METRICS.add_registry_contention();
+            shard.write().unwrap()
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::admin::random_token;
+use crate::message::HeldMessages;
+use crate::UserId;
+use ahash::RandomState;
+use color_eyre::{eyre::WrapErr, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long a resume token stays valid after the connection it belongs to is closed
+const RESUME_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+struct ResumeEntry {
+    user: UserId,
+    /// `None` while the connection the token was issued for is still open
+    expires: Option<Instant>,
+    held: HeldMessages,
+}
+
+impl ResumeEntry {
+    fn is_valid(&self) -> bool {
+        self.expires
+            .map(|expires| expires > Instant::now())
+            .unwrap_or(true)
+    }
+}
+
+/// Tokens that let a client re-authenticate without credentials shortly after losing its connection
+///
+/// Any messages that were held back by debouncing when the connection was lost are delivered once the
+/// client resumes.
+#[derive(Default)]
+pub struct ResumeTokens(DashMap<String, ResumeEntry, RandomState>);
+
+impl ResumeTokens {
+    pub fn issue(&self, user: UserId) -> String {
+        let token = random_token();
+        self.0.insert(
+            token.clone(),
+            ResumeEntry {
+                user,
+                expires: None,
+                held: HeldMessages::default(),
+            },
+        );
+        token
+    }
+
+    /// Use a token, tokens can only be used once
+    pub fn take(&self, token: &str) -> Option<(UserId, HeldMessages)> {
+        self.0
+            .remove(token)
+            .filter(|(_, entry)| entry.is_valid())
+            .map(|(_, entry)| (entry.user, entry.held))
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.0.remove(token);
+    }
+
+    pub fn set_held(&self, token: &str, held: HeldMessages) {
+        if let Some(mut entry) = self.0.get_mut(token) {
+            entry.held = held;
+        }
+    }
+
+    /// Drop the messages held for all tokens, the tokens themselves stay valid
+    pub fn clear_held(&self) {
+        for mut entry in self.0.iter_mut() {
+            entry.held = HeldMessages::default();
+        }
+    }
+
+    /// Start the grace period for the token once the connection is closed
+    pub fn release(&self, token: &str) {
+        self.0.retain(|_, entry| entry.is_valid());
+        if let Some(mut entry) = self.0.get_mut(token) {
+            entry.expires = Some(Instant::now() + RESUME_GRACE_PERIOD);
+        }
+    }
+
+    /// Save all tokens to a file so clients can resume after a restart
+    ///
+    /// Users are saved by name, since the hashed user ids can differ between builds.
+    /// The tokens can be used to log in as the users, so the file is only readable by the owner.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let snapshot: Vec<SavedToken> = self
+            .0
+            .iter()
+            .filter(|entry| entry.is_valid())
+            .filter_map(|entry| {
+                Some(SavedToken {
+                    token: entry.key().clone(),
+                    user: entry.user.name()?,
+                    held: entry.held,
+                })
+            })
+            .collect();
+        let data = serde_json::to_vec(&snapshot)?;
+
+        // write to a temporary file first, so a crash while saving doesn't leave a partial state behind
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .and_then(|mut file| file.write_all(&data))
+            .wrap_err_with(|| format!("Failed to write state to {}", temp_path.display()))?;
+        fs::rename(&temp_path, path)
+            .wrap_err_with(|| format!("Failed to write state to {}", path.display()))?;
+        log::info!("Saved {} resume tokens", snapshot.len());
+        Ok(())
+    }
+
+    /// Load tokens saved before a restart, the grace period for all loaded tokens starts now
+    pub fn restore(&self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let data = fs::read(path)
+            .wrap_err_with(|| format!("Failed to read state from {}", path.display()))?;
+        fs::remove_file(path).ok();
+        let snapshot: Vec<SavedToken> =
+            serde_json::from_slice(&data).wrap_err("Failed to parse saved state")?;
+        let expires = Instant::now() + RESUME_GRACE_PERIOD;
+        log::info!("Restored {} resume tokens", snapshot.len());
+        for saved in snapshot {
+            self.0.insert(
+                saved.token,
+                ResumeEntry {
+                    user: UserId::new(&saved.user),
+                    expires: Some(expires),
+                    held: saved.held,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedToken {
+    token: String,
+    /// The user name
+    user: String,
+    held: HeldMessages,
+}
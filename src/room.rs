@@ -0,0 +1,212 @@
+use crate::admin::random_token;
+use crate::redis::WriteCommand;
+use crate::{App, UserId};
+use ahash::RandomState;
+use color_eyre::{eyre::WrapErr, Result};
+use dashmap::DashMap;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use warp::filters::ws::Message;
+
+/// Maximum number of rooms a single connection can join
+pub const MAX_ROOMS: usize = 32;
+pub const MAX_ROOM_LENGTH: usize = 128;
+/// Maximum size of a relayed payload in bytes
+pub const MAX_RELAY_SIZE: usize = 4096;
+
+/// How long the answer from Nextcloud about whether a user can join a room is used
+const ACCESS_CACHE_TIME: Duration = Duration::from_secs(300);
+
+static NEXT_MEMBER_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Error)]
+pub enum RoomError {
+    #[error("rooms are not enabled")]
+    Disabled,
+    #[error("access to room denied")]
+    AccessDenied,
+    #[error("too many rooms")]
+    TooManyRooms,
+    #[error("not in room")]
+    NotJoined,
+    #[error("relay payload too large")]
+    TooLarge,
+}
+
+#[derive(Clone)]
+struct Member {
+    id: u64,
+    tx: mpsc::Sender<Message>,
+}
+
+/// Connections that joined a room, keyed by the opaque room id
+///
+/// Rooms only exist in the push server the connections are made to, ephemeral messages are relayed
+/// directly between the connections without going through redis or Nextcloud.
+#[derive(Default)]
+pub struct Rooms {
+    rooms: DashMap<String, Vec<Member>, RandomState>,
+    access: DashMap<(UserId, String), (bool, Instant), RandomState>,
+}
+
+impl Rooms {
+    fn add(&self, room: &str, member: Member) {
+        self.rooms.entry(room.to_string()).or_default().push(member);
+    }
+
+    fn remove(&self, room: &str, id: u64) {
+        if let Some(mut members) = self.rooms.get_mut(room) {
+            members.retain(|member| member.id != id);
+        }
+        self.rooms.remove_if(room, |_, members| members.is_empty());
+    }
+
+    /// Send a message to every member of the room except the sender, returning the number of connections it was send to
+    ///
+    /// Connections that can't keep up miss the message, relayed messages are only meant for short-lived state.
+    fn relay(&self, room: &str, sender: u64, message: &Message) -> usize {
+        let members = match self.rooms.get(room) {
+            Some(members) => members.clone(),
+            None => return 0,
+        };
+        members
+            .iter()
+            .filter(|member| member.id != sender)
+            .filter(|member| member.tx.try_send(message.clone()).is_ok())
+            .count()
+    }
+
+    pub fn room_count(&self) -> usize {
+        self.rooms.len()
+    }
+
+    fn cached_access(&self, user: &UserId, room: &str) -> Option<bool> {
+        let key = (user.clone(), room.to_string());
+        match self.access.get(&key) {
+            Some(entry) if entry.1.elapsed() < ACCESS_CACHE_TIME => Some(entry.0),
+            _ => None,
+        }
+    }
+}
+
+/// Ask Nextcloud whether the user can join the room
+///
+/// Like the mapping check, the request is authenticated with a short-lived token the push server puts in redis.
+pub async fn check_access(app: &App, user: &UserId, room: &str) -> Result<bool> {
+    if let Some(allowed) = app.rooms.cached_access(user, room) {
+        return Ok(allowed);
+    }
+    let name = match user.name() {
+        Some(name) => name,
+        None => return Ok(false),
+    };
+
+    let token = random_token();
+    let key = format!("notify_push_room_token_{}", token);
+    app.redis_writer
+        .write(vec![WriteCommand::Set {
+            key: key.clone(),
+            value: "1".into(),
+            ttl: Some(60),
+        }])
+        .await
+        .wrap_err("Failed to set room token")?;
+    let result = app
+        .nc_client
+        .get_room_access(&name, room, &token)
+        .await
+        .wrap_err("Failed to get room access from Nextcloud");
+    app.redis_writer
+        .queue(vec![WriteCommand::Del { key }])
+        .await;
+
+    let allowed = result?;
+    app.rooms
+        .access
+        .retain(|_, (_, checked)| checked.elapsed() < ACCESS_CACHE_TIME);
+    app.rooms
+        .access
+        .insert((user.clone(), room.to_string()), (allowed, Instant::now()));
+    Ok(allowed)
+}
+
+/// The rooms joined by a single connection
+pub struct RoomMembership {
+    id: u64,
+    user: UserId,
+    tx: mpsc::Sender<Message>,
+    joined: HashSet<String>,
+}
+
+impl RoomMembership {
+    /// Relayed messages are send to the connection through the sender
+    pub fn new(user: UserId, tx: mpsc::Sender<Message>) -> Self {
+        RoomMembership {
+            id: NEXT_MEMBER_ID.fetch_add(1, Ordering::Relaxed),
+            user,
+            tx,
+            joined: HashSet::new(),
+        }
+    }
+
+    /// Check whether the connection can join another room, before asking Nextcloud for access
+    pub fn can_join(&self, room: &str) -> Result<(), RoomError> {
+        if !self.joined.contains(room) && self.joined.len() >= MAX_ROOMS {
+            Err(RoomError::TooManyRooms)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Join a room the user was granted access to
+    pub fn join(&mut self, rooms: &Rooms, room: String) -> Result<(), RoomError> {
+        self.can_join(&room)?;
+        if !self.joined.contains(&room) {
+            rooms.add(
+                &room,
+                Member {
+                    id: self.id,
+                    tx: self.tx.clone(),
+                },
+            );
+            self.joined.insert(room);
+        }
+        Ok(())
+    }
+
+    pub fn leave(&mut self, rooms: &Rooms, room: &str) {
+        if self.joined.remove(room) {
+            rooms.remove(room, self.id);
+        }
+    }
+
+    pub fn leave_all(&mut self, rooms: &Rooms) {
+        for room in self.joined.drain() {
+            rooms.remove(&room, self.id);
+        }
+    }
+
+    /// Relay a payload to the other connections in the room
+    pub fn relay(&self, rooms: &Rooms, room: &str, payload: &str) -> Result<usize, RoomError> {
+        if !self.joined.contains(room) {
+            return Err(RoomError::NotJoined);
+        }
+        if payload.len() > MAX_RELAY_SIZE {
+            return Err(RoomError::TooLarge);
+        }
+        // payloads that aren't json are relayed as string
+        let payload: Value =
+            serde_json::from_str(payload).unwrap_or_else(|_| Value::String(payload.into()));
+        let message = json!({
+            "type": "relay",
+            "room": room,
+            "user": self.user.name(),
+            "payload": payload,
+        });
+        Ok(rooms.relay(room, self.id, &Message::text(message.to_string())))
+    }
+}
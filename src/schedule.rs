@@ -0,0 +1,213 @@
+use crate::instance::unix_timestamp;
+use crate::message::MessageType;
+use crate::{App, UserId};
+use ahash::RandomState;
+use dashmap::DashMap;
+use futures::future::select;
+use futures::pin_mut;
+use std::mem::take;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tokio::time::interval;
+
+/// Maximum number of messages waiting for their delivery time
+pub const MAX_SCHEDULED: usize = 10_000;
+/// Messages can be scheduled at most a week ahead
+pub const MAX_DELAY: u64 = 7 * 24 * 60 * 60;
+/// How long a due message is kept for users that aren't connected, unless the event sets its own time
+pub const DEFAULT_DELIVERY_TTL: u64 = 60 * 60;
+/// Maximum time a due message can be kept for users that aren't connected
+pub const MAX_DELIVERY_TTL: u64 = 24 * 60 * 60;
+
+/// Number of slots in the timing wheel, one per second
+const WHEEL_SLOTS: usize = 3600;
+
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    #[error("too many scheduled messages")]
+    TooMany,
+    #[error("delivery time is more than {} seconds in the future", MAX_DELAY)]
+    TooFarAhead,
+}
+
+struct Scheduled {
+    deliver_at: u64,
+    ttl: u64,
+    user: UserId,
+    message: MessageType,
+}
+
+/// A message that is due, with the time until which it's kept for users that aren't connected
+pub struct DueMessage {
+    pub user: UserId,
+    pub message: MessageType,
+    pub expires: u64,
+}
+
+/// Timing wheel with a slot for every second, messages further ahead than the wheel wait for more rounds in their slot
+struct TimingWheel {
+    slots: Vec<Vec<Scheduled>>,
+    /// The last second that was handled
+    current: u64,
+    len: usize,
+}
+
+impl TimingWheel {
+    fn new(now: u64) -> Self {
+        TimingWheel {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            current: now,
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, scheduled: Scheduled) {
+        // messages for seconds that were already handled go in the next slot
+        let second = scheduled.deliver_at.max(self.current + 1);
+        self.slots[second as usize % WHEEL_SLOTS].push(scheduled);
+        self.len += 1;
+    }
+
+    /// Remove all messages that are due at or before `now`
+    fn advance(&mut self, now: u64, due: &mut Vec<Scheduled>) {
+        // after a long pause every slot is visited once
+        let start = (self.current + 1).max(now.saturating_sub(WHEEL_SLOTS as u64 - 1));
+        for second in start..=now {
+            let slot = &mut self.slots[second as usize % WHEEL_SLOTS];
+            if slot.is_empty() {
+                continue;
+            }
+            let (ready, waiting) = take(slot)
+                .into_iter()
+                .partition(|scheduled| scheduled.deliver_at <= now);
+            *slot = waiting;
+            self.len -= ready.len();
+            due.extend(ready);
+        }
+        self.current = self.current.max(now);
+    }
+}
+
+/// Messages scheduled for later delivery
+///
+/// Once a message is due it's send to the connections of the user, users that aren't connected
+/// get the message when they connect before it expires.
+pub struct Schedule {
+    wheel: Mutex<TimingWheel>,
+    missed: DashMap<UserId, Vec<(u64, MessageType)>, RandomState>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            wheel: Mutex::new(TimingWheel::new(unix_timestamp())),
+            missed: DashMap::default(),
+        }
+    }
+}
+
+impl Schedule {
+    /// Schedule a message for the user at a unix timestamp, `ttl` is the time in seconds the message
+    /// is kept after the delivery time if the user isn't connected
+    pub fn schedule(
+        &self,
+        user: UserId,
+        message: MessageType,
+        deliver_at: u64,
+        ttl: Option<u64>,
+    ) -> Result<(), ScheduleError> {
+        if deliver_at > unix_timestamp() + MAX_DELAY {
+            return Err(ScheduleError::TooFarAhead);
+        }
+        let mut wheel = self.wheel.lock().unwrap();
+        if wheel.len >= MAX_SCHEDULED {
+            return Err(ScheduleError::TooMany);
+        }
+        wheel.insert(Scheduled {
+            deliver_at,
+            ttl: ttl.unwrap_or(DEFAULT_DELIVERY_TTL).min(MAX_DELIVERY_TTL),
+            user,
+            message,
+        });
+        Ok(())
+    }
+
+    pub fn scheduled_count(&self) -> usize {
+        self.wheel.lock().unwrap().len
+    }
+
+    /// Take the messages that are due
+    pub fn due(&self, now: u64) -> Vec<DueMessage> {
+        let mut due = Vec::new();
+        self.wheel.lock().unwrap().advance(now, &mut due);
+        due.into_iter()
+            .map(|scheduled| DueMessage {
+                user: scheduled.user,
+                message: scheduled.message,
+                expires: scheduled.deliver_at + scheduled.ttl,
+            })
+            .collect()
+    }
+
+    /// Keep a due message until the user connects
+    ///
+    /// Tagged messages and messages for a topic are dropped, new connections didn't subscribe to any yet.
+    pub fn hold(&self, due: DueMessage) {
+        if let MessageType::Custom(_, _, None, None) = due.message {
+            self.missed
+                .entry(due.user)
+                .or_default()
+                .push((due.expires, due.message));
+        }
+    }
+
+    /// Take the held messages for a user that just connected
+    pub fn take_missed(&self, user: &UserId) -> Vec<MessageType> {
+        let now = unix_timestamp();
+        self.missed
+            .remove(user)
+            .map(|(_, messages)| {
+                messages
+                    .into_iter()
+                    .filter(|(expires, _)| *expires >= now)
+                    .map(|(_, message)| message)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn expire_missed(&self, now: u64) {
+        self.missed.retain(|_, messages| {
+            messages.retain(|(expires, _)| *expires >= now);
+            !messages.is_empty()
+        });
+    }
+}
+
+/// Deliver scheduled messages once they are due
+pub async fn schedule_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
+    let loop_ = async move {
+        let mut interval = interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let now = unix_timestamp();
+            for due in app.schedule.due(now) {
+                if app.connections.presence().get(&due.user).is_some() {
+                    app.connections.send_to_user(&due.user, due.message).await;
+                } else {
+                    log::debug!(
+                        "Holding scheduled {} until {} connects",
+                        due.message,
+                        due.user
+                    );
+                    app.schedule.hold(due);
+                }
+            }
+            app.schedule.expire_missed(now);
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
@@ -0,0 +1,92 @@
+use serde::Serialize;
+use sqlx::{Any, AnyPool};
+use thiserror::Error;
+
+/// Oldest Nextcloud version with a database layout the push server can use
+pub const MIN_NEXTCLOUD_VERSION: u32 = 9;
+
+/// Layout of the Nextcloud tables the push server queries, detected on startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Schema {
+    /// Major version of the Nextcloud installation the database belongs to
+    pub version: u32,
+    /// Whether the mounts table stores where each storage is mounted, older versions only store the root of the mount
+    pub mount_point: bool,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Schema {
+            version: MIN_NEXTCLOUD_VERSION,
+            mount_point: true,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("no Nextcloud installation found in the database, check the database name and table prefix `{prefix}`: {source}")]
+    NotNextcloud { prefix: String, source: sqlx::Error },
+    #[error("invalid Nextcloud version {0} in the database")]
+    InvalidVersion(String),
+    #[error(
+        "Nextcloud {0} is not supported, the push server requires at least Nextcloud {min}",
+        min = MIN_NEXTCLOUD_VERSION
+    )]
+    Unsupported(String),
+    #[error("the {table} table doesn't have the expected columns, make sure the Nextcloud database is upgraded: {source}")]
+    MissingColumns {
+        table: &'static str,
+        source: sqlx::Error,
+    },
+}
+
+/// Detect the Nextcloud version and the layout of the tables used to resolve storages to users
+pub async fn probe_schema(connection: &AnyPool, prefix: &str) -> Result<Schema, SchemaError> {
+    let installed = sqlx::query_scalar::<Any, String>(&format!(
+        "SELECT configvalue FROM {}appconfig WHERE appid = 'core' AND configkey = 'installedversion'",
+        prefix
+    ))
+    .fetch_one(connection)
+    .await
+    .map_err(|source| SchemaError::NotNextcloud {
+        prefix: prefix.to_string(),
+        source,
+    })?;
+
+    let version: u32 = installed
+        .split('.')
+        .next()
+        .and_then(|major| major.parse().ok())
+        .ok_or_else(|| SchemaError::InvalidVersion(installed.clone()))?;
+    if version < MIN_NEXTCLOUD_VERSION {
+        return Err(SchemaError::Unsupported(installed));
+    }
+
+    check_columns(connection, prefix, "filecache", "fileid, path").await?;
+    check_columns(connection, prefix, "mounts", "storage_id, root_id, user_id").await?;
+    let mount_point = check_columns(connection, prefix, "mounts", "mount_point")
+        .await
+        .is_ok();
+
+    Ok(Schema {
+        version,
+        mount_point,
+    })
+}
+
+async fn check_columns(
+    connection: &AnyPool,
+    prefix: &str,
+    table: &'static str,
+    columns: &str,
+) -> Result<(), SchemaError> {
+    sqlx::query(&format!(
+        "SELECT {} FROM {}{} WHERE 1 = 0",
+        columns, prefix, table
+    ))
+    .fetch_optional(connection)
+    .await
+    .map(|_| ())
+    .map_err(|source| SchemaError::MissingColumns { table, source })
+}
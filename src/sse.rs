@@ -0,0 +1,201 @@
+use crate::auth::AuthError;
+use crate::connection::authenticate_request;
+use crate::control::ControlMessage;
+use crate::disconnect::DisconnectReason;
+use crate::message::{DebounceMap, HeldMessages};
+use crate::metrics::{MetricsBatch, METRICS};
+use crate::observer::DaemonEvent;
+use crate::protocol::ConnectionOptions;
+use crate::{App, UserId};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::spawn;
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+use warp::sse::Event;
+use warp::Reply;
+
+/// Credentials for an event stream, either basic auth or a pre-auth token in the query
+#[derive(Debug, Default, Deserialize)]
+pub struct SseQuery {
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// Serve push messages as server-sent events for clients that can't use websockets
+///
+/// Messages are always send in the format of protocol version 2 and go through the same debouncing and limits
+/// as websocket connections, since clients can't send commands there are no tags or resume tokens.
+pub async fn handle_sse(
+    app: Arc<App>,
+    authorization: Option<String>,
+    query: SseQuery,
+    forwarded_for: Vec<IpAddr>,
+) -> Result<impl Reply, Infallible> {
+    let client_ip = forwarded_for.first().copied();
+    let (user_id, held) =
+        match authenticate_request(&app, authorization, query.token, forwarded_for).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => {
+                e.record("event stream");
+                let status = e.status();
+                return Ok(warp::reply::with_status(format!("err: {}", e), status).into_response());
+            }
+        };
+    log::info!(target: "notify_push::auth", "new event stream authenticated as {}", user_id);
+
+    let keep_alive = app.fallback_keep_alive.unwrap_or(app.ping.interval);
+    let (tx, rx) = mpsc::channel::<Result<Event, Infallible>>(8);
+    spawn(async move {
+        METRICS.add_event_stream();
+        stream_messages(&app, user_id, held, client_ip, tx, |item| {
+            Ok(match item {
+                StreamItem::Message(json) => Event::default().data(json.to_string()),
+                StreamItem::Error(e) => Event::default()
+                    .event("error")
+                    .data(e.to_json().to_string()),
+            })
+        })
+        .await;
+        METRICS.remove_event_stream();
+    });
+
+    let stream = warp::sse::keep_alive()
+        .interval(keep_alive)
+        .stream(ReceiverStream::new(rx));
+    Ok(warp::sse::reply(stream).into_response())
+}
+
+/// An item send over a one-way stream of messages
+pub(crate) enum StreamItem {
+    /// A message in the format of protocol version 2
+    Message(Value),
+    /// The stream was rejected, this is the last item send
+    Error(AuthError),
+}
+
+/// Forward messages for the user until the client disconnects
+///
+/// Used for every transport where the client can't send commands, `encode` converts the items to the format of the transport.
+pub(crate) async fn stream_messages<T>(
+    app: &App,
+    user_id: UserId,
+    held: HeldMessages,
+    client_ip: Option<IpAddr>,
+    tx: mpsc::Sender<T>,
+    encode: impl Fn(StreamItem) -> T,
+) {
+    let _permit = match app.limits.admit(&user_id, client_ip) {
+        Ok(permit) => permit,
+        Err(e) => {
+            let e = AuthError::from(e);
+            e.record(&format!("event stream for {}", user_id));
+            tx.send(encode(StreamItem::Error(e))).await.ok();
+            return;
+        }
+    };
+
+    let mut rx = app.connections.add(user_id.clone()).await;
+    for msg in app.schedule.take_missed(&user_id) {
+        tx.send(encode(StreamItem::Message(msg.to_json())))
+            .await
+            .ok();
+    }
+    for msg in app.connections.take_offline(&user_id).await {
+        tx.send(encode(StreamItem::Message(msg.to_json())))
+            .await
+            .ok();
+    }
+    app.connections.presence().connect(&user_id);
+    METRICS.add_connection();
+    app.observer
+        .emit(|| DaemonEvent::ConnectionOpened(user_id.clone()));
+
+    let options = ConnectionOptions::default();
+    let mut debounce = DebounceMap::with_held(held);
+    let mut rate = app.limits.message_rate(&user_id);
+    let mut control = app.control_rx();
+    let mut metrics = MetricsBatch::default();
+
+    let reason = loop {
+        let mut send = Vec::new();
+        let rate_ready = rate.ready_at().map(Instant::from_std);
+        tokio::select! {
+            msg = timeout(app.ping.interval, rx.recv()) => {
+                match msg {
+                    Ok(Ok(msg)) if !options.accepts(&msg) => {}
+                    Ok(Ok(msg)) => {
+                        if !debounce.should_send(&msg) {
+                            app.observer.emit(|| DaemonEvent::MessageDebounced(user_id.clone(), msg));
+                        } else {
+                            send.extend(rate.admit(msg));
+                        }
+                    }
+                    Err(_timeout) => {
+                        metrics.flush();
+                        for msg in debounce.get_held_messages() {
+                            if debounce.should_send(&msg) {
+                                send.push(msg);
+                            }
+                        }
+                    }
+                    Ok(Err(RecvError::Lagged(count))) => {
+                        METRICS.add_lagged_messages(&user_id, count);
+                        let sync = json!({"type": "sync_recommended", "dropped": count});
+                        tx.send(encode(StreamItem::Message(sync))).await.ok();
+                        app.observer.emit(|| DaemonEvent::MessagesDropped(user_id.clone(), count));
+                    }
+                    Ok(Err(RecvError::Closed)) => {}
+                }
+            },
+            Ok(message) = control.recv() => {
+                match message {
+                    ControlMessage::Action(action, _) if message.applies_to(&user_id) => {
+                        tx.send(encode(StreamItem::Message(action.to_json()))).await.ok();
+                        if action.closes_connection() {
+                            break DisconnectReason::ServerReset;
+                        }
+                    }
+                    _ if message.applies_to(&user_id) => break DisconnectReason::ServerReset,
+                    _ => {}
+                }
+            },
+            _ = sleep_until(rate_ready.unwrap_or_else(Instant::now)), if rate_ready.is_some() => {
+                send.extend(rate.take_ready());
+                let dropped = rate.take_dropped();
+                if dropped > 0 {
+                    let sync = json!({"type": "sync_recommended", "dropped": dropped});
+                    tx.send(encode(StreamItem::Message(sync))).await.ok();
+                    app.observer.emit(|| DaemonEvent::MessagesDropped(user_id.clone(), dropped));
+                }
+            },
+            _ = tx.closed() => break DisconnectReason::Closed,
+        }
+
+        for msg in send {
+            log::debug!(target: "notify_push::send", "Sending {} to {} as event", msg, user_id);
+            metrics.add_message();
+            if tx
+                .send(encode(StreamItem::Message(msg.to_json())))
+                .await
+                .is_err()
+            {
+                break;
+            }
+            app.observer
+                .emit(|| DaemonEvent::MessageDelivered(user_id.clone(), msg));
+        }
+    };
+
+    log::debug!("event stream for {} closed: {}", user_id, reason);
+    METRICS.add_disconnect(reason);
+    app.connections.disconnect(&user_id);
+    METRICS.remove_connection();
+    app.observer
+        .emit(|| DaemonEvent::ConnectionClosed(user_id, reason));
+}
@@ -0,0 +1,136 @@
+use crate::metrics::{collect_metrics, MetricsFormat, Sample};
+use crate::App;
+use futures::future::select;
+use futures::pin_mut;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::time::interval;
+
+/// How often the metrics are pushed
+const PUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Prefix for all metric names
+const PREFIX: &str = "notify_push";
+
+/// Maximum size of a single datagram, small enough to not be fragmented on common networks
+const MAX_PACKET_SIZE: usize = 1432;
+
+/// Server to push the metrics to, parsed from `statsd://host:port` or `datadog://host:port`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsPush {
+    pub address: String,
+    /// Send the labels as datadog tags instead of appending them to the metric name
+    pub tags: bool,
+}
+
+#[derive(Debug, Error)]
+#[error("invalid metrics push target {0}, expected statsd://host:port or datadog://host:port")]
+pub struct InvalidMetricsPush(String);
+
+impl FromStr for MetricsPush {
+    type Err = InvalidMetricsPush;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let separator = s
+            .find("://")
+            .ok_or_else(|| InvalidMetricsPush(s.to_string()))?;
+        let tags = match &s[..separator] {
+            "statsd" => false,
+            "datadog" => true,
+            _ => return Err(InvalidMetricsPush(s.to_string())),
+        };
+        let address = s[separator + 3..].trim_end_matches('/');
+        if address.is_empty() {
+            return Err(InvalidMetricsPush(s.to_string()));
+        }
+        // ipv6 addresses need to be in brackets
+        let has_port = address.parse::<SocketAddr>().is_ok()
+            || (!address.starts_with('[') && address.matches(':').count() == 1);
+        let address = if has_port {
+            address.to_string()
+        } else {
+            format!("{}:8125", address)
+        };
+        Ok(MetricsPush { address, tags })
+    }
+}
+
+/// The statsd line format, all values are send as gauges since the metrics are totals, not increments
+pub struct Statsd {
+    pub tags: bool,
+}
+
+impl MetricsFormat for Statsd {
+    fn write(&self, sample: &Sample, out: &mut String) {
+        let _ = write!(out, "{}.{}", PREFIX, sample.name);
+        if !self.tags {
+            for (_, value) in &sample.labels {
+                let _ = write!(out, ".{}", value);
+            }
+        }
+        let _ = write!(out, ":{}|g", sample.value);
+        if self.tags && !sample.labels.is_empty() {
+            out.push_str("|#");
+            for (i, (name, value)) in sample.labels.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "{}:{}", name, value);
+            }
+        }
+        out.push('\n');
+    }
+}
+
+/// Push the metrics to a statsd or datadog agent until cancelled
+pub async fn metrics_push_loop(app: Arc<App>, target: MetricsPush, cancel: oneshot::Receiver<()>) {
+    let format = Statsd { tags: target.tags };
+    let loop_ = async move {
+        let mut interval = interval(PUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let lines = collect_metrics(&app).into_iter().map(|sample| {
+                let mut line = String::new();
+                format.write(&sample, &mut line);
+                line
+            });
+            if let Err(e) = push(&target.address, lines).await {
+                log::warn!("Failed to push metrics to {}: {}", target.address, e);
+            }
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
+
+/// Send the lines in as few datagrams as possible
+async fn push(address: &str, lines: impl Iterator<Item = String>) -> std::io::Result<()> {
+    let target = lookup_host(address).await?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "address didn't resolve")
+    })?;
+    let local: IpAddr = if target.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind((local, 0)).await?;
+
+    let mut packet = String::with_capacity(MAX_PACKET_SIZE);
+    for line in lines {
+        if !packet.is_empty() && packet.len() + line.len() > MAX_PACKET_SIZE {
+            socket.send_to(packet.as_bytes(), target).await?;
+            packet.clear();
+        }
+        packet.push_str(&line);
+    }
+    if !packet.is_empty() {
+        socket.send_to(packet.as_bytes(), target).await?;
+    }
+    Ok(())
+}
@@ -1,20 +1,459 @@
 use crate::metrics::METRICS;
+use crate::schema::{probe_schema, Schema};
 use crate::UserId;
 use color_eyre::{eyre::WrapErr, Result};
-use dashmap::mapref::one::Ref;
 use dashmap::DashMap;
+use futures::future::{ready, BoxFuture};
+use futures::FutureExt;
+use md5::{Digest, Md5};
 use rand::{thread_rng, Rng};
-use sqlx::any::AnyConnectOptions;
+use sqlx::any::{AnyConnectOptions, AnyKind};
 use sqlx::{Any, AnyPool, FromRow};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
 use std::time::Instant;
+use thiserror::Error;
+use tokio::spawn;
+use tokio::sync::{Mutex, Notify};
 use tokio::time::Duration;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 
+/// How the path of a storage update is matched against the root of a mount
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathMatchMode {
+    /// The mount root is a plain string prefix of the path
+    Prefix,
+    /// The mount root is either the path itself or one of its parent folders
+    Exact,
+}
+
+impl Default for PathMatchMode {
+    fn default() -> Self {
+        PathMatchMode::Prefix
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid path match mode {0}, expected `prefix` or `exact`")]
+pub struct InvalidPathMatchMode(String);
+
+impl FromStr for PathMatchMode {
+    type Err = InvalidPathMatchMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefix" => Ok(PathMatchMode::Prefix),
+            "exact" => Ok(PathMatchMode::Exact),
+            _ => Err(InvalidPathMatchMode(s.to_string())),
+        }
+    }
+}
+
+/// What to do with storage updates when the users for the storage can't be loaded from the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatabaseErrorStrategy {
+    /// Drop the update
+    Drop,
+    /// Keep the update and handle it once the database is available again
+    Buffer,
+    /// Notify all users that had access to the storage when the mapping was last loaded
+    Cached,
+}
+
+impl Default for DatabaseErrorStrategy {
+    fn default() -> Self {
+        DatabaseErrorStrategy::Drop
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid database error strategy {0}, expected `drop`, `buffer` or `cached`")]
+pub struct InvalidDatabaseErrorStrategy(String);
+
+impl FromStr for DatabaseErrorStrategy {
+    type Err = InvalidDatabaseErrorStrategy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(DatabaseErrorStrategy::Drop),
+            "buffer" => Ok(DatabaseErrorStrategy::Buffer),
+            "cached" => Ok(DatabaseErrorStrategy::Cached),
+            _ => Err(InvalidDatabaseErrorStrategy(s.to_string())),
+        }
+    }
+}
+
+/// Maximum number of storage updates kept while the database is unavailable
+const UPDATE_BUFFER_SIZE: usize = 1024;
+
+/// Storage updates that couldn't be handled because the database was unavailable
+#[derive(Default)]
+pub struct UpdateBuffer(StdMutex<VecDeque<(u32, String)>>);
+
+impl UpdateBuffer {
+    /// Add an update to the buffer, returns false if an older update had to be dropped to make room
+    pub fn push(&self, storage: u32, path: String) -> bool {
+        let mut buffer = self.0.lock().unwrap();
+        if buffer.iter().any(|(buffered_storage, buffered_path)| {
+            *buffered_storage == storage && *buffered_path == path
+        }) {
+            return true;
+        }
+        let has_room = buffer.len() < UPDATE_BUFFER_SIZE;
+        if !has_room {
+            buffer.pop_front();
+        }
+        buffer.push_back((storage, path));
+        has_room
+    }
+
+    pub fn take(&self) -> VecDeque<(u32, String)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PathMatch {
+    pub mode: PathMatchMode,
+    /// Normalize both paths to unicode NFC before comparing
+    pub normalize_unicode: bool,
+    /// Compare paths case-insensitively, for databases using a case-insensitive collation
+    pub case_insensitive: bool,
+}
+
+impl PathMatch {
+    fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let path = if self.normalize_unicode && !is_nfc(path) {
+            Cow::Owned(path.nfc().collect())
+        } else {
+            Cow::Borrowed(path)
+        };
+        if self.case_insensitive {
+            Cow::Owned(path.to_lowercase())
+        } else {
+            path
+        }
+    }
+
+    /// Check if a path falls inside a mount root, both should already be normalized
+    fn matches(&self, root: &str, path: &str) -> bool {
+        match self.mode {
+            PathMatchMode::Prefix => path.starts_with(root),
+            PathMatchMode::Exact => {
+                root.is_empty()
+                    || path == root
+                    || (path.starts_with(root) && path[root.len()..].starts_with('/'))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UserStorageAccess {
+    user: UserId,
+    /// The normalized root, used for matching
+    root: String,
+    /// The root as stored in the database
+    raw_root: String,
+    mount_point: Option<String>,
+}
+
+impl UserStorageAccess {
+    /// Path of a file in the storage as seen by the user, relative to the user's files
+    ///
+    /// Only known for files inside the user's files, e.g. not for trash or versions.
+    /// `normalized` is the path as it was matched against the root, normalizing can change the length of the root.
+    fn user_path(&self, path: &str, normalized: &str) -> Option<String> {
+        let mount_point = self.mount_point.as_deref()?;
+        // keep the path as it was send if it's spelled the same as the root,
+        // otherwise it's only spelled the same after normalizing
+        let relative = match path.strip_prefix(self.raw_root.as_str()) {
+            Some(relative) => relative,
+            None => normalized.get(self.root.len()..)?,
+        };
+        if !self.root.is_empty() && !relative.is_empty() && !relative.starts_with('/') {
+            // prefix match on a sibling folder with a longer name
+            return None;
+        }
+        // mount points look like `/<user>/files/<folder>/`, or `/<user>/` for the home storage
+        let mut parts = mount_point.trim_start_matches('/').splitn(2, '/');
+        parts.next()?;
+        let full = format!(
+            "{}/{}",
+            parts.next().unwrap_or_default().trim_end_matches('/'),
+            relative.trim_start_matches('/')
+        );
+        let in_files = full.trim_start_matches('/').strip_prefix("files")?;
+        if !in_files.is_empty() && !in_files.starts_with('/') {
+            return None;
+        }
+        Some(format!("/{}", in_files.trim_matches('/')))
+    }
+}
+
+/// The current state of a file in the filecache
+#[derive(Debug, Clone, FromRow)]
+pub struct FileChange {
+    pub fileid: i64,
+    pub mtime: i64,
+    pub etag: String,
+}
+
+/// A user with access to a storage and the path of the user's mount root inside the storage
 #[derive(Debug, Clone, FromRow)]
-pub struct UserStorageAccess {
+pub struct MountAccess {
     #[sqlx(rename = "user_id")]
-    user: UserId,
+    pub user: String,
     #[sqlx(rename = "path")]
-    root: String,
+    pub root: String,
+    /// Where the storage is mounted for the user, e.g. `/user/files/Shared/`
+    pub mount_point: Option<String>,
+}
+
+/// Source of the storage mapping and file metadata
+///
+/// [`StorageMapping`] takes care of caching and path matching, backends only need to load the raw mapping.
+pub trait MappingBackend: Send + Sync {
+    /// Load all users with access to the storage
+    fn load_storage_mapping(&self, storage: u32) -> BoxFuture<'_, Result<Vec<MountAccess>>>;
+
+    /// Get the current state of a file, backends without file metadata can always return `None`
+    fn get_file_change<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Option<FileChange>>>;
+
+    /// Load the ids of all users in a group, backends without groups don't have to implement this
+    fn load_group_members<'a>(&'a self, _group: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        ready(Ok(Vec::new())).boxed()
+    }
+
+    /// Check that the backend can be reached, backends without connections don't have to implement this
+    fn ping(&self) -> BoxFuture<'_, Result<()>> {
+        ready(Ok(())).boxed()
+    }
+
+    /// Replace the connections to the backend after it became unreachable, backends without connections don't have to implement this
+    fn reconnect(&self) -> BoxFuture<'_, Result<()>> {
+        ready(Ok(())).boxed()
+    }
+}
+
+/// Load the storage mapping from the Nextcloud database
+pub struct SqlMapping {
+    connection: RwLock<AnyPool>,
+    /// Options to create a new pool with when reconnecting, pools created elsewhere are kept as is
+    options: Option<AnyConnectOptions>,
+    prefix: String,
+    schema: Schema,
+}
+
+impl SqlMapping {
+    /// Mapping for a database with the current Nextcloud schema
+    pub fn new(connection: AnyPool, prefix: String) -> Self {
+        Self::with_schema(connection, prefix, Schema::default())
+    }
+
+    pub fn with_schema(connection: AnyPool, prefix: String, schema: Schema) -> Self {
+        SqlMapping {
+            connection: RwLock::new(connection),
+            options: None,
+            prefix,
+            schema,
+        }
+    }
+
+    /// Recreate the pool with these options when reconnecting
+    pub fn with_connect_options(mut self, options: AnyConnectOptions) -> Self {
+        self.options = Some(options);
+        self
+    }
+
+    fn pool(&self) -> AnyPool {
+        self.connection.read().unwrap().clone()
+    }
+
+    fn mapping_query(&self, storage: u32) -> String {
+        format!(
+            "\
+                SELECT user_id, path{mount_point} \
+                FROM {prefix}mounts \
+                INNER JOIN {prefix}filecache ON root_id = fileid \
+                WHERE storage_id = {storage}",
+            mount_point = if self.schema.mount_point {
+                ", mount_point"
+            } else {
+                ""
+            },
+            prefix = self.prefix,
+            storage = storage
+        )
+    }
+
+    fn group_query(&self) -> String {
+        // unlike storage ids, group ids can contain anything so they're bound as parameter
+        #[allow(unreachable_patterns)]
+        let placeholder = match self.pool().any_kind() {
+            #[cfg(feature = "postgres")]
+            AnyKind::Postgres => "$1",
+            _ => "?",
+        };
+        format!(
+            "SELECT uid FROM {prefix}group_user WHERE gid = {placeholder}",
+            prefix = self.prefix,
+            placeholder = placeholder
+        )
+    }
+}
+
+impl MappingBackend for SqlMapping {
+    fn load_storage_mapping(&self, storage: u32) -> BoxFuture<'_, Result<Vec<MountAccess>>> {
+        async move {
+            log::debug!(target: "notify_push::db", "querying storage mapping for {}", storage);
+            let query = self.mapping_query(storage);
+            let access = if self.schema.mount_point {
+                sqlx::query_as::<Any, MountAccess>(&query)
+                    .fetch_all(&self.pool())
+                    .await
+            } else {
+                // older versions don't store the mount point, so files are only matched by the mount root
+                sqlx::query_as::<Any, (String, String)>(&query)
+                    .fetch_all(&self.pool())
+                    .await
+                    .map(|rows| {
+                        rows.into_iter()
+                            .map(|(user, root)| MountAccess {
+                                user,
+                                root,
+                                mount_point: None,
+                            })
+                            .collect()
+                    })
+            }
+            .wrap_err("Failed to load storage mapping from database")?;
+            METRICS.add_mapping_query();
+            Ok(access)
+        }
+        .boxed()
+    }
+
+    fn get_file_change<'a>(
+        &'a self,
+        storage: u32,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<Option<FileChange>>> {
+        async move {
+            // the hash is plain hex, so it's safe to include in the query directly
+            let path_hash = format!("{:x}", Md5::digest(path.as_bytes()));
+            sqlx::query_as::<Any, FileChange>(&format!(
+                "SELECT fileid, mtime, etag FROM {prefix}filecache WHERE storage = {storage} AND path_hash = '{path_hash}'",
+                prefix = self.prefix,
+                storage = storage,
+                path_hash = path_hash,
+            ))
+            .fetch_optional(&self.pool())
+            .await
+            .wrap_err("Failed to load file from database")
+        }
+        .boxed()
+    }
+
+    fn load_group_members<'a>(&'a self, group: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        async move {
+            log::debug!(target: "notify_push::db", "querying members of group {}", group);
+            let members = sqlx::query_scalar::<Any, String>(&self.group_query())
+                .bind(group)
+                .fetch_all(&self.pool())
+                .await
+                .wrap_err("Failed to load group members from database")?;
+            METRICS.add_mapping_query();
+            Ok(members)
+        }
+        .boxed()
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<()>> {
+        async move {
+            sqlx::query("SELECT 1")
+                .execute(&self.pool())
+                .await
+                .wrap_err("Failed to query database")?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn reconnect(&self) -> BoxFuture<'_, Result<()>> {
+        async move {
+            // pools created elsewhere are only checked, they open new connections by themselves once the database is back
+            let options = match &self.options {
+                Some(options) => options.clone(),
+                None => return Ok(()),
+            };
+            let pool = AnyPool::connect_with(options)
+                .await
+                .wrap_err("Failed to connect to Nextcloud database")?;
+            let old = std::mem::replace(&mut *self.connection.write().unwrap(), pool);
+            // queries still running on the old pool can finish
+            spawn(async move { old.close().await });
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Fixed storage mapping without any file metadata, mainly intended for testing
+#[derive(Debug, Clone, Default)]
+pub struct StaticMapping {
+    storages: HashMap<u32, Vec<MountAccess>>,
+    groups: HashMap<String, Vec<String>>,
+}
+
+impl StaticMapping {
+    pub fn new(storages: HashMap<u32, Vec<MountAccess>>) -> Self {
+        StaticMapping {
+            storages,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Set the members of each group, by user id
+    pub fn with_groups(mut self, groups: HashMap<String, Vec<String>>) -> Self {
+        self.groups = groups;
+        self
+    }
+}
+
+impl MappingBackend for StaticMapping {
+    fn load_storage_mapping(&self, storage: u32) -> BoxFuture<'_, Result<Vec<MountAccess>>> {
+        let access = self.storages.get(&storage).cloned().unwrap_or_default();
+        ready(Ok(access)).boxed()
+    }
+
+    fn get_file_change<'a>(
+        &'a self,
+        _storage: u32,
+        _path: &'a str,
+    ) -> BoxFuture<'a, Result<Option<FileChange>>> {
+        ready(Ok(None)).boxed()
+    }
+
+    fn load_group_members<'a>(&'a self, group: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+        let members = self.groups.get(group).cloned().unwrap_or_default();
+        ready(Ok(members)).boxed()
+    }
 }
 
 struct CachedAccess {
@@ -23,12 +462,18 @@ struct CachedAccess {
 }
 
 impl CachedAccess {
-    pub fn new(access: Vec<UserStorageAccess>) -> Self {
-        let mut rng = thread_rng();
+    pub fn new(access: Vec<MountAccess>, path_match: &PathMatch) -> Self {
         Self {
-            access,
-            valid_till: Instant::now()
-                + Duration::from_millis(rng.gen_range((4 * 60 * 1000)..(5 * 60 * 1000))),
+            access: access
+                .into_iter()
+                .map(|access| UserStorageAccess {
+                    root: path_match.normalize(&access.root).into_owned(),
+                    raw_root: access.root,
+                    user: UserId::new(&access.user),
+                    mount_point: access.mount_point,
+                })
+                .collect(),
+            valid_till: cache_expiry(),
         }
     }
 
@@ -37,38 +482,163 @@ impl CachedAccess {
     }
 }
 
+struct CachedGroup {
+    members: Vec<UserId>,
+    valid_till: Instant,
+}
+
+/// Cached entries expire after 4 to 5 minutes, spread out so they don't all expire at once
+fn cache_expiry() -> Instant {
+    Instant::now() + Duration::from_millis(thread_rng().gen_range((4 * 60 * 1000)..(5 * 60 * 1000)))
+}
+
+/// Returned instead of querying the database while it's being reconnected
+#[derive(Debug, Error)]
+#[error("The database is unavailable, reconnecting")]
+pub struct DatabaseUnavailable;
+
 pub struct StorageMapping {
-    cache: DashMap<u32, CachedAccess>,
-    connection: AnyPool,
-    prefix: String,
+    /// Shared so a lookup can keep using a mapping that gets invalidated while it's being used
+    cache: DashMap<u32, Arc<CachedAccess>>,
+    groups: DashMap<String, CachedGroup>,
+    /// Locks for storages that are currently being loaded from the database
+    loading: DashMap<u32, Arc<Mutex<()>>>,
+    backend: Box<dyn MappingBackend>,
+    path_match: PathMatch,
+    /// Cleared while the database is being reconnected, so updates fail fast instead of waiting for a connection
+    available: AtomicBool,
+    /// Notified when a query fails, to check if the database is still reachable
+    failed: Notify,
 }
 
 impl StorageMapping {
-    pub async fn from_connection(connection: AnyPool, prefix: String) -> Result<Self> {
-        Ok(StorageMapping {
+    /// Use a custom backend to resolve storages to users
+    pub fn with_backend(backend: impl MappingBackend + 'static, path_match: PathMatch) -> Self {
+        StorageMapping {
             cache: Default::default(),
-            connection,
-            prefix,
-        })
+            groups: Default::default(),
+            loading: Default::default(),
+            backend: Box::new(backend),
+            path_match,
+            available: AtomicBool::new(true),
+            failed: Notify::new(),
+        }
+    }
+
+    /// Use the Nextcloud database, failing if the database schema isn't supported
+    pub async fn from_connection(
+        connection: AnyPool,
+        prefix: String,
+        path_match: PathMatch,
+    ) -> Result<Self> {
+        Ok(Self::with_backend(
+            Self::sql_mapping(connection, prefix).await?,
+            path_match,
+        ))
     }
 
-    pub async fn new(options: AnyConnectOptions, prefix: String) -> Result<Self> {
-        let connection = AnyPool::connect_with(options)
+    async fn sql_mapping(connection: AnyPool, prefix: String) -> Result<SqlMapping> {
+        let schema = probe_schema(&connection, &prefix).await?;
+        log::info!(target: "notify_push::db", "Detected database schema {:?}", schema);
+        Ok(SqlMapping::with_schema(connection, prefix, schema))
+    }
+
+    pub async fn new(
+        options: AnyConnectOptions,
+        prefix: String,
+        path_match: PathMatch,
+    ) -> Result<Self> {
+        let connection = AnyPool::connect_with(options.clone())
             .await
             .wrap_err("Failed to connect to Nextcloud database")?;
 
-        Self::from_connection(connection, prefix).await
+        Ok(Self::with_backend(
+            Self::sql_mapping(connection, prefix)
+                .await?
+                .with_connect_options(options),
+            path_match,
+        ))
+    }
+
+    /// Whether the backend is reachable, false while reconnecting
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    pub fn set_available(&self, available: bool) {
+        self.available.store(available, Ordering::Relaxed);
+    }
+
+    /// Wait until a query to the backend fails
+    pub async fn failed(&self) {
+        self.failed.notified().await
     }
 
-    async fn get_storage_mapping(&self, storage: u32) -> Result<Ref<'_, u32, CachedAccess>> {
-        if let Some(cached) = self.cache.get(&storage).filter(|cached| cached.is_valid()) {
-            Ok(cached)
+    fn ensure_available(&self) -> Result<()> {
+        if self.is_available() {
+            Ok(())
         } else {
-            let users = self.load_storage_mapping(storage).await?;
+            Err(DatabaseUnavailable.into())
+        }
+    }
+
+    fn report_failure<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.failed.notify_one();
+        }
+        result
+    }
+
+    fn cached_mapping(&self, storage: u32) -> Option<Arc<CachedAccess>> {
+        self.cache
+            .get(&storage)
+            .filter(|cached| cached.is_valid())
+            .map(|cached| cached.value().clone())
+    }
+
+    async fn get_storage_mapping(&self, storage: u32) -> Result<Arc<CachedAccess>> {
+        if let Some(cached) = self.cached_mapping(storage) {
+            return Ok(cached);
+        }
+
+        // only a single task loads the mapping for a storage at a time,
+        // any other task requesting the same storage waits for it and uses the freshly cached result
+        let lock = self.loading.entry(storage).or_default().clone();
+        let guard = lock.lock().await;
 
-            self.cache.insert(storage, CachedAccess::new(users));
-            Ok(self.cache.get(&storage).unwrap())
+        if let Some(cached) = self.cached_mapping(storage) {
+            return Ok(cached);
         }
+
+        let loaded = match self.ensure_available() {
+            Ok(()) => self.report_failure(self.backend.load_storage_mapping(storage).await),
+            Err(e) => Err(e),
+        }
+        .map(|users| {
+            let cached = Arc::new(CachedAccess::new(users, &self.path_match));
+            self.cache.insert(storage, cached.clone());
+            cached
+        });
+        // nobody else is waiting for the lock if only the map and we are holding it,
+        // anyone arriving after it's removed creates a new lock, so it can only be removed once we're done loading
+        drop(guard);
+        self.loading
+            .remove_if(&storage, |_, lock| Arc::strong_count(lock) <= 2);
+
+        // the cached entry can already be invalidated again, so the loaded mapping is returned directly
+        loaded
+    }
+
+    /// Check that the backend can be reached, bypassing the cache
+    pub async fn check_backend(&self) -> Result<()> {
+        self.ensure_available()?;
+        self.backend.ping().await
+    }
+
+    /// Replace the connections to the backend and check that it can be reached again
+    pub async fn reconnect(&self) -> Result<()> {
+        self.backend.reconnect().await?;
+        self.backend.ping().await
     }
 
     pub async fn get_users_for_storage_path(
@@ -77,11 +647,12 @@ impl StorageMapping {
         path: &str,
     ) -> Result<impl Iterator<Item = UserId>> {
         let cached = self.get_storage_mapping(storage).await?;
+        let path = self.path_match.normalize(path);
         Ok(cached
             .access
             .iter()
-            .filter_map(move |access| {
-                if path.starts_with(&access.root) {
+            .filter_map(|access| {
+                if self.path_match.matches(&access.root, &path) {
                     Some(access.user.clone())
                 } else {
                     None
@@ -91,22 +662,106 @@ impl StorageMapping {
             .into_iter())
     }
 
-    async fn load_storage_mapping(&self, storage: u32) -> Result<Vec<UserStorageAccess>> {
-        log::debug!("querying storage mapping for {}", storage);
-        let users = sqlx::query_as::<Any, UserStorageAccess>(&format!(
-            "\
-                SELECT user_id, path \
-                FROM {prefix}mounts \
-                INNER JOIN {prefix}filecache ON root_id = fileid \
-                WHERE storage_id = {storage}",
-            prefix = self.prefix,
-            storage = storage
-        ))
-        .fetch_all(&self.connection)
-        .await
-        .wrap_err("Failed to load storage mapping from database")?;
-        METRICS.add_mapping_query();
+    /// Get all users with access to a storage path, together with the path of the file as seen by the user if it's known
+    pub async fn get_user_paths_for_storage_path(
+        &self,
+        storage: u32,
+        path: &str,
+    ) -> Result<Vec<(UserId, Option<String>)>> {
+        let cached = self.get_storage_mapping(storage).await?;
+        let normalized = self.path_match.normalize(path);
+        Ok(cached
+            .access
+            .iter()
+            .filter(|access| self.path_match.matches(&access.root, &normalized))
+            .map(|access| (access.user.clone(), access.user_path(path, &normalized)))
+            .collect())
+    }
 
+    /// Get all users that had access to the storage when the mapping was last loaded, even if the cached mapping expired
+    pub fn get_stale_users_for_storage(&self, storage: u32) -> Option<HashSet<UserId>> {
+        self.cache.get(&storage).map(|cached| {
+            cached
+                .access
+                .iter()
+                .map(|access| access.user.clone())
+                .collect()
+        })
+    }
+
+    /// Drop the cached mapping for a storage, so it's loaded again on the next update for the storage
+    pub fn invalidate_storage(&self, storage: u32) {
+        self.cache.remove(&storage);
+    }
+
+    /// Drop the cached mappings of all storages the user has access to
+    pub fn invalidate_user(&self, user: &UserId) {
+        self.cache
+            .retain(|_, cached| !cached.access.iter().any(|access| &access.user == user));
+    }
+
+    /// Get the members of a group, the members are cached the same way as the storage mappings
+    pub async fn get_group_members(&self, group: &str) -> Result<Vec<UserId>> {
+        if let Some(cached) = self
+            .groups
+            .get(group)
+            .filter(|cached| cached.valid_till > Instant::now())
+        {
+            return Ok(cached.members.clone());
+        }
+        self.ensure_available()?;
+        let members: Vec<UserId> = self
+            .report_failure(self.backend.load_group_members(group).await)?
+            .iter()
+            .map(|user| UserId::new(user))
+            .collect();
+        self.groups.insert(
+            group.to_string(),
+            CachedGroup {
+                members: members.clone(),
+                valid_till: cache_expiry(),
+            },
+        );
+        Ok(members)
+    }
+
+    /// Drop the cached members of a group, so they're loaded again on the next message for the group
+    pub fn invalidate_group(&self, group: &str) {
+        self.groups.remove(group);
+    }
+
+    /// Drop all cached mappings
+    pub fn invalidate_all(&self) {
+        self.cache.clear();
+        self.groups.clear();
+    }
+
+    /// Get the names of all users with access to a storage path, bypassing the cache
+    ///
+    /// This is intended for debugging the mapping, use `get_users_for_storage_path` for anything else
+    pub async fn get_user_names_for_storage_path(
+        &self,
+        storage: u32,
+        path: &str,
+    ) -> Result<Vec<String>> {
+        let access = self.backend.load_storage_mapping(storage).await?;
+
+        let path = self.path_match.normalize(path);
+        let mut users: Vec<String> = access
+            .into_iter()
+            .filter(|access| {
+                self.path_match
+                    .matches(&self.path_match.normalize(&access.root), &path)
+            })
+            .map(|access| access.user)
+            .collect();
+        users.sort();
+        users.dedup();
         Ok(users)
     }
+
+    pub async fn get_file_change(&self, storage: u32, path: &str) -> Result<Option<FileChange>> {
+        self.ensure_available()?;
+        self.report_failure(self.backend.get_file_change(storage, path).await)
+    }
 }
@@ -0,0 +1,256 @@
+//! Helpers for testing clients and embedders against a push server without a Nextcloud instance, database or redis
+//!
+//! Only available with the `testing` feature.
+//!
+//! ```no_run
+//! # async fn example() -> color_eyre::Result<()> {
+//! use notify_push::testing::{MockNextcloud, TestServer};
+//!
+//! let nextcloud = MockNextcloud::start().await?;
+//! nextcloud.add_user("foo", "bar");
+//!
+//! let server = TestServer::start(nextcloud.app(Default::default()).await?).await?;
+//! let mut client = server.connect_auth("foo", "bar").await?;
+//!
+//! server.publish("notify_activity", r#"{"user":"foo"}"#).await?;
+//! client.assert_next("notify_activity").await;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::config::{Bind, Config};
+use crate::daemon::Daemon;
+use crate::event::Event;
+use crate::storage_mapping::{MountAccess, PathMatch, StaticMapping, StorageMapping};
+use crate::App;
+use color_eyre::{eyre::WrapErr, Report, Result};
+use dashmap::DashMap;
+use flexi_logger::{Logger, LoggerHandle};
+use futures::{FutureExt, SinkExt, StreamExt};
+use http_auth_basic::Credentials;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+/// How long to wait for a message before a client assertion fails
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_millis(200);
+
+static LOG_HANDLE: Lazy<LoggerHandle> =
+    Lazy::new(|| Logger::try_with_str("").unwrap().start().unwrap());
+
+/// Logger handle for apps created in tests
+///
+/// Only one logger can be started per process, tests that create apps themselves should use this handle too.
+pub fn log_handle() -> LoggerHandle {
+    LOG_HANDLE.clone()
+}
+
+/// A fake Nextcloud server that only verifies the credentials of the configured users
+pub struct MockNextcloud {
+    addr: SocketAddr,
+    users: Arc<DashMap<String, String>>,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl MockNextcloud {
+    pub async fn start() -> Result<Self> {
+        let tcp = TcpListener::bind("127.0.0.1:0")
+            .await
+            .wrap_err("Failed to bind nextcloud mock")?;
+        let addr = tcp.local_addr()?;
+        let users: Arc<DashMap<String, String>> = Arc::default();
+
+        let users_filter = users.clone();
+        let uid = warp::any()
+            .and(warp::header::<String>("authorization"))
+            .map(move |auth| {
+                let credentials = match Credentials::from_header(auth) {
+                    Ok(credentials) => credentials,
+                    Err(_) => return Box::new(StatusCode::BAD_REQUEST) as Box<dyn Reply>,
+                };
+                match users_filter.get(&credentials.user_id) {
+                    Some(pass) if pass.value() == &credentials.password => {
+                        Box::new(credentials.user_id)
+                    }
+                    _ => Box::new(StatusCode::UNAUTHORIZED),
+                }
+            });
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        tokio::spawn(warp::serve(uid).serve_incoming_with_graceful_shutdown(
+            TcpListenerStream::new(tcp),
+            shutdown_rx.map(|_| ()),
+        ));
+
+        Ok(MockNextcloud {
+            addr,
+            users,
+            _shutdown: shutdown,
+        })
+    }
+
+    pub fn add_user(&self, username: &str, password: &str) {
+        self.users.insert(username.into(), password.into());
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /// Config pointing to the mock with the defaults for everything else
+    ///
+    /// The configured redis server isn't expected to exist, events are fed in with [`TestServer::dispatch`] instead.
+    pub fn config(&self) -> Result<Config> {
+        Config::with_defaults(
+            "sqlite::memory:".parse()?,
+            vec!["redis://127.0.0.1".parse()?],
+            self.url(),
+        )
+    }
+
+    /// App using the mock with the storages mapped to the users that can access them
+    pub async fn app(&self, storages: HashMap<u32, Vec<MountAccess>>) -> Result<App> {
+        let storage_mapping =
+            StorageMapping::with_backend(StaticMapping::new(storages), PathMatch::default());
+        App::with_storage_mapping(storage_mapping, self.config()?, log_handle()).await
+    }
+}
+
+/// A push server listening on a local port
+pub struct TestServer {
+    daemon: Daemon,
+    addr: SocketAddr,
+}
+
+impl TestServer {
+    /// Serve the app, the redis listener isn't started
+    pub async fn start(app: App) -> Result<Self> {
+        Self::start_shared(Arc::new(app)).await
+    }
+
+    pub async fn start_shared(app: Arc<App>) -> Result<Self> {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .wrap_err("Failed to find a free port")?
+            .local_addr()?;
+
+        let mut daemon = Daemon::new(app);
+        daemon.start_server(Bind::Tcp(vec![addr]), None).await?;
+
+        // give the server a chance to bind
+        sleep(Duration::from_millis(10)).await;
+
+        Ok(TestServer { daemon, addr })
+    }
+
+    pub fn app(&self) -> &Arc<App> {
+        self.daemon.app()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Handle an event as if it was received from redis
+    pub async fn dispatch(&self, event: Event) {
+        self.app().dispatch(event).await;
+    }
+
+    /// Handle an event from the redis channel it would be published on and its json payload
+    pub async fn publish(&self, channel: &str, payload: &str) -> Result<()> {
+        let event = Event::parse(channel, payload.as_bytes())
+            .wrap_err_with(|| format!("Invalid event for {}", channel))?;
+        self.dispatch(event).await;
+        Ok(())
+    }
+
+    /// Open a websocket connection without authenticating
+    pub async fn connect(&self) -> Result<TestClient> {
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", self.addr))
+            .await
+            .wrap_err("Failed to connect to push server")?;
+        Ok(TestClient { socket })
+    }
+
+    /// Open a websocket connection and authenticate with username and password
+    pub async fn connect_auth(&self, username: &str, password: &str) -> Result<TestClient> {
+        let mut client = self.connect().await?;
+        client.send(username).await?;
+        client.send(password).await?;
+        match client.next_text().await? {
+            Some(reply) if reply == "authenticated" => Ok(client),
+            Some(reply) => Err(Report::msg(format!("Authentication failed: {}", reply))),
+            None => Err(Report::msg("No reply to authentication")),
+        }
+    }
+
+    pub async fn shutdown(self) {
+        self.daemon.shutdown().await;
+    }
+}
+
+/// A websocket connection to a [`TestServer`]
+pub struct TestClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, text: &str) -> Result<()> {
+        self.socket
+            .send(Message::Text(text.into()))
+            .await
+            .wrap_err("Failed to send message")
+    }
+
+    /// The next text message, `None` if no message arrives within [`MESSAGE_TIMEOUT`]
+    ///
+    /// Pings and other non-text frames are skipped.
+    pub async fn next_text(&mut self) -> Result<Option<String>> {
+        loop {
+            match timeout(MESSAGE_TIMEOUT, self.socket.next()).await {
+                Err(_) => return Ok(None),
+                Ok(None) => return Err(Report::msg("Connection closed")),
+                Ok(Some(message)) => match message.wrap_err("Failed to receive message")? {
+                    Message::Text(text) => return Ok(Some(text)),
+                    Message::Close(_) => return Err(Report::msg("Connection closed")),
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    #[track_caller]
+    pub async fn assert_next(&mut self, expected: &str) {
+        assert_eq!(self.next_text().await.unwrap().as_deref(), Some(expected));
+    }
+
+    #[track_caller]
+    pub async fn assert_next_json(&mut self, expected: serde_json::Value) {
+        let text = self
+            .next_text()
+            .await
+            .unwrap()
+            .expect("no message received");
+        let parsed: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(parsed, expected);
+    }
+
+    /// Assert that no message arrives within a short time
+    #[track_caller]
+    pub async fn assert_no_message(&mut self) {
+        sleep(Duration::from_millis(5)).await;
+        assert!(timeout(Duration::from_millis(10), self.socket.next())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use color_eyre::{Report, Result};
+use std::env;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// Replace the running process with a new instance of the (possibly upgraded) binary
+///
+/// The listening sockets are passed on the same way systemd passes them with socket activation, so connections
+/// that arrive during the upgrade wait in the socket backlog instead of being refused. This is only possible for
+/// sockets that were inherited, those are the only ones that aren't closed on exec.
+///
+/// Anything the new binary has to pick up, like the resume tokens, has to be saved beforehand in a format that doesn't depend
+/// on the build, e.g. users by name instead of by their hashed id.
+pub fn exec_upgrade(sockets: &[RawFd]) -> Result<()> {
+    // the sockets need to be numbered from 3 upwards without gaps for the new process to find them
+    let expected: Vec<RawFd> = (3..3 + sockets.len() as RawFd).collect();
+    if sockets.is_empty() || sockets != expected.as_slice() {
+        return Err(Report::msg(
+            "Upgrading without downtime requires the listening sockets to be passed by systemd",
+        ));
+    }
+
+    let mut args = env::args_os();
+    let program = args
+        .next()
+        .map(Ok)
+        .unwrap_or_else(|| env::current_exe().map(|path| path.into_os_string()))?;
+    log::info!(
+        "Handing the listening sockets to {}",
+        program.to_string_lossy()
+    );
+
+    // exec keeps the pid, so the new process recognizes the sockets as being meant for it
+    let error = Command::new(program)
+        .args(args)
+        .env("LISTEN_FDS", sockets.len().to_string())
+        .env("LISTEN_PID", std::process::id().to_string())
+        .exec();
+    Err(Report::new(error).wrap_err("Failed to start the new binary"))
+}
@@ -10,9 +10,17 @@ use sqlx::{Database, Decode, Type};
 use std::collections::hash_map::DefaultHasher;
 use std::fmt;
 use std::hash::Hasher;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 static USER_NAMES: Lazy<DashMap<u64, String, RandomState>> = Lazy::new(DashMap::default);
 
+/// Keep the names of all users regardless of the log level, for bridges that need the user name
+static KEEP_USER_NAMES: AtomicBool = AtomicBool::new(false);
+
+pub fn keep_user_names() {
+    KEEP_USER_NAMES.store(true, Ordering::Relaxed);
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct UserId {
     hash: u64,
@@ -24,7 +32,7 @@ impl UserId {
         hash.write(user_id.as_bytes());
         let hash = hash.finish();
 
-        if log::max_level() >= LevelFilter::Info {
+        if log::max_level() >= LevelFilter::Info || KEEP_USER_NAMES.load(Ordering::Relaxed) {
             USER_NAMES
                 .entry(hash)
                 .or_insert_with(|| user_id.to_string());
@@ -32,6 +40,11 @@ impl UserId {
 
         UserId { hash }
     }
+
+    /// The user name, only known if the log level is info or more verbose or names are kept with [`keep_user_names`]
+    pub(crate) fn name(&self) -> Option<String> {
+        USER_NAMES.get(&self.hash).map(|name| name.value().clone())
+    }
 }
 
 impl<'de> Deserialize<'de> for UserId {
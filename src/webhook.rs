@@ -0,0 +1,176 @@
+use crate::message::MessageType;
+use crate::user::keep_user_names;
+use crate::App;
+use derivative::Derivative;
+use futures::future::select;
+use futures::pin_mut;
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+use sha2::Sha256;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::spawn;
+use tokio::time::sleep;
+
+/// Maximum number of webhook requests in flight, messages are dropped when the endpoints can't keep up
+const MAX_CONCURRENT_DELIVERIES: usize = 64;
+
+/// Number of attempts for a delivery before giving up
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry, doubled for every following retry
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Time to wait for a webhook endpoint to respond
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header containing the signature of the body
+pub const SIGNATURE_HEADER: &str = "x-notify-push-signature";
+
+#[derive(Derivative, Clone, Default)]
+#[derivative(Debug)]
+pub struct WebhookConfig {
+    pub urls: Vec<Url>,
+    /// Message types to forward, e.g. `notify_file` or the name of a custom message, all types when empty
+    pub events: Vec<String>,
+    /// Secret to sign the body with, the signature is send as `sha256=<hex hmac>`
+    #[derivative(Debug = "ignore")]
+    pub secret: Option<String>,
+}
+
+impl WebhookConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.urls.is_empty()
+    }
+
+    fn forwards(&self, msg: &MessageType) -> bool {
+        self.events.is_empty() || self.events.iter().any(|event| *event == msg.to_string())
+    }
+}
+
+/// Sign the body with the shared secret
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(body);
+    let mut signature = String::from("sha256=");
+    for byte in mac.finalize().into_bytes().iter() {
+        write!(&mut signature, "{:02x}", byte).ok();
+    }
+    signature
+}
+
+/// POST every message send to a user to the configured webhooks until cancelled
+pub async fn webhook_loop(app: Arc<App>, config: WebhookConfig, cancel: oneshot::Receiver<()>) {
+    keep_user_names();
+    let mut rx = app.connections.mirror();
+    let http = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(http) => http,
+        Err(e) => {
+            log::error!("Failed to create http client for webhooks: {}", e);
+            return;
+        }
+    };
+    let config = Arc::new(config);
+    let deliveries = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+
+    let loop_ = async move {
+        loop {
+            let (user, msg) = match rx.recv().await {
+                Ok(message) => message,
+                Err(RecvError::Lagged(count)) => {
+                    log::warn!("Dropped {} messages for the webhooks", count);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if !config.forwards(&msg) {
+                continue;
+            }
+            // users restored from the state file are only known by their hash
+            let name = match user.name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let body = json!({
+                "user": name,
+                "type": msg.to_string(),
+                "message": msg.to_json(),
+            })
+            .to_string();
+            let signature = config
+                .secret
+                .as_ref()
+                .map(|secret| sign(secret, body.as_bytes()));
+
+            for url in &config.urls {
+                let permit = match deliveries.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                };
+                let http = http.clone();
+                let url = url.clone();
+                let body = body.clone();
+                let signature = signature.clone();
+                spawn(async move {
+                    deliver(&http, url, body, signature).await;
+                    drop(permit);
+                });
+            }
+        }
+    };
+    pin_mut!(loop_);
+    select(cancel, loop_).await;
+}
+
+/// Send a message to a webhook, retrying with an increasing delay when the endpoint is unavailable
+async fn deliver(http: &reqwest::Client, url: Url, body: String, signature: Option<String>) {
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut request = http
+            .post(url.clone())
+            .header("content-type", "application/json")
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response)
+                if response.status().is_server_error()
+                    || response.status() == StatusCode::TOO_MANY_REQUESTS =>
+            {
+                format!("status {}", response.status())
+            }
+            Ok(response) => {
+                log::warn!(
+                    "Webhook {} rejected the message with status {}",
+                    url,
+                    response.status()
+                );
+                return;
+            }
+            Err(e) => e.to_string(),
+        };
+        if attempt == MAX_ATTEMPTS {
+            log::warn!(
+                "Failed to deliver message to webhook {} after {} attempts: {}",
+                url,
+                attempt,
+                error
+            );
+        } else {
+            log::debug!(
+                "Failed to deliver message to webhook {}: {}, retrying",
+                url,
+                error
+            );
+            sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
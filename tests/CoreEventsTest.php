@@ -90,6 +90,7 @@ class CoreEventsTest extends TestCase {
 		$this->getListener($events);
 
 		$group->addUser($user);
+		$events = $this->withoutActivityDetails($events);
 
 		$this->assertEquals([
 			'notify_group_membership_update' => [
@@ -103,6 +104,7 @@ class CoreEventsTest extends TestCase {
 		$events = [];
 
 		$group->removeUser($user);
+		$events = $this->withoutActivityDetails($events);
 
 		$this->assertEquals([
 			'notify_group_membership_update' => [
@@ -113,4 +115,16 @@ class CoreEventsTest extends TestCase {
 			],
 		], $events);
 	}
+
+	/**
+	 * The activity type and object depend on the app creating the activity, only check the affected user
+	 */
+	private function withoutActivityDetails(array $events): array {
+		if (isset($events['notify_activity'])) {
+			$events['notify_activity'] = array_map(function (array $activity) {
+				return ['user' => $activity['user']];
+			}, $events['notify_activity']);
+		}
+		return $events;
+	}
 }
@@ -1,23 +1,48 @@
 use dashmap::DashMap;
-use flexi_logger::{Logger, LoggerHandle};
-use futures::future::select;
-use futures::{pin_mut, FutureExt};
+use flexi_logger::LoggerHandle;
+use futures::future::{ready, BoxFuture};
+use futures::FutureExt;
 use futures::{SinkExt, StreamExt};
 use http_auth_basic::Credentials;
 use notify_push::config::{Bind, Config};
-use notify_push::{listen_loop, serve, App};
+use notify_push::daemon::Daemon;
+use notify_push::event::{Event, EventLimits, StorageUpdate};
+use notify_push::forwarded::ForwardedForTrust;
+use notify_push::input::event_input_loop;
+use notify_push::instance::{announce, InstanceInfo};
+use notify_push::latency::LATENCIES;
+use notify_push::limits::LimitsConfig;
+use notify_push::memory::memory_loop;
+use notify_push::message::{DebounceBounds, DebounceWindows};
+use notify_push::pre_auth::TokenRequirements;
+use notify_push::protocol::PingConfig;
+use notify_push::reconnect::database_reconnect_loop;
+use notify_push::registry::{RegistryConfig, RegistryKind};
+use notify_push::schedule::schedule_loop;
+use notify_push::statsd::metrics_push_loop;
+use notify_push::storage_mapping::{
+    DatabaseErrorStrategy, FileChange, MappingBackend, MountAccess, PathMatch, StaticMapping,
+    StorageMapping,
+};
+use notify_push::webhook::{sign, webhook_loop, WebhookConfig, SIGNATURE_HEADER};
+use notify_push::{serve, App, UserId};
 use once_cell::sync::Lazy;
 use redis::AsyncCommands;
 use smallvec::alloc::sync::Arc;
 use sqlx::AnyPool;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::oneshot;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc, oneshot, Semaphore};
 use tokio::task::spawn;
 use tokio::time::timeout;
 use tokio::time::{sleep, Duration};
 use tokio_stream::wrappers::TcpListenerStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use warp::http::StatusCode;
@@ -40,17 +65,258 @@ async fn listen_available_port() -> Option<TcpListener> {
     None
 }
 
+/// Value stored by [`ListRedis`]
+enum Stored {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+}
+
+enum RespReply {
+    Status(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<RespReply>),
+}
+
+impl RespReply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RespReply::Status(status) => {
+                out.extend_from_slice(format!("+{}\r\n", status).as_bytes())
+            }
+            RespReply::Error(error) => out.extend_from_slice(format!("-{}\r\n", error).as_bytes()),
+            RespReply::Int(int) => out.extend_from_slice(format!(":{}\r\n", int).as_bytes()),
+            RespReply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            RespReply::Bulk(Some(data)) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            RespReply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// Minimal redis server that supports lists, which mini-redis doesn't implement
+///
+/// Only the commands used by the push server and the tests are supported, expiry is ignored.
+struct ListRedis {
+    data: Mutex<HashMap<Vec<u8>, Stored>>,
+    pubsub: broadcast::Sender<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ListRedis {
+    async fn spawn() -> SocketAddr {
+        let tcp = listen_available_port()
+            .await
+            .expect("Can't find open port for redis");
+        let addr = tcp.local_addr().unwrap();
+        let redis = Arc::new(ListRedis {
+            data: Mutex::default(),
+            pubsub: broadcast::channel(64).0,
+        });
+        spawn(async move {
+            while let Ok((stream, _)) = tcp.accept().await {
+                spawn(redis.clone().handle(stream));
+            }
+        });
+        addr
+    }
+
+    async fn handle(self: Arc<Self>, stream: TcpStream) {
+        let (read, mut write) = stream.into_split();
+        let (command_tx, mut commands) = mpsc::channel(16);
+        // commands are read in a separate task, so a published message can't interrupt reading a command
+        spawn(async move {
+            let mut reader = BufReader::new(read);
+            while let Some(command) = read_command(&mut reader).await {
+                if command_tx.send(command).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut messages = self.pubsub.subscribe();
+        let mut subscribed: Vec<Vec<u8>> = Vec::new();
+        let mut transaction: Option<Vec<Vec<Vec<u8>>>> = None;
+        loop {
+            let mut replies = Vec::new();
+            tokio::select! {
+                command = commands.recv() => {
+                    let command = match command {
+                        Some(command) if !command.is_empty() => command,
+                        _ => return,
+                    };
+                    let name = String::from_utf8_lossy(&command[0]).to_uppercase();
+                    match (name.as_str(), transaction.as_mut()) {
+                        ("MULTI", _) => {
+                            transaction = Some(Vec::new());
+                            replies.push(RespReply::Status("OK"));
+                        }
+                        ("EXEC", Some(_)) => {
+                            let queued = transaction.take().unwrap();
+                            replies.push(RespReply::Array(
+                                queued.iter().map(|command| self.execute(command)).collect(),
+                            ));
+                        }
+                        (_, Some(queued)) => {
+                            queued.push(command);
+                            replies.push(RespReply::Status("QUEUED"));
+                        }
+                        ("SUBSCRIBE", None) => {
+                            for channel in &command[1..] {
+                                subscribed.push(channel.clone());
+                                replies.push(RespReply::Array(vec![
+                                    RespReply::Bulk(Some(b"subscribe".to_vec())),
+                                    RespReply::Bulk(Some(channel.clone())),
+                                    RespReply::Int(subscribed.len() as i64),
+                                ]));
+                            }
+                        }
+                        (_, None) => replies.push(self.execute(&command)),
+                    }
+                }
+                Ok((channel, message)) = messages.recv() => {
+                    if subscribed.contains(&channel) {
+                        replies.push(RespReply::Array(vec![
+                            RespReply::Bulk(Some(b"message".to_vec())),
+                            RespReply::Bulk(Some(channel)),
+                            RespReply::Bulk(Some(message)),
+                        ]));
+                    }
+                }
+            }
+            let mut out = Vec::new();
+            for reply in replies {
+                reply.encode(&mut out);
+            }
+            if write.write_all(&out).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn execute(&self, command: &[Vec<u8>]) -> RespReply {
+        let name = String::from_utf8_lossy(&command[0]).to_uppercase();
+        let mut data = self.data.lock().unwrap();
+        match (name.as_str(), &command[1..]) {
+            ("PING", _) => RespReply::Status("PONG"),
+            ("GET", [key]) => match data.get(key) {
+                Some(Stored::String(value)) => RespReply::Bulk(Some(value.clone())),
+                _ => RespReply::Bulk(None),
+            },
+            ("SET", [key, value, ..]) => {
+                data.insert(key.clone(), Stored::String(value.clone()));
+                RespReply::Status("OK")
+            }
+            ("DEL", keys) => RespReply::Int(
+                keys.iter()
+                    .filter(|key| data.remove(*key).is_some())
+                    .count() as i64,
+            ),
+            ("EXPIRE", [_, _]) | ("SADD", [_, _]) => RespReply::Int(1),
+            ("RPUSH", [key, values @ ..]) => {
+                match data
+                    .entry(key.clone())
+                    .or_insert_with(|| Stored::List(Vec::new()))
+                {
+                    Stored::List(list) => {
+                        list.extend(values.iter().cloned());
+                        RespReply::Int(list.len() as i64)
+                    }
+                    Stored::String(_) => RespReply::Error("WRONGTYPE".into()),
+                }
+            }
+            ("LTRIM", [key, start, stop]) => {
+                if let Some(Stored::List(list)) = data.get_mut(key) {
+                    let range = list_range(list.len(), start, stop);
+                    *list = list[range].to_vec();
+                }
+                RespReply::Status("OK")
+            }
+            ("LRANGE", [key, start, stop]) => match data.get(key) {
+                Some(Stored::List(list)) => RespReply::Array(
+                    list[list_range(list.len(), start, stop)]
+                        .iter()
+                        .map(|item| RespReply::Bulk(Some(item.clone())))
+                        .collect(),
+                ),
+                _ => RespReply::Array(Vec::new()),
+            },
+            ("PUBLISH", [channel, message]) => RespReply::Int(
+                self.pubsub
+                    .send((channel.clone(), message.clone()))
+                    .unwrap_or(0) as i64,
+            ),
+            _ => RespReply::Error(format!("ERR unknown command '{}'", name)),
+        }
+    }
+}
+
+/// Read a command send as array of bulk strings
+async fn read_command(
+    reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.ok()? == 0 {
+        return None;
+    }
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut command = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        command.push(arg);
+    }
+    Some(command)
+}
+
+/// Items of a list selected by redis style start and stop indexes, where negative indexes count from the end
+fn list_range(len: usize, start: &[u8], stop: &[u8]) -> std::ops::Range<usize> {
+    let index = |arg: &[u8]| {
+        let index: i64 = String::from_utf8_lossy(arg).parse().unwrap_or(0);
+        if index < 0 {
+            (len as i64 + index).max(0)
+        } else {
+            index
+        }
+    };
+    let start = index(start).min(len as i64) as usize;
+    let stop = (index(stop) + 1).min(len as i64) as usize;
+    start..stop.max(start)
+}
+
 struct Services {
     redis: SocketAddr,
     nextcloud: SocketAddr,
     _redis_shutdown: oneshot::Sender<()>,
     _nextcloud_shutdown: oneshot::Sender<()>,
     users: Arc<DashMap<String, String>>,
+    /// Rooms users are allowed to join, as `(user, room)`
+    rooms: Arc<DashMap<(String, String), ()>>,
     db: AnyPool,
 }
 
-static LOG_HANDLE: Lazy<LoggerHandle> =
-    Lazy::new(|| Logger::try_with_str("").unwrap().start().unwrap());
+#[cfg(not(feature = "testing"))]
+static LOG_HANDLE: Lazy<LoggerHandle> = Lazy::new(|| {
+    flexi_logger::Logger::try_with_str("")
+        .unwrap()
+        .start()
+        .unwrap()
+});
+
+// only one logger can be started, share it with the testing helpers
+#[cfg(feature = "testing")]
+static LOG_HANDLE: Lazy<LoggerHandle> = Lazy::new(notify_push::testing::log_handle);
 
 impl Services {
     pub async fn new() -> Self {
@@ -80,7 +346,9 @@ impl Services {
             .execute(&db)
             .await
             .unwrap();
-        sqlx::query("CREATE TABLE oc_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT)")
+        sqlx::query(
+            "CREATE TABLE oc_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT, mount_point TEXT)",
+        )
             .execute(&db)
             .await
             .unwrap();
@@ -92,6 +360,18 @@ impl Services {
             .execute(&db)
             .await
             .unwrap();
+        sqlx::query("CREATE TABLE oc_group_user(gid TEXT, uid TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE oc_appconfig(appid TEXT, configkey TEXT, configvalue TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO oc_appconfig(appid, configkey, configvalue) VALUES('core', 'installedversion', '23.0.0.10')")
+            .execute(&db)
+            .await
+            .unwrap();
 
         let users: Arc<DashMap<String, String>> = Arc::default();
 
@@ -114,11 +394,26 @@ impl Services {
                 }
             });
 
+        let rooms: Arc<DashMap<(String, String), ()>> = Arc::default();
+        let rooms_filter = rooms.clone();
+        let room = warp::path!("index.php" / "apps" / "notify_push" / "room")
+            .and(warp::query::<HashMap<String, String>>())
+            .map(move |query: HashMap<String, String>| {
+                let key = (
+                    query.get("user").cloned().unwrap_or_default(),
+                    query.get("room").cloned().unwrap_or_default(),
+                );
+                warp::reply::json(&rooms_filter.contains_key(&key))
+            });
+
+        let cookie =
+            warp::path!("index.php" / "apps" / "notify_push" / "test" / "cookie").map(|| "0");
+
         let (redis_shutdown, redis_shutdown_rx) = oneshot::channel();
         let (nextcloud_shutdown, nextcloud_shutdown_rx) = oneshot::channel();
 
         spawn(async move {
-            warp::serve(uid)
+            warp::serve(room.or(cookie).or(uid))
                 .serve_incoming_with_graceful_shutdown(
                     TcpListenerStream::new(nextcloud_tcp),
                     nextcloud_shutdown_rx.map(|_| ()),
@@ -137,6 +432,7 @@ impl Services {
             _redis_shutdown: redis_shutdown,
             _nextcloud_shutdown: nextcloud_shutdown,
             users,
+            rooms,
             db,
         }
     }
@@ -149,24 +445,64 @@ impl Services {
                 .parse()
                 .unwrap()],
             nextcloud_url: format!("http://{}/", self.nextcloud),
+            nextcloud_fallback_urls: Vec::new(),
             metrics_bind: None,
+            grpc_bind: Vec::new(),
+            mqtt_url: None,
+            metrics_push: None,
             log_level: "".to_string(),
-            bind: Bind::Tcp(self.nextcloud.clone()),
+            log_targets: Vec::new(),
+            bind: Bind::Tcp(vec![self.nextcloud.clone()]),
             allow_self_signed: false,
             no_ansi: false,
             tls: None,
+            path_match: PathMatch::default(),
+            admin_token: None,
+            webhook: WebhookConfig::default(),
+            storage_update_concurrency: 32,
+            event_concurrency: 512,
+            state_file: None,
+            event_input: None,
+            path_prefixes: vec!["push".into()],
+            forwarded_for_trust: ForwardedForTrust::First,
+            limits: LimitsConfig::default(),
+            database_error_strategy: DatabaseErrorStrategy::Drop,
+            file_change_hints: false,
+            presence: false,
+            rooms: false,
+            user_patterns: false,
+            offline_queue_ttl: None,
+            dedup_window: None,
+            fallback_keep_alive: None,
+            handshake_banner: false,
+            heartbeat_interval: None,
+            debounce_windows: DebounceWindows::default(),
+            debounce_bounds: DebounceBounds::default(),
+            ping: PingConfig::default(),
+            backpressure_threshold: None,
+            registry: RegistryConfig::default(),
+            pre_auth_requirements: TokenRequirements::default(),
+            nextcloud_ca_bundle: None,
+            nextcloud_client_cert: None,
         }
     }
 
-    async fn app(&self) -> App {
-        let config = self.config();
-        App::with_connection(self.db.clone(), config, LOG_HANDLE.clone(), false)
+    async fn spawn_server(&self) -> ServerHandle {
+        self.spawn_server_with_config(self.config()).await
+    }
+
+    async fn spawn_server_with_config(&self, config: Config) -> ServerHandle {
+        let app = App::with_connection(self.db.clone(), config, LOG_HANDLE.clone(), false)
             .await
-            .unwrap()
+            .unwrap();
+        self.spawn_app(app).await
     }
 
-    async fn spawn_server(&self) -> ServerHandle {
-        let app = Arc::new(self.app().await);
+    async fn spawn_app(&self, app: App) -> ServerHandle {
+        self.spawn_shared_app(Arc::new(app)).await
+    }
+
+    async fn spawn_shared_app(&self, app: Arc<App>) -> ServerHandle {
         let addr = async {
             let tcp = listen_available_port().await.unwrap();
             tcp.local_addr()
@@ -174,25 +510,18 @@ impl Services {
         .await
         .unwrap();
 
-        let (serve_tx, serve_rx) = oneshot::channel();
-        let (listen_tx, listen_rx) = oneshot::channel();
-
-        let bind = Bind::Tcp(addr);
-        spawn(async move {
-            let serve = serve(app.clone(), bind, serve_rx, None).unwrap();
-            let listen = listen_loop(app.clone(), listen_rx);
-
-            pin_mut!(serve);
-            pin_mut!(listen);
-
-            select(serve, listen).await;
-        });
+        let mut daemon = Daemon::new(app);
+        daemon.spawn_background("memory", memory_loop);
+        daemon
+            .start_server(Bind::Tcp(vec![addr]), None)
+            .await
+            .unwrap();
+        daemon.start_listener().await;
 
         sleep(Duration::from_millis(10)).await;
 
         ServerHandle {
-            _serve_handle: serve_tx,
-            _listen_handle: listen_tx,
+            daemon,
             port: addr.port(),
         }
     }
@@ -206,10 +535,26 @@ impl Services {
         self.users.insert(username.into(), password.into());
     }
 
+    fn allow_room(&self, username: &str, room: &str) {
+        self.rooms.insert((username.into(), room.into()), ());
+    }
+
     async fn add_storage_mapping(&self, username: &str, storage: u32, root: u32) {
-        sqlx::query("INSERT INTO oc_mounts(storage_id, root_id, user_id) VALUES(?, ?, ?)")
-            .bind(storage as i64)
-            .bind(root as i64)
+        sqlx::query(
+            "INSERT INTO oc_mounts(storage_id, root_id, user_id, mount_point) VALUES(?, ?, ?, ?)",
+        )
+        .bind(storage as i64)
+        .bind(root as i64)
+        .bind(username)
+        .bind(format!("/{}/", username))
+        .execute(&self.db)
+        .await
+        .unwrap();
+    }
+
+    async fn add_group_member(&self, group: &str, username: &str) {
+        sqlx::query("INSERT INTO oc_group_user(gid, uid) VALUES(?, ?)")
+            .bind(group)
             .bind(username)
             .execute(&self.db)
             .await
@@ -227,8 +572,7 @@ impl Services {
 }
 
 struct ServerHandle {
-    _serve_handle: oneshot::Sender<()>,
-    _listen_handle: oneshot::Sender<()>,
+    daemon: Daemon,
     port: u16,
 }
 
@@ -273,6 +617,25 @@ async fn test_auth() {
     assert_next_message(&mut client, "authenticated").await;
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_fallback_url() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    // nothing is listening on the primary url
+    let unreachable = listen_available_port().await.unwrap().local_addr().unwrap();
+    let mut config = services.config();
+    config.nextcloud_fallback_urls = vec![config.nextcloud_url.clone()];
+    config.nextcloud_url = format!("http://{}/nextcloud", unreachable);
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+
+    assert_next_message(&mut client, "authenticated").await;
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_auth_failure() {
     let services = Services::new().await;
@@ -301,6 +664,20 @@ async fn assert_next_message(
     );
 }
 
+#[track_caller]
+async fn assert_next_json(
+    client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    expected: serde_json::Value,
+) {
+    let message = timeout(Duration::from_millis(200), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+    assert_eq!(parsed, expected);
+}
+
 #[track_caller]
 async fn assert_no_message(client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) {
     sleep(Duration::from_millis(5)).await;
@@ -327,6 +704,74 @@ async fn test_notify_activity() {
     std::mem::forget(services);
 }
 
+#[cfg(feature = "testing")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_testing_helpers() {
+    use notify_push::testing::{MockNextcloud, TestServer};
+
+    let nextcloud = MockNextcloud::start().await.unwrap();
+    nextcloud.add_user("foo", "bar");
+
+    let mut storages = HashMap::new();
+    storages.insert(
+        10,
+        vec![MountAccess {
+            user: "foo".into(),
+            root: "".into(),
+            mount_point: None,
+        }],
+    );
+    let server = TestServer::start(nextcloud.app(storages).await.unwrap())
+        .await
+        .unwrap();
+    assert!(server.connect_auth("foo", "not_bar").await.is_err());
+    let mut client = server.connect_auth("foo", "bar").await.unwrap();
+
+    server
+        .publish("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    client.assert_next("notify_activity").await;
+
+    server
+        .publish(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/bar"}"#,
+        )
+        .await
+        .unwrap();
+    client.assert_next("notify_file").await;
+
+    server
+        .publish("notify_activity", r#"{"user":"other"}"#)
+        .await
+        .unwrap();
+    client.assert_no_message().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_activity_multiple_users() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    services.add_user("foo3", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+    let mut client3 = server_handle.connect_auth("foo3", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":["foo", "foo2"]}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "notify_activity").await;
+    assert_next_message(&mut client2, "notify_activity").await;
+    assert_no_message(&mut client3).await;
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_notify_activity_other_user() {
     let services = Services::new().await;
@@ -367,6 +812,73 @@ async fn test_notify_file() {
     assert_next_message(&mut client, "notify_file").await;
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_file_path() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(10, "").await;
+    services.add_filecache_item(11, "files/bar.txt").await;
+    services.add_storage_mapping("foo", 10, 10).await;
+
+    let mut config = services.config();
+    config.file_change_hints = true;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("version 2".into()))
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "version", "version": 2}),
+    )
+    .await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files/bar.txt"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "file", "path": "/bar.txt"}),
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_message_order() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(10, "foo").await;
+    services.add_filecache_item(11, "foo/bar").await;
+    services.add_storage_mapping("foo", 10, 11).await;
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/bar"}"#,
+        )
+        .await
+        .unwrap();
+    redis
+        .publish::<_, _, ()>("notify_notification", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    // the notification doesn't overtake the file change that needs a database lookup
+    assert_next_message(&mut client, "notify_file").await;
+    assert_next_message(&mut client, "notify_notification").await;
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_notify_file_different_storage() {
     let services = Services::new().await;
@@ -432,13 +944,16 @@ async fn test_pre_auth() {
 
     let mut redis = services.redis_client().await;
     redis
-        .publish::<_, _, ()>("notify_pre_auth", r#"{"user":"foo", "token": "token"}"#)
+        .publish::<_, _, ()>(
+            "notify_pre_auth",
+            r#"{"user":"foo", "token": "Jq8fXc2LrT5wKz9mVb3N"}"#,
+        )
         .await
         .unwrap();
 
     sleep(Duration::from_millis(100)).await;
 
-    let mut client = server_handle.connect_auth("", "token").await;
+    let mut client = server_handle.connect_auth("", "Jq8fXc2LrT5wKz9mVb3N").await;
 
     // verify that we are the correct user
     redis
@@ -450,47 +965,125 @@ async fn test_pre_auth() {
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn test_notify_notification() {
+async fn test_request_rate_limit() {
     let services = Services::new().await;
-    services.add_user("foo", "bar");
-    services.add_user("foo2", "bar");
+    let mut config = services.config();
+    config.limits.max_requests_per_second = Some(1);
+
+    let server_handle = services.spawn_server_with_config(config).await;
 
+    let http = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/test/cookie", server_handle.port);
+    // bursts of up to twice the rate are allowed
+    for _ in 0..2 {
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let limited = http.get(&url).send().await.unwrap();
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_ready() {
+    let services = Services::new().await;
     let server_handle = services.spawn_server().await;
-    let mut client1 = server_handle.connect_auth("foo", "bar").await;
-    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
 
+    let url = format!("http://127.0.0.1:{}/ready", server_handle.port);
+    let response = reqwest::Client::new().get(&url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = response.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(report["redis"]["status"], "ok");
+    assert_eq!(report["database"]["status"], "ok");
+    assert_eq!(report["nextcloud"]["status"], "ok");
+    assert_eq!(report["receiving_events"], true);
+    assert_eq!(report["queues"]["scheduled_messages"], 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pre_auth_http() {
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.admin_token = Some("admin_token".into());
+
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let http = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/admin/pre_auth", server_handle.port);
+    let unauthorized = http
+        .post(&url)
+        .bearer_auth("wrong_token")
+        .json(&serde_json::json!({"user": "foo"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::NOT_FOUND);
+
+    let token = http
+        .post(&url)
+        .bearer_auth("admin_token")
+        .json(&serde_json::json!({"user": "foo"}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let mut client = server_handle.connect_auth("", &token).await;
+
+    // verify that we are the correct user
     let mut redis = services.redis_client().await;
     redis
-        .publish::<_, _, ()>("notify_notification", r#"{"user":"foo"}"#)
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
         .await
         .unwrap();
 
-    assert_next_message(&mut client1, "notify_notification").await;
-    assert_no_message(&mut client2).await;
+    assert_next_message(&mut client, "notify_activity").await;
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn test_notify_share() {
+async fn test_presence() {
     let services = Services::new().await;
     services.add_user("foo", "bar");
-    services.add_user("foo2", "bar");
+    let mut config = services.config();
+    config.admin_token = Some("admin_token".into());
+
+    let server_handle = services.spawn_server_with_config(config).await;
+    let client1 = server_handle.connect_auth("foo", "bar").await;
+    let client2 = server_handle.connect_auth("foo", "bar").await;
+
+    let http = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/admin/presence", server_handle.port);
+    let presence = |query: &'static str| {
+        let request = http
+            .get(&url)
+            .query(&[("user", query)])
+            .bearer_auth("admin_token");
+        async move {
+            request
+                .send()
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap()
+        }
+    };
 
-    let server_handle = services.spawn_server().await;
-    let mut client1 = server_handle.connect_auth("foo", "bar").await;
-    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+    let foo = presence("foo").await;
+    assert_eq!(foo["online"], true);
+    assert_eq!(foo["connections"], 2);
+    assert_eq!(presence("foo2").await["online"], false);
 
-    let mut redis = services.redis_client().await;
-    redis
-        .publish::<_, _, ()>("notify_user_share_created", r#"{"user":"foo"}"#)
-        .await
-        .unwrap();
+    drop(client1);
+    drop(client2);
+    sleep(Duration::from_millis(50)).await;
 
-    assert_next_message(&mut client1, "notify_file").await;
-    assert_no_message(&mut client2).await;
+    assert_eq!(presence("foo").await["online"], false);
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn test_notify_group() {
+async fn test_client_action() {
     let services = Services::new().await;
     services.add_user("foo", "bar");
     services.add_user("foo2", "bar");
@@ -502,18 +1095,33 @@ async fn test_notify_group() {
     let mut redis = services.redis_client().await;
     redis
         .publish::<_, _, ()>(
-            "notify_group_membership_update",
-            r#"{"user":"foo", "group":"asd"}"#,
+            "notify_signal",
+            r#"{"client_action":{"action":"reload_config"}}"#,
         )
         .await
         .unwrap();
+    assert_next_message(&mut client1, "action reload_config").await;
+    assert_next_message(&mut client2, "action reload_config").await;
 
-    assert_next_message(&mut client1, "notify_file").await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_signal",
+            r#"{"client_action":{"action":"reauth","user":"foo"}}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client1, "action reauth").await;
+    assert!(matches!(
+        timeout(Duration::from_millis(200), client1.next())
+            .await
+            .unwrap(),
+        Some(Ok(Message::Close(_))) | None
+    ));
     assert_no_message(&mut client2).await;
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn test_notify_custom() {
+async fn test_notify_notification() {
     let services = Services::new().await;
     services.add_user("foo", "bar");
     services.add_user("foo2", "bar");
@@ -524,10 +1132,73 @@ async fn test_notify_custom() {
 
     let mut redis = services.redis_client().await;
     redis
-        .publish::<_, _, ()>(
-            "notify_custom",
-            r#"{"user":"foo", "message":"my_custom_message"}"#,
-        )
+        .publish::<_, _, ()>("notify_notification", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "notify_notification").await;
+    assert_no_message(&mut client2).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_share() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_user_share_created", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "notify_file").await;
+    assert_no_message(&mut client2).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_group() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_group_membership_update",
+            r#"{"user":"foo", "group":"asd"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "notify_file").await;
+    assert_no_message(&mut client2).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_custom() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"my_custom_message"}"#,
+        )
         .await
         .unwrap();
 
@@ -535,6 +1206,124 @@ async fn test_notify_custom() {
     assert_no_message(&mut client2).await;
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_offline_queue() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let redis_addr = ListRedis::spawn().await;
+    let mut config = services.config();
+    config.redis = vec![format!("redis://{}", redis_addr).parse().unwrap()];
+    config.offline_queue_ttl = Some(Duration::from_secs(60));
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut redis = redis::Client::open(format!("redis://{}", redis_addr))
+        .unwrap()
+        .get_async_connection()
+        .await
+        .unwrap();
+
+    // messages received while connected are delivered directly and not queued
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    redis
+        .publish::<_, _, ()>("notify_custom", r#"{"user":"foo", "message":"live"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "live").await;
+    client.close(None).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    for message in ["first", "second"].iter() {
+        redis
+            .publish::<_, _, ()>(
+                "notify_custom",
+                format!(r#"{{"user":"foo", "message":"{}"}}"#, message),
+            )
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client, "first").await;
+    assert_next_message(&mut client, "second").await;
+    assert_no_message(&mut client).await;
+    client.close(None).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    // the queue is emptied once it's replayed
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_offline_queue_file_backlog() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(10, "foo").await;
+    services.add_filecache_item(11, "foo/bar").await;
+    services.add_storage_mapping("foo", 10, 11).await;
+
+    let redis_addr = ListRedis::spawn().await;
+    let mut config = services.config();
+    config.redis = vec![format!("redis://{}", redis_addr).parse().unwrap()];
+    config.offline_queue_ttl = Some(Duration::from_secs(60));
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut redis = redis::Client::open(format!("redis://{}", redis_addr))
+        .unwrap()
+        .get_async_connection()
+        .await
+        .unwrap();
+
+    let client = server_handle.connect_auth("foo", "bar").await;
+    drop(client);
+    sleep(Duration::from_millis(50)).await;
+
+    for _ in 0..20 {
+        redis
+            .publish::<_, _, ()>(
+                "notify_storage_update",
+                r#"{"storage":10, "path":"foo/bar"}"#,
+            )
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    // identical file messages are only replayed once
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client, "notify_file").await;
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_custom_dedup() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.dedup_window = Some(Duration::from_secs(60));
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    for id in [1, 1, 2].iter() {
+        redis
+            .publish::<_, _, ()>(
+                "notify_custom",
+                format!(
+                    r#"{{"user":"foo", "message":"changed", "body":{{"id":{}}}}}"#,
+                    id
+                ),
+            )
+            .await
+            .unwrap();
+    }
+
+    assert_next_message(&mut client, r#"changed {"id":1}"#).await;
+    assert_next_message(&mut client, r#"changed {"id":2}"#).await;
+    assert_no_message(&mut client).await;
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_notify_custom_body() {
     let services = Services::new().await;
@@ -557,3 +1346,1791 @@ async fn test_notify_custom_body() {
     assert_next_message(&mut client1, "my_custom_message [1,2,3]").await;
     assert_no_message(&mut client2).await;
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_custom_scheduled() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let mut server_handle = services.spawn_server().await;
+    server_handle
+        .daemon
+        .spawn_background("schedule", schedule_loop);
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let deliver_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 2;
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            format!(
+                r#"{{"user":["foo","foo2"], "message":"reminder", "deliver_at": {}}}"#,
+                deliver_at
+            ),
+        )
+        .await
+        .unwrap();
+
+    assert_no_message(&mut client).await;
+    sleep(Duration::from_millis(2500)).await;
+    assert_next_message(&mut client, "reminder").await;
+
+    // the second user gets the message once connecting
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+    assert_next_message(&mut client2, "reminder").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_group_message() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    services.add_group_member("admins", "foo").await;
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_group_message",
+            r#"{"group":"admins", "message":"my_group_message"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "my_group_message").await;
+    assert_no_message(&mut client2).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_broadcast() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_push_broadcast",
+            r#"{"message":"maintenance", "body": {"minutes": 5}}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, r#"maintenance {"minutes":5}"#).await;
+    assert_next_message(&mut client2, r#"maintenance {"minutes":5}"#).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_broadcast_user_pattern() {
+    let services = Services::new().await;
+    services.add_user("company-a-foo", "bar");
+    services.add_user("company-b-foo", "bar");
+
+    let mut config = services.config();
+    config.user_patterns = true;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client1 = server_handle.connect_auth("company-a-foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("company-b-foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_push_broadcast",
+            r#"{"message":"maintenance", "pattern": "company-a-*"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "maintenance").await;
+    assert_no_message(&mut client2).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_subprotocol_negotiation() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let url = format!("ws://127.0.0.1:{}/ws", server_handle.port);
+
+    let mut request = url.clone().into_client_request().unwrap();
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        "unknown, nextcloud-push-v2".parse().unwrap(),
+    );
+    let (mut client, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+    assert_eq!(
+        response.headers().get("Sec-WebSocket-Protocol").unwrap(),
+        "nextcloud-push-v2"
+    );
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_next_message(&mut client, "authenticated").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_notification",
+            r#"{"user":"foo", "id": 5, "app": "spreed"}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "notification", "id": 5, "app": "spreed"}),
+    )
+    .await;
+
+    let mut request = url.into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", "unknown".parse().unwrap());
+    assert!(tokio_tungstenite::connect_async(request).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_failure_reason_v2() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut request = format!("ws://127.0.0.1:{}/ws", server_handle.port)
+        .into_client_request()
+        .unwrap();
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        "nextcloud-push-v2".parse().unwrap(),
+    );
+    let (mut client, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("wrong".into())).await.unwrap();
+
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "error", "reason": "invalid_credentials", "message": "Invalid credentials"}),
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_msgpack_encoding() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("encoding msgpack".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "encoding msgpack").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_notification",
+            r#"{"user":"foo", "id": 5, "app": "spreed"}"#,
+        )
+        .await
+        .unwrap();
+
+    let message = timeout(Duration::from_millis(200), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(message.is_binary());
+    let parsed: serde_json::Value = rmp_serde::from_slice(&message.into_data()).unwrap();
+    assert_eq!(
+        parsed,
+        serde_json::json!({"type": "notification", "id": 5, "app": "spreed"})
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_notification_v2() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("version 2".into()))
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "version", "version": 2}),
+    )
+    .await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_notification",
+            r#"{"user":"foo", "id": 5, "app": "spreed"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "notification", "id": 5, "app": "spreed"}),
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_notification_subject() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("version 2".into()))
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "version", "version": 2}),
+    )
+    .await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_notification",
+            r#"{"user":"foo", "app": "spreed", "subject": "mention"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "notification", "app": "spreed", "subject": "mention"}),
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_resume_token() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("resume_token".into()))
+        .await
+        .unwrap();
+    let reply = timeout(Duration::from_millis(200), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let token = reply
+        .to_text()
+        .unwrap()
+        .strip_prefix("resume_token ")
+        .unwrap()
+        .to_string();
+    client.close(None).await.unwrap();
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client = server_handle.connect_auth("", &token).await;
+
+    // verify that we are the correct user
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_activity").await;
+
+    // tokens can only be used once
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("".into())).await.unwrap();
+    client.send(Message::Text(token)).await.unwrap();
+    assert_next_message(&mut client, "err: Invalid credentials").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_resume_token_state_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let state_file =
+        std::env::temp_dir().join(format!("notify_push_state_{}.json", std::process::id()));
+    let config = || {
+        let mut config = services.config();
+        config.state_file = Some(state_file.clone());
+        config
+    };
+
+    let app = Arc::new(
+        App::with_connection(services.db.clone(), config(), LOG_HANDLE.clone(), false)
+            .await
+            .unwrap(),
+    );
+    let server_handle = services.spawn_shared_app(app.clone()).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("resume_token".into()))
+        .await
+        .unwrap();
+    let reply = timeout(Duration::from_millis(200), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let token = reply
+        .to_text()
+        .unwrap()
+        .strip_prefix("resume_token ")
+        .unwrap()
+        .to_string();
+    client.close(None).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    app.save_state(&state_file).unwrap();
+    let metadata = std::fs::metadata(&state_file).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    // the user is saved by name, so the token can be restored by a different build
+    let saved: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&state_file).unwrap()).unwrap();
+    assert_eq!(saved[0]["user"], "foo");
+
+    let app = App::with_connection(services.db.clone(), config(), LOG_HANDLE.clone(), false)
+        .await
+        .unwrap();
+    app.restore_state(&state_file).unwrap();
+    let server_handle = services.spawn_app(app).await;
+    let mut client = server_handle.connect_auth("", &token).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_path_prefix() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.path_prefixes = vec!["nextcloud/push".into()];
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let mut client = tokio_tungstenite::connect_async(format!(
+        "ws://127.0.0.1:{}/nextcloud/push/ws",
+        server_handle.port
+    ))
+    .await
+    .unwrap()
+    .0;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+
+    assert_next_message(&mut client, "authenticated").await;
+
+    // the default prefix is replaced
+    assert!(tokio_tungstenite::connect_async(format!(
+        "ws://127.0.0.1:{}/push/ws",
+        server_handle.port
+    ))
+    .await
+    .is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_custom_tag() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo", "bar").await;
+    client1
+        .send(Message::Text("tag board-7".into()))
+        .await
+        .unwrap();
+
+    sleep(Duration::from_millis(10)).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"my_custom_message", "tag": "board-7"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "my_custom_message").await;
+    assert_no_message(&mut client2).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_ack_mode() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("version 2".into()))
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "version", "version": 2}),
+    )
+    .await;
+    client
+        .send(Message::Text("ack_mode on".into()))
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "ack_mode", "enabled": true}),
+    )
+    .await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"2fa_approve"}"#,
+        )
+        .await
+        .unwrap();
+
+    let expected = serde_json::json!({"type": "custom", "message": "2fa_approve", "id": 1});
+    assert_next_json(&mut client, expected.clone()).await;
+
+    // not acknowledged, so it's send again
+    sleep(Duration::from_millis(2100)).await;
+    assert_next_json(&mut client, expected).await;
+
+    client.send(Message::Text("ack 1".into())).await.unwrap();
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_notify_custom_topic() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo", "bar").await;
+    client1
+        .send(Message::Text("subscribe deck/board/*".into()))
+        .await
+        .unwrap();
+    client2
+        .send(Message::Text("subscribe deck/**".into()))
+        .await
+        .unwrap();
+
+    sleep(Duration::from_millis(10)).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"card_updated", "topic": "deck/board/7/card/12"}"#,
+        )
+        .await
+        .unwrap();
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"board_updated", "topic": "deck/board/7"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "board_updated").await;
+    assert_no_message(&mut client1).await;
+    assert_next_message(&mut client2, "card_updated").await;
+    assert_next_message(&mut client2, "board_updated").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_listen_command() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("listen notify_notification".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "listen notify_notification").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    redis
+        .publish::<_, _, ()>("notify_notification", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_notification").await;
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_sub_path_command() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(10, "").await;
+    services
+        .add_filecache_item(11, "files/Photos/cat.jpg")
+        .await;
+    services.add_filecache_item(12, "files/notes.txt").await;
+    services.add_storage_mapping("foo", 10, 10).await;
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("sub_path /Photos/".into()))
+        .await
+        .unwrap();
+
+    sleep(Duration::from_millis(10)).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files/notes.txt"}"#,
+        )
+        .await
+        .unwrap();
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"files/Photos/cat.jpg"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_file").await;
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_debounce_command() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.debounce_bounds.min = Duration::from_secs(10);
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    client
+        .send(Message::Text("debounce 1".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "debounce 10").await;
+
+    client
+        .send(Message::Text("debounce 300".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "debounce 300").await;
+
+    client
+        .send(Message::Text("debounce default".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "debounce default").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_ping_interval() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.ping.interval = Duration::from_secs(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let message = timeout(Duration::from_secs(2), client.next())
+        .await
+        .expect("no ping received")
+        .unwrap()
+        .unwrap();
+    assert!(message.is_ping());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_connection_rtt() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.ping.interval = Duration::from_secs(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let message = timeout(Duration::from_secs(2), client.next())
+        .await
+        .expect("no ping received")
+        .unwrap()
+        .unwrap();
+    assert!(message.is_ping());
+    // the pong is send while waiting for the next message
+    assert_no_message(&mut client).await;
+    sleep(Duration::from_millis(10)).await;
+
+    assert!(!LATENCIES.percentiles(&[0.5]).is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_user_connection_limit() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let mut config = services.config();
+    config.limits.max_connections_per_user = Some(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let _client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client2, "connection limit exceeded").await;
+
+    // other users are not affected
+    let mut client3 = server_handle.connect_auth("foo2", "bar").await;
+    assert_no_message(&mut client3).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_user_connection_limit_exempt() {
+    let services = Services::new().await;
+    services.add_user("monitor-1", "bar");
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.limits.max_connections_per_user = Some(1);
+    config.limits.exempt_users = vec!["monitor-*".parse().unwrap()];
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let _client1 = server_handle.connect_auth("monitor-1", "bar").await;
+    let mut client2 = server_handle.connect_auth("monitor-1", "bar").await;
+    assert_no_message(&mut client2).await;
+
+    let _client3 = server_handle.connect_auth("foo", "bar").await;
+    let mut client4 = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client4, "connection limit exceeded").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_ip_connection_limit() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let mut config = services.config();
+    config.limits.max_connections_per_ip = Some(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let _client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+    assert_next_message(&mut client2, "connection limit for ip exceeded").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_forwarded_for_trust_last() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let mut config = services.config();
+    config.limits.max_connections_per_ip = Some(1);
+    config.forwarded_for_trust = ForwardedForTrust::Last;
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let mut clients = Vec::new();
+    for (user, forwarded_for) in [
+        ("foo", "10.0.0.1, 192.0.2.7"),
+        ("foo2", "10.0.0.2, 192.0.2.7"),
+    ]
+    .iter()
+    {
+        let mut request = format!("ws://127.0.0.1:{}/ws", server_handle.port)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("X-Forwarded-For", forwarded_for.parse().unwrap());
+        let (mut client, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        client.send(Message::Text(user.to_string())).await.unwrap();
+        client.send(Message::Text("bar".into())).await.unwrap();
+        assert_next_message(&mut client, "authenticated").await;
+        clients.push(client);
+    }
+
+    // the addresses added by the client are ignored, both connections are from the same ip
+    assert_next_message(&mut clients[1], "connection limit for ip exceeded").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_global_connection_limit() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.limits.max_connections = Some(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client2, "global connection limit exceeded").await;
+
+    // closed connections no longer count against the limit
+    drop(client1);
+    drop(client2);
+    sleep(Duration::from_millis(50)).await;
+    let mut client3 = server_handle.connect_auth("foo", "bar").await;
+    assert_no_message(&mut client3).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_memory_limit() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    // any process uses more than a kilobyte
+    let mut config = services.config();
+    config.limits.max_memory = Some(1024);
+    let server_handle = services.spawn_server_with_config(config).await;
+    sleep(Duration::from_millis(50)).await;
+
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client, "memory limit exceeded").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_message_rate_limit() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.limits.max_messages_per_second = Some(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    for _ in 0..3 {
+        redis
+            .publish::<_, _, ()>(
+                "notify_custom",
+                r#"{"user":"foo", "message":"my_custom_message"}"#,
+            )
+            .await
+            .unwrap();
+    }
+
+    // a burst of twice the rate is allowed
+    assert_next_message(&mut client, "my_custom_message").await;
+    assert_next_message(&mut client, "my_custom_message").await;
+    assert_no_message(&mut client).await;
+
+    // messages over the rate are delivered once the rate allows it
+    let deferred = timeout(Duration::from_millis(1500), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(deferred.to_text().unwrap(), "my_custom_message");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_tune_connection_limit() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let _client1 = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_config",
+            r#"{"tune":{"max_connections_per_user":1}}"#,
+        )
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client2 = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client2, "connection limit exceeded").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_handshake_banner() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.handshake_banner = true;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    assert_next_json(
+        &mut client,
+        serde_json::json!({
+            "type": "banner",
+            "version": 1,
+            "max_version": 2,
+            "ping_interval": 30,
+            "debounce": {"file": 60, "activity": 120, "notification": 30},
+            "max_frame_size": 65536,
+            "encodings": ["text", "msgpack"],
+            "events": ["file", "activity", "notification", "custom"],
+            "commands": ["version", "resume_token", "tag", "untag", "mode", "encoding", "capabilities", "listen", "debounce", "sub_path", "unsub_path", "subscribe", "unsubscribe", "ack_mode", "ack"],
+        }),
+    )
+    .await;
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_capabilities_command() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("version 2".into()))
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "version", "version": 2}),
+    )
+    .await;
+
+    client
+        .send(Message::Text("capabilities".into()))
+        .await
+        .unwrap();
+    let message = timeout(Duration::from_millis(200), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let banner: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+    assert_eq!(banner["type"], "banner");
+    assert_eq!(banner["version"], 2);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_capabilities_document() {
+    let services = Services::new().await;
+
+    let redis_addr = ListRedis::spawn().await;
+    let mut config = services.config();
+    config.redis = vec![format!("redis://{}", redis_addr).parse().unwrap()];
+    config.file_change_hints = true;
+    let storage_mapping =
+        StorageMapping::with_backend(StaticMapping::default(), PathMatch::default());
+    let app = App::with_storage_mapping(storage_mapping, config, LOG_HANDLE.clone())
+        .await
+        .unwrap();
+    announce(&app).await.unwrap();
+
+    let mut redis = redis::Client::open(format!("redis://{}", redis_addr))
+        .unwrap()
+        .get_async_connection()
+        .await
+        .unwrap();
+    let id = InstanceInfo::new(&app).id;
+    let document: String = redis
+        .get(format!("notify_push_capabilities_{}", id))
+        .await
+        .unwrap();
+    let document: serde_json::Value = serde_json::from_str(&document).unwrap();
+    assert_eq!(document["protocol_versions"], serde_json::json!([1, 2]));
+    let events = document["events"].as_array().unwrap();
+    assert!(events.contains(&serde_json::json!("notify_storage_update")));
+    assert!(events.contains(&serde_json::json!("notify_custom")));
+    let features = document["features"].as_array().unwrap();
+    assert!(features.contains(&serde_json::json!("custom_tags")));
+    assert!(features.contains(&serde_json::json!("file_change_hints")));
+    assert!(!features.contains(&serde_json::json!("rooms")));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_mobile_mode_batches_messages() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("mode mobile".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "mode mobile").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"my_custom_message"}"#,
+        )
+        .await
+        .unwrap();
+
+    // held back until the batch is send
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_sharded_registry() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.registry = RegistryConfig {
+        kind: RegistryKind::Sharded,
+        shards: Some(4),
+    };
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo", "message":"my_custom_message"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "my_custom_message").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_room_relay() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    services.allow_room("foo", "board");
+    services.allow_room("foo2", "board");
+
+    let mut config = services.config();
+    config.rooms = true;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    client1
+        .send(Message::Text("join board".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client1, "joined board").await;
+    client2
+        .send(Message::Text("join board".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client2, "joined board").await;
+
+    client1
+        .send(Message::Text(r#"relay board {"typing":true}"#.into()))
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client2,
+        serde_json::json!({"type": "relay", "room": "board", "user": "foo", "payload": {"typing": true}}),
+    )
+    .await;
+    // the sender doesn't get its own message back
+    assert_no_message(&mut client1).await;
+
+    client1
+        .send(Message::Text("join other".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client1, "err: access to room denied").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_unsupported_nextcloud_version() {
+    let services = Services::new().await;
+    sqlx::query(
+        "UPDATE oc_appconfig SET configvalue = '8.2.0.12' WHERE configkey = 'installedversion'",
+    )
+    .execute(&services.db)
+    .await
+    .unwrap();
+
+    let result = App::with_connection(
+        services.db.clone(),
+        services.config(),
+        LOG_HANDLE.clone(),
+        false,
+    )
+    .await;
+    let error = result
+        .err()
+        .expect("old Nextcloud versions should be rejected");
+    assert!(error
+        .to_string()
+        .contains("Nextcloud 8.2.0.12 is not supported"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pre_auth_replayed() {
+    let services = Services::new().await;
+
+    let server_handle = services.spawn_server().await;
+
+    let mut redis = services.redis_client().await;
+    let event = r#"{"user":"foo", "token": "Jq8fXc2LrT5wKz9mVb3N"}"#;
+    redis
+        .publish::<_, _, ()>("notify_pre_auth", event)
+        .await
+        .unwrap();
+
+    sleep(Duration::from_millis(100)).await;
+
+    let _client = server_handle.connect_auth("", "Jq8fXc2LrT5wKz9mVb3N").await;
+
+    // receiving the same event again doesn't make the used token valid again
+    redis
+        .publish::<_, _, ()>("notify_pre_auth", event)
+        .await
+        .unwrap();
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("".into())).await.unwrap();
+    client
+        .send(Message::Text("Jq8fXc2LrT5wKz9mVb3N".into()))
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "err: Invalid credentials").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pre_auth_weak_token() {
+    let services = Services::new().await;
+
+    let server_handle = services.spawn_server().await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_pre_auth",
+            r#"{"user":"foo", "token": "aaaaaaaaaaaaaaaaaaaaaaaa"}"#,
+        )
+        .await
+        .unwrap();
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("".into())).await.unwrap();
+    client
+        .send(Message::Text("aaaaaaaaaaaaaaaaaaaaaaaa".into()))
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "err: Invalid credentials").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pre_auth_hashed_token() {
+    let services = Services::new().await;
+
+    let server_handle = services.spawn_server().await;
+
+    // sha256 of "Jq8fXc2LrT5wKz9mVb3N"
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_pre_auth",
+            r#"{"user":"foo", "token_hash": "c77e6a5ecac8725db86eb69dad80111f4a735fc7587bd78d9dbf3980507ca3cd"}"#,
+        )
+        .await
+        .unwrap();
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut client = server_handle.connect_auth("", "Jq8fXc2LrT5wKz9mVb3N").await;
+
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_sse() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+
+    let http = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/events", server_handle.port);
+    let unauthorized = http
+        .get(&url)
+        .basic_auth("foo", Some("wrong"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+
+    let mut response = http
+        .get(&url)
+        .basic_auth("foo", Some("bar"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    let chunk = timeout(Duration::from_secs(1), response.chunk())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        std::str::from_utf8(&chunk).unwrap(),
+        "data:{\"type\":\"activity\"}\n\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_poll() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+
+    let url = format!("http://127.0.0.1:{}/poll?timeout=2", server_handle.port);
+    let poll = spawn(async move {
+        reqwest::Client::new()
+            .get(&url)
+            .basic_auth("foo", Some("bar"))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap()
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        timeout(Duration::from_secs(1), poll)
+            .await
+            .unwrap()
+            .unwrap(),
+        serde_json::json!({"messages": [{"type": "activity"}]})
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_poll_keep_alive() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.fallback_keep_alive = Some(Duration::from_millis(200));
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let url = format!("http://127.0.0.1:{}/poll?timeout=1", server_handle.port);
+    let mut response = reqwest::Client::new()
+        .get(&url)
+        .basic_auth("foo", Some("bar"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let chunk = timeout(Duration::from_millis(500), response.chunk())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(&chunk[..], b" ");
+
+    let body = timeout(Duration::from_secs(2), response.text())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+        serde_json::json!({"messages": []})
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_static_storage_mapping() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut storages = HashMap::new();
+    storages.insert(
+        10,
+        vec![MountAccess {
+            user: "foo".into(),
+            root: "foo".into(),
+            mount_point: None,
+        }],
+    );
+    let storage_mapping =
+        StorageMapping::with_backend(StaticMapping::new(storages), PathMatch::default());
+    let app = App::with_storage_mapping(storage_mapping, services.config(), LOG_HANDLE.clone())
+        .await
+        .unwrap();
+
+    let server_handle = services.spawn_app(app).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/bar"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_file").await;
+}
+
+/// Mapping backend that only returns a mapping once it's released
+struct GatedMapping {
+    release: Arc<Semaphore>,
+}
+
+impl MappingBackend for GatedMapping {
+    fn load_storage_mapping(
+        &self,
+        _storage: u32,
+    ) -> BoxFuture<'_, color_eyre::Result<Vec<MountAccess>>> {
+        async move {
+            self.release.acquire().await.unwrap().forget();
+            Ok(vec![MountAccess {
+                user: "foo".into(),
+                root: "foo".into(),
+                mount_point: None,
+            }])
+        }
+        .boxed()
+    }
+
+    fn get_file_change<'a>(
+        &'a self,
+        _storage: u32,
+        _path: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<Option<FileChange>>> {
+        ready(Ok(None)).boxed()
+    }
+}
+
+/// Backend that can't be reached while `down` is set
+struct FlakyMapping {
+    down: Arc<AtomicBool>,
+}
+
+impl FlakyMapping {
+    fn check(&self) -> color_eyre::Result<()> {
+        if self.down.load(Ordering::SeqCst) {
+            Err(color_eyre::eyre::eyre!("database is down"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl MappingBackend for FlakyMapping {
+    fn load_storage_mapping(
+        &self,
+        _storage: u32,
+    ) -> BoxFuture<'_, color_eyre::Result<Vec<MountAccess>>> {
+        ready(self.check().map(|_| Vec::new())).boxed()
+    }
+
+    fn get_file_change<'a>(
+        &'a self,
+        _storage: u32,
+        _path: &'a str,
+    ) -> BoxFuture<'a, color_eyre::Result<Option<FileChange>>> {
+        ready(self.check().map(|_| None)).boxed()
+    }
+
+    fn ping(&self) -> BoxFuture<'_, color_eyre::Result<()>> {
+        ready(self.check()).boxed()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_database_reconnect() {
+    let services = Services::new().await;
+
+    let down = Arc::new(AtomicBool::new(false));
+    let storage_mapping =
+        StorageMapping::with_backend(FlakyMapping { down: down.clone() }, PathMatch::default());
+    let app = Arc::new(
+        App::with_storage_mapping(storage_mapping, services.config(), LOG_HANDLE.clone())
+            .await
+            .unwrap(),
+    );
+    let mut server_handle = services.spawn_shared_app(app.clone()).await;
+    server_handle
+        .daemon
+        .spawn_background("database reconnect", database_reconnect_loop);
+    sleep(Duration::from_millis(50)).await;
+    assert!(app.health().await.database.is_ok());
+
+    // a failing query triggers the check
+    down.store(true, Ordering::SeqCst);
+    spawn({
+        let app = app.clone();
+        async move {
+            app.dispatch(Event::StorageUpdate(StorageUpdate {
+                storage: 10,
+                path: "foo/bar".into(),
+            }))
+            .await
+        }
+    });
+    sleep(Duration::from_millis(100)).await;
+    assert!(!app.health().await.database.is_ok());
+
+    // the first reconnect is attempted after a second
+    down.store(false, Ordering::SeqCst);
+    sleep(Duration::from_millis(500)).await;
+    assert!(!app.health().await.database.is_ok());
+    sleep(Duration::from_millis(1000)).await;
+    assert!(app.health().await.database.is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_event_limits_fair_across_storages() {
+    let limits = Arc::new(EventLimits::new(1, 1));
+    let update = |storage: u32| {
+        Event::StorageUpdate(StorageUpdate {
+            storage,
+            path: "foo".into(),
+        })
+    };
+    let handled = Arc::new(Mutex::new(Vec::new()));
+
+    let first = limits.acquire(&update(10)).await;
+    // a burst of updates for the storage of one user, followed by a single update for another user
+    let mut waiting = Vec::new();
+    for storage in [10, 10, 10, 10, 11].iter().copied() {
+        let limits = limits.clone();
+        let handled = handled.clone();
+        let event = update(storage);
+        waiting.push(spawn(async move {
+            let _permit = limits.acquire(&event).await;
+            handled.lock().unwrap().push(storage);
+            sleep(Duration::from_millis(10)).await;
+        }));
+        sleep(Duration::from_millis(10)).await;
+    }
+    drop(first);
+    for task in waiting {
+        timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    // the other storage gets its turn after the first waiting update instead of after the whole burst
+    assert_eq!(vec![10, 11, 10, 10, 10], *handled.lock().unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_user_path_normalized_root() {
+    let mut storages = HashMap::new();
+    // the root is stored decomposed
+    storages.insert(
+        10,
+        vec![MountAccess {
+            user: "foo".into(),
+            root: "Cafe\u{301}".into(),
+            mount_point: Some("/foo/files/Shared/".into()),
+        }],
+    );
+    // the root is stored composed
+    storages.insert(
+        11,
+        vec![MountAccess {
+            user: "foo".into(),
+            root: "Caf\u{e9}".into(),
+            mount_point: Some("/foo/files/Other/".into()),
+        }],
+    );
+    let mapping = StorageMapping::with_backend(
+        StaticMapping::new(storages),
+        PathMatch {
+            normalize_unicode: true,
+            ..PathMatch::default()
+        },
+    );
+
+    for storage in [10, 11].iter() {
+        for path in ["Cafe\u{301}/doc.txt", "Caf\u{e9}/doc.txt"].iter() {
+            let users = mapping
+                .get_user_paths_for_storage_path(*storage, path)
+                .await
+                .unwrap();
+            let expected = if *storage == 10 {
+                "/Shared/doc.txt"
+            } else {
+                "/Other/doc.txt"
+            };
+            assert_eq!(
+                vec![(UserId::new("foo"), Some(expected.to_string()))],
+                users
+            );
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_storage_mapping_invalidate_during_lookup() {
+    let release = Arc::new(Semaphore::new(0));
+    let mapping = Arc::new(StorageMapping::with_backend(
+        GatedMapping {
+            release: release.clone(),
+        },
+        PathMatch::default(),
+    ));
+    let lookup = |mapping: Arc<StorageMapping>| async move {
+        mapping
+            .get_users_for_storage_path(10, "foo/bar")
+            .await
+            .map(|users| users.collect::<Vec<_>>())
+            .unwrap()
+    };
+
+    let pending = spawn(lookup(mapping.clone()));
+    sleep(Duration::from_millis(50)).await;
+    mapping.invalidate_all();
+    release.add_permits(1);
+    let users = timeout(Duration::from_secs(1), pending)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(vec![UserId::new("foo")], users);
+
+    // invalidate continuously while the mapping is loaded and cached again
+    release.add_permits(1000);
+    let invalidate = spawn({
+        let mapping = mapping.clone();
+        async move {
+            for _ in 0..1000 {
+                mapping.invalidate_storage(10);
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+    for _ in 0..100 {
+        assert_eq!(vec![UserId::new("foo")], lookup(mapping.clone()).await);
+    }
+    invalidate.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_event_input() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let path = std::env::temp_dir().join(format!("notify_push_events_{}", std::process::id()));
+    std::fs::write(
+        &path,
+        "# comment\n\nnotify_activity {\"user\":\"foo\"}\nnotify_custom invalid\n",
+    )
+    .unwrap();
+
+    let mut server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    let input = path.clone();
+    server_handle
+        .daemon
+        .spawn_background("event input", |app, cancel| {
+            event_input_loop(app, input, cancel)
+        });
+
+    assert_next_message(&mut client, "notify_activity").await;
+    assert_no_message(&mut client).await;
+    std::fs::remove_file(path).ok();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_unix_socket_in_use() {
+    let services = Services::new().await;
+    let app = Arc::new(
+        App::with_connection(
+            services.db.clone(),
+            services.config(),
+            LOG_HANDLE.clone(),
+            false,
+        )
+        .await
+        .unwrap(),
+    );
+
+    let socket_path =
+        std::env::temp_dir().join(format!("notify_push_test_{}.sock", std::process::id()));
+    std::fs::remove_file(&socket_path).ok();
+    let _listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+
+    let (_cancel_tx, cancel_rx) = oneshot::channel();
+    assert!(serve(app, Bind::Unix(socket_path.clone(), 0o666), cancel_rx, None).is_err());
+    assert!(socket_path.exists());
+
+    std::fs::remove_file(&socket_path).ok();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_mount_added_invalidates_mapping() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    services.add_filecache_item(10, "foo").await;
+    services.add_storage_mapping("foo", 10, 10).await;
+
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/bar"}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "notify_file").await;
+    assert_no_message(&mut client2).await;
+
+    services.add_storage_mapping("foo2", 10, 10).await;
+    redis
+        .publish::<_, _, ()>("notify_mount_added", r#"{"user":"foo2", "storage":10}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client2, "notify_file").await;
+
+    // new connection, so the message isn't debounced
+    let mut client3 = server_handle.connect_auth("foo2", "bar").await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/bar"}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client3, "notify_file").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_share_deleted_v2() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    client
+        .send(Message::Text("version 2".into()))
+        .await
+        .unwrap();
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "version", "version": 2}),
+    )
+    .await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_user_share_deleted",
+            r#"{"user":"foo", "storage": 10, "file_id": 12}"#,
+        )
+        .await
+        .unwrap();
+
+    assert_next_json(
+        &mut client,
+        serde_json::json!({"type": "file", "file_id": 12, "reason": "share_deleted"}),
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_statsd_push() {
+    let services = Services::new().await;
+    let app = App::with_connection(
+        services.db.clone(),
+        services.config(),
+        LOG_HANDLE.clone(),
+        false,
+    )
+    .await
+    .unwrap();
+
+    let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target = format!("datadog://{}", agent.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let (_cancel, cancel_rx) = oneshot::channel();
+    spawn(metrics_push_loop(Arc::new(app), target, cancel_rx));
+
+    let mut buf = vec![0; 2048];
+    let len = timeout(Duration::from_secs(1), agent.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let packet = String::from_utf8_lossy(&buf[..len]);
+    // the counters are shared with the other tests, so only the format is checked
+    assert!(packet.lines().any(|line| {
+        line.starts_with("notify_push.active_connection_count:") && line.ends_with("|g")
+    }));
+    assert!(packet.lines().any(|line| {
+        line.starts_with("notify_push.disconnect_error_count:")
+            && line.ends_with("|g|#reason:peer_reset")
+    }));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_webhook() {
+    let services = Services::new().await;
+
+    let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel();
+    let hook = warp::post()
+        .and(warp::header::<String>(SIGNATURE_HEADER))
+        .and(warp::body::bytes())
+        .map(move |signature: String, body: warp::hyper::body::Bytes| {
+            received_tx.send((signature, body.to_vec())).ok();
+            "ok"
+        });
+    let hook_tcp = listen_available_port().await.unwrap();
+    let hook_addr = hook_tcp.local_addr().unwrap();
+    spawn(warp::serve(hook).run_incoming(TcpListenerStream::new(hook_tcp)));
+
+    let mut config = services.config();
+    config.webhook = WebhookConfig {
+        urls: vec![format!("http://{}/hook", hook_addr).parse().unwrap()],
+        events: vec!["notify_notification".into()],
+        secret: Some("secret".into()),
+    };
+    let webhook = config.webhook.clone();
+    let app = Arc::new(
+        App::with_connection(services.db.clone(), config, LOG_HANDLE.clone(), false)
+            .await
+            .unwrap(),
+    );
+    let (_cancel, cancel_rx) = oneshot::channel();
+    spawn(webhook_loop(app.clone(), webhook, cancel_rx));
+    let _server_handle = services.spawn_shared_app(app).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_custom", r#"{"user":"foo", "message":"ignored"}"#)
+        .await
+        .unwrap();
+    redis
+        .publish::<_, _, ()>("notify_notification", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    let (signature, body) = timeout(Duration::from_secs(1), received_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(sign("secret", &body), signature);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        serde_json::json!({"user": "foo", "type": "notify_notification", "message": {"type": "notification"}}),
+        body
+    );
+    assert!(timeout(Duration::from_millis(100), received_rx.recv())
+        .await
+        .is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_restart_listener() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    let mut redis = services.redis_client().await;
+
+    server_handle.daemon.stop_listener().await;
+    redis
+        .publish::<_, _, ()>("notify_notification", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_no_message(&mut client).await;
+
+    // clients stay connected while the listener restarts
+    server_handle.daemon.start_listener().await;
+    sleep(Duration::from_millis(50)).await;
+    redis
+        .publish::<_, _, ()>("notify_notification", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_notification").await;
+}